@@ -0,0 +1,36 @@
+use std::{hint::black_box, mem};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dfs::fs::block::ReplicatedBlock;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+const BLOCKS: usize = 1_000_000;
+
+/// Heap bytes the block map takes per block, not counting the id strings,
+/// which are shared with the namespace.
+fn block_map_memory(c: &mut Criterion) {
+    let ids = common::block_ids(BLOCKS);
+    let stores = common::store_ids();
+    let before = common::live_bytes();
+    let map = common::fill(&ids, &stores);
+    let bytes = common::live_bytes() - before;
+    println!(
+        "{} blocks x{}: {} bytes per block, {} of them ReplicatedBlock",
+        map.len(),
+        common::REPLICATION,
+        bytes / BLOCKS,
+        mem::size_of::<ReplicatedBlock>(),
+    );
+    let mut i = 0;
+    c.bench_function("stores_1m_blocks", |b| {
+        b.iter(|| {
+            i = (i + 7919) % BLOCKS;
+            black_box(map.stores(&ids[i]).len())
+        })
+    });
+}
+
+criterion_group!(benches, block_map_memory);
+criterion_main!(benches);
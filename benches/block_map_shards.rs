@@ -0,0 +1,67 @@
+use std::{hint::black_box, num::NonZeroUsize, thread};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dfs::{
+    fs::{
+        block::{
+            BlockBody, BlockId, BlockShard, ReplicatedBlock, ReplicatedBlocksMap, ReportedBlock,
+            StoreIndex,
+        },
+        inode::ROOT_INODE,
+    },
+    store::StoreId,
+};
+
+const BLOCKS: usize = 100_000;
+const OPS_PER_THREAD: usize = 10_000;
+const SHARDS: usize = 16;
+
+fn block_map(shards: usize, ids: &[BlockId]) -> ReplicatedBlocksMap {
+    let mut map = ReplicatedBlocksMap::with_shards(NonZeroUsize::new(shards).unwrap());
+    for id in ids {
+        let block = ReplicatedBlock::new(BlockBody::new(1, 0), ROOT_INODE + 1);
+        map.insert(id.clone(), block).unwrap();
+    }
+    map
+}
+
+/// Every other operation records a replica; the rest look up where one is.
+fn op(shard: &mut BlockShard, store: StoreIndex, id: &BlockId, i: usize) {
+    if i.is_multiple_of(2) {
+        let block = ReportedBlock::new(id.clone(), BlockBody::new(1, 0));
+        shard.push_store(store, block).unwrap();
+    } else {
+        black_box(shard.stores(id).len());
+    }
+}
+
+fn block_map_shards(c: &mut Criterion) {
+    let ids: Vec<BlockId> = (0..BLOCKS).map(|i| format!("blk_{i:010}").into()).collect();
+    let stores: Vec<StoreId> = (0..8).map(|i| format!("store-{i}").into()).collect();
+    for threads in [1, 2, 4, 8] {
+        for shards in [1, SHARDS] {
+            let mut map = block_map(shards, &ids);
+            let stores: Vec<StoreIndex> = stores.iter().map(|s| map.intern_store(s)).collect();
+            let locks = map.shards();
+            c.bench_function(&format!("{shards}_shards_{threads}_threads"), |b| {
+                b.iter(|| {
+                    thread::scope(|s| {
+                        for t in 0..threads {
+                            let (ids, store) = (&ids, stores[t]);
+                            s.spawn(move || {
+                                for i in 0..OPS_PER_THREAD {
+                                    let id = &ids[(t * 7919 + i * 31) % BLOCKS];
+                                    let mut shard = locks.write(locks.shard_of(id));
+                                    op(&mut shard, store, id, i);
+                                }
+                            });
+                        }
+                    })
+                })
+            });
+        }
+    }
+}
+
+criterion_group!(benches, block_map_shards);
+criterion_main!(benches);
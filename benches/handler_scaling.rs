@@ -0,0 +1,113 @@
+use std::{
+    hint::black_box,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::SystemTime,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dfs::{
+    clock::SystemClock,
+    fs::{
+        block::{BlockIdGenerator, ReplicatedBlocksMap},
+        inode::ROOT_INODE,
+        perm::{Caller, Permission, DEFAULT_SUPERUSER},
+        virt::{Directory, DirectoryAttribute, FsNode, FsNodeAttribute, FsNodeBody, OpenFileTable},
+    },
+    proto::control::{ChmodReq, ControlReq, ListReq, MkdirReq, StatReq},
+    server::control::{config::ControlSettings, handler::Handler},
+    store::StoreStatusesMap,
+};
+
+const DIRS: usize = 64;
+const OPS_PER_THREAD: usize = 2_000;
+
+fn handler(caller: &Caller) -> Handler {
+    let mut handler = Handler::new(
+        FsNode::new(
+            FsNodeAttribute::new(ROOT_INODE, SystemTime::now(), Permission::default()),
+            FsNodeBody::Directory(Directory::new(DirectoryAttribute::new())),
+        ),
+        OpenFileTable::new(),
+        StoreStatusesMap::new(),
+        ReplicatedBlocksMap::new(),
+        BlockIdGenerator::new(),
+        ControlSettings::default(),
+        Arc::new(SystemClock),
+    );
+    for i in 0..DIRS {
+        let req = MkdirReq {
+            path: format!("/d{i}/e"),
+            create_parents: true,
+        };
+        handler.handle_req(caller, ControlReq::MkdirReq(req));
+    }
+    handler
+}
+
+/// 95% reads: one in twenty is a chmod and the rest alternate between stat
+/// and list.
+fn request(thread: usize, i: usize) -> ControlReq {
+    let n = thread * 7 + i;
+    let path = format!("/d{}", n % DIRS);
+    match i % 20 {
+        0 => ControlReq::ChmodReq(ChmodReq { path, mode: 0o755 }),
+        n if n % 2 == 0 => ControlReq::StatReq(StatReq { path }),
+        _ => ControlReq::ListReq(ListReq {
+            path,
+            start_after: None,
+            limit: None,
+        }),
+    }
+}
+
+/// Reads under the shared lock and writes under the exclusive one, as the
+/// server takes them.
+fn run(shared: &RwLock<Handler>, caller: &Caller, threads: usize) {
+    thread::scope(|s| {
+        for t in 0..threads {
+            s.spawn(move || {
+                for i in 0..OPS_PER_THREAD {
+                    let res = shared
+                        .read()
+                        .unwrap()
+                        .handle_read_req(caller, request(t, i));
+                    let resp = match res {
+                        Ok(resp) => resp,
+                        Err(msg) => shared.write().unwrap().handle_req(caller, msg),
+                    };
+                    black_box(resp);
+                }
+            });
+        }
+    })
+}
+
+fn handler_scaling(c: &mut Criterion) {
+    let caller = Caller::new(DEFAULT_SUPERUSER.into(), vec![DEFAULT_SUPERUSER.into()]);
+    for threads in [1, 2, 4, 8] {
+        let single = Mutex::new(handler(&caller));
+        c.bench_function(&format!("mutex_{threads}_threads"), |b| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for t in 0..threads {
+                        let (single, caller) = (&single, &caller);
+                        s.spawn(move || {
+                            for i in 0..OPS_PER_THREAD {
+                                let msg = request(t, i);
+                                black_box(single.lock().unwrap().handle_req(caller, msg));
+                            }
+                        });
+                    }
+                })
+            })
+        });
+        let shared = RwLock::new(handler(&caller));
+        c.bench_function(&format!("rwlock_{threads}_threads"), |b| {
+            b.iter(|| run(&shared, &caller, threads))
+        });
+    }
+}
+
+criterion_group!(benches, handler_scaling);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+use std::{
+    hint::black_box,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dfs::fs::virt::{OpenFileTable, PathSplit};
+
+const OPEN_FILES: usize = 1_000_000;
+const TTL: Duration = Duration::from_secs(60);
+
+/// A timer tick over a million open files of which none has expired.
+fn lease_timeout(c: &mut Criterion) {
+    let start = Instant::now();
+    let mut table = OpenFileTable::new();
+    let client: Arc<str> = Arc::from("client");
+    for i in 0..OPEN_FILES {
+        let path = PathSplit::from_uri(&format!("/dir{}/file{i}", i % 1000));
+        let now = start + Duration::from_millis((i % 1000) as u64);
+        table.open(path, client.clone(), true, now, TTL).unwrap();
+    }
+    let now = start + Duration::from_secs(1);
+    c.bench_function("clear_timeout_1m_open", |b| {
+        b.iter(|| black_box(table.clear_timeout(TTL, black_box(now))))
+    });
+}
+
+criterion_group!(benches, lease_timeout);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+use std::{hint::black_box, time::SystemTime};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dfs::fs::{
+    inode::{InodeIdGenerator, ROOT_INODE},
+    perm::Permission,
+    virt::{
+        Directory, DirectoryAttribute, FsNode, FsNodeAttribute, FsNodeBody, PathCursor, PathSplit,
+    },
+};
+
+const DEPTH: usize = 32;
+const FAN_OUT: usize = 16;
+
+/// `/d0/d1/.../d31`, with `FAN_OUT - 1` empty siblings at every level.
+fn deep_tree() -> (FsNode, String) {
+    let mut root = FsNode::new(
+        FsNodeAttribute::new(ROOT_INODE, SystemTime::now(), Permission::default()),
+        FsNodeBody::Directory(Directory::new(DirectoryAttribute::new())),
+    );
+    let mut inode_ids = InodeIdGenerator::new();
+    let mut attr = || {
+        FsNodeAttribute::new(
+            inode_ids.next_id(),
+            SystemTime::now(),
+            Permission::default(),
+        )
+    };
+    let mut path = String::new();
+    for level in 0..DEPTH {
+        for sibling in 1..FAN_OUT {
+            let dir = PathSplit::from_uri(&format!("{path}/s{sibling}"));
+            root.create_dirs(PathCursor::new(dir).unwrap(), false, &mut attr)
+                .unwrap();
+        }
+        path.push_str(&format!("/d{level}"));
+        let dir = PathSplit::from_uri(&path);
+        root.create_dirs(PathCursor::new(dir).unwrap(), false, &mut attr)
+            .unwrap();
+    }
+    (root, path)
+}
+
+fn path_resolution(c: &mut Criterion) {
+    let (root, path) = deep_tree();
+    c.bench_function("parse_and_get", |b| {
+        b.iter(|| {
+            let path = PathSplit::from_uri(black_box(&path));
+            black_box(root.get(PathCursor::new(path)).is_ok())
+        })
+    });
+    c.bench_function("parse_and_get_by_segs", |b| {
+        b.iter(|| {
+            let path = PathSplit::from_uri(black_box(&path));
+            black_box(root.get_by_segs(path.names()).is_some())
+        })
+    });
+    let parsed = PathSplit::from_uri(&path);
+    c.bench_function("get", |b| {
+        b.iter(|| {
+            black_box(
+                root.get(PathCursor::new(black_box(&parsed).clone()))
+                    .is_ok(),
+            )
+        })
+    });
+    c.bench_function("get_by_segs", |b| {
+        b.iter(|| black_box(root.get_by_segs(black_box(&parsed).names()).is_some()))
+    });
+}
+
+criterion_group!(benches, path_resolution);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dfs::{
+    fs::block::{BlockBody, BlockList, BlockReport, BlockReportType, ReportedBlock},
+    proto::{control::BlockReportReq, format::WireFormat},
+};
+
+const BLOCKS: usize = 100_000;
+
+fn block_report() -> BlockReportReq {
+    let mut body = BlockList::new();
+    for i in 0..BLOCKS {
+        let id = format!("blk_{i:010}").into();
+        body.push(ReportedBlock::new(id, BlockBody::new(128 << 20, 1)));
+    }
+    BlockReportReq {
+        store: "store-0".into(),
+        report: BlockReport::new(BlockReportType::Full, body),
+        chunk: None,
+    }
+}
+
+/// Encoding and decoding a full report of 100k blocks in each format.
+fn wire_format(c: &mut Criterion) {
+    let report = block_report();
+    for &format in WireFormat::ALL {
+        let buf = format.encode(&report).unwrap();
+        println!(
+            "{format}: {BLOCKS} blocks in {} bytes, {} per block",
+            buf.len(),
+            buf.len() / BLOCKS,
+        );
+        let mut group = c.benchmark_group(format!("block_report_{format}"));
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_function("encode", |b| {
+            b.iter(|| black_box(format.encode(&report).unwrap()))
+        });
+        group.bench_function("decode", |b| {
+            b.iter(|| black_box(format.decode::<BlockReportReq>(&buf).unwrap()))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, wire_format);
+criterion_main!(benches);
@@ -0,0 +1,396 @@
+use std::{io, pin::pin, process::ExitCode, time::Instant};
+
+use dfs::{
+    client::DfsClient,
+    fs::{epoch_millis::to_millis, fsck::FsckSummary, perm::Caller},
+    proto::{control::NamespaceEventKind, format::WireFormat},
+};
+use futures::TryStreamExt;
+use tokio::io::AsyncWriteExt;
+use tracing_subscriber::EnvFilter;
+
+const CONTROL_ENV: &str = "DFS_CONTROL";
+const DEFAULT_CONTROL: &str = "127.0.0.1:9000";
+const WIRE_FORMAT_ENV: &str = "DFS_WIRE_FORMAT";
+const TRACE_ID_ENV: &str = "DFS_TRACE_ID";
+
+const RM_FLAGS: [&str; 2] = ["-r", "-skipTrash"];
+const FSCK_FLAGS: [&str; 2] = ["-listCorrupt", "-deleteOrphans"];
+
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_NOT_FOUND: u8 = 3;
+const EXIT_TRANSPORT: u8 = 4;
+
+const USAGE: &str = "usage: dfs [--control ADDR] <command>
+commands:
+  ls PATH
+  put LOCAL REMOTE
+  get REMOTE LOCAL
+  rm [-r] [-skipTrash] PATH
+  stat PATH
+  du PATH
+  count [-q] PATH
+  checksum PATH
+  report
+  fsck [-listCorrupt] [-deleteOrphans] PATH
+  metasave CONTROL_LOCAL_PATH
+  events [-after TXID] PATH
+the control address defaults to $DFS_CONTROL or 127.0.0.1:9000
+set $DFS_WIRE_FORMAT=json to send control messages as json
+set $DFS_TRACE_ID to tag every request with an id that appears in server logs
+logs go to stderr, filtered by $RUST_LOG (default warn)";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        )
+        .init();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut control = std::env::var(CONTROL_ENV).unwrap_or_else(|_| DEFAULT_CONTROL.to_string());
+    if args.first().map(|arg| arg.as_str()) == Some("--control") {
+        if args.len() < 2 {
+            eprintln!("{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+        control = args.remove(1);
+        args.remove(0);
+    }
+    let Some(command) = Command::parse(&args) else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(EXIT_USAGE);
+    };
+    match run(&control, command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("dfs: {e}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+enum Command<'a> {
+    Ls(&'a str),
+    Put {
+        local: &'a str,
+        remote: &'a str,
+    },
+    Get {
+        remote: &'a str,
+        local: &'a str,
+    },
+    Rm {
+        path: &'a str,
+        recursive: bool,
+        skip_trash: bool,
+    },
+    Stat(&'a str),
+    Du(&'a str),
+    Count {
+        path: &'a str,
+        quota: bool,
+    },
+    Checksum(&'a str),
+    Report,
+    Fsck {
+        path: &'a str,
+        list_corrupt: bool,
+        delete_orphans: bool,
+    },
+    MetaSave(&'a str),
+    Events {
+        path: &'a str,
+        after_txid: u64,
+    },
+}
+impl<'a> Command<'a> {
+    fn parse(args: &'a [String]) -> Option<Self> {
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        Some(match args[..] {
+            ["ls", path] => Self::Ls(path),
+            ["put", local, remote] => Self::Put { local, remote },
+            ["get", remote, local] => Self::Get { remote, local },
+            ["rm", ref flags @ .., path] if flags.iter().all(|flag| RM_FLAGS.contains(flag)) => {
+                Self::Rm {
+                    path,
+                    recursive: flags.contains(&"-r"),
+                    skip_trash: flags.contains(&"-skipTrash"),
+                }
+            }
+            ["stat", path] => Self::Stat(path),
+            ["du", path] => Self::Du(path),
+            ["count", path] => Self::Count { path, quota: false },
+            ["count", "-q", path] => Self::Count { path, quota: true },
+            ["checksum", path] => Self::Checksum(path),
+            ["report"] => Self::Report,
+            ["fsck", ref flags @ .., path]
+                if flags.iter().all(|flag| FSCK_FLAGS.contains(flag)) =>
+            {
+                Self::Fsck {
+                    path,
+                    list_corrupt: flags.contains(&"-listCorrupt"),
+                    delete_orphans: flags.contains(&"-deleteOrphans"),
+                }
+            }
+            ["metasave", path] => Self::MetaSave(path),
+            ["events", path] => Self::Events {
+                path,
+                after_txid: 0,
+            },
+            ["events", "-after", txid, path] => Self::Events {
+                path,
+                after_txid: txid.parse().ok()?,
+            },
+            _ => return None,
+        })
+    }
+}
+
+async fn run(control: &str, command: Command<'_>) -> io::Result<()> {
+    let format = match std::env::var(WIRE_FORMAT_ENV) {
+        Ok(format) => format.parse()?,
+        Err(_) => WireFormat::Bincode,
+    };
+    let client = DfsClient::connect_with(control, Caller::from_env(), format).await?;
+    if let Ok(trace_id) = std::env::var(TRACE_ID_ENV) {
+        client.set_trace_id(Some(trace_id.into()));
+    }
+    match command {
+        Command::Ls(path) => {
+            let mut entries = pin!(client.list_stream(path));
+            while let Some(entry) = entries.try_next().await? {
+                let kind = if entry.is_dir { 'd' } else { '-' };
+                let replication = entry
+                    .replication
+                    .map(|r| r.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{kind}{} {replication:>3} {:<8} {:<8} {:>14} {}",
+                    mode_string(entry.mode),
+                    entry.owner,
+                    entry.group,
+                    entry.len,
+                    entry.name
+                );
+            }
+        }
+        Command::Put { local, remote } => {
+            let start = Instant::now();
+            let mut src = tokio::fs::File::open(local).await?;
+            let mut dst = client.create(remote).await?;
+            let n = tokio::io::copy(&mut src, &mut dst).await?;
+            dst.shutdown().await?;
+            print_throughput(n, start);
+        }
+        Command::Get { remote, local } => {
+            let start = Instant::now();
+            let mut src = client.open(remote).await?;
+            let mut dst = tokio::fs::File::create(local).await?;
+            let n = tokio::io::copy(&mut src, &mut dst).await?;
+            dst.sync_all().await?;
+            src.close().await?;
+            print_throughput(n, start);
+        }
+        Command::Rm {
+            path,
+            recursive,
+            skip_trash,
+        } => client.delete_with(path, recursive, skip_trash).await?,
+        Command::Stat(path) => {
+            let status = client.stat(path).await?;
+            let replication = status
+                .replication
+                .map(|r| r.to_string())
+                .unwrap_or_else(|| "-".to_string());
+            println!("path:        {path}");
+            println!("inode:       {}", status.inode);
+            println!(
+                "type:        {}",
+                if status.is_dir { "directory" } else { "file" }
+            );
+            println!("len:         {}", status.len);
+            println!("replication: {replication}");
+            println!("blocks:      {}", status.block_count);
+            println!("children:    {}", status.children);
+            println!("owner:       {}", status.owner);
+            println!("group:       {}", status.group);
+            println!("mode:        {:o}", status.mode);
+            println!("ctime:       {}", to_millis(status.ctime));
+            println!("mtime:       {}", to_millis(status.mtime));
+            println!("atime:       {}", to_millis(status.atime));
+        }
+        Command::Du(path) => {
+            let summary = client.content_summary(path).await?.summary;
+            println!("{:>14} {:>14} {path}", summary.len, summary.space);
+        }
+        Command::Count { path, quota } => {
+            let ok = client.content_summary(path).await?;
+            if quota {
+                let limit = |limit: Option<u64>| {
+                    limit
+                        .map(|limit| limit.to_string())
+                        .unwrap_or_else(|| "none".to_string())
+                };
+                print!("{:>12} {:>14} ", limit(ok.max_nodes), limit(ok.max_space));
+            }
+            let summary = ok.summary;
+            println!(
+                "{:>12} {:>12} {:>14} {path}",
+                summary.directories, summary.files, summary.len
+            );
+        }
+        Command::Checksum(path) => {
+            let ok = client.file_checksum(path).await?;
+            let hex: String = ok.checksum.iter().map(|b| format!("{b:02x}")).collect();
+            println!("{path}\t{}\t{hex}", ok.algorithm);
+        }
+        Command::Fsck {
+            path,
+            list_corrupt,
+            delete_orphans,
+        } => {
+            let mut summary = FsckSummary::default();
+            let mut start_after = None;
+            loop {
+                let page = client
+                    .fsck_page(path, list_corrupt, delete_orphans, start_after)
+                    .await?;
+                for file in page.files {
+                    for problem in file.problems {
+                        println!("{}: {problem:?}", file.path);
+                    }
+                }
+                for block in page.orphans {
+                    let action = if delete_orphans { "deleted" } else { "found" };
+                    println!("orphaned block {block} {action}");
+                }
+                summary.merge(&page.summary);
+                start_after = page.next;
+                if start_after.is_none() {
+                    break;
+                }
+            }
+            println!("files:            {}", summary.files);
+            println!("blocks:           {}", summary.blocks);
+            println!("healthy:          {}", summary.healthy);
+            println!("under replicated: {}", summary.under_replicated);
+            println!("missing:          {}", summary.missing);
+            println!("corrupt:          {}", summary.corrupt);
+            println!("unmapped:         {}", summary.unmapped);
+            println!("orphaned:         {}", summary.orphaned);
+            println!("non contiguous:   {}", summary.non_contiguous);
+            println!("status:           {:?}", summary.verdict());
+        }
+        Command::Report => {
+            let status = client.cluster_status().await?;
+            let safe_mode = if status.safe_mode.on { "on" } else { "off" };
+            println!("capacity:         {}", status.capacity);
+            println!("used:             {}", status.used);
+            println!("remaining:        {}", status.remaining);
+            println!("files:            {}", status.files);
+            println!("directories:      {}", status.directories);
+            println!("blocks:           {}", status.blocks);
+            println!("under replicated: {}", status.under_replicated);
+            println!("corrupt:          {}", status.corrupt);
+            println!("safe mode:        {safe_mode}");
+            if status.safe_mode.invariant_violations != 0 {
+                let n = status.safe_mode.invariant_violations;
+                println!("violations:       {n} (see control node log)");
+            }
+            println!();
+            println!(
+                "{:<16} {:<21} {:<6} {:<16} {:>14} {:>14} {:>14} {:>8} {:>9}",
+                "store",
+                "addr",
+                "state",
+                "admin",
+                "capacity",
+                "used",
+                "remaining",
+                "blocks",
+                "heartbeat"
+            );
+            for store in status.stores {
+                let heartbeat = store
+                    .last_heartbeat_age
+                    .map(|age| format!("{}s", age.as_secs()))
+                    .unwrap_or_else(|| "never".to_string());
+                println!(
+                    "{:<16} {:<21} {:<6} {:<16} {:>14} {:>14} {:>14} {:>8} {:>9}",
+                    store.store,
+                    store.addr.to_string(),
+                    format!("{:?}", store.liveness),
+                    format!("{:?}", store.admin_state),
+                    store.capacity,
+                    store.used,
+                    store.remaining,
+                    store.block_count,
+                    heartbeat
+                );
+            }
+        }
+        Command::MetaSave(path) => client.metasave(path).await?,
+        Command::Events { path, after_txid } => {
+            let mut events = pin!(client.subscribe_events(path, after_txid));
+            while let Some(event) = events.try_next().await? {
+                let txid = event.txid;
+                match event.kind {
+                    NamespaceEventKind::Create { path, is_dir } => {
+                        let op = if is_dir { "mkdir" } else { "create" };
+                        println!("{txid} {op} {path}");
+                    }
+                    NamespaceEventKind::Close { path } => println!("{txid} close {path}"),
+                    NamespaceEventKind::Delete { path } => println!("{txid} delete {path}"),
+                    NamespaceEventKind::Rename { src, dst } => {
+                        println!("{txid} rename {src} {dst}")
+                    }
+                    NamespaceEventKind::SetReplication { path, replication } => {
+                        println!("{txid} setrep {replication} {path}")
+                    }
+                    NamespaceEventKind::Concat { target, sources } => {
+                        println!("{txid} concat {target} {}", sources.join(" "))
+                    }
+                    NamespaceEventKind::Missed => println!("{txid} missed, resync"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn mode_string(mode: u16) -> String {
+    (0..9)
+        .map(|i| match mode & (0o400 >> i) {
+            0 => '-',
+            _ => ['r', 'w', 'x'][i % 3],
+        })
+        .collect()
+}
+
+fn print_throughput(bytes: u64, start: Instant) {
+    let secs = start.elapsed().as_secs_f64();
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    eprintln!(
+        "{bytes} bytes in {secs:.2}s ({:.2} MiB/s)",
+        mib / secs.max(f64::EPSILON)
+    );
+}
+
+fn exit_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable => EXIT_TRANSPORT,
+        _ => EXIT_FAILURE,
+    }
+}
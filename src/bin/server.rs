@@ -1,2 +1,122 @@
+use std::{path::Path, process::ExitCode};
+
+use dfs::server::{config::Config, control::node::ControlNode, store::node::StoreNode};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+const EXIT_USAGE: u8 = 2;
+const EXIT_CONFIG: u8 = 3;
+const EXIT_START: u8 = 4;
+const EXIT_SHUTDOWN: u8 = 5;
+
+const USAGE: &str = "usage: server CONFIG
+       server --example
+--example prints a commented config with every default
+SIGHUP reloads the control node's store and host lists from CONFIG";
+
 #[tokio::main]
-async fn main() {}
+async fn main() -> ExitCode {
+    // $RUST_LOG overrides, e.g. RUST_LOG=dfs=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = match args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>()[..] {
+        ["--example"] => {
+            print!("{}", Config::example());
+            return ExitCode::SUCCESS;
+        }
+        [path] => path,
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    let config = match Config::load(Path::new(path)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("server: {path}: {e}");
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    let control = match &config.control {
+        Some(control) => match ControlNode::start(control).await {
+            Ok(node) => Some(node),
+            Err(e) => {
+                eprintln!("server: failed to start the control node: {e}");
+                return ExitCode::from(EXIT_START);
+            }
+        },
+        None => None,
+    };
+    let store = match &config.store {
+        Some(store) => match StoreNode::start(store).await {
+            Ok(node) => Some(node),
+            Err(e) => {
+                eprintln!("server: failed to start the store node: {e}");
+                return ExitCode::from(EXIT_START);
+            }
+        },
+        None => None,
+    };
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!("server: failed to listen for SIGHUP: {e}");
+            return ExitCode::from(EXIT_START);
+        }
+    };
+    loop {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => {
+                if let Err(e) = res {
+                    eprintln!("server: failed to wait for a signal: {e}");
+                }
+                break;
+            }
+            _ = hangup.recv() => reload(Path::new(path), control.as_ref()).await,
+        }
+    }
+    drop(store);
+    if let Some(control) = control {
+        if let Err(e) = control.shutdown().await {
+            eprintln!("server: failed to save the namespace: {e}");
+            return ExitCode::from(EXIT_SHUTDOWN);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// A bad edit is logged and leaves the running config in place.
+async fn reload(path: &Path, control: Option<&ControlNode>) {
+    let Some(control) = control else {
+        return;
+    };
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("not reloading {}: {e}", path.display());
+            return;
+        }
+    };
+    let Some(config) = &config.control else {
+        warn!(
+            "not reloading {}: the control section is gone",
+            path.display()
+        );
+        return;
+    };
+    match control.refresh_stores(config).await {
+        Ok(ok) => info!(
+            added = ?ok.added,
+            removed = ?ok.removed,
+            changed = ?ok.changed,
+            excluded = ?ok.excluded,
+            "store list reloaded"
+        ),
+        Err(e) => warn!("not reloading {}: {e}", path.display()),
+    }
+}
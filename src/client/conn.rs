@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+    sync::{mpsc, oneshot, Mutex},
+};
+use tracing::warn;
+
+use crate::{
+    fs::perm::Caller,
+    proto::{
+        control::{ControlReq, ControlResp},
+        format::WireFormat,
+        handshake, read_frame_as, write_frame_as, Envelope, TraceId,
+    },
+    store::{AddrResolver, StoreAddr},
+};
+
+use super::retry::{is_idempotent, RetryPolicy};
+
+pub const ADDR_CACHE_TTL: Duration = Duration::from_secs(60);
+
+type Pending = Arc<std::sync::Mutex<Option<HashMap<u64, oneshot::Sender<ControlResp>>>>>;
+
+/// Requests from all handles of a client share one connection; responses
+/// are matched back to their callers by `req_id`.
+#[derive(Debug)]
+pub struct Control {
+    addr: SocketAddr,
+    next_req_id: AtomicU64,
+    conn: Mutex<Option<Connection>>,
+    format: WireFormat,
+    caller: Caller,
+    resolver: AddrResolver,
+    trace_id: std::sync::Mutex<Option<TraceId>>,
+}
+impl Control {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::connect_with(addr, Caller::from_env(), WireFormat::Bincode).await
+    }
+    pub async fn connect_with(
+        addr: impl ToSocketAddrs,
+        caller: Caller,
+        format: WireFormat,
+    ) -> io::Result<Self> {
+        let (stream, _) = handshake::connect_with(addr, &[format]).await?;
+        Ok(Self {
+            addr: stream.peer_addr()?,
+            next_req_id: AtomicU64::new(0),
+            conn: Mutex::new(Some(Connection::new(stream, format))),
+            format,
+            caller,
+            resolver: AddrResolver::new(ADDR_CACHE_TTL),
+            trace_id: std::sync::Mutex::new(None),
+        })
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+    pub fn caller(&self) -> &Caller {
+        &self.caller
+    }
+    pub fn resolver(&self) -> &AddrResolver {
+        &self.resolver
+    }
+    /// Sent with every control and store request until changed, so an
+    /// operation can be found in the logs of every node it touched.
+    pub fn set_trace_id(&self, trace_id: Option<TraceId>) {
+        *self.trace_id.lock().unwrap() = trace_id;
+    }
+    pub fn trace_id(&self) -> Option<TraceId> {
+        self.trace_id.lock().unwrap().clone()
+    }
+    pub async fn connect_store(&self, addr: &StoreAddr) -> io::Result<TcpStream> {
+        let resolved = self.resolver.resolve(addr).await?;
+        match handshake::connect(resolved).await {
+            Ok((stream, _)) => Ok(stream),
+            Err(e) => {
+                // the name may point somewhere else by now
+                self.resolver.invalidate(addr);
+                Err(e)
+            }
+        }
+    }
+    pub async fn call(&self, req: ControlReq, retry: &RetryPolicy) -> io::Result<ControlResp> {
+        let attempts = match is_idempotent(&req) {
+            true => retry.max_attempts.max(1),
+            false => 1,
+        };
+        let mut attempt = 0;
+        loop {
+            match self.call_once(&req).await {
+                Err(_) if attempt + 1 < attempts => {
+                    tokio::time::sleep(retry.delay(attempt)).await;
+                    attempt += 1;
+                }
+                Ok(ControlResp::InvalidPath(e)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidFilename,
+                        format!("invalid path: {e}"),
+                    ));
+                }
+                Ok(ControlResp::EditFailed(e)) => {
+                    return Err(io::Error::other(format!("edit failed: {e}")));
+                }
+                res => return res,
+            }
+        }
+    }
+    async fn call_once(&self, req: &ControlReq) -> io::Result<ControlResp> {
+        let req_id = self.next_req_id.fetch_add(1, Ordering::Relaxed);
+        let (resp_tx, resp_rx) = oneshot::channel();
+        {
+            let mut conn = self.conn.lock().await;
+            if conn.as_ref().is_none_or(|conn| conn.is_closed()) {
+                let (stream, _) = handshake::connect_with(self.addr, &[self.format]).await?;
+                *conn = Some(Connection::new(stream, self.format));
+            }
+            let msg = Envelope {
+                req_id,
+                trace_id: self.trace_id(),
+                caller: Some(self.caller.clone()),
+                msg: req.clone(),
+            };
+            conn.as_ref().unwrap().send(msg, resp_tx)?;
+        }
+        resp_rx.await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("control connection closed before answering request {req_id}"),
+            )
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    req_tx: mpsc::UnboundedSender<(Envelope<ControlReq>, oneshot::Sender<ControlResp>)>,
+}
+impl Connection {
+    fn new(stream: TcpStream, format: WireFormat) -> Self {
+        let (r, w) = stream.into_split();
+        let (req_tx, req_rx) = mpsc::unbounded_channel();
+        let pending: Pending = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+        tokio::spawn(write_requests(w, format, req_rx, pending.clone()));
+        tokio::spawn(read_responses(r, format, pending));
+        Self { req_tx }
+    }
+    fn is_closed(&self) -> bool {
+        self.req_tx.is_closed()
+    }
+    fn send(
+        &self,
+        msg: Envelope<ControlReq>,
+        resp_tx: oneshot::Sender<ControlResp>,
+    ) -> io::Result<()> {
+        self.req_tx.send((msg, resp_tx)).map_err(|_| {
+            io::Error::new(io::ErrorKind::NotConnected, "control connection is closed")
+        })
+    }
+}
+
+async fn write_requests(
+    mut w: OwnedWriteHalf,
+    format: WireFormat,
+    mut req_rx: mpsc::UnboundedReceiver<(Envelope<ControlReq>, oneshot::Sender<ControlResp>)>,
+    pending: Pending,
+) {
+    while let Some((msg, resp_tx)) = req_rx.recv().await {
+        {
+            let mut pending = pending.lock().unwrap();
+            // the read half is gone; dropping the sender fails the caller
+            let Some(pending) = pending.as_mut() else {
+                return;
+            };
+            pending.insert(msg.req_id, resp_tx);
+        }
+        if write_frame_as(&mut w, format, &msg).await.is_err() {
+            pending.lock().unwrap().take();
+            return;
+        }
+    }
+}
+
+async fn read_responses(mut r: OwnedReadHalf, format: WireFormat, pending: Pending) {
+    loop {
+        let resp: Envelope<ControlResp> = match read_frame_as(&mut r, format).await {
+            Ok(resp) => resp,
+            Err(_) => {
+                pending.lock().unwrap().take();
+                return;
+            }
+        };
+        let resp_tx = pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|pending| pending.remove(&resp.req_id));
+        match resp_tx {
+            Some(resp_tx) => {
+                let _ = resp_tx.send(resp.msg);
+            }
+            None => warn!(req_id = resp.req_id, "dropping response to unknown request"),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    fs::virt::ClientId,
+    proto::control::{ControlReq, ControlResp, RenewLeasesReq, RenewLeasesResp},
+};
+
+use super::{retry::RetryPolicy, unexpected, Control};
+
+#[derive(Debug)]
+pub struct LeaseRenewer {
+    client: ClientId,
+    control: Arc<Control>,
+    period: Duration,
+    state: Mutex<RenewerState>,
+}
+impl LeaseRenewer {
+    pub fn new(client: ClientId, control: Arc<Control>, ttl: Duration) -> Self {
+        Self {
+            client,
+            control,
+            period: ttl / 3,
+            state: Mutex::new(RenewerState::default()),
+        }
+    }
+    pub fn register(self: &Arc<Self>, path: String) -> LeaseGuard {
+        let lost = Arc::new(AtomicBool::new(false));
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.held.insert(
+            id,
+            HeldLease {
+                path: path.clone(),
+                lost: lost.clone(),
+            },
+        );
+        if state.task.is_none() {
+            state.task = Some(spawn_renewal(Arc::downgrade(self), self.period));
+        }
+        LeaseGuard {
+            id,
+            path,
+            lost,
+            renewer: Arc::downgrade(self),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().held.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub async fn renew(&self) -> io::Result<()> {
+        let paths: HashSet<String> = {
+            let state = self.state.lock().unwrap();
+            state.held.values().map(|held| held.path.clone()).collect()
+        };
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let req = RenewLeasesReq {
+            client: self.client.clone(),
+            paths: paths.into_iter().collect(),
+        };
+        // a failed round is retried on the next tick anyway
+        let retry = RetryPolicy::once();
+        let lost = match self
+            .control
+            .call(ControlReq::RenewLeasesReq(req), &retry)
+            .await?
+        {
+            ControlResp::RenewLeasesResp(RenewLeasesResp { lost }) => lost,
+            resp => return Err(unexpected(resp)),
+        };
+        let state = self.state.lock().unwrap();
+        for held in state.held.values() {
+            if lost.contains(&held.path) {
+                held.lost.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+    fn release(&self, id: u64) {
+        self.state.lock().unwrap().held.remove(&id);
+    }
+}
+impl Drop for LeaseRenewer {
+    fn drop(&mut self) {
+        if let Some(task) = self.state.get_mut().unwrap().task.take() {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RenewerState {
+    next_id: u64,
+    held: HashMap<u64, HeldLease>,
+    task: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct HeldLease {
+    path: String,
+    lost: Arc<AtomicBool>,
+}
+
+/// Keeps a path in the renewal set until dropped.
+#[derive(Debug)]
+pub struct LeaseGuard {
+    id: u64,
+    path: String,
+    lost: Arc<AtomicBool>,
+    renewer: Weak<LeaseRenewer>,
+}
+impl LeaseGuard {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+    pub fn check(&self) -> io::Result<()> {
+        if !self.is_lost() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("lease lost on {}", self.path),
+        ))
+    }
+}
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        if let Some(renewer) = self.renewer.upgrade() {
+            renewer.release(self.id);
+        }
+    }
+}
+
+fn spawn_renewal(renewer: Weak<LeaseRenewer>, period: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let Some(renewer) = renewer.upgrade() else {
+                return;
+            };
+            let _ = renewer.renew().await;
+        }
+    })
+}
@@ -0,0 +1,838 @@
+use std::{
+    io,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{atomic::AtomicU64, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, Stream, TryStreamExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::{
+    fs::{
+        perm::{Caller, GroupId, UserId},
+        snapshot::DiffEntry,
+        virt::ClientId,
+    },
+    proto::{
+        control::{
+            ChmodRejected, ChmodReq, ChmodResp, ChownRejected, ChownReq, ChownResp,
+            ClearQuotaRejected, ClearQuotaReq, ClearQuotaResp, ClusterStatusReq, ClusterStatusResp,
+            ConcatRejected, ConcatReq, ConcatResp, ContentSummaryRejected, ContentSummaryReq,
+            ContentSummaryResp, ContentSummaryRespOk, ControlReq, ControlResp,
+            CreateSnapshotRejected, CreateSnapshotReq, CreateSnapshotResp, DeleteDirectoryReq,
+            DeleteDirectoryResp, DeleteFileReq, DeleteFileResp, DeleteSnapshotRejected,
+            DeleteSnapshotReq, DeleteSnapshotResp, FileStatus, FsckRejected, FsckReq, FsckResp,
+            FsckRespOk, GetBlockLocationsRejected, GetBlockLocationsReq, GetBlockLocationsResp,
+            GetFileChecksumRejected, GetFileChecksumReq, GetFileChecksumResp,
+            GetFileChecksumRespOk, GetXattrRejected, GetXattrReq, GetXattrResp, ListEntry,
+            ListRejected, ListReq, ListResp, ListRespOk, ListXattrsRejected, ListXattrsReq,
+            ListXattrsResp, MetaSaveRejected, MetaSaveReq, MetaSaveResp, NamespaceEvent,
+            OpenRejected, OpenReq, OpenResp, RemoveXattrRejected, RemoveXattrReq, RemoveXattrResp,
+            SetQuotaRejected, SetQuotaReq, SetQuotaResp, SetTimesRejected, SetTimesReq,
+            SetTimesResp, SetXattrRejected, SetXattrReq, SetXattrResp, SnapshotDiffRejected,
+            SnapshotDiffReq, SnapshotDiffResp, StatRejected, StatReq, StatResp,
+            SubscribeEventsRejected, SubscribeEventsReq, SubscribeEventsResp, UnresolvedPath,
+        },
+        format::WireFormat,
+        handshake, read_frame_as,
+        store::StoreProto,
+        write_frame_as, Envelope, TraceId,
+    },
+};
+
+pub mod conn;
+pub mod lease;
+pub mod reader;
+pub mod retry;
+pub mod writer;
+
+use conn::Control;
+use lease::LeaseRenewer;
+use reader::DfsReader;
+use retry::RetryPolicy;
+use writer::DfsWriter;
+
+/// What writers cut blocks at until an allocation tells them the control
+/// node's block size.
+const BLOCK_SIZE: u64 = 128 * 1024 * 1024;
+const LEASE_TTL: Duration = Duration::from_secs(60);
+
+/// A connection to the control node.
+///
+/// ```no_run
+/// use dfs::client::DfsClient;
+/// use tokio::io::AsyncWriteExt;
+///
+/// # async fn run() -> std::io::Result<()> {
+/// let client = DfsClient::connect("127.0.0.1:9000").await?;
+/// let mut file = client.create("/data/big.bin").await?;
+/// let chunk = vec![0xab; 1024 * 1024];
+/// // 300 MiB spans three blocks at the default 128 MiB block size
+/// for _ in 0..300 {
+///     file.write_all(&chunk).await?;
+/// }
+/// file.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DfsClient {
+    id: ClientId,
+    control: Arc<Control>,
+    leases: Arc<LeaseRenewer>,
+    block_size: Option<u64>,
+    /// The control node's block size, as the last allocation reported it.
+    server_block_size: Arc<AtomicU64>,
+    retry: RetryPolicy,
+}
+impl DfsClient {
+    /// Acts as `$USER` over bincode.
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::connect_with(addr, Caller::from_env(), WireFormat::Bincode).await
+    }
+    /// `WireFormat::Json` makes control traffic readable in a packet dump.
+    pub async fn connect_with(
+        addr: impl ToSocketAddrs,
+        caller: Caller,
+        format: WireFormat,
+    ) -> io::Result<Self> {
+        let id = new_client_id();
+        let control = Arc::new(Control::connect_with(addr, caller, format).await?);
+        Ok(Self {
+            leases: Arc::new(LeaseRenewer::new(id.clone(), control.clone(), LEASE_TTL)),
+            id,
+            control,
+            block_size: None,
+            server_block_size: Arc::new(AtomicU64::new(BLOCK_SIZE)),
+            retry: RetryPolicy::default(),
+        })
+    }
+    pub fn id(&self) -> &ClientId {
+        &self.id
+    }
+    pub fn control_addr(&self) -> SocketAddr {
+        self.control.addr()
+    }
+    pub fn set_trace_id(&self, trace_id: Option<TraceId>) {
+        self.control.set_trace_id(trace_id);
+    }
+    /// Cuts blocks at `block_size` rather than the control node's size; a
+    /// size the control node refuses falls back to its limit.
+    pub fn set_block_size(&mut self, block_size: u64) {
+        self.block_size = Some(block_size);
+    }
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+    pub async fn call(&self, req: ControlReq) -> io::Result<ControlResp> {
+        self.control.call(req, &self.retry).await
+    }
+    pub async fn call_with(&self, req: ControlReq, retry: &RetryPolicy) -> io::Result<ControlResp> {
+        self.control.call(req, retry).await
+    }
+    /// Only affects files opened afterwards.
+    pub fn set_lease_ttl(&mut self, ttl: Duration) {
+        self.leases = Arc::new(LeaseRenewer::new(
+            self.id.clone(),
+            self.control.clone(),
+            ttl,
+        ));
+    }
+    /// Fails if `path` already exists.
+    pub async fn create(&self, path: &str) -> io::Result<DfsWriter> {
+        self.open_writer(path, false).await
+    }
+    /// Creates `path`, or truncates it if it already exists.
+    pub async fn overwrite(&self, path: &str) -> io::Result<DfsWriter> {
+        self.open_writer(path, true).await
+    }
+    async fn open_writer(&self, path: &str, overwrite: bool) -> io::Result<DfsWriter> {
+        let req = OpenReq {
+            client: self.id.clone(),
+            write: true,
+            create: true,
+            exclusive: !overwrite,
+            append: false,
+            overwrite,
+            path: path.to_string(),
+        };
+        match self.call(ControlReq::OpenReq(req)).await? {
+            ControlResp::OpenResp(OpenResp::Ok(_)) => (),
+            ControlResp::OpenResp(OpenResp::Rejected(r)) => return Err(open_error(r)),
+            resp => return Err(unexpected(resp)),
+        }
+        Ok(DfsWriter::new(
+            self.id.clone(),
+            self.control.clone(),
+            self.leases.register(path.to_string()),
+            self.block_size,
+            self.server_block_size.clone(),
+            self.retry,
+        ))
+    }
+    pub async fn stat(&self, path: &str) -> io::Result<FileStatus> {
+        let req = StatReq {
+            path: path.to_string(),
+        };
+        match self.call(ControlReq::StatReq(req)).await? {
+            ControlResp::StatResp(StatResp::Ok(status)) => Ok(status),
+            ControlResp::StatResp(StatResp::Rejected(StatRejected::NotFound(at))) => {
+                Err(unresolved(&at))
+            }
+            ControlResp::StatResp(StatResp::Rejected(StatRejected::PermissionDenied)) => {
+                Err(permission_denied(path))
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// `None` leaves that time as it is.
+    pub async fn set_times(
+        &self,
+        path: &str,
+        mtime: Option<SystemTime>,
+        atime: Option<SystemTime>,
+    ) -> io::Result<()> {
+        let req = SetTimesReq {
+            path: path.to_string(),
+            mtime,
+            atime,
+        };
+        match self.call(ControlReq::SetTimesReq(req)).await? {
+            ControlResp::SetTimesResp(SetTimesResp::Ok) => Ok(()),
+            ControlResp::SetTimesResp(SetTimesResp::Rejected(SetTimesRejected::NotFound)) => {
+                Err(not_found(path))
+            }
+            ControlResp::SetTimesResp(SetTimesResp::Rejected(SetTimesRejected::SafeMode)) => {
+                Err(safe_mode())
+            }
+            ControlResp::SetTimesResp(SetTimesResp::Rejected(
+                SetTimesRejected::PermissionDenied,
+            )) => Err(permission_denied(path)),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// `None` leaves that field as it is.
+    pub async fn chown(
+        &self,
+        path: &str,
+        owner: Option<UserId>,
+        group: Option<GroupId>,
+    ) -> io::Result<()> {
+        let req = ChownReq {
+            path: path.to_string(),
+            owner,
+            group,
+        };
+        match self.call(ControlReq::ChownReq(req)).await? {
+            ControlResp::ChownResp(ChownResp::Ok) => Ok(()),
+            ControlResp::ChownResp(ChownResp::Rejected(ChownRejected::NotFound)) => {
+                Err(not_found(path))
+            }
+            ControlResp::ChownResp(ChownResp::Rejected(ChownRejected::PermissionDenied)) => {
+                Err(permission_denied(path))
+            }
+            ControlResp::ChownResp(ChownResp::Rejected(ChownRejected::SafeMode)) => {
+                Err(safe_mode())
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn chmod(&self, path: &str, mode: u16) -> io::Result<()> {
+        let req = ChmodReq {
+            path: path.to_string(),
+            mode,
+        };
+        match self.call(ControlReq::ChmodReq(req)).await? {
+            ControlResp::ChmodResp(ChmodResp::Ok) => Ok(()),
+            ControlResp::ChmodResp(ChmodResp::Rejected(ChmodRejected::NotFound)) => {
+                Err(not_found(path))
+            }
+            ControlResp::ChmodResp(ChmodResp::Rejected(ChmodRejected::PermissionDenied)) => {
+                Err(permission_denied(path))
+            }
+            ControlResp::ChmodResp(ChmodResp::Rejected(ChmodRejected::SafeMode)) => {
+                Err(safe_mode())
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn set_quota(
+        &self,
+        path: &str,
+        max_nodes: Option<u64>,
+        max_space: Option<u64>,
+    ) -> io::Result<()> {
+        let req = SetQuotaReq {
+            path: path.to_string(),
+            max_nodes,
+            max_space,
+        };
+        match self.call(ControlReq::SetQuotaReq(req)).await? {
+            ControlResp::SetQuotaResp(SetQuotaResp::Ok) => Ok(()),
+            ControlResp::SetQuotaResp(SetQuotaResp::Rejected(rejected)) => Err(match rejected {
+                SetQuotaRejected::NotFound => not_found(path),
+                SetQuotaRejected::NotDirectory => not_a_directory(path),
+                SetQuotaRejected::PermissionDenied => permission_denied(path),
+                SetQuotaRejected::SafeMode => safe_mode(),
+            }),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn clear_quota(&self, path: &str) -> io::Result<()> {
+        let req = ClearQuotaReq {
+            path: path.to_string(),
+        };
+        match self.call(ControlReq::ClearQuotaReq(req)).await? {
+            ControlResp::ClearQuotaResp(ClearQuotaResp::Ok) => Ok(()),
+            ControlResp::ClearQuotaResp(ClearQuotaResp::Rejected(rejected)) => {
+                Err(match rejected {
+                    ClearQuotaRejected::NotFound => not_found(path),
+                    ClearQuotaRejected::NotDirectory => not_a_directory(path),
+                    ClearQuotaRejected::PermissionDenied => permission_denied(path),
+                    ClearQuotaRejected::SafeMode => safe_mode(),
+                })
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// Appends the blocks of `sources` to `target` and removes the sources,
+    /// without moving any data.
+    pub async fn concat(&self, target: &str, sources: &[&str]) -> io::Result<()> {
+        let req = ConcatReq {
+            target: target.to_string(),
+            sources: sources.iter().map(|source| source.to_string()).collect(),
+        };
+        match self.call(ControlReq::ConcatReq(req)).await? {
+            ControlResp::ConcatResp(ConcatResp::Ok) => Ok(()),
+            ControlResp::ConcatResp(ConcatResp::Rejected(rejected)) => Err(match rejected {
+                ConcatRejected::NoSources => {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no files to concat")
+                }
+                ConcatRejected::DuplicateSource(path) => io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{path} is named more than once"),
+                ),
+                ConcatRejected::NotFound(path) => not_found(&path),
+                ConcatRejected::NotFile(path) => {
+                    io::Error::new(io::ErrorKind::IsADirectory, format!("{path} is not a file"))
+                }
+                ConcatRejected::Open(path) => {
+                    io::Error::new(io::ErrorKind::ResourceBusy, format!("{path} is open"))
+                }
+                ConcatRejected::NotFinalized(path) => io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!("{path} is not complete"),
+                ),
+                ConcatRejected::ReplicationMismatch(path) => io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("replication of {path} differs from {target}"),
+                ),
+                ConcatRejected::SafeMode => safe_mode(),
+                ConcatRejected::PermissionDenied => permission_denied(target),
+                ConcatRejected::QuotaExceeded(e) => io::Error::new(
+                    io::ErrorKind::QuotaExceeded,
+                    format!("quota of {} exceeded: {:?}", e.dir, e.limit),
+                ),
+            }),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// Readable afterwards under `path/.snapshot/name`.
+    pub async fn create_snapshot(&self, path: &str, name: &str) -> io::Result<()> {
+        let req = CreateSnapshotReq {
+            path: path.to_string(),
+            name: name.to_string(),
+        };
+        match self.call(ControlReq::CreateSnapshotReq(req)).await? {
+            ControlResp::CreateSnapshotResp(CreateSnapshotResp::Ok) => Ok(()),
+            ControlResp::CreateSnapshotResp(CreateSnapshotResp::Rejected(rejected)) => {
+                Err(match rejected {
+                    CreateSnapshotRejected::NotFound => not_found(path),
+                    CreateSnapshotRejected::NotDirectory => not_a_directory(path),
+                    CreateSnapshotRejected::Exists => io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("snapshot {name} of {path} already exists"),
+                    ),
+                    CreateSnapshotRejected::InvalidName(e) => {
+                        io::Error::new(io::ErrorKind::InvalidFilename, e)
+                    }
+                    CreateSnapshotRejected::PermissionDenied => permission_denied(path),
+                    CreateSnapshotRejected::SafeMode => safe_mode(),
+                })
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn delete_snapshot(&self, path: &str, name: &str) -> io::Result<()> {
+        let req = DeleteSnapshotReq {
+            path: path.to_string(),
+            name: name.to_string(),
+        };
+        match self.call(ControlReq::DeleteSnapshotReq(req)).await? {
+            ControlResp::DeleteSnapshotResp(DeleteSnapshotResp::Ok) => Ok(()),
+            ControlResp::DeleteSnapshotResp(DeleteSnapshotResp::Rejected(rejected)) => {
+                Err(match rejected {
+                    DeleteSnapshotRejected::NotFound => not_found(path),
+                    DeleteSnapshotRejected::NotDirectory => not_a_directory(path),
+                    DeleteSnapshotRejected::NoSuchSnapshot => {
+                        not_found(&format!("{path}/.snapshot/{name}"))
+                    }
+                    DeleteSnapshotRejected::PermissionDenied => permission_denied(path),
+                    DeleteSnapshotRejected::SafeMode => safe_mode(),
+                })
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// Changes from snapshot `from` to snapshot `to`, or to the live
+    /// directory when `to` is `None`.
+    pub async fn snapshot_diff(
+        &self,
+        path: &str,
+        from: &str,
+        to: Option<&str>,
+    ) -> io::Result<Vec<DiffEntry>> {
+        let req = SnapshotDiffReq {
+            path: path.to_string(),
+            from: from.to_string(),
+            to: to.map(|to| to.to_string()),
+        };
+        match self.call(ControlReq::SnapshotDiffReq(req)).await? {
+            ControlResp::SnapshotDiffResp(SnapshotDiffResp::Ok(ok)) => Ok(ok.entries),
+            ControlResp::SnapshotDiffResp(SnapshotDiffResp::Rejected(rejected)) => {
+                Err(match rejected {
+                    SnapshotDiffRejected::NotFound => not_found(path),
+                    SnapshotDiffRejected::NotDirectory => not_a_directory(path),
+                    SnapshotDiffRejected::NoSuchSnapshot => io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no such snapshot of {path}"),
+                    ),
+                    SnapshotDiffRejected::PermissionDenied => permission_denied(path),
+                })
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn file_checksum(&self, path: &str) -> io::Result<GetFileChecksumRespOk> {
+        let req = GetFileChecksumReq {
+            path: path.to_string(),
+        };
+        match self.call(ControlReq::GetFileChecksumReq(req)).await? {
+            ControlResp::GetFileChecksumResp(GetFileChecksumResp::Ok(ok)) => Ok(ok),
+            ControlResp::GetFileChecksumResp(GetFileChecksumResp::Rejected(rejected)) => {
+                Err(match rejected {
+                    GetFileChecksumRejected::NotFound => not_found(path),
+                    GetFileChecksumRejected::NotFile => {
+                        io::Error::new(io::ErrorKind::IsADirectory, format!("{path} is not a file"))
+                    }
+                    GetFileChecksumRejected::NotFinalized => io::Error::new(
+                        io::ErrorKind::ResourceBusy,
+                        format!("{path} is not complete"),
+                    ),
+                    GetFileChecksumRejected::MissingChecksums { missing, blocks } => {
+                        io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            format!("{missing} of {blocks} blocks of {path} have no checksum yet"),
+                        )
+                    }
+                    GetFileChecksumRejected::PermissionDenied => permission_denied(path),
+                })
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// Orphans are only collected, and deleted, on the first page.
+    pub async fn fsck_page(
+        &self,
+        path: &str,
+        list_corrupt: bool,
+        delete_orphans: bool,
+        start_after: Option<String>,
+    ) -> io::Result<FsckRespOk> {
+        let req = FsckReq {
+            path: path.to_string(),
+            list_corrupt,
+            delete_orphans,
+            start_after,
+        };
+        match self.call(ControlReq::FsckReq(req)).await? {
+            ControlResp::FsckResp(FsckResp::Ok(ok)) => Ok(ok),
+            ControlResp::FsckResp(FsckResp::Rejected(rejected)) => Err(match rejected {
+                FsckRejected::NotFound => not_found(path),
+                FsckRejected::PermissionDenied => permission_denied(path),
+                FsckRejected::SafeMode => safe_mode(),
+            }),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn cluster_status(&self) -> io::Result<ClusterStatusResp> {
+        match self
+            .call(ControlReq::ClusterStatusReq(ClusterStatusReq {}))
+            .await?
+        {
+            ControlResp::ClusterStatusResp(resp) => Ok(resp),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// `path` is on the control node's local filesystem.
+    pub async fn metasave(&self, path: &str) -> io::Result<()> {
+        let req = MetaSaveReq {
+            path: path.to_string(),
+        };
+        match self.call(ControlReq::MetaSaveReq(req)).await? {
+            ControlResp::MetaSaveResp(MetaSaveResp::Ok) => Ok(()),
+            ControlResp::MetaSaveResp(MetaSaveResp::Rejected(rejected)) => Err(match rejected {
+                MetaSaveRejected::PermissionDenied => permission_denied(path),
+                MetaSaveRejected::Unsupported => {
+                    io::Error::new(io::ErrorKind::Unsupported, "metasave is not supported")
+                }
+                MetaSaveRejected::Io(e) => io::Error::other(format!("metasave failed: {e}")),
+            }),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn content_summary(&self, path: &str) -> io::Result<ContentSummaryRespOk> {
+        let req = ContentSummaryReq {
+            path: path.to_string(),
+        };
+        match self.call(ControlReq::ContentSummaryReq(req)).await? {
+            ControlResp::ContentSummaryResp(ContentSummaryResp::Ok(ok)) => Ok(ok),
+            ControlResp::ContentSummaryResp(ContentSummaryResp::Rejected(rejected)) => {
+                Err(match rejected {
+                    ContentSummaryRejected::NotFound => not_found(path),
+                    ContentSummaryRejected::PermissionDenied => permission_denied(path),
+                })
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn set_xattr(&self, path: &str, name: &str, value: Vec<u8>) -> io::Result<()> {
+        let req = SetXattrReq {
+            path: path.to_string(),
+            name: name.to_string(),
+            value,
+        };
+        match self.call(ControlReq::SetXattrReq(req)).await? {
+            ControlResp::SetXattrResp(SetXattrResp::Ok) => Ok(()),
+            ControlResp::SetXattrResp(SetXattrResp::Rejected(rejected)) => Err(match rejected {
+                SetXattrRejected::NotFound => not_found(path),
+                SetXattrRejected::TooLarge => io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("xattr {name} on {path} is too large"),
+                ),
+                SetXattrRejected::PermissionDenied => permission_denied(path),
+                SetXattrRejected::SafeMode => safe_mode(),
+            }),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn get_xattr(&self, path: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let req = GetXattrReq {
+            path: path.to_string(),
+            name: name.to_string(),
+        };
+        match self.call(ControlReq::GetXattrReq(req)).await? {
+            ControlResp::GetXattrResp(GetXattrResp::Ok(ok)) => Ok(Some(ok.value)),
+            ControlResp::GetXattrResp(GetXattrResp::Rejected(rejected)) => match rejected {
+                GetXattrRejected::NoSuchAttr => Ok(None),
+                GetXattrRejected::NotFound => Err(not_found(path)),
+                GetXattrRejected::PermissionDenied => Err(permission_denied(path)),
+            },
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// Returns `false` if the attribute did not exist.
+    pub async fn remove_xattr(&self, path: &str, name: &str) -> io::Result<bool> {
+        let req = RemoveXattrReq {
+            path: path.to_string(),
+            name: name.to_string(),
+        };
+        match self.call(ControlReq::RemoveXattrReq(req)).await? {
+            ControlResp::RemoveXattrResp(RemoveXattrResp::Ok) => Ok(true),
+            ControlResp::RemoveXattrResp(RemoveXattrResp::Rejected(rejected)) => match rejected {
+                RemoveXattrRejected::NoSuchAttr => Ok(false),
+                RemoveXattrRejected::NotFound => Err(not_found(path)),
+                RemoveXattrRejected::PermissionDenied => Err(permission_denied(path)),
+                RemoveXattrRejected::SafeMode => Err(safe_mode()),
+            },
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn list_xattrs(&self, path: &str) -> io::Result<Vec<String>> {
+        let req = ListXattrsReq {
+            path: path.to_string(),
+        };
+        match self.call(ControlReq::ListXattrsReq(req)).await? {
+            ControlResp::ListXattrsResp(ListXattrsResp::Ok(ok)) => Ok(ok.names),
+            ControlResp::ListXattrsResp(ListXattrsResp::Rejected(rejected)) => {
+                Err(match rejected {
+                    ListXattrsRejected::NotFound => not_found(path),
+                    ListXattrsRejected::PermissionDenied => permission_denied(path),
+                })
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn list(&self, path: &str) -> io::Result<Vec<ListEntry>> {
+        self.list_stream(path).try_collect().await
+    }
+    /// Lists `path` in name order, fetching one page at a time.
+    pub fn list_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> impl Stream<Item = io::Result<ListEntry>> + 'a {
+        stream::try_unfold(Some(None), move |start_after| async move {
+            let Some(start_after) = start_after else {
+                return io::Result::Ok(None);
+            };
+            let page = self.list_page(path, start_after, None).await?;
+            let next = match page.entries.last() {
+                Some(last) if page.has_more => Some(Some(last.name.clone())),
+                _ => None,
+            };
+            Ok(Some((stream::iter(page.entries.into_iter().map(Ok)), next)))
+        })
+        .try_flatten()
+    }
+    /// Namespace events touching `path_prefix` after `after_txid`, as they
+    /// happen. A
+    /// [`NamespaceEventKind::Missed`](crate::proto::control::NamespaceEventKind::Missed)
+    /// means the control node no longer has some of them. The control node
+    /// streams them over a connection of their own so other calls are not
+    /// held up; if it drops, the stream ends with the error and can be
+    /// picked up again after the last txid seen.
+    pub fn subscribe_events<'a>(
+        &'a self,
+        path_prefix: &'a str,
+        after_txid: u64,
+    ) -> impl Stream<Item = io::Result<NamespaceEvent>> + 'a {
+        let format = self.control.format();
+        stream::try_unfold(None::<TcpStream>, move |conn| async move {
+            let mut conn = match conn {
+                Some(conn) => conn,
+                None => {
+                    let (mut conn, _) =
+                        handshake::connect_with(self.control.addr(), &[format]).await?;
+                    let req = SubscribeEventsReq {
+                        path_prefix: path_prefix.to_string(),
+                        after_txid,
+                    };
+                    let req = Envelope {
+                        req_id: 0,
+                        trace_id: self.control.trace_id(),
+                        caller: Some(self.control.caller().clone()),
+                        msg: ControlReq::SubscribeEventsReq(req),
+                    };
+                    write_frame_as(&mut conn, format, &req).await?;
+                    conn
+                }
+            };
+            let resp: Envelope<ControlResp> = read_frame_as(&mut conn, format).await?;
+            let ok = match resp.msg {
+                ControlResp::SubscribeEventsResp(SubscribeEventsResp::Ok(ok)) => ok,
+                ControlResp::SubscribeEventsResp(SubscribeEventsResp::Rejected(
+                    SubscribeEventsRejected::PermissionDenied,
+                )) => return Err(permission_denied(path_prefix)),
+                ControlResp::InvalidPath(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidFilename,
+                        format!("invalid path: {e}"),
+                    ))
+                }
+                resp => return Err(unexpected(resp)),
+            };
+            Ok(Some((
+                stream::iter(ok.events.into_iter().map(Ok)),
+                Some(conn),
+            )))
+        })
+        .try_flatten()
+    }
+    /// One page of entries after `start_after`; the control node caps
+    /// `limit`.
+    pub async fn list_page(
+        &self,
+        path: &str,
+        start_after: Option<String>,
+        limit: Option<NonZeroUsize>,
+    ) -> io::Result<ListRespOk> {
+        let req = ListReq {
+            path: path.to_string(),
+            start_after,
+            limit,
+        };
+        match self.call(ControlReq::ListReq(req)).await? {
+            ControlResp::ListResp(ListResp::Ok(ok)) => Ok(ok),
+            ControlResp::ListResp(ListResp::Rejected(ListRejected::NotFound(at))) => {
+                Err(unresolved(&at))
+            }
+            ControlResp::ListResp(ListResp::Rejected(ListRejected::PermissionDenied)) => {
+                Err(permission_denied(path))
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    /// Moves `path` to the trash if the control node keeps one.
+    pub async fn delete(&self, path: &str, recursive: bool) -> io::Result<()> {
+        self.delete_with(path, recursive, false).await
+    }
+    pub async fn delete_with(
+        &self,
+        path: &str,
+        recursive: bool,
+        skip_trash: bool,
+    ) -> io::Result<()> {
+        let rejected = || {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("delete of {path} rejected"),
+            )
+        };
+        if !self.stat(path).await?.is_dir {
+            let req = DeleteFileReq {
+                path: path.to_string(),
+                skip_trash,
+            };
+            return match self.call(ControlReq::DeleteFileReq(req)).await? {
+                ControlResp::DeleteFileResp(DeleteFileResp::Ok) => Ok(()),
+                ControlResp::DeleteFileResp(DeleteFileResp::Rejected) => Err(rejected()),
+                ControlResp::DeleteFileResp(DeleteFileResp::SafeMode) => Err(safe_mode()),
+                ControlResp::DeleteFileResp(DeleteFileResp::PermissionDenied) => {
+                    Err(permission_denied(path))
+                }
+                resp => Err(unexpected(resp)),
+            };
+        }
+        let req = DeleteDirectoryReq {
+            path: path.to_string(),
+            recursive,
+            skip_trash,
+        };
+        match self.call(ControlReq::DeleteDirectoryReq(req)).await? {
+            ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Ok) => Ok(()),
+            ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Rejected) => Err(rejected()),
+            ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::SafeMode) => Err(safe_mode()),
+            ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::PermissionDenied) => {
+                Err(permission_denied(path))
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+    pub async fn open(&self, path: &str) -> io::Result<DfsReader> {
+        let req = OpenReq {
+            client: self.id.clone(),
+            write: false,
+            create: false,
+            exclusive: false,
+            append: false,
+            overwrite: false,
+            path: path.to_string(),
+        };
+        let len = match self.call(ControlReq::OpenReq(req)).await? {
+            ControlResp::OpenResp(OpenResp::Ok(ok)) => ok.len,
+            ControlResp::OpenResp(OpenResp::Rejected(r)) => return Err(open_error(r)),
+            resp => return Err(unexpected(resp)),
+        };
+        let req = GetBlockLocationsReq {
+            path: path.to_string(),
+            off_range: None,
+        };
+        let blocks = match self.call(ControlReq::GetBlockLocationsReq(req)).await? {
+            ControlResp::GetBlockLocationsResp(GetBlockLocationsResp::Ok(ok)) => ok.blocks,
+            ControlResp::GetBlockLocationsResp(GetBlockLocationsResp::Rejected(r)) => {
+                let kind = match r {
+                    GetBlockLocationsRejected::FileNotExist => io::ErrorKind::NotFound,
+                    GetBlockLocationsRejected::NotFile => io::ErrorKind::IsADirectory,
+                    GetBlockLocationsRejected::PermissionDenied => io::ErrorKind::PermissionDenied,
+                };
+                return Err(io::Error::new(
+                    kind,
+                    format!("block lookup rejected: {r:?}"),
+                ));
+            }
+            resp => return Err(unexpected(resp)),
+        };
+        Ok(DfsReader::new(
+            self.id.clone(),
+            self.control.clone(),
+            self.leases.register(path.to_string()),
+            len,
+            blocks,
+            self.retry,
+        ))
+    }
+}
+
+fn new_client_id() -> ClientId {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("client_{nanos:x}_{:x}", std::process::id()).into()
+}
+
+fn open_error(r: OpenRejected) -> io::Error {
+    let kind = match r {
+        OpenRejected::Conflict { .. } => io::ErrorKind::ResourceBusy,
+        OpenRejected::ParentMissing(at) | OpenRejected::NotFound(at) => return unresolved(&at),
+        OpenRejected::IsDirectory => io::ErrorKind::IsADirectory,
+        OpenRejected::InvalidPath | OpenRejected::InvalidMode => io::ErrorKind::InvalidInput,
+        OpenRejected::FileExists => io::ErrorKind::AlreadyExists,
+        OpenRejected::SafeMode => io::ErrorKind::ReadOnlyFilesystem,
+        OpenRejected::PermissionDenied => io::ErrorKind::PermissionDenied,
+        OpenRejected::QuotaExceeded(_) => io::ErrorKind::QuotaExceeded,
+        OpenRejected::PathLimit(_) => io::ErrorKind::InvalidFilename,
+    };
+    io::Error::new(kind, format!("open rejected: {r:?}"))
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{path} does not exist"))
+}
+
+fn unresolved(at: &UnresolvedPath) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist under {}", at.remaining, at.resolved),
+    )
+}
+
+fn permission_denied(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("permission denied on {path}"),
+    )
+}
+
+fn not_a_directory(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotADirectory,
+        format!("{path} is not a directory"),
+    )
+}
+
+fn safe_mode() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ReadOnlyFilesystem,
+        "control node is in safe mode",
+    )
+}
+
+fn unexpected_store(msg: StoreProto) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected store message: {msg:?}"),
+    )
+}
+
+fn unexpected(resp: ControlResp) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response: {resp:?}"),
+    )
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::{poll_fn, Future},
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{
+    fs::{
+        block::{BlockId, BlockMeta},
+        virt::ClientId,
+    },
+    proto::{
+        control::{CloseReq, ControlReq, ControlResp, LocatedBlock, ReportBadBlockReq},
+        read_frame,
+        store::{ReadBlockReq, ReadBlockResp, StoreProto},
+        write_frame, TraceId,
+    },
+    store::StoreAddr,
+};
+
+use super::{lease::LeaseGuard, retry::RetryPolicy, unexpected, unexpected_store, Control};
+
+/// Seeks that leave at most this much of a block unread drain the stream
+/// so the connection can be reused.
+const DRAIN_LIMIT: u64 = 1024 * 1024;
+
+type Pending = Pin<Box<dyn Future<Output = (ReaderInner, io::Result<()>)> + Send>>;
+
+pub struct DfsReader {
+    inner: Option<ReaderInner>,
+    pending: Option<Pending>,
+    seek: Option<SeekFrom>,
+    lease: LeaseGuard,
+}
+impl DfsReader {
+    pub fn new(
+        client: ClientId,
+        control: Arc<Control>,
+        lease: LeaseGuard,
+        len: u64,
+        blocks: Vec<LocatedBlock>,
+        retry: RetryPolicy,
+    ) -> Self {
+        let inner = ReaderInner {
+            client,
+            control,
+            retry,
+            path: lease.path().to_string(),
+            blocks,
+            len,
+            pos: 0,
+            chunk: None,
+            stream: None,
+            idle: HashMap::new(),
+            excluded: HashSet::new(),
+        };
+        Self {
+            inner: Some(inner),
+            pending: None,
+            seek: None,
+            lease,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        self.inner.as_ref().map(|inner| inner.len).unwrap_or(0)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub async fn close(mut self) -> io::Result<()> {
+        let _ = poll_fn(|cx| self.poll_pending(cx)).await;
+        let inner = self.inner.take().unwrap();
+        let req = CloseReq {
+            client: inner.client,
+            path: inner.path,
+        };
+        match inner
+            .control
+            .call(ControlReq::CloseReq(req), &inner.retry)
+            .await?
+        {
+            ControlResp::CloseResp(_) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(pending) = &mut self.pending else {
+            return Poll::Ready(Ok(()));
+        };
+        let (inner, res) = ready!(pending.as_mut().poll(cx));
+        self.pending = None;
+        self.inner = Some(inner);
+        Poll::Ready(res)
+    }
+}
+impl fmt::Debug for DfsReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DfsReader")
+            .field("inner", &self.inner)
+            .field("seek", &self.seek)
+            .field("lease", &self.lease)
+            .finish_non_exhaustive()
+    }
+}
+impl AsyncRead for DfsReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_pending(cx))?;
+            this.lease.check()?;
+            let inner = this.inner.as_mut().unwrap();
+            if inner.pos >= inner.len || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(data) = inner.buffered() {
+                let n = data.len().min(buf.remaining());
+                buf.put_slice(&data[..n]);
+                inner.pos += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            let mut inner = this.inner.take().unwrap();
+            this.pending = Some(Box::pin(async move {
+                let res = inner.fill().await;
+                (inner, res)
+            }));
+        }
+    }
+}
+impl AsyncSeek for DfsReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.get_mut().seek = Some(position);
+        Ok(())
+    }
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        let inner = this.inner.as_mut().unwrap();
+        if let Some(position) = this.seek.take() {
+            let pos = match position {
+                SeekFrom::Start(pos) => Some(pos),
+                SeekFrom::Current(delta) => inner.pos.checked_add_signed(delta),
+                SeekFrom::End(delta) => inner.len.checked_add_signed(delta),
+            };
+            let Some(pos) = pos else {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek before the start of the file",
+                )));
+            };
+            // the data is fetched lazily on the next read
+            inner.pos = pos;
+        }
+        Poll::Ready(Ok(inner.pos))
+    }
+}
+
+#[derive(Debug)]
+struct ReaderInner {
+    client: ClientId,
+    control: Arc<Control>,
+    retry: RetryPolicy,
+    path: String,
+    blocks: Vec<LocatedBlock>,
+    len: u64,
+    pos: u64,
+    chunk: Option<VerifiedChunk>,
+    stream: Option<BlockStream>,
+    idle: HashMap<StoreAddr, TcpStream>,
+    excluded: HashSet<(BlockId, StoreAddr)>,
+}
+impl ReaderInner {
+    fn buffered(&self) -> Option<&[u8]> {
+        let chunk = self.chunk.as_ref()?;
+        let rel = self.pos.checked_sub(chunk.start)?;
+        let rel = usize::try_from(rel).ok()?;
+        chunk.data.get(rel..).filter(|data| !data.is_empty())
+    }
+    async fn fill(&mut self) -> io::Result<()> {
+        loop {
+            if self.len <= self.pos || self.buffered().is_some() {
+                return Ok(());
+            }
+            let Some(index) = self
+                .blocks
+                .iter()
+                .position(|b| b.off_range.0 <= self.pos && self.pos < b.off_range.1)
+            else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no block covers offset {}", self.pos),
+                ));
+            };
+            let block_off = self.pos - self.blocks[index].off_range.0;
+            let reusable = self.stream.as_ref().is_some_and(|s| {
+                s.index == index && s.next <= block_off && block_off - s.next <= DRAIN_LIMIT
+            });
+            if !reusable {
+                self.release_stream().await;
+                self.open_stream(index, block_off).await?;
+            }
+            match self.read_chunk().await {
+                Ok(()) => (),
+                Err(ChunkError::Io) => {
+                    self.exclude_stream();
+                }
+                Err(ChunkError::Checksum) => {
+                    let addr = self.exclude_stream();
+                    let req = ReportBadBlockReq {
+                        block: self.blocks[index].block.clone(),
+                        store_addr: addr,
+                    };
+                    let req = ControlReq::ReportBadBlockReq(req);
+                    let _ = self.control.call(req, &self.retry).await;
+                }
+            }
+        }
+    }
+    async fn open_stream(&mut self, index: usize, block_off: u64) -> io::Result<()> {
+        let located = &self.blocks[index];
+        let mut candidates: Vec<StoreAddr> = located
+            .store_addrs
+            .iter()
+            .filter(|addr| {
+                !self
+                    .excluded
+                    .contains(&(located.block.clone(), (*addr).clone()))
+            })
+            .cloned()
+            .collect();
+        // stores we already hold a connection to go first
+        candidates.sort_by_key(|addr| !self.idle.contains_key(addr));
+        let trace_id = self.control.trace_id();
+        for addr in candidates {
+            if let Some(stream) = self.idle.remove(&addr) {
+                if let Ok(opened) =
+                    request_block(stream, located, block_off, trace_id.clone()).await
+                {
+                    self.stream = Some(BlockStream::new(index, addr, opened, block_off));
+                    return Ok(());
+                }
+            }
+            let opened = match self.control.connect_store(&addr).await {
+                Ok(stream) => request_block(stream, located, block_off, trace_id.clone()).await,
+                Err(e) => Err(e),
+            };
+            match opened {
+                Ok(opened) => {
+                    self.stream = Some(BlockStream::new(index, addr, opened, block_off));
+                    return Ok(());
+                }
+                Err(_) => {
+                    self.excluded.insert((located.block.clone(), addr));
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!("no healthy replica of block {}", located.block),
+        ))
+    }
+    async fn read_chunk(&mut self) -> Result<(), ChunkError> {
+        let s = self.stream.as_mut().unwrap();
+        let chunk = match read_frame(&mut s.stream).await? {
+            StoreProto::BlockChunk(chunk) if chunk.offset == s.next => chunk,
+            _ => return Err(ChunkError::Io),
+        };
+        let chunk_size = s.meta.chunk_size() as u64;
+        let expected = (s.size - chunk.offset).min(chunk_size);
+        if chunk.data.len() as u64 != expected {
+            return Err(ChunkError::Checksum);
+        }
+        let chunk_index = (chunk.offset / chunk_size) as usize;
+        if !chunk.data.is_empty() && !s.meta.verify_chunk(chunk_index, &chunk.data) {
+            return Err(ChunkError::Checksum);
+        }
+        s.next = chunk.offset + chunk.data.len() as u64;
+        self.chunk = Some(VerifiedChunk {
+            start: self.blocks[s.index].off_range.0 + chunk.offset,
+            data: chunk.data,
+        });
+        if chunk.last {
+            let s = self.stream.take().unwrap();
+            self.idle.insert(s.addr, s.stream);
+        }
+        Ok(())
+    }
+    async fn release_stream(&mut self) {
+        let Some(mut s) = self.stream.take() else {
+            return;
+        };
+        if DRAIN_LIMIT < s.size - s.next {
+            return;
+        }
+        loop {
+            match read_frame(&mut s.stream).await {
+                Ok(StoreProto::BlockChunk(chunk)) if chunk.last => break,
+                Ok(StoreProto::BlockChunk(_)) => (),
+                _ => return,
+            }
+        }
+        self.idle.insert(s.addr, s.stream);
+    }
+    fn exclude_stream(&mut self) -> StoreAddr {
+        let s = self.stream.take().unwrap();
+        let block = self.blocks[s.index].block.clone();
+        self.excluded.insert((block, s.addr.clone()));
+        s.addr
+    }
+}
+
+#[derive(Debug)]
+struct VerifiedChunk {
+    start: u64,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct BlockStream {
+    index: usize,
+    addr: StoreAddr,
+    stream: TcpStream,
+    meta: BlockMeta,
+    size: u64,
+    next: u64,
+}
+impl BlockStream {
+    fn new(index: usize, addr: StoreAddr, opened: OpenedBlock, block_off: u64) -> Self {
+        let chunk_size = opened.meta.chunk_size() as u64;
+        Self {
+            index,
+            addr,
+            stream: opened.stream,
+            next: block_off.min(opened.size) / chunk_size * chunk_size,
+            meta: opened.meta,
+            size: opened.size,
+        }
+    }
+}
+
+struct OpenedBlock {
+    stream: TcpStream,
+    meta: BlockMeta,
+    size: u64,
+}
+
+async fn request_block(
+    mut stream: TcpStream,
+    located: &LocatedBlock,
+    offset: u64,
+    trace_id: Option<TraceId>,
+) -> io::Result<OpenedBlock> {
+    let req = ReadBlockReq {
+        block: located.block.clone(),
+        offset,
+        trace_id,
+    };
+    write_frame(&mut stream, &StoreProto::ReadBlockReq(req)).await?;
+    match read_frame(&mut stream).await? {
+        StoreProto::ReadBlockResp(ReadBlockResp {
+            body: Some(body),
+            meta: Some(meta),
+        }) if located.generation <= body.generation() => Ok(OpenedBlock {
+            stream,
+            meta,
+            size: body.size(),
+        }),
+        StoreProto::ReadBlockResp(_) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("store does not hold block {}", located.block),
+        )),
+        msg => Err(unexpected_store(msg)),
+    }
+}
+
+#[derive(Debug)]
+enum ChunkError {
+    Io,
+    Checksum,
+}
+impl From<io::Error> for ChunkError {
+    fn from(_: io::Error) -> Self {
+        Self::Io
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::proto::control::ControlReq;
+
+const MAX_ATTEMPTS: u32 = 4;
+const BASE_DELAY: Duration = Duration::from_millis(100);
+const MAX_DELAY: Duration = Duration::from_secs(5);
+const JITTER: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomized.
+    pub jitter: f64,
+}
+impl RetryPolicy {
+    pub fn once() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        let scale = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+        backoff.mul_f64(scale).min(self.max_delay)
+    }
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: MAX_ATTEMPTS,
+            base_delay: BASE_DELAY,
+            max_delay: MAX_DELAY,
+            jitter: JITTER,
+        }
+    }
+}
+
+pub fn is_idempotent(req: &ControlReq) -> bool {
+    match req {
+        ControlReq::OpenLeaseReq(_)
+        | ControlReq::RenewLeasesReq(_)
+        | ControlReq::CloseReq(_)
+        | ControlReq::GetBlockLocationsReq(_)
+        | ControlReq::StatReq(_)
+        | ControlReq::SetTimesReq(_)
+        | ControlReq::SetXattrReq(_)
+        | ControlReq::GetXattrReq(_)
+        | ControlReq::ListXattrsReq(_)
+        | ControlReq::SetQuotaReq(_)
+        | ControlReq::ClearQuotaReq(_)
+        | ControlReq::ContentSummaryReq(_)
+        | ControlReq::SnapshotDiffReq(_)
+        | ControlReq::GetFileChecksumReq(_)
+        | ControlReq::ClusterStatusReq(_)
+        | ControlReq::MetaSaveReq(_)
+        | ControlReq::SubscribeEventsReq(_)
+        | ControlReq::ListReq(_) => true,
+        ControlReq::AllocBlockReq(req) => req.request_id.is_some(),
+        _ => false,
+    }
+}
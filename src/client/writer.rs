@@ -0,0 +1,528 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::AsyncWrite;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    fs::{block::BlockId, virt::ClientId},
+    proto::{
+        control::{
+            AbandonBlockReq, AllocBlockRejected, AllocBlockReq, AllocBlockResp, AllocBlockRespOk,
+            CloseReq, CloseResp, CompleteFileRejected, CompleteFileReq, CompleteFileResp,
+            ControlReq, ControlResp, GetAdditionalStoreReq, GetAdditionalStoreResp,
+        },
+        read_frame,
+        store::{
+            BlockChunk, ChunkAck, ChunkAckStatus, OpenBlockReq, OpenBlockResp, ResumeBlock,
+            StoreProto, TransferPartialBlockReq, TransferPartialBlockResp,
+        },
+        write_frame,
+    },
+    store::{StoreAddr, StoreId},
+};
+
+use super::{lease::LeaseGuard, retry::RetryPolicy, unexpected, unexpected_store, Control};
+
+const TRANSFER_CHUNK: usize = 64 * 1024;
+const COMPLETE_RETRIES: usize = 10;
+const COMPLETE_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+type Pending = Pin<Box<dyn Future<Output = (WriterInner, io::Result<()>)> + Send>>;
+
+pub struct DfsWriter {
+    inner: Option<WriterInner>,
+    pending: Option<Pending>,
+    state: WriterState,
+    lease: Option<LeaseGuard>,
+}
+impl DfsWriter {
+    pub fn new(
+        client: ClientId,
+        control: Arc<Control>,
+        lease: LeaseGuard,
+        block_size: Option<u64>,
+        server_block_size: Arc<AtomicU64>,
+        retry: RetryPolicy,
+    ) -> Self {
+        let inner = WriterInner {
+            client,
+            control,
+            retry,
+            path: lease.path().to_string(),
+            block_size,
+            server_block_size,
+            buf: Vec::new(),
+            offset: 0,
+        };
+        Self {
+            inner: Some(inner),
+            pending: None,
+            state: WriterState::Open,
+            lease: Some(lease),
+        }
+    }
+    pub fn path(&self) -> Option<&str> {
+        self.inner.as_ref().map(|inner| inner.path.as_str())
+    }
+    fn start<F>(&mut self, f: impl FnOnce(WriterInner) -> F)
+    where
+        F: Future<Output = (WriterInner, io::Result<()>)> + Send + 'static,
+    {
+        let inner = self.inner.take().unwrap();
+        self.pending = Some(Box::pin(f(inner)));
+    }
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(pending) = &mut self.pending else {
+            return Poll::Ready(Ok(()));
+        };
+        let (inner, res) = ready!(pending.as_mut().poll(cx));
+        self.pending = None;
+        self.inner = Some(inner);
+        Poll::Ready(res)
+    }
+    fn check_lease(&self) -> io::Result<()> {
+        match &self.lease {
+            Some(lease) => lease.check(),
+            None => Ok(()),
+        }
+    }
+}
+impl fmt::Debug for DfsWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DfsWriter")
+            .field("inner", &self.inner)
+            .field("state", &self.state)
+            .field("lease", &self.lease)
+            .finish_non_exhaustive()
+    }
+}
+impl AsyncWrite for DfsWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_pending(cx))?;
+            this.check_lease()?;
+            if this.state != WriterState::Open {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "writer is closed",
+                )));
+            }
+            let inner = this.inner.as_mut().unwrap();
+            let room = inner.block_size().saturating_sub(inner.buf.len() as u64);
+            if room == 0 {
+                this.start(|mut inner| async move {
+                    let res = inner.flush_block().await;
+                    (inner, res)
+                });
+                continue;
+            }
+            let n = buf.len().min(usize::try_from(room).unwrap_or(usize::MAX));
+            inner.buf.extend_from_slice(&buf[..n]);
+            return Poll::Ready(Ok(n));
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // partial blocks only become visible on close
+        self.get_mut().poll_pending(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let res = ready!(this.poll_pending(cx));
+            match this.state {
+                WriterState::Open => {
+                    res?;
+                    this.check_lease()?;
+                    this.state = WriterState::Closing;
+                    this.start(|mut inner| async move {
+                        let res = inner.close().await;
+                        (inner, res)
+                    });
+                }
+                WriterState::Closing => {
+                    // a failed close can be retried by calling shutdown again
+                    this.state = match res {
+                        Ok(()) => WriterState::Closed,
+                        Err(_) => WriterState::Open,
+                    };
+                    if this.state == WriterState::Closed {
+                        this.lease = None;
+                    }
+                    return Poll::Ready(res);
+                }
+                WriterState::Closed => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriterState {
+    Open,
+    Closing,
+    Closed,
+}
+
+#[derive(Debug)]
+struct WriterInner {
+    client: ClientId,
+    control: Arc<Control>,
+    retry: RetryPolicy,
+    path: String,
+    /// Set by the caller; `None` follows the control node.
+    block_size: Option<u64>,
+    server_block_size: Arc<AtomicU64>,
+    buf: Vec<u8>,
+    offset: u64,
+}
+impl WriterInner {
+    async fn call(&self, req: ControlReq) -> io::Result<ControlResp> {
+        self.control.call(req, &self.retry).await
+    }
+    fn block_size(&self) -> u64 {
+        self.block_size
+            .unwrap_or_else(|| self.server_block_size.load(Ordering::Relaxed))
+    }
+    /// Writes the buffer out, in as many blocks as the control node's block
+    /// size needs.
+    async fn flush_block(&mut self) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            self.flush_one_block().await?;
+        }
+        Ok(())
+    }
+    async fn flush_one_block(&mut self) -> io::Result<()> {
+        let len = (self.buf.len() as u64).min(self.block_size());
+        let end = self.offset + len;
+        let req = AllocBlockReq {
+            client: self.client.clone(),
+            path: self.path.clone(),
+            off_range: (self.offset, end),
+            request_id: Some(rand::random()),
+        };
+        let alloc = match self.call(ControlReq::AllocBlockReq(req)).await? {
+            ControlResp::AllocBlockResp(AllocBlockResp::Ok(ok)) => ok,
+            ControlResp::AllocBlockResp(AllocBlockResp::Rejected(
+                AllocBlockRejected::BlockTooLarge { max },
+            )) if 0 < max && max < len => {
+                // the control node cuts smaller blocks; the caller retries
+                // with what is left over
+                debug!(max, "adopting the control node's block size");
+                self.server_block_size.store(max, Ordering::Relaxed);
+                self.block_size = self.block_size.map(|size| size.min(max));
+                return Ok(());
+            }
+            ControlResp::AllocBlockResp(AllocBlockResp::Rejected(r)) => return Err(alloc_error(r)),
+            resp => return Err(unexpected(resp)),
+        };
+        self.server_block_size
+            .store(alloc.max_block_size, Ordering::Relaxed);
+        let data = &self.buf[..len as usize];
+        let mut pipeline = Pipeline::new(&alloc);
+        let mut recoveries = 0;
+        loop {
+            let Err(e) = stream_block(&self.control, &self.client, &pipeline, data).await else {
+                break;
+            };
+            let res = match e.failed {
+                Some(failed) if recoveries < pipeline.targets.len() => {
+                    recoveries += 1;
+                    self.recover(&mut pipeline, &failed, e.acked).await
+                }
+                _ => Err(e.error),
+            };
+            if let Err(e) = res {
+                let req = AbandonBlockReq {
+                    client: self.client.clone(),
+                    path: self.path.clone(),
+                    block: alloc.block,
+                };
+                let _ = self.call(ControlReq::AbandonBlockReq(req)).await;
+                return Err(e);
+            }
+        }
+        self.offset = end;
+        self.buf.drain(..len as usize);
+        Ok(())
+    }
+    /// Swaps `failed` for a fresh store at a new generation, handing it what
+    /// the survivors already hold.
+    async fn recover(
+        &self,
+        pipeline: &mut Pipeline,
+        failed: &StoreAddr,
+        acked: u64,
+    ) -> io::Result<()> {
+        let Some(i) = pipeline.targets.iter().position(|target| target == failed) else {
+            return Err(io::Error::other(format!(
+                "store {failed} is not in the pipeline"
+            )));
+        };
+        pipeline.targets.remove(i);
+        let failed = pipeline.stores.remove(i);
+        warn!(block = %pipeline.block, store = %failed, acked, "recovering write pipeline");
+        let req = GetAdditionalStoreReq {
+            client: self.client.clone(),
+            path: self.path.clone(),
+            block: pipeline.block.clone(),
+            existing: pipeline.stores.clone(),
+            excluded: vec![failed],
+        };
+        let additional = match self.call(ControlReq::GetAdditionalStoreReq(req)).await? {
+            ControlResp::GetAdditionalStoreResp(GetAdditionalStoreResp::Ok(ok)) => ok,
+            ControlResp::GetAdditionalStoreResp(GetAdditionalStoreResp::Rejected(r)) => {
+                return Err(io::Error::other(format!("no replacement store: {r:?}")));
+            }
+            resp => return Err(unexpected(resp)),
+        };
+        // with nothing acknowledged every store simply starts over
+        pipeline.resume = None;
+        if acked > 0 {
+            let Some(source) = pipeline.targets.first() else {
+                return Err(io::Error::other("no store survived the pipeline"));
+            };
+            let req = TransferPartialBlockReq {
+                client: self.client.clone(),
+                block: pipeline.block.clone(),
+                generation: pipeline.generation,
+                new_generation: additional.generation,
+                offset: acked,
+                target: additional.addr.clone(),
+            };
+            let mut stream = self.control.connect_store(source).await?;
+            write_frame(&mut stream, &StoreProto::TransferPartialBlockReq(req)).await?;
+            match read_frame(&mut stream).await? {
+                StoreProto::TransferPartialBlockResp(TransferPartialBlockResp::Ok) => (),
+                StoreProto::TransferPartialBlockResp(TransferPartialBlockResp::Failed) => {
+                    return Err(io::Error::other(format!(
+                        "store {source} could not hand over block {}",
+                        pipeline.block
+                    )));
+                }
+                msg => return Err(unexpected_store(msg)),
+            }
+            pipeline.resume = Some(ResumeBlock {
+                from_generation: pipeline.generation,
+                offset: acked,
+            });
+        }
+        pipeline.generation = additional.generation;
+        pipeline.stores.push(additional.store);
+        pipeline.targets.push(additional.addr);
+        Ok(())
+    }
+    async fn close(&mut self) -> io::Result<()> {
+        self.flush_block().await?;
+        let mut retries = 0;
+        loop {
+            let req = CompleteFileReq {
+                client: self.client.clone(),
+                path: self.path.clone(),
+                len: self.offset,
+            };
+            match self.call(ControlReq::CompleteFileReq(req)).await? {
+                ControlResp::CompleteFileResp(CompleteFileResp::Ok) => break,
+                ControlResp::CompleteFileResp(CompleteFileResp::Retry)
+                    if retries < COMPLETE_RETRIES =>
+                {
+                    // stores have not reported enough replicas yet
+                    retries += 1;
+                    tokio::time::sleep(COMPLETE_RETRY_INTERVAL).await;
+                }
+                ControlResp::CompleteFileResp(CompleteFileResp::Retry) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "file did not reach minimum replication",
+                    ));
+                }
+                ControlResp::CompleteFileResp(CompleteFileResp::Rejected(r)) => {
+                    return Err(complete_error(r));
+                }
+                resp => return Err(unexpected(resp)),
+            }
+        }
+        let req = CloseReq {
+            client: self.client.clone(),
+            path: self.path.clone(),
+        };
+        match self.call(ControlReq::CloseReq(req)).await? {
+            // completing the file already released the lease
+            ControlResp::CloseResp(CloseResp { .. }) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+}
+
+/// The stores a block is being written through, and where to pick up after
+/// a recovery.
+#[derive(Debug)]
+struct Pipeline {
+    block: BlockId,
+    generation: u64,
+    targets: Vec<StoreAddr>,
+    stores: Vec<StoreId>,
+    resume: Option<ResumeBlock>,
+}
+impl Pipeline {
+    fn new(alloc: &AllocBlockRespOk) -> Self {
+        Self {
+            block: alloc.block.clone(),
+            generation: alloc.generation,
+            targets: alloc.targets.clone(),
+            stores: alloc.stores.clone(),
+            resume: None,
+        }
+    }
+}
+
+/// `failed` is the member to replace, if the pipeline can be recovered.
+#[derive(Debug)]
+struct PipelineError {
+    failed: Option<StoreAddr>,
+    /// Every member holds the block up to here.
+    acked: u64,
+    error: io::Error,
+}
+impl PipelineError {
+    fn new(failed: Option<&StoreAddr>, acked: u64, error: io::Error) -> Self {
+        Self {
+            failed: failed.cloned(),
+            acked,
+            error,
+        }
+    }
+}
+
+#[instrument(
+    skip_all,
+    fields(block = %pipeline.block, bytes = data.len(), trace_id = control.trace_id().as_deref())
+)]
+async fn stream_block(
+    control: &Control,
+    client: &ClientId,
+    pipeline: &Pipeline,
+    data: &[u8],
+) -> Result<(), PipelineError> {
+    let mut offset = pipeline.resume.map_or(0, |resume| resume.offset);
+    let Some((first, rest)) = pipeline.targets.split_first() else {
+        let e = io::Error::new(io::ErrorKind::NotConnected, "no store to write to");
+        return Err(PipelineError::new(None, offset, e));
+    };
+    let start = Instant::now();
+    let failed = |target: &StoreAddr, offset, e| PipelineError::new(Some(target), offset, e);
+    let mut stream = match control.connect_store(first).await {
+        Ok(stream) => stream,
+        Err(e) => return Err(failed(first, offset, e)),
+    };
+    let open = OpenBlockReq {
+        client: client.clone(),
+        block: pipeline.block.clone(),
+        generation: pipeline.generation,
+        write: true,
+        targets: rest.to_vec(),
+        resume: pipeline.resume,
+        trace_id: control.trace_id(),
+    };
+    let res = write_frame(&mut stream, &StoreProto::OpenBlockReq(open)).await;
+    if let Err(e) = res {
+        return Err(failed(first, offset, e));
+    }
+    match read_frame(&mut stream).await {
+        Ok(StoreProto::OpenBlockResp(OpenBlockResp {
+            permitted: true, ..
+        })) => (),
+        Ok(StoreProto::OpenBlockResp(OpenBlockResp { failed_target, .. })) => {
+            let target = failed_target.unwrap_or_else(|| first.clone());
+            let e = io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("store {target} refused block {}", pipeline.block),
+            );
+            return Err(failed(&target, offset, e));
+        }
+        Ok(msg) => return Err(PipelineError::new(None, offset, unexpected_store(msg))),
+        Err(e) => return Err(failed(first, offset, e)),
+    }
+    let size = data.len() as u64;
+    for part in data[offset as usize..].chunks(TRANSFER_CHUNK) {
+        let last = offset + part.len() as u64 == size;
+        let chunk = BlockChunk {
+            offset,
+            data: part.to_vec(),
+            last,
+        };
+        let res = write_frame(&mut stream, &StoreProto::BlockChunk(chunk)).await;
+        if let Err(e) = res {
+            return Err(failed(first, offset, e));
+        }
+        let target = match read_frame(&mut stream).await {
+            Ok(StoreProto::ChunkAck(ChunkAck {
+                status: ChunkAckStatus::Ok,
+                ..
+            })) => {
+                offset += part.len() as u64;
+                continue;
+            }
+            Ok(StoreProto::ChunkAck(ChunkAck {
+                status: ChunkAckStatus::DownstreamFailed { target },
+                ..
+            })) => target,
+            Ok(StoreProto::ChunkAck(ChunkAck {
+                status: ChunkAckStatus::LocalFailed,
+                ..
+            }))
+            | Err(_) => first.clone(),
+            Ok(msg) => return Err(PipelineError::new(None, offset, unexpected_store(msg))),
+        };
+        let e = io::Error::other(format!(
+            "block {} failed at offset {offset} on store {target}",
+            pipeline.block
+        ));
+        return Err(failed(&target, offset, e));
+    }
+    debug!(elapsed = ?start.elapsed(), "block written");
+    Ok(())
+}
+
+fn alloc_error(r: AllocBlockRejected) -> io::Error {
+    let kind = match r {
+        AllocBlockRejected::FileNotExist => io::ErrorKind::NotFound,
+        AllocBlockRejected::NotFile => io::ErrorKind::IsADirectory,
+        AllocBlockRejected::OffsetGap { .. }
+        | AllocBlockRejected::NonZeroStart
+        | AllocBlockRejected::EmptyRange
+        | AllocBlockRejected::ReversedRange
+        | AllocBlockRejected::BlockTooLarge { .. } => io::ErrorKind::InvalidInput,
+        AllocBlockRejected::NoStore => io::ErrorKind::StorageFull,
+        AllocBlockRejected::NoLease => io::ErrorKind::PermissionDenied,
+        AllocBlockRejected::SafeMode => io::ErrorKind::ReadOnlyFilesystem,
+        AllocBlockRejected::QuotaExceeded(_) => io::ErrorKind::QuotaExceeded,
+    };
+    io::Error::new(kind, format!("block allocation rejected: {r:?}"))
+}
+
+fn complete_error(r: CompleteFileRejected) -> io::Error {
+    let kind = match r {
+        CompleteFileRejected::FileNotExist => io::ErrorKind::NotFound,
+        CompleteFileRejected::NotFile => io::ErrorKind::IsADirectory,
+        CompleteFileRejected::NoLease => io::ErrorKind::PermissionDenied,
+        CompleteFileRejected::LengthMismatch { .. } => io::ErrorKind::InvalidData,
+        CompleteFileRejected::SafeMode => io::ErrorKind::ReadOnlyFilesystem,
+    };
+    io::Error::new(kind, format!("file completion rejected: {r:?}"))
+}
@@ -0,0 +1,54 @@
+use std::{fmt, sync::Arc};
+
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserializer,
+};
+
+/// Builds the `Arc<str>` straight from the decoder's string slice; the
+/// serde default goes through a `String` and copies it again into the
+/// `Arc`, which adds up in block reports with 100k ids.
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Arc<str>, D::Error> {
+    d.deserialize_str(ArcStrVisitor)
+}
+
+pub fn deserialize_slice<'de, D: Deserializer<'de>>(d: D) -> Result<Arc<[Arc<str>]>, D::Error> {
+    d.deserialize_seq(ArcStrSliceVisitor)
+}
+
+struct ArcStrVisitor;
+impl Visitor<'_> for ArcStrVisitor {
+    type Value = Arc<str>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string")
+    }
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Arc<str>, E> {
+        Ok(v.into())
+    }
+}
+
+struct ArcStrSliceVisitor;
+impl<'de> Visitor<'de> for ArcStrSliceVisitor {
+    type Value = Arc<[Arc<str>]>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of strings")
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut segs = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1024));
+        while let Some(seg) = seq.next_element_seed(ArcStrSeed)? {
+            segs.push(seg);
+        }
+        Ok(segs.into())
+    }
+}
+
+struct ArcStrSeed;
+impl<'de> serde::de::DeserializeSeed<'de> for ArcStrSeed {
+    type Value = Arc<str>;
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<Arc<str>, D::Error> {
+        deserialize(d)
+    }
+}
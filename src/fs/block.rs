@@ -1,87 +1,634 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread,
+};
 
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 
 use crate::store::StoreId;
 
-use super::virt::PathSplit;
+use super::{
+    inode::{InodeId, InodeTable},
+    virt::{atomic_persist, FsNode, FsNodeBody},
+};
 
 pub type BlockId = Arc<str>;
 
+/// Checks that `id` is `blk_` and digits, as [`BlockIdGenerator`] hands
+/// them out. Stores name files after block ids, so an id off the wire must
+/// pass this before it reaches a path.
+pub fn validate_block_id(id: &str) -> Result<(), InvalidBlockIdError> {
+    match id.strip_prefix("blk_") {
+        Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => Ok(()),
+        _ => Err(InvalidBlockIdError(id.into())),
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidBlockIdError(BlockId);
+impl fmt::Display for InvalidBlockIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid block id {:?}", self.0)
+    }
+}
+impl std::error::Error for InvalidBlockIdError {}
+impl From<InvalidBlockIdError> for io::Error {
+    fn from(e: InvalidBlockIdError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
 #[derive(Debug, Clone)]
+pub struct BlockIdGenerator {
+    next: u64,
+}
+impl BlockIdGenerator {
+    pub fn new() -> Self {
+        Self { next: 1 }
+    }
+    pub fn from_high_water_mark(next: u64) -> Self {
+        Self { next }
+    }
+
+    pub fn next_id(&mut self) -> BlockId {
+        let id = format!("blk_{:010}", self.next);
+        self.next += 1;
+        id.into()
+    }
+    pub fn high_water_mark(&self) -> u64 {
+        self.next
+    }
+    pub fn observe(&mut self, id: &BlockId) {
+        let Some(n) = id.strip_prefix("blk_").and_then(|n| n.parse::<u64>().ok()) else {
+            return;
+        };
+        self.next = self.next.max(n + 1);
+    }
+    pub async fn persist(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        atomic_persist(path, self.next.to_string().into_bytes()).await
+    }
+    pub async fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let buf = tokio::fs::read_to_string(path).await?;
+        let next = buf
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self { next })
+    }
+}
+impl Default for BlockIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Split by block id into shards that each carry their own store index and
+/// their own lock, so a full report can be diffed one shard at a time
+/// while the rest of the map stays writable.
+///
+/// Changes go through `&mut self` and so still need the owner's exclusive
+/// access; the locks only keep them apart from readers of
+/// [`ReplicatedBlocksMap::shards`].
+#[derive(Debug)]
 pub struct ReplicatedBlocksMap {
-    map: HashMap<BlockId, ReplicatedBlock>,
+    shards: BlockShards,
+    stores: StoreTable,
 }
 impl ReplicatedBlocksMap {
+    /// One shard per available CPU.
     pub fn new() -> Self {
+        Self::with_shards(default_shards())
+    }
+    pub fn with_shards(shards: NonZeroUsize) -> Self {
         Self {
-            map: HashMap::new(),
+            shards: BlockShards::new(shards),
+            stores: StoreTable::new(),
         }
     }
-    pub fn insert(&mut self, id: BlockId, block: ReplicatedBlock) {
-        assert!(!self.map.contains_key(&id));
-        self.map.insert(id, block);
+    pub fn shards(&self) -> &BlockShards {
+        &self.shards
+    }
+    pub fn store_table(&self) -> &StoreTable {
+        &self.stores
     }
-    pub fn remove(&mut self, id: &BlockId) {
-        assert!(self.map.remove(id).is_some());
+    /// Interns `store` ahead of recording replicas on it shard by shard.
+    pub fn intern_store(&mut self, store: &StoreId) -> StoreIndex {
+        self.stores.intern(store)
+    }
+    fn shard(&self, id: &BlockId) -> RwLockReadGuard<'_, BlockShard> {
+        self.shards.read(self.shards.shard_of(id))
+    }
+    fn shard_mut(&mut self, id: &BlockId) -> RwLockWriteGuard<'_, BlockShard> {
+        self.shards.write(self.shards.shard_of(id))
+    }
+
+    pub fn insert(&mut self, id: BlockId, block: ReplicatedBlock) -> Result<(), BlockExistsError> {
+        self.shard_mut(&id).insert(id, block)
+    }
+    pub fn remove(&mut self, id: &BlockId) -> Result<ReplicatedBlock, BlockNotFoundError> {
+        self.shard_mut(id).remove(id)
     }
     pub fn push_store(
         &mut self,
         store: StoreId,
         block: ReportedBlock,
-    ) -> Result<(), CorruptedBlockError> {
+    ) -> Result<(), PushReplicaError> {
+        let index = self.stores.intern(&store);
+        self.shard_mut(block.id())
+            .push_store(index, block)
+            .map_err(|e| e.on(store))
+    }
+    /// A copy, as the shard is unlocked again on return.
+    pub fn get(&self, id: &BlockId) -> Option<ReplicatedBlock> {
+        self.shard(id).get(id).cloned()
+    }
+    pub fn bump_generation(&mut self, id: &BlockId) -> Option<u64> {
+        self.shard_mut(id).bump_generation(id)
+    }
+
+    pub fn contains(&self, id: &BlockId) -> bool {
+        self.shard(id).contains(id)
+    }
+    /// Locks one shard at a time, so `visit` must not reach back into the
+    /// map.
+    pub fn visit(&self, visit: &mut impl FnMut(&BlockId, &ReplicatedBlock)) {
+        for shard in self.shards.iter() {
+            for (id, block) in shard.iter() {
+                visit(id, block);
+            }
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+    pub fn reported_len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.reported_len()).sum()
+    }
+
+    pub fn remove_store(&mut self, id: &BlockId, store: &StoreId) {
+        let Some(index) = self.stores.index(store) else {
+            return;
+        };
+        self.shard_mut(id).remove_store(id, index);
+    }
+    pub fn remove_all_of_store(&mut self, store: &StoreId) -> Vec<BlockId> {
+        let Some(index) = self.stores.index(store) else {
+            return vec![];
+        };
+        (0..self.shards.len())
+            .flat_map(|i| self.shards.write(i).remove_all_of_store(index))
+            .collect()
+    }
+    pub fn blocks_on(&self, store: &StoreId) -> Vec<BlockId> {
+        let Some(index) = self.stores.index(store) else {
+            return vec![];
+        };
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.blocks_on(index).cloned().collect::<Vec<_>>())
+            .collect()
+    }
+    pub fn count_on(&self, store: &StoreId) -> usize {
+        let Some(index) = self.stores.index(store) else {
+            return 0;
+        };
+        self.shards
+            .iter()
+            .map(|shard| shard.blocks_on(index).count())
+            .sum()
+    }
+
+    pub fn set_inode(&mut self, id: &BlockId, inode: InodeId) {
+        self.shard_mut(id).set_inode(id, inode);
+    }
+    pub fn stores(&self, block: &BlockId) -> Replicas<'_> {
+        let indices = SmallVec::from_slice(self.shard(block).stores(block));
+        Replicas::new(indices, &self.stores)
+    }
+    /// The replication of the file owning `block`, if it is in the live
+    /// tree.
+    pub fn replication_target(
+        &self,
+        block: &BlockId,
+        virt_fs: &FsNode,
+        inodes: &InodeTable,
+    ) -> Option<usize> {
+        let replicated = self.get(block)?;
+        let path = inodes.path(replicated.inode())?;
+        let node = virt_fs.get_by_segs(path.names())?;
+        let FsNodeBody::File(file) = node.body() else {
+            return None;
+        };
+        Some(file.attr().replication().get())
+    }
+    pub fn replication_deficit(
+        &self,
+        block: &BlockId,
+        virt_fs: &FsNode,
+        inodes: &InodeTable,
+    ) -> Option<usize> {
+        let target = self.replication_target(block, virt_fs, inodes)?;
+        let deficit = target.saturating_sub(self.stores(block).len());
+        (deficit != 0).then_some(deficit)
+    }
+}
+
+/// The shard of `id` among `shards`.
+pub fn shard_of(id: &BlockId, shards: usize) -> usize {
+    // ids are sequential, so they are hashed rather than taken mod `shards`
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+pub fn default_shards() -> NonZeroUsize {
+    thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+}
+
+/// The shards of a [`ReplicatedBlocksMap`], each behind its own lock and
+/// shared, so they can be read without the map's owner.
+#[derive(Debug, Clone)]
+pub struct BlockShards {
+    shards: Arc<[RwLock<BlockShard>]>,
+}
+impl BlockShards {
+    fn new(shards: NonZeroUsize) -> Self {
+        Self {
+            shards: (0..shards.get())
+                .map(|_| RwLock::new(BlockShard::new()))
+                .collect(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+    pub fn shard_of(&self, id: &BlockId) -> usize {
+        shard_of(id, self.shards.len())
+    }
+    pub fn read(&self, shard: usize) -> RwLockReadGuard<'_, BlockShard> {
+        self.shards[shard].read().unwrap()
+    }
+    pub fn write(&self, shard: usize) -> RwLockWriteGuard<'_, BlockShard> {
+        self.shards[shard].write().unwrap()
+    }
+    /// Locks each shard in turn, never two at once.
+    fn iter(&self) -> impl Iterator<Item = RwLockReadGuard<'_, BlockShard>> {
+        (0..self.shards.len()).map(|i| self.read(i))
+    }
+    /// `blocks` split by the shard each falls in.
+    pub fn partition<'a>(&self, blocks: &'a [ReportedBlock]) -> Vec<Vec<&'a ReportedBlock>> {
+        let mut parts = vec![vec![]; self.shards.len()];
+        for block in blocks {
+            parts[self.shard_of(block.id())].push(block);
+        }
+        parts
+    }
+}
+
+/// Takes 140 to 280 bytes of heap per block with three replicas, depending
+/// on how full its tables are: 73 bytes a slot in `map`, for the id, the
+/// 56-byte [`ReplicatedBlock`] and a control byte, and 17 a replica in
+/// `store_blocks`. The id strings are the namespace's, so they only add a
+/// reference count.
+///
+/// That is well over 64 bytes a block, but the id, body, inode and three
+/// replicas alone come to 60, so no table keyed by id gets there. The store
+/// index is kept all the same: full report diffs and decommissioning would
+/// otherwise walk every block for each store.
+#[derive(Debug, Clone, Default)]
+pub struct BlockShard {
+    map: HashMap<BlockId, ReplicatedBlock>,
+    store_blocks: HashMap<StoreIndex, HashSet<BlockId>>,
+}
+impl BlockShard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn insert(&mut self, id: BlockId, block: ReplicatedBlock) -> Result<(), BlockExistsError> {
+        if self.map.contains_key(&id) {
+            return Err(BlockExistsError);
+        }
+        for &store in block.stores() {
+            self.index(store, id.clone());
+        }
+        self.map.insert(id, block);
+        Ok(())
+    }
+    pub fn remove(&mut self, id: &BlockId) -> Result<ReplicatedBlock, BlockNotFoundError> {
+        let block = self.map.remove(id).ok_or(BlockNotFoundError)?;
+        for &store in block.stores() {
+            self.unindex(store, id);
+        }
+        Ok(block)
+    }
+    pub fn push_store(
+        &mut self,
+        store: StoreIndex,
+        block: ReportedBlock,
+    ) -> Result<(), ReplicaError> {
         let Some(b) = self.map.get_mut(block.id()) else {
-            return Err(CorruptedBlockError { store });
+            return Err(ReplicaError::Unknown);
         };
         b.push(store, block.body())?;
+        self.index(store, block.id().clone());
         Ok(())
     }
-    pub fn stores(&self, block: &BlockId) -> &[StoreId] {
+    pub fn get(&self, id: &BlockId) -> Option<&ReplicatedBlock> {
+        self.map.get(id)
+    }
+    pub fn bump_generation(&mut self, id: &BlockId) -> Option<u64> {
+        let block = self.map.get_mut(id)?;
+        let stores = block.stores.clone();
+        let generation = block.bump_generation();
+        for store in stores {
+            self.unindex(store, id);
+        }
+        Some(generation)
+    }
+
+    pub fn contains(&self, id: &BlockId) -> bool {
+        self.map.contains_key(id)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&BlockId, &ReplicatedBlock)> {
+        self.map.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    pub fn reported_len(&self) -> usize {
+        self.map
+            .values()
+            .filter(|block| !block.stores.is_empty())
+            .count()
+    }
+
+    pub fn remove_store(&mut self, id: &BlockId, store: StoreIndex) {
+        let Some(block) = self.map.get_mut(id) else {
+            return;
+        };
+        block.stores.retain(|s| *s != store);
+        self.unindex(store, id);
+    }
+    pub fn remove_all_of_store(&mut self, store: StoreIndex) -> Vec<BlockId> {
+        let Some(blocks) = self.store_blocks.remove(&store) else {
+            return vec![];
+        };
+        for id in &blocks {
+            if let Some(block) = self.map.get_mut(id) {
+                block.stores.retain(|s| *s != store);
+            }
+        }
+        blocks.into_iter().collect()
+    }
+    pub fn blocks_on(&self, store: StoreIndex) -> impl Iterator<Item = &BlockId> {
+        self.store_blocks.get(&store).into_iter().flatten()
+    }
+
+    pub fn set_inode(&mut self, id: &BlockId, inode: InodeId) {
+        let Some(block) = self.map.get_mut(id) else {
+            return;
+        };
+        block.inode = inode;
+    }
+    pub fn stores(&self, block: &BlockId) -> &[StoreIndex] {
         self.map
             .get(block)
             .map(|x| x.stores())
             .unwrap_or_else(|| &[])
     }
+
+    fn index(&mut self, store: StoreIndex, id: BlockId) {
+        self.store_blocks.entry(store).or_default().insert(id);
+    }
+    fn unindex(&mut self, store: StoreIndex, id: &BlockId) {
+        let Some(blocks) = self.store_blocks.get_mut(&store) else {
+            return;
+        };
+        blocks.remove(id);
+        if blocks.is_empty() {
+            self.store_blocks.remove(&store);
+        }
+    }
 }
+
+pub type StoreIndex = u32;
+
+/// Stores number in the hundreds while replicas number in the hundreds of
+/// millions, so replicas name their store by index into this table.
+#[derive(Debug, Clone, Default)]
+pub struct StoreTable {
+    ids: Vec<StoreId>,
+    indices: HashMap<StoreId, StoreIndex>,
+}
+impl StoreTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn intern(&mut self, store: &StoreId) -> StoreIndex {
+        if let Some(&index) = self.indices.get(store) {
+            return index;
+        }
+        let index = StoreIndex::try_from(self.ids.len()).expect("too many stores");
+        self.ids.push(store.clone());
+        self.indices.insert(store.clone(), index);
+        index
+    }
+    pub fn index(&self, store: &StoreId) -> Option<StoreIndex> {
+        self.indices.get(store).copied()
+    }
+    pub fn get(&self, index: StoreIndex) -> &StoreId {
+        &self.ids[index as usize]
+    }
+}
+
+/// The stores holding a replica of one block, copied out of its shard.
+#[derive(Debug, Clone)]
+pub struct Replicas<'a> {
+    indices: SmallVec<[StoreIndex; 4]>,
+    table: &'a StoreTable,
+}
+impl<'a> Replicas<'a> {
+    pub fn new(indices: SmallVec<[StoreIndex; 4]>, table: &'a StoreTable) -> Self {
+        Self { indices, table }
+    }
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+    pub fn contains(&self, store: &StoreId) -> bool {
+        self.table
+            .index(store)
+            .is_some_and(|index| self.indices.contains(&index))
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &'a StoreId> + 'a {
+        let table = self.table;
+        self.indices
+            .clone()
+            .into_iter()
+            .map(move |index| table.get(index))
+    }
+    pub fn to_vec(&self) -> Vec<StoreId> {
+        self.iter().cloned().collect()
+    }
+}
+
 impl Default for ReplicatedBlocksMap {
     fn default() -> Self {
         Self::new()
     }
 }
+/// A deep copy, with shards of its own.
+impl Clone for ReplicatedBlocksMap {
+    fn clone(&self) -> Self {
+        Self {
+            shards: BlockShards {
+                shards: self
+                    .shards
+                    .iter()
+                    .map(|shard| RwLock::new(shard.clone()))
+                    .collect(),
+            },
+            stores: self.stores.clone(),
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockExistsError;
+impl fmt::Display for BlockExistsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block already exists")
+    }
+}
+impl std::error::Error for BlockExistsError {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockNotFoundError;
+impl fmt::Display for BlockNotFoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block not found")
+    }
+}
+impl std::error::Error for BlockNotFoundError {}
 
 #[derive(Debug, Clone)]
 pub struct ReplicatedBlock {
     body: BlockBody,
-    stores: Vec<StoreId>,
-    virt_path: PathSplit,
+    /// Almost never more than four.
+    stores: SmallVec<[StoreIndex; 4]>,
+    /// The owning file's.
+    inode: InodeId,
 }
 impl ReplicatedBlock {
-    pub fn new(body: BlockBody, virt_path: PathSplit) -> Self {
+    pub fn new(body: BlockBody, inode: InodeId) -> Self {
         Self {
             body,
-            stores: vec![],
-            virt_path,
+            stores: SmallVec::new(),
+            inode,
         }
     }
     pub fn body(&self) -> &BlockBody {
         &self.body
     }
-    pub fn stores(&self) -> &[StoreId] {
+    pub fn stores(&self) -> &[StoreIndex] {
         &self.stores
     }
-    pub fn virt_path(&self) -> &PathSplit {
-        &self.virt_path
+    pub fn inode(&self) -> InodeId {
+        self.inode
     }
-    pub fn push(&mut self, store: StoreId, body: &BlockBody) -> Result<(), CorruptedBlockError> {
-        if self.body != *body {
-            return Err(CorruptedBlockError { store });
+    pub fn bump_generation(&mut self) -> u64 {
+        self.body.generation += 1;
+        self.stores.clear();
+        self.body.generation
+    }
+    pub fn push(&mut self, store: StoreIndex, body: &BlockBody) -> Result<(), ReplicaError> {
+        if body.generation < self.body.generation {
+            return Err(ReplicaError::Stale);
+        }
+        let reason = if body.generation > self.body.generation {
+            Some(CorruptReason::Generation)
+        } else if body.size != self.body.size {
+            Some(CorruptReason::Size)
+        } else if !self.body.agrees_with(body) {
+            Some(CorruptReason::Checksum)
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(ReplicaError::Corrupted(reason));
+        }
+        if self.stores.contains(&store) {
+            return Ok(());
+        }
+
+        if self.body.checksum.is_none() {
+            self.body.checksum = body.checksum;
         }
         self.stores.push(store);
         Ok(())
     }
 }
-pub struct CorruptedBlockError {
-    pub store: StoreId,
+/// [`PushReplicaError`] before it is told which store the replica is on.
+#[derive(Debug, Clone)]
+pub enum ReplicaError {
+    Unknown,
+    Corrupted(CorruptReason),
+    Stale,
+}
+impl ReplicaError {
+    pub fn on(self, store: StoreId) -> PushReplicaError {
+        match self {
+            Self::Unknown => PushReplicaError::Unknown { store },
+            Self::Corrupted(reason) => PushReplicaError::Corrupted { store, reason },
+            Self::Stale => PushReplicaError::Stale { store },
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub enum PushReplicaError {
+    Unknown {
+        store: StoreId,
+    },
+    Corrupted {
+        store: StoreId,
+        reason: CorruptReason,
+    },
+    Stale {
+        store: StoreId,
+    },
+}
+impl fmt::Display for PushReplicaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown { store } => write!(f, "replica on {store} is of an unknown block"),
+            Self::Corrupted { store, reason } => {
+                write!(f, "replica on {store} is corrupt: {reason:?}")
+            }
+            Self::Stale { store } => write!(f, "replica on {store} is stale"),
+        }
+    }
+}
+impl std::error::Error for PushReplicaError {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CorruptReason {
+    Size,
+    Checksum,
+    Generation,
+    ClientReported,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -119,6 +666,18 @@ impl BlockList {
     pub fn push(&mut self, block: ReportedBlock) {
         self.blocks.push(block);
     }
+    pub fn extend(&mut self, other: &BlockList) {
+        self.blocks.extend(other.blocks.iter().cloned());
+    }
+    pub fn blocks(&self) -> &[ReportedBlock] {
+        &self.blocks
+    }
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
 }
 impl Default for BlockList {
     fn default() -> Self {
@@ -128,6 +687,7 @@ impl Default for BlockList {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReportedBlock {
+    #[serde(deserialize_with = "super::arc_str::deserialize")]
     id: BlockId,
     body: BlockBody,
 }
@@ -141,17 +701,241 @@ impl ReportedBlock {
     pub fn body(&self) -> &BlockBody {
         &self.body
     }
+    pub fn generation(&self) -> u64 {
+        self.body.generation
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BlockBody {
-    size: u32,
+    size: u64,
+    #[serde(default)]
+    checksum: Option<u32>,
+    #[serde(default)]
+    generation: u64,
 }
 impl BlockBody {
-    pub fn new(size: u32) -> Self {
-        Self { size }
+    pub fn new(size: u64, generation: u64) -> Self {
+        Self {
+            size,
+            checksum: None,
+            generation,
+        }
+    }
+    pub fn with_checksum(size: u64, checksum: u32, generation: u64) -> Self {
+        Self {
+            size,
+            checksum: Some(checksum),
+            generation,
+        }
+    }
+    pub fn from_contents(buf: &[u8], generation: u64) -> Self {
+        Self {
+            size: buf.len() as u64,
+            checksum: Some(crc32c::crc32c(buf)),
+            generation,
+        }
     }
-    pub fn size(&self) -> u32 {
+    pub fn size(&self) -> u64 {
         self.size
     }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn checksum(&self) -> Option<u32> {
+        self.checksum
+    }
+    pub fn agrees_with(&self, other: &BlockBody) -> bool {
+        if self.size != other.size {
+            return false;
+        }
+        match (self.checksum, other.checksum) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+}
+
+pub const COMPOSITE_CRC32C: &str = "COMPOSITE-CRC32C";
+
+/// Folds the block count and each block's `(size, crc32c)` into one crc32c,
+/// so the result depends on block boundaries as well as contents.
+pub fn composite_checksum(blocks: &[(u64, u32)]) -> Vec<u8> {
+    let mut crc = crc32c::crc32c(&(blocks.len() as u64).to_be_bytes());
+    for (size, checksum) in blocks {
+        crc = crc32c::crc32c_append(crc, &size.to_be_bytes());
+        crc = crc32c::crc32c_append(crc, &checksum.to_be_bytes());
+    }
+    crc.to_be_bytes().to_vec()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    Crc32c,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockMeta {
+    algorithm: ChecksumAlgorithm,
+    chunk_size: u32,
+    checksum: u32,
+    chunks: Vec<u32>,
+}
+impl BlockMeta {
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+    pub fn chunks(&self) -> &[u32] {
+        &self.chunks
+    }
+    pub fn covers(&self, size: u64) -> bool {
+        size.div_ceil(self.chunk_size as u64) == self.chunks.len() as u64
+    }
+    pub fn verify_chunk(&self, index: usize, data: &[u8]) -> bool {
+        self.chunks
+            .get(index)
+            .is_some_and(|&c| c == crc32c::crc32c(data))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkedChecksum {
+    chunk_size: u32,
+    checksum: u32,
+    chunk: u32,
+    chunk_len: u32,
+    chunks: Vec<u32>,
+}
+impl ChunkedChecksum {
+    pub fn new(chunk_size: u32) -> Self {
+        Self {
+            chunk_size,
+            checksum: 0,
+            chunk: 0,
+            chunk_len: 0,
+            chunks: vec![],
+        }
+    }
+    pub fn update(&mut self, mut buf: &[u8]) {
+        self.checksum = crc32c::crc32c_append(self.checksum, buf);
+        while !buf.is_empty() {
+            let take = ((self.chunk_size - self.chunk_len) as usize).min(buf.len());
+            self.chunk = crc32c::crc32c_append(self.chunk, &buf[..take]);
+            self.chunk_len += take as u32;
+            buf = &buf[take..];
+            if self.chunk_len == self.chunk_size {
+                self.chunks.push(self.chunk);
+                self.chunk = 0;
+                self.chunk_len = 0;
+            }
+        }
+    }
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+    pub fn finish(mut self) -> BlockMeta {
+        if self.chunk_len != 0 {
+            self.chunks.push(self.chunk);
+        }
+        BlockMeta {
+            algorithm: ChecksumAlgorithm::Crc32c,
+            chunk_size: self.chunk_size,
+            checksum: self.checksum,
+            chunks: self.chunks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    /// Every replica the forward map records is in the reverse index, and
+    /// nothing else is.
+    fn assert_consistent(map: &ReplicatedBlocksMap, stores: &[StoreId]) {
+        let mut forward: HashSet<(StoreId, BlockId)> = HashSet::new();
+        map.visit(&mut |id, block| {
+            for &store in block.stores() {
+                forward.insert((map.store_table().get(store).clone(), id.clone()));
+            }
+        });
+        let reverse: HashSet<(StoreId, BlockId)> = stores
+            .iter()
+            .flat_map(|store| {
+                let blocks = map.blocks_on(store);
+                blocks.into_iter().map(|id| (store.clone(), id))
+            })
+            .collect();
+        assert_eq!(forward, reverse);
+    }
+
+    #[test]
+    fn a_shard_held_by_a_reader_leaves_the_others_writable() {
+        let mut map = ReplicatedBlocksMap::with_shards(NonZeroUsize::new(2).unwrap());
+        let body = BlockBody::new(10, 0);
+        let blocks: Vec<ReportedBlock> = (0..16)
+            .map(|i| ReportedBlock::new(format!("blk_{i}").into(), body.clone()))
+            .collect();
+        let shards = map.shards().clone();
+        let parts = shards.partition(&blocks);
+        assert_eq!(parts.iter().map(Vec::len).sum::<usize>(), blocks.len());
+        for (i, part) in parts.iter().enumerate() {
+            assert!(part.iter().all(|block| shards.shard_of(block.id()) == i));
+        }
+
+        // as while a full report is diffed against it
+        let held = shards.read(0);
+        for block in &parts[1] {
+            let id = block.id().clone();
+            map.insert(id, ReplicatedBlock::new(body.clone(), 1))
+                .unwrap();
+            map.push_store("store-0".into(), (*block).clone()).unwrap();
+        }
+        assert!(held.is_empty());
+        drop(held);
+        assert_eq!(map.count_on(&"store-0".into()), parts[1].len());
+    }
+
+    #[test]
+    fn reverse_index_follows_random_operations() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut map = ReplicatedBlocksMap::with_shards(NonZeroUsize::new(3).unwrap());
+        let stores: Vec<StoreId> = (0..4).map(|i| format!("store-{i}").into()).collect();
+        let ids: Vec<BlockId> = (0..20).map(|i| format!("blk_{i}").into()).collect();
+        let body = BlockBody::new(10, 0);
+        for _ in 0..10_000 {
+            let id = &ids[rng.gen_range(0..ids.len())];
+            let store = &stores[rng.gen_range(0..stores.len())];
+            match rng.gen_range(0..5) {
+                0 => {
+                    let _ = map.insert(id.clone(), ReplicatedBlock::new(body.clone(), 1));
+                }
+                1 => {
+                    let _ = map.remove(id);
+                }
+                2 => {
+                    let _ =
+                        map.push_store(store.clone(), ReportedBlock::new(id.clone(), body.clone()));
+                }
+                3 => map.remove_store(id, store),
+                _ if rng.gen_ratio(1, 20) => {
+                    map.remove_all_of_store(store);
+                }
+                _ => {
+                    let _ =
+                        map.push_store(store.clone(), ReportedBlock::new(id.clone(), body.clone()));
+                }
+            }
+            assert_consistent(&map, &stores);
+        }
+    }
 }
@@ -0,0 +1,212 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    num::NonZeroUsize,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block::BlockId,
+    perm::{GroupId, Permission, UserId},
+    virt::PathSplit,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EditRecord {
+    CreateFile {
+        path: PathSplit,
+        replication: NonZeroUsize,
+        perm: Permission,
+    },
+    Mkdir {
+        path: PathSplit,
+        create_parents: bool,
+        perm: Permission,
+    },
+    Delete {
+        path: PathSplit,
+    },
+    Truncate {
+        path: PathSplit,
+    },
+    Reopen {
+        path: PathSplit,
+    },
+    CompleteFile {
+        path: PathSplit,
+    },
+    /// The last writer let go without completing the file.
+    CloseFile {
+        path: PathSplit,
+    },
+    /// The writer's lease is gone; the last block keeps what the stores
+    /// have of it, if they reported it.
+    RecoverLease {
+        path: PathSplit,
+        last_block_len: Option<u64>,
+    },
+
+    Rename {
+        src: PathSplit,
+        dst: PathSplit,
+    },
+    AllocBlock {
+        path: PathSplit,
+        off_range: (u64, u64),
+        block: BlockId,
+    },
+    BumpGeneration {
+        path: PathSplit,
+        block: BlockId,
+    },
+    AbandonBlock {
+        path: PathSplit,
+        block: BlockId,
+    },
+    SetReplication {
+        path: PathSplit,
+        replication: NonZeroUsize,
+    },
+    SetTimes {
+        path: PathSplit,
+        #[serde(with = "super::epoch_millis::option")]
+        mtime: Option<SystemTime>,
+        #[serde(with = "super::epoch_millis::option")]
+        atime: Option<SystemTime>,
+    },
+    SetOwner {
+        path: PathSplit,
+        owner: Option<UserId>,
+        group: Option<GroupId>,
+    },
+    SetMode {
+        path: PathSplit,
+        mode: u16,
+    },
+    SetXattr {
+        path: PathSplit,
+        name: Arc<str>,
+        value: Vec<u8>,
+    },
+    RemoveXattr {
+        path: PathSplit,
+        name: Arc<str>,
+    },
+    SetQuota {
+        path: PathSplit,
+        max_nodes: Option<u64>,
+        max_space: Option<u64>,
+    },
+    ClearQuota {
+        path: PathSplit,
+    },
+    CreateSnapshot {
+        path: PathSplit,
+        name: Arc<str>,
+    },
+    DeleteSnapshot {
+        path: PathSplit,
+        name: Arc<str>,
+    },
+    Concat {
+        target: PathSplit,
+        sources: Vec<PathSplit>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditEntry {
+    txid: u64,
+    /// Replay stamps nodes with this instead of the wall clock.
+    #[serde(with = "super::epoch_millis")]
+    time: SystemTime,
+    record: EditRecord,
+}
+impl EditEntry {
+    pub fn new(txid: u64, time: SystemTime, record: EditRecord) -> Self {
+        Self { txid, time, record }
+    }
+    pub fn txid(&self) -> u64 {
+        self.txid
+    }
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+    pub fn record(&self) -> &EditRecord {
+        &self.record
+    }
+}
+
+#[derive(Debug)]
+pub struct EditLog {
+    file: BufWriter<fs::File>,
+    sync_interval: Duration,
+    last_sync: Instant,
+    dirty: bool,
+}
+impl EditLog {
+    pub fn open(path: impl AsRef<Path>, sync_interval: Duration, now: Instant) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            sync_interval,
+            last_sync: now,
+            dirty: false,
+        })
+    }
+    pub fn append(&mut self, entry: &EditEntry, now: Instant) -> io::Result<()> {
+        let buf = bincode::serialize(entry).map_err(io::Error::other)?;
+        let len = u32::try_from(buf.len()).map_err(io::Error::other)?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(&buf)?;
+        self.dirty = true;
+        self.sync_if_due(now)
+    }
+    pub fn sync_if_due(&mut self, now: Instant) -> io::Result<()> {
+        if !self.dirty || now.duration_since(self.last_sync) < self.sync_interval {
+            return Ok(());
+        }
+        self.sync(now)
+    }
+    pub fn sync(&mut self, now: Instant) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.dirty = false;
+        self.last_sync = now;
+        Ok(())
+    }
+    pub fn truncate(&mut self, now: Instant) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().set_len(0)?;
+        self.sync(now)
+    }
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<EditEntry>> {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut entries = vec![];
+        let mut rest = &buf[..];
+        while let Some((len, body)) = rest.split_first_chunk::<4>() {
+            let len = u32::from_le_bytes(*len) as usize;
+            if body.len() < len {
+                // torn tail from a crash mid-append
+                break;
+            }
+            let (record, body) = body.split_at(len);
+            let entry = bincode::deserialize(record)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            entries.push(entry);
+            rest = body;
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+use super::block::BlockId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FsckVerdict {
+    Healthy,
+    Degraded,
+    Corrupt,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FsckSummary {
+    pub files: u64,
+    pub blocks: u64,
+    pub healthy: u64,
+    pub under_replicated: u64,
+    pub missing: u64,
+    pub corrupt: u64,
+    /// Referenced by a file but absent from the block map.
+    pub unmapped: u64,
+    /// In the block map but referenced by no file or snapshot.
+    pub orphaned: u64,
+    pub non_contiguous: u64,
+}
+impl FsckSummary {
+    /// Only a shortage of replicas degrades; anything that loses data or
+    /// splits the namespace from the block map is corrupt.
+    pub fn verdict(&self) -> FsckVerdict {
+        if self.missing != 0 || self.corrupt != 0 || self.unmapped != 0 || self.non_contiguous != 0
+        {
+            FsckVerdict::Corrupt
+        } else if self.under_replicated != 0 || self.orphaned != 0 {
+            FsckVerdict::Degraded
+        } else {
+            FsckVerdict::Healthy
+        }
+    }
+    pub fn merge(&mut self, other: &FsckSummary) {
+        self.files += other.files;
+        self.blocks += other.blocks;
+        self.healthy += other.healthy;
+        self.under_replicated += other.under_replicated;
+        self.missing += other.missing;
+        self.corrupt += other.corrupt;
+        self.unmapped += other.unmapped;
+        self.orphaned += other.orphaned;
+        self.non_contiguous += other.non_contiguous;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsckFile {
+    pub path: String,
+    pub problems: Vec<FsckProblem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FsckProblem {
+    UnderReplicated {
+        block: BlockId,
+        live: usize,
+        target: usize,
+    },
+    Missing(BlockId),
+    /// Every remaining replica failed verification.
+    Corrupt(BlockId),
+    Unmapped(BlockId),
+    /// The block does not start where the previous one ended.
+    NonContiguous {
+        block: BlockId,
+        expected: u64,
+        start: u64,
+    },
+}
@@ -0,0 +1,113 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::store::ClusterId;
+
+use super::virt::FsNode;
+
+const MAGIC: &[u8; 4] = b"DFSI";
+const VERSION: u32 = 11;
+const HEADER_LEN: usize = MAGIC.len() + std::mem::size_of::<u32>();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsImage {
+    cluster_id: ClusterId,
+    root: FsNode,
+    next_block_id: u64,
+    next_inode: u64,
+    last_txid: u64,
+}
+impl FsImage {
+    pub fn new(
+        cluster_id: ClusterId,
+        root: FsNode,
+        next_block_id: u64,
+        next_inode: u64,
+        last_txid: u64,
+    ) -> Self {
+        Self {
+            cluster_id,
+            root,
+            next_block_id,
+            next_inode,
+            last_txid,
+        }
+    }
+    pub fn cluster_id(&self) -> &ClusterId {
+        &self.cluster_id
+    }
+
+    pub fn root(&self) -> &FsNode {
+        &self.root
+    }
+    pub fn next_block_id(&self) -> u64 {
+        self.next_block_id
+    }
+    pub fn next_inode(&self) -> u64 {
+        self.next_inode
+    }
+    pub fn last_txid(&self) -> u64 {
+        self.last_txid
+    }
+    pub fn into_root(self) -> FsNode {
+        self.root
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        self.write_to(&mut buf).expect("in-memory serialization");
+        buf
+    }
+    pub fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        bincode::serialize_into(w, self).map_err(io::Error::other)
+    }
+    pub fn decode(buf: &[u8]) -> Result<Self, FsImageDecodeError> {
+        if buf.len() < HEADER_LEN || &buf[..MAGIC.len()] != MAGIC {
+            return Err(FsImageDecodeError::BadMagic);
+        }
+        let version = u32::from_le_bytes(buf[MAGIC.len()..HEADER_LEN].try_into().unwrap());
+        if version != VERSION {
+            return Err(FsImageDecodeError::UnsupportedVersion(version));
+        }
+        bincode::deserialize(&buf[HEADER_LEN..]).map_err(FsImageDecodeError::Body)
+    }
+}
+
+#[derive(Debug)]
+pub enum FsImageDecodeError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Body(bincode::Error),
+}
+impl fmt::Display for FsImageDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not an fsimage"),
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported fsimage version {version}, expected {VERSION}"
+                )
+            }
+            Self::Body(e) => write!(f, "malformed fsimage body: {e}"),
+        }
+    }
+}
+impl std::error::Error for FsImageDecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Body(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<FsImageDecodeError> for io::Error {
+    fn from(e: FsImageDecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
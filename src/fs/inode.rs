@@ -0,0 +1,108 @@
+use std::{collections::HashMap, sync::Arc};
+
+use super::virt::{FsNode, FsNodeBody, PathSplit};
+
+pub type InodeId = u64;
+
+/// The root directory's.
+pub const ROOT_INODE: InodeId = 1;
+
+/// Hands out inode ids in increasing order, so an id is never reused even
+/// after its node is gone.
+#[derive(Debug, Clone)]
+pub struct InodeIdGenerator {
+    next: u64,
+}
+impl InodeIdGenerator {
+    pub fn new() -> Self {
+        Self {
+            next: ROOT_INODE + 1,
+        }
+    }
+    pub fn from_high_water_mark(next: u64) -> Self {
+        Self {
+            next: next.max(ROOT_INODE + 1),
+        }
+    }
+
+    pub fn next_id(&mut self) -> InodeId {
+        let id = self.next;
+        self.next += 1;
+        id
+    }
+    pub fn high_water_mark(&self) -> u64 {
+        self.next
+    }
+    pub fn observe(&mut self, id: InodeId) {
+        self.next = self.next.max(id + 1);
+    }
+}
+impl Default for InodeIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where each node of the live tree sits, as its parent's inode and its
+/// name there, so moving a subtree changes one entry however large it is.
+#[derive(Debug, Clone, Default)]
+pub struct InodeTable {
+    parents: HashMap<InodeId, (InodeId, Arc<str>)>,
+}
+impl InodeTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Indexes every node under `root`. Snapshots are not part of the live
+    /// tree and are left out.
+    pub fn build(root: &FsNode) -> Self {
+        let mut table = Self::new();
+        table.insert_children(root);
+        table
+    }
+
+    /// Places `inode` at `name` under `parent`, moving it if it was
+    /// elsewhere.
+    pub fn insert(&mut self, inode: InodeId, parent: InodeId, name: Arc<str>) {
+        self.parents.insert(inode, (parent, name));
+    }
+    /// Indexes `node`, placed at `name` under `parent`, and everything under
+    /// it.
+    pub fn insert_subtree(&mut self, parent: InodeId, name: Arc<str>, node: &FsNode) {
+        self.insert(node.attr().inode(), parent, name);
+        self.insert_children(node);
+    }
+    fn insert_children(&mut self, node: &FsNode) {
+        let FsNodeBody::Directory(directory) = node.body() else {
+            return;
+        };
+        for (name, child) in directory.nodes() {
+            self.insert_subtree(node.attr().inode(), name.clone(), child);
+        }
+    }
+    /// Forgets `node` and everything under it.
+    pub fn remove_subtree(&mut self, node: &FsNode) {
+        self.parents.remove(&node.attr().inode());
+        let FsNodeBody::Directory(directory) = node.body() else {
+            return;
+        };
+        for child in directory.nodes().values() {
+            self.remove_subtree(child);
+        }
+    }
+
+    pub fn contains(&self, inode: InodeId) -> bool {
+        inode == ROOT_INODE || self.parents.contains_key(&inode)
+    }
+    /// `None` if `inode` is not in the live tree.
+    pub fn path(&self, mut inode: InodeId) -> Option<PathSplit> {
+        let mut segs = vec![];
+        while inode != ROOT_INODE {
+            let (parent, name) = self.parents.get(&inode)?;
+            segs.push(name.clone());
+            inode = *parent;
+        }
+        segs.reverse();
+        Some(PathSplit::from_segs(segs))
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use super::{block::BlockId, inode::InodeId, quota::Usage};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// A child name that path parsing would refuse.
+    InvalidName {
+        dir: String,
+        name: String,
+    },
+    /// A cached quota usage that differs from a recount of the subtree.
+    QuotaUsage {
+        path: String,
+        cached: Usage,
+        actual: Usage,
+    },
+    NonContiguous {
+        path: String,
+        block: BlockId,
+        expected: u64,
+        start: u64,
+    },
+    Reversed {
+        path: String,
+        block: BlockId,
+    },
+    Unmapped {
+        path: String,
+        block: BlockId,
+    },
+    /// The block map attributes the block to another file.
+    WrongOwner {
+        path: String,
+        block: BlockId,
+        owner: InodeId,
+    },
+    SharedBlock {
+        block: BlockId,
+        first: String,
+        second: String,
+    },
+}
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidName { dir, name } => write!(f, "{dir}: invalid child name {name:?}"),
+            Self::QuotaUsage {
+                path,
+                cached,
+                actual,
+            } => write!(
+                f,
+                "{path}: cached usage {}n/{}b, counted {}n/{}b",
+                cached.nodes, cached.space, actual.nodes, actual.space
+            ),
+            Self::NonContiguous {
+                path,
+                block,
+                expected,
+                start,
+            } => write!(f, "{path}: {block} starts at {start}, expected {expected}"),
+            Self::Reversed { path, block } => write!(f, "{path}: {block} ends before it starts"),
+            Self::Unmapped { path, block } => write!(f, "{path}: {block} is not in the block map"),
+            Self::WrongOwner { path, block, owner } => {
+                write!(f, "{path}: {block} is mapped to inode {owner}")
+            }
+            Self::SharedBlock {
+                block,
+                first,
+                second,
+            } => write!(f, "{block} is in both {first} and {second}"),
+        }
+    }
+}
@@ -1,2 +1,13 @@
+pub mod arc_str;
 pub mod block;
+pub mod edit_log;
+pub mod epoch_millis;
+pub mod fsck;
+pub mod image;
+pub mod inode;
+pub mod invariant;
+pub mod perm;
+pub mod quota;
+pub mod replication;
+pub mod snapshot;
 pub mod virt;
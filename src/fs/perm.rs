@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+pub type UserId = Arc<str>;
+pub type GroupId = Arc<str>;
+
+pub const DEFAULT_SUPERUSER: &str = "dfs";
+pub const DEFAULT_UMASK: u16 = 0o022;
+
+pub const READ: u16 = 0o4;
+pub const WRITE: u16 = 0o2;
+pub const EXECUTE: u16 = 0o1;
+
+/// Who a request claims to come from. Nothing verifies the claim yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Caller {
+    pub user: UserId,
+    pub groups: Vec<GroupId>,
+}
+impl Caller {
+    pub fn new(user: UserId, groups: Vec<GroupId>) -> Self {
+        Self { user, groups }
+    }
+    pub fn anonymous() -> Self {
+        Self::new("nobody".into(), vec![])
+    }
+    /// `$USER` with a group of the same name, or [`Caller::anonymous`] if
+    /// it is unset; never the superuser.
+    pub fn from_env() -> Self {
+        match std::env::var("USER") {
+            Ok(user) if !user.is_empty() => {
+                let user: UserId = user.into();
+                Self::new(user.clone(), vec![user])
+            }
+            _ => Self::anonymous(),
+        }
+    }
+    pub fn primary_group(&self) -> &GroupId {
+        self.groups.first().unwrap_or(&self.user)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permission {
+    owner: UserId,
+    group: GroupId,
+    mode: u16,
+}
+impl Permission {
+    pub fn new(owner: UserId, group: GroupId, mode: u16) -> Self {
+        Self {
+            owner,
+            group,
+            mode: mode & 0o777,
+        }
+    }
+    pub fn owner(&self) -> &UserId {
+        &self.owner
+    }
+    pub fn group(&self) -> &GroupId {
+        &self.group
+    }
+    pub fn mode(&self) -> u16 {
+        self.mode
+    }
+    pub fn set_owner(&mut self, owner: UserId) {
+        self.owner = owner;
+    }
+    pub fn set_group(&mut self, group: GroupId) {
+        self.group = group;
+    }
+    pub fn set_mode(&mut self, mode: u16) {
+        self.mode = mode & 0o777;
+    }
+    /// Only the most specific class applies: an owner without the owner
+    /// bits is denied even if the other bits would allow it.
+    pub fn allows(&self, caller: &Caller, access: u16) -> bool {
+        let shift = if caller.user == self.owner {
+            6
+        } else if caller.groups.contains(&self.group) {
+            3
+        } else {
+            0
+        };
+        (self.mode >> shift) & access == access
+    }
+}
+impl Default for Permission {
+    fn default() -> Self {
+        Self::new(DEFAULT_SUPERUSER.into(), DEFAULT_SUPERUSER.into(), 0o755)
+    }
+}
@@ -0,0 +1,89 @@
+use std::ops::{Add, Sub};
+
+use serde::{Deserialize, Serialize};
+
+/// What a subtree consumes: descendant nodes and file bytes times replication.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub nodes: u64,
+    pub space: u64,
+}
+impl Add for Usage {
+    type Output = Usage;
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            nodes: self.nodes + other.nodes,
+            space: self.space + other.space,
+        }
+    }
+}
+/// Saturates at zero, so a stale cache can't underflow.
+impl Sub for Usage {
+    type Output = Usage;
+    fn sub(self, other: Usage) -> Usage {
+        Usage {
+            nodes: self.nodes.saturating_sub(other.nodes),
+            space: self.space.saturating_sub(other.space),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    max_nodes: Option<u64>,
+    max_space: Option<u64>,
+    /// Cached so checks don't walk the subtree.
+    usage: Usage,
+}
+impl Quota {
+    pub fn new(max_nodes: Option<u64>, max_space: Option<u64>, usage: Usage) -> Self {
+        Self {
+            max_nodes,
+            max_space,
+            usage,
+        }
+    }
+    pub fn max_nodes(&self) -> Option<u64> {
+        self.max_nodes
+    }
+    pub fn max_space(&self) -> Option<u64> {
+        self.max_space
+    }
+    pub fn usage(&self) -> Usage {
+        self.usage
+    }
+    pub fn set_usage(&mut self, usage: Usage) {
+        self.usage = usage;
+    }
+    /// Only growth is rejected, so a directory already over its quota can
+    /// still shrink.
+    pub fn check(&self, delta: Usage) -> Result<(), QuotaLimit> {
+        let usage = self.usage + delta;
+        if let Some(max) = self.max_nodes {
+            if 0 < delta.nodes && max < usage.nodes {
+                return Err(QuotaLimit::Nodes(max));
+            }
+        }
+        if let Some(max) = self.max_space {
+            if 0 < delta.space && max < usage.space {
+                return Err(QuotaLimit::Space(max));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaLimit {
+    Nodes(u64),
+    Space(u64),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentSummary {
+    pub files: u64,
+    /// Includes the directory summarized.
+    pub directories: u64,
+    pub len: u64,
+    pub space: u64,
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::Instant,
+};
+
+use crate::store::StoreId;
+
+use super::block::{BlockId, CorruptReason};
+
+#[derive(Debug, Clone)]
+pub struct ReplicationQueue {
+    queued: BTreeSet<(usize, BlockId)>,
+    priorities: HashMap<BlockId, usize>,
+    pending: HashMap<BlockId, PendingReplication>,
+    stats: ReplicationQueueStats,
+}
+impl ReplicationQueue {
+    pub fn new() -> Self {
+        Self {
+            queued: BTreeSet::new(),
+            priorities: HashMap::new(),
+            pending: HashMap::new(),
+            stats: ReplicationQueueStats::default(),
+        }
+    }
+    pub fn push(&mut self, block: BlockId, live: usize) {
+        if self.pending.contains_key(&block) {
+            return;
+        }
+        if let Some(prev) = self.priorities.insert(block.clone(), live) {
+            self.queued.remove(&(prev, block.clone()));
+        }
+        self.queued.insert((live, block));
+    }
+    pub fn remove(&mut self, block: &BlockId) {
+        if let Some(prev) = self.priorities.remove(block) {
+            self.queued.remove(&(prev, block.clone()));
+        }
+        self.pending.remove(block);
+    }
+    pub fn pop(&mut self) -> Option<BlockId> {
+        let (_, block) = self.queued.pop_first()?;
+        self.priorities.remove(&block);
+        Some(block)
+    }
+    pub fn start(&mut self, block: BlockId, targets: Vec<StoreId>, deadline: Instant) {
+        self.stats.scheduled += 1;
+        self.pending
+            .insert(block, PendingReplication { targets, deadline });
+    }
+    pub fn fail(&mut self, block: &BlockId, target: &StoreId) {
+        let Some(pending) = self.pending.get_mut(block) else {
+            return;
+        };
+        if !pending.targets.contains(target) {
+            return;
+        }
+        self.stats.failed += 1;
+        pending.targets.retain(|store| store != target);
+        if pending.targets.is_empty() {
+            self.pending.remove(block);
+        }
+    }
+    pub fn pending(&self, block: &BlockId) -> Option<&PendingReplication> {
+        self.pending.get(block)
+    }
+    pub fn expire(&mut self, now: Instant) -> Vec<BlockId> {
+        let expired: Vec<BlockId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(block, _)| block.clone())
+            .collect();
+        for block in &expired {
+            self.pending.remove(block);
+        }
+        self.stats.timed_out += expired.len() as u64;
+        expired
+    }
+    /// Lowest live count first, the order blocks are popped in.
+    pub fn iter_queued(&self) -> impl Iterator<Item = (usize, &BlockId)> {
+        self.queued.iter().map(|(live, block)| (*live, block))
+    }
+    pub fn iter_pending(&self) -> impl Iterator<Item = (&BlockId, &PendingReplication)> {
+        self.pending.iter()
+    }
+    pub fn queued_len(&self) -> usize {
+        self.queued.len()
+    }
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn stats(&self) -> &ReplicationQueueStats {
+        &self.stats
+    }
+}
+impl Default for ReplicationQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingReplication {
+    targets: Vec<StoreId>,
+    deadline: Instant,
+}
+impl PendingReplication {
+    pub fn targets(&self) -> &[StoreId] {
+        &self.targets
+    }
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplicationQueueStats {
+    pub scheduled: u64,
+    pub timed_out: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExcessReplicas {
+    map: HashMap<BlockId, Vec<StoreId>>,
+}
+impl ExcessReplicas {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+    pub fn insert(&mut self, block: BlockId, store: StoreId) {
+        let stores = self.map.entry(block).or_default();
+        if !stores.contains(&store) {
+            stores.push(store);
+        }
+    }
+    pub fn remove(&mut self, block: &BlockId, store: &StoreId) {
+        let Some(stores) = self.map.get_mut(block) else {
+            return;
+        };
+        stores.retain(|s| s != store);
+        if stores.is_empty() {
+            self.map.remove(block);
+        }
+    }
+    pub fn remove_block(&mut self, block: &BlockId) {
+        self.map.remove(block);
+    }
+    pub fn stores(&self, block: &BlockId) -> &[StoreId] {
+        self.map
+            .get(block)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockId> {
+        self.map.keys()
+    }
+    pub fn len(&self) -> usize {
+        self.map.values().map(|stores| stores.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+impl Default for ExcessReplicas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidateQueue {
+    map: HashMap<StoreId, HashSet<BlockId>>,
+}
+impl InvalidateQueue {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+    pub fn push(&mut self, store: StoreId, block: BlockId) {
+        self.map.entry(store).or_default().insert(block);
+    }
+    pub fn remove(&mut self, store: &StoreId, block: &BlockId) {
+        let Some(blocks) = self.map.get_mut(store) else {
+            return;
+        };
+        blocks.remove(block);
+        if blocks.is_empty() {
+            self.map.remove(store);
+        }
+    }
+    pub fn retain(&mut self, store: &StoreId, mut keep: impl FnMut(&BlockId) -> bool) {
+        let Some(blocks) = self.map.get_mut(store) else {
+            return;
+        };
+        blocks.retain(|block| keep(block));
+        if blocks.is_empty() {
+            self.map.remove(store);
+        }
+    }
+    pub fn drain(&mut self, store: &StoreId, max: usize) -> Vec<BlockId> {
+        let Some(blocks) = self.map.get_mut(store) else {
+            return vec![];
+        };
+        let batch: Vec<BlockId> = blocks.iter().take(max).cloned().collect();
+        for block in &batch {
+            blocks.remove(block);
+        }
+        if blocks.is_empty() {
+            self.map.remove(store);
+        }
+        batch
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&StoreId, &HashSet<BlockId>)> {
+        self.map.iter()
+    }
+    pub fn blocks_of(&self, store: &StoreId) -> impl Iterator<Item = &BlockId> {
+        self.map.get(store).into_iter().flatten()
+    }
+    pub fn len_of(&self, store: &StoreId) -> usize {
+        self.map.get(store).map(|blocks| blocks.len()).unwrap_or(0)
+    }
+    pub fn len(&self) -> usize {
+        self.map.values().map(|blocks| blocks.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+impl Default for InvalidateQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorruptReplicas {
+    map: HashMap<BlockId, Vec<CorruptReplica>>,
+}
+impl CorruptReplicas {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+    pub fn insert(&mut self, block: BlockId, store: StoreId, reason: CorruptReason, now: Instant) {
+        let replicas = self.map.entry(block).or_default();
+        if replicas.iter().any(|r| r.store == store) {
+            return;
+        }
+        replicas.push(CorruptReplica {
+            store,
+            reason,
+            first_seen: now,
+        });
+    }
+    pub fn contains(&self, block: &BlockId, store: &StoreId) -> bool {
+        self.get(block).iter().any(|r| &r.store == store)
+    }
+    pub fn remove(&mut self, block: &BlockId, store: &StoreId) {
+        let Some(replicas) = self.map.get_mut(block) else {
+            return;
+        };
+        replicas.retain(|r| &r.store != store);
+        if replicas.is_empty() {
+            self.map.remove(block);
+        }
+    }
+    pub fn remove_block(&mut self, block: &BlockId) -> Vec<CorruptReplica> {
+        self.map.remove(block).unwrap_or_default()
+    }
+    pub fn remove_store(&mut self, store: &StoreId) {
+        self.map.retain(|_, replicas| {
+            replicas.retain(|r| &r.store != store);
+            !replicas.is_empty()
+        });
+    }
+    pub fn get(&self, block: &BlockId) -> &[CorruptReplica] {
+        self.map
+            .get(block)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockId> {
+        self.map.keys()
+    }
+    pub fn len(&self) -> usize {
+        self.map.values().map(|replicas| replicas.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+impl Default for CorruptReplicas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorruptReplica {
+    store: StoreId,
+    reason: CorruptReason,
+    first_seen: Instant,
+}
+impl CorruptReplica {
+    pub fn store(&self) -> &StoreId {
+        &self.store
+    }
+    pub fn reason(&self) -> CorruptReason {
+        self.reason
+    }
+    pub fn first_seen(&self) -> Instant {
+        self.first_seen
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block::BlockId,
+    virt::{Directory, File, FsNode, FsNodeBody, PathSplit},
+};
+
+/// Paths are relative to the snapshotted directory, which is `/`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiffEntry {
+    Created(String),
+    Deleted(String),
+    Modified(String),
+    Renamed { from: String, to: String },
+}
+
+/// Compares two versions of a directory. Children shared between them are
+/// skipped by pointer, so the walk only descends where something changed.
+pub fn diff(from: &FsNode, to: &FsNode) -> Vec<DiffEntry> {
+    diff_counting(from, to).0
+}
+
+/// Also returns how many pairs of directories were compared.
+fn diff_counting(from: &FsNode, to: &FsNode) -> (Vec<DiffEntry>, usize) {
+    let mut entries = vec![];
+    let mut compared = 0;
+    let mut deleted = vec![];
+    let mut created = vec![];
+    let root = PathSplit::from_uri("");
+    if attr_changed(from, to) {
+        entries.push(DiffEntry::Modified(root.to_uri()));
+    }
+    let (FsNodeBody::Directory(from), FsNodeBody::Directory(to)) = (from.body(), to.body()) else {
+        return (entries, compared);
+    };
+    let mut stack: Vec<(PathSplit, &Directory, &Directory)> = vec![(root, from, to)];
+    while let Some((path, from, to)) = stack.pop() {
+        compared += 1;
+        for (name, old) in from.nodes() {
+            let path = path.child(name);
+            let Some(new) = to.nodes().get(name) else {
+                deleted.push((path, old));
+                continue;
+            };
+            if Arc::ptr_eq(old, new) {
+                continue;
+            }
+            match (old.body(), new.body()) {
+                (FsNodeBody::Directory(old_dir), FsNodeBody::Directory(new_dir)) => {
+                    if attr_changed(old, new) {
+                        entries.push(DiffEntry::Modified(path.to_uri()));
+                    }
+                    stack.push((path, old_dir, new_dir));
+                }
+                (FsNodeBody::File(old_file), FsNodeBody::File(new_file)) => {
+                    if attr_changed(old, new) || file_changed(old_file, new_file) {
+                        entries.push(DiffEntry::Modified(path.to_uri()));
+                    }
+                }
+                _ => {
+                    deleted.push((path.clone(), old));
+                    created.push((path, new));
+                }
+            }
+        }
+        for (name, new) in to.nodes() {
+            if !from.nodes().contains_key(name) {
+                created.push((path.child(name), new));
+            }
+        }
+    }
+    pair_renames(deleted, created, &mut entries);
+    (entries, compared)
+}
+
+/// A created node that is the same allocation as a deleted one, or a file
+/// starting with the same block, was moved rather than replaced.
+fn pair_renames(
+    deleted: Vec<(PathSplit, &Arc<FsNode>)>,
+    created: Vec<(PathSplit, &Arc<FsNode>)>,
+    entries: &mut Vec<DiffEntry>,
+) {
+    let mut by_ptr: HashMap<*const FsNode, usize> = HashMap::new();
+    let mut by_block: HashMap<&BlockId, usize> = HashMap::new();
+    for (i, (_, node)) in deleted.iter().enumerate() {
+        by_ptr.insert(Arc::as_ptr(node), i);
+        if let Some(block) = first_block(node) {
+            by_block.insert(block, i);
+        }
+    }
+    let mut renamed = vec![None; deleted.len()];
+    for (path, node) in created {
+        let found = by_ptr
+            .remove(&Arc::as_ptr(node))
+            .or_else(|| first_block(node).and_then(|block| by_block.remove(block)));
+        match found {
+            Some(i) if renamed[i].is_none() => renamed[i] = Some(path),
+            _ => entries.push(DiffEntry::Created(path.to_uri())),
+        }
+    }
+    for ((path, _), renamed) in deleted.into_iter().zip(renamed) {
+        entries.push(match renamed {
+            Some(to) => DiffEntry::Renamed {
+                from: path.to_uri(),
+                to: to.to_uri(),
+            },
+            None => DiffEntry::Deleted(path.to_uri()),
+        });
+    }
+}
+
+fn first_block(node: &FsNode) -> Option<&BlockId> {
+    match node.body() {
+        FsNodeBody::File(file) => file.blocks().first().map(|block| block.id()),
+        FsNodeBody::Directory(_) => None,
+    }
+}
+
+fn attr_changed(old: &FsNode, new: &FsNode) -> bool {
+    old.attr().mtime() != new.attr().mtime()
+        || old.attr().perm() != new.attr().perm()
+        || old.attr().xattrs() != new.attr().xattrs()
+}
+
+fn file_changed(old: &File, new: &File) -> bool {
+    old.len() != new.len()
+        || old.attr().replication() != new.attr().replication()
+        || old.blocks().len() != new.blocks().len()
+        || old
+            .blocks()
+            .iter()
+            .zip(new.blocks())
+            .any(|(old, new)| old.id() != new.id() || old.generation() != new.generation())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::SystemTime};
+
+    use crate::fs::{
+        perm::Permission,
+        virt::{DirectoryAttribute, FileAttribute, FileBlock, FsNodeAttribute, PathCursor},
+    };
+
+    use super::*;
+
+    fn node(body: FsNodeBody) -> FsNode {
+        let attr = FsNodeAttribute::new(0, SystemTime::UNIX_EPOCH, Permission::default());
+        FsNode::new(attr, body)
+    }
+
+    /// `fanout` children per directory, `depth` levels of directories above
+    /// the files.
+    fn tree(fanout: usize, depth: usize) -> FsNode {
+        if depth == 0 {
+            let attr = FileAttribute::new(NonZeroUsize::new(3).unwrap());
+            return node(FsNodeBody::File(File::new(attr)));
+        }
+        let mut dir = Directory::new(DirectoryAttribute::new());
+        for i in 0..fanout {
+            dir.insert(i.to_string().into(), tree(fanout, depth - 1))
+                .unwrap();
+        }
+        node(FsNodeBody::Directory(dir))
+    }
+
+    #[test]
+    fn diff_of_a_million_nodes_only_walks_the_changed_path() {
+        let from = tree(100, 3);
+        let mut to = from.clone();
+        let path = PathSplit::from_uri("/42/7/99");
+        let cursor = PathCursor::new(path.clone());
+        let FsNodeBody::File(file) = to.get_mut(cursor).unwrap().body_mut() else {
+            panic!("not a file");
+        };
+        file.append_block(FileBlock::new((0, 10), "blk_1".into()))
+            .unwrap();
+
+        let (entries, compared) = diff_counting(&from, &to);
+        assert_eq!(entries, [DiffEntry::Modified(path.to_uri())]);
+        // `/`, `/42` and `/42/7`
+        assert_eq!(compared, 3);
+        let (entries, compared) = diff_counting(&from, &from.clone());
+        assert!(entries.is_empty());
+        assert_eq!(compared, 1);
+    }
+}
@@ -1,123 +1,341 @@
 use std::{
-    collections::HashMap,
-    io::{self, Write},
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    fmt,
+    io::{self, BufWriter, Write},
+    mem,
     num::NonZeroUsize,
-    path::Path,
+    ops::{Bound, ControlFlow},
+    path::{Path, PathBuf},
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use tokio::task::spawn_blocking;
 
-use super::block::BlockId;
+use super::{
+    block::{BlockId, ReplicatedBlocksMap},
+    inode::InodeId,
+    invariant::InvariantViolation,
+    perm::Permission,
+    quota::{ContentSummary, Quota, Usage},
+};
+
+pub type ClientId = Arc<str>;
+
+pub const MAX_NAME_LEN: usize = 255;
+/// Reserved name under which a directory's snapshots are readable.
+pub const SNAPSHOT_DIR: &str = ".snapshot";
 
 #[derive(Debug, Clone)]
 pub struct OpenFileTable {
     map: HashMap<PathSplit, OpenFileAttribute>,
+    /// Paths by when a holder last took or renewed a lease, so a tick only
+    /// visits the due ones. Renewals and closes leave stale entries behind,
+    /// which `map` has the last word on.
+    leased_at: BTreeMap<Instant, Vec<PathSplit>>,
 }
 impl OpenFileTable {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            leased_at: BTreeMap::new(),
         }
     }
     pub fn open(
         &mut self,
         path: PathSplit,
+        client: ClientId,
         write: bool,
         now: Instant,
-    ) -> Result<(), OpenExclusionError> {
-        if self
-            .map
-            .get(&path)
-            .is_some_and(|attr| attr.write() || write)
-        {
-            return Err(OpenExclusionError { path });
+        soft_limit: Duration,
+    ) -> Result<Option<ExpiredLease>, OpenExclusionError> {
+        let mut taken_over = None;
+        if let Some(attr) = self.map.get(&path) {
+            let stale_writer = attr
+                .write()
+                .then(|| attr.holders().next().unwrap().clone())
+                .filter(|writer| soft_limit < attr.since_last_lease(writer, now).unwrap());
+            if let (true, Some(writer)) = (write, stale_writer) {
+                self.map.remove(&path);
+                taken_over = Some(ExpiredLease {
+                    path: path.clone(),
+                    client: writer,
+                    write: true,
+                });
+            }
+        }
+        if let Some(attr) = self.map.get(&path) {
+            if attr.write() || write {
+                let held_for_write = attr.write();
+                return Err(OpenExclusionError {
+                    path,
+                    held_for_write,
+                });
+            }
         }
+        self.leased_at.entry(now).or_default().push(path.clone());
         let Some(attr) = self.map.get_mut(&path) else {
-            self.map.insert(path, OpenFileAttribute::new(write, now));
-            return Ok(());
+            self.map
+                .insert(path, OpenFileAttribute::new(client, write, now));
+            return Ok(taken_over);
         };
-        attr.read();
-        Ok(())
+        attr.read(client, now);
+        Ok(taken_over)
     }
-    pub fn lease(&mut self, path: &PathSplit, now: Instant) -> Result<(), LeaseNotFoundError> {
+    pub fn lease(
+        &mut self,
+        path: &PathSplit,
+        client: &ClientId,
+        now: Instant,
+    ) -> Result<(), LeaseNotFoundError> {
         let Some(attr) = self.map.get_mut(path) else {
             return Err(LeaseNotFoundError);
         };
-        attr.lease(now);
+        attr.lease(client, now)?;
+        self.leased_at.entry(now).or_default().push(path.clone());
         Ok(())
     }
-    pub fn close(&mut self, path: &PathSplit) {
+    pub fn close(&mut self, path: &PathSplit, client: &ClientId) -> Result<(), LeaseNotFoundError> {
         let Some(attr) = self.map.get_mut(path) else {
-            return;
+            return Err(LeaseNotFoundError);
         };
-        attr.close();
+        attr.close(client)?;
         if attr.is_free() {
             self.map.remove(path).unwrap();
         }
+        Ok(())
+    }
+    pub fn is_open(&self, path: &PathSplit) -> bool {
+        self.map.contains_key(path)
     }
-    pub fn clear_timeout(&mut self, ttl: Duration, now: Instant) {
-        let mut timed_out = vec![];
-        for (path, attr) in &self.map {
-            if attr.is_timeout(ttl, now) {
-                timed_out.push(path.clone());
+    pub fn is_any_open_under(&self, dir: &PathSplit) -> bool {
+        self.map.keys().any(|path| path.starts_with(dir))
+    }
+    pub fn get(&self, path: &PathSplit) -> Option<&OpenFileAttribute> {
+        self.map.get(path)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&PathSplit, &OpenFileAttribute)> {
+        self.map.iter()
+    }
+    /// Files open by anyone, however many holders each has.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    pub fn force_close(&mut self, path: &PathSplit) -> Option<OpenFileAttribute> {
+        self.map.remove(path)
+    }
+    pub fn clear_timeout(&mut self, ttl: Duration, now: Instant) -> Vec<ExpiredLease> {
+        let mut expired = vec![];
+        let Some(cutoff) = now.checked_sub(ttl) else {
+            return expired;
+        };
+        let pending = self.leased_at.split_off(&cutoff);
+        let due = mem::replace(&mut self.leased_at, pending);
+        for path in due.into_values().flatten() {
+            let Some(attr) = self.map.get_mut(&path) else {
+                continue;
+            };
+            for client in attr.clear_timeout(ttl, now) {
+                expired.push(ExpiredLease {
+                    path: path.clone(),
+                    client,
+                    write: attr.write(),
+                });
+            }
+            if attr.is_free() {
+                self.map.remove(&path);
             }
         }
-        for path in timed_out {
-            self.map.remove(&path);
-        }
+        expired
     }
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpenExclusionError {
     pub path: PathSplit,
+    pub held_for_write: bool,
+}
+impl fmt::Display for OpenExclusionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.held_for_write { "write" } else { "read" };
+        write!(f, "{} is held open for {mode}", self.path)
+    }
 }
+impl std::error::Error for OpenExclusionError {}
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LeaseNotFoundError;
+impl fmt::Display for LeaseNotFoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no lease held")
+    }
+}
+impl std::error::Error for LeaseNotFoundError {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredLease {
+    pub path: PathSplit,
+    pub client: ClientId,
+    pub write: bool,
+}
 impl Default for OpenFileTable {
     fn default() -> Self {
         Self::new()
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LeaseLimits {
+    pub soft: Duration,
+    pub hard: Duration,
+}
+impl Default for LeaseLimits {
+    fn default() -> Self {
+        Self {
+            soft: Duration::from_secs(60),
+            hard: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Sizes count the name and the value.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct XattrLimits {
+    pub max_entry: usize,
+    pub max_per_node: usize,
+}
+impl XattrLimits {
+    pub fn permits(&self, attr: &FsNodeAttribute, name: &str, value_len: usize) -> bool {
+        let entry = xattr_size(name, value_len);
+        let replaced = attr
+            .xattrs()
+            .get(name)
+            .map(|value| xattr_size(name, value.len()))
+            .unwrap_or(0);
+        entry <= self.max_entry && attr.xattrs_size() - replaced + entry <= self.max_per_node
+    }
+}
+impl Default for XattrLimits {
+    fn default() -> Self {
+        Self {
+            max_entry: 16 * 1024,
+            max_per_node: 64 * 1024,
+        }
+    }
+}
+
+/// Bounds the depth of the namespace tree, which serialization walks
+/// recursively.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PathLimits {
+    pub max_depth: usize,
+    pub max_name_len: usize,
+}
+impl PathLimits {
+    pub fn check(&self, path: &PathSplit) -> Result<(), PathLimitError> {
+        if self.max_depth < path.segs().len() {
+            return Err(PathLimitError::PathTooDeep {
+                max: self.max_depth,
+            });
+        }
+        if path.segs().iter().any(|seg| self.max_name_len < seg.len()) {
+            return Err(PathLimitError::NameTooLong {
+                max: self.max_name_len,
+            });
+        }
+        Ok(())
+    }
+}
+impl Default for PathLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 1000,
+            max_name_len: MAX_NAME_LEN,
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum PathLimitError {
+    PathTooDeep { max: usize },
+    NameTooLong { max: usize },
+}
+impl fmt::Display for PathLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PathTooDeep { max } => write!(f, "path is deeper than {max} segments"),
+            Self::NameTooLong { max } => write!(f, "name is longer than {max} bytes"),
+        }
+    }
+}
+impl std::error::Error for PathLimitError {}
+
+fn xattr_size(name: &str, value_len: usize) -> usize {
+    name.len() + value_len
+}
+
 #[derive(Debug, Clone)]
 pub struct OpenFileAttribute {
     write: bool,
-    last_lease: Instant,
-    holders: usize,
+    holders: HashMap<ClientId, Instant>,
 }
 impl OpenFileAttribute {
-    pub fn new(write: bool, now: Instant) -> Self {
+    pub fn new(client: ClientId, write: bool, now: Instant) -> Self {
         Self {
             write,
-            last_lease: now,
-            holders: 1,
+            holders: HashMap::from_iter([(client, now)]),
         }
     }
-    pub fn read(&mut self) {
+    pub fn read(&mut self, client: ClientId, now: Instant) {
         if self.write {
             return;
         }
-        self.holders += 1;
+        self.holders.insert(client, now);
     }
     pub fn write(&self) -> bool {
         self.write
     }
-    pub fn lease(&mut self, now: Instant) {
-        self.last_lease = now;
+    pub fn holders(&self) -> impl Iterator<Item = &ClientId> {
+        self.holders.keys()
     }
-    pub fn close(&mut self) {
-        self.holders = self.holders.saturating_sub(1);
+    pub fn is_held_by(&self, client: &ClientId) -> bool {
+        self.holders.contains_key(client)
+    }
+    pub fn since_last_lease(&self, client: &ClientId, now: Instant) -> Option<Duration> {
+        let last_lease = self.holders.get(client)?;
+        Some(now.duration_since(*last_lease))
+    }
+    pub fn since_latest_lease(&self, now: Instant) -> Option<Duration> {
+        let last_lease = self.holders.values().max()?;
+        Some(now.duration_since(*last_lease))
+    }
+
+    pub fn lease(&mut self, client: &ClientId, now: Instant) -> Result<(), LeaseNotFoundError> {
+        let Some(last_lease) = self.holders.get_mut(client) else {
+            return Err(LeaseNotFoundError);
+        };
+        *last_lease = now;
+        Ok(())
+    }
+    pub fn close(&mut self, client: &ClientId) -> Result<(), LeaseNotFoundError> {
+        self.holders.remove(client).ok_or(LeaseNotFoundError)?;
+        Ok(())
     }
     pub fn is_free(&self) -> bool {
-        self.holders == 0
+        self.holders.is_empty()
     }
-    pub fn is_timeout(&self, ttl: Duration, now: Instant) -> bool {
-        let unrefreshed_for = now.duration_since(self.last_lease);
-        ttl < unrefreshed_for
+    pub fn clear_timeout(&mut self, ttl: Duration, now: Instant) -> Vec<ClientId> {
+        let timed_out: Vec<ClientId> = self
+            .holders
+            .iter()
+            .filter(|(_, last_lease)| ttl < now.duration_since(**last_lease))
+            .map(|(client, _)| client.clone())
+            .collect();
+        for client in &timed_out {
+            self.holders.remove(client);
+        }
+        timed_out
     }
 }
 
@@ -130,104 +348,675 @@ impl FsNode {
     pub fn new(attr: FsNodeAttribute, body: FsNodeBody) -> Self {
         Self { attr, body }
     }
+    pub fn attr(&self) -> &FsNodeAttribute {
+        &self.attr
+    }
+    pub fn attr_mut(&mut self) -> &mut FsNodeAttribute {
+        &mut self.attr
+    }
     pub fn body(&self) -> &FsNodeBody {
         &self.body
     }
     pub fn body_mut(&mut self) -> &mut FsNodeBody {
         &mut self.body
     }
+    /// Visits the children of the directory at `path` in name order, or the
+    /// file itself; `visit` breaks to stop early.
     pub fn list(
         &self,
-        path: Option<PathCursor>,
-        mut visit: impl FnMut(&FsNode),
+        mut path: Option<PathCursor>,
+        start_after: Option<&str>,
+        mut visit: impl FnMut(&Arc<str>, &FsNode) -> ControlFlow<()>,
     ) -> Result<(), FsNodeQueryError> {
-        let Some(path) = path else {
-            match &self.body {
-                FsNodeBody::Directory(directory) => {
-                    directory.nodes().values().for_each(visit);
-                }
-                FsNodeBody::File(_) => {
-                    visit(self);
+        let mut node = self;
+        let mut in_snapshot = false;
+        while let Some(cursor) = path {
+            let FsNodeBody::Directory(directory) = &node.body else {
+                return Err(FsNodeQueryError::DirectoryNotExist(DirectoryNotExist {
+                    path: cursor,
+                }));
+            };
+            let Some(child) = directory.lookup(cursor.curr(), &mut in_snapshot) else {
+                return Err(FsNodeQueryError::FileNotExist(FileNotExist {
+                    path: cursor,
+                }));
+            };
+            let next = cursor.next();
+            if let (None, FsNodeBody::File(_)) = (&next, child.body()) {
+                if start_after.is_none() {
+                    let _ = visit(cursor.curr(), child);
                 }
+                return Ok(());
             }
-            return Ok(());
-        };
-        match &self.body {
+            node = child;
+            path = next;
+        }
+        match &node.body {
             FsNodeBody::Directory(directory) => {
-                let Some(node) = directory.nodes().get(path.curr()) else {
-                    return Err(FsNodeQueryError::FileNotExist(FileNotExist { path }));
+                for (name, node) in directory.nodes_after(start_after) {
+                    if visit(name, node).is_break() {
+                        break;
+                    }
+                }
+            }
+            FsNodeBody::File(_) => {
+                if start_after.is_none() {
+                    let _ = visit(&Arc::from(""), node);
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn get(&self, mut path: Option<PathCursor>) -> Result<&FsNode, FsNodeQueryError> {
+        let mut node = self;
+        let mut in_snapshot = false;
+        while let Some(cursor) = path {
+            let FsNodeBody::Directory(directory) = &node.body else {
+                return Err(FsNodeQueryError::DirectoryNotExist(DirectoryNotExist {
+                    path: cursor,
+                }));
+            };
+            let Some(child) = directory.lookup(cursor.curr(), &mut in_snapshot) else {
+                return Err(FsNodeQueryError::FileNotExist(FileNotExist {
+                    path: cursor,
+                }));
+            };
+            node = child;
+            path = cursor.next();
+        }
+        Ok(node)
+    }
+    /// Like [`FsNode::get`] but walks borrowed names, so resolving a path
+    /// allocates nothing; `None` where it does not resolve.
+    pub fn get_by_segs<'a>(&self, segs: impl IntoIterator<Item = &'a str>) -> Option<&FsNode> {
+        self.walk_segs(segs, |_| {})
+    }
+    /// [`FsNode::get_by_segs`], calling `visit` on every directory passed
+    /// through, as [`FsNode::ancestors`] would return them.
+    pub fn walk_segs<'a>(
+        &self,
+        segs: impl IntoIterator<Item = &'a str>,
+        mut visit: impl FnMut(&FsNode),
+    ) -> Option<&FsNode> {
+        let mut node = self;
+        let mut in_snapshot = false;
+        for seg in segs {
+            let FsNodeBody::Directory(directory) = &node.body else {
+                return None;
+            };
+            visit(node);
+            node = directory.lookup(seg, &mut in_snapshot)?;
+        }
+        Some(node)
+    }
+    /// The directories passed through on the way to `path`, starting at
+    /// `self`; stops early where the path does not resolve.
+    pub fn ancestors(&self, mut path: Option<PathCursor>) -> Vec<&FsNode> {
+        let mut nodes = vec![];
+        let mut node = self;
+        let mut in_snapshot = false;
+        while let Some(cursor) = path {
+            let FsNodeBody::Directory(directory) = &node.body else {
+                break;
+            };
+            nodes.push(node);
+            let Some(child) = directory.lookup(cursor.curr(), &mut in_snapshot) else {
+                break;
+            };
+            node = child;
+            path = cursor.next();
+        }
+        nodes
+    }
+    pub fn get_mut(
+        &mut self,
+        mut path: Option<PathCursor>,
+    ) -> Result<&mut FsNode, FsNodeQueryError> {
+        let mut node = self;
+        while let Some(cursor) = path {
+            let FsNodeBody::Directory(directory) = &mut node.body else {
+                return Err(FsNodeQueryError::DirectoryNotExist(DirectoryNotExist {
+                    path: cursor,
+                }));
+            };
+            let Some(child) = directory.nodes_mut().get_mut(cursor.curr()) else {
+                return Err(FsNodeQueryError::FileNotExist(FileNotExist {
+                    path: cursor,
+                }));
+            };
+            node = Arc::make_mut(child);
+            path = cursor.next();
+        }
+        Ok(node)
+    }
+    /// The error [`FsNode::create_node`] would return, creating nothing.
+    pub fn check_create_node(&self, mut path: PathCursor) -> Result<(), FsNodeCreateFileError> {
+        let mut node = self;
+        loop {
+            let FsNodeBody::Directory(directory) = &node.body else {
+                return Err(FsNodeCreateFileError::DirectoryNotExist(
+                    DirectoryNotExist { path },
+                ));
+            };
+            let Some(child) = path.next() else {
+                if directory.nodes().contains_key(path.curr()) {
+                    return Err(FsNodeCreateFileError::FileExist(FileExist { path }));
+                }
+                return Ok(());
+            };
+            let Some(next) = directory.nodes().get(path.curr()) else {
+                return Err(FsNodeCreateFileError::DirectoryNotExist(
+                    DirectoryNotExist { path },
+                ));
+            };
+            node = next;
+            path = child;
+        }
+    }
+    pub fn create_node<N>(
+        &mut self,
+        mut path: PathCursor,
+        new_node: impl FnOnce() -> N,
+    ) -> Result<(), FsNodeCreateFileError>
+    where
+        N: Into<Arc<FsNode>>,
+    {
+        let mut node = self;
+        loop {
+            let FsNodeBody::Directory(directory) = &mut node.body else {
+                return Err(FsNodeCreateFileError::DirectoryNotExist(
+                    DirectoryNotExist { path },
+                ));
+            };
+            let Some(child) = path.next() else {
+                if directory.nodes().contains_key(path.curr()) {
+                    return Err(FsNodeCreateFileError::FileExist(FileExist { path }));
+                }
+                directory
+                    .nodes_mut()
+                    .insert(path.curr().clone(), new_node().into());
+                return Ok(());
+            };
+            let Some(next) = directory.nodes_mut().get_mut(path.curr()) else {
+                return Err(FsNodeCreateFileError::DirectoryNotExist(
+                    DirectoryNotExist { path },
+                ));
+            };
+            node = Arc::make_mut(next);
+            path = child;
+        }
+    }
+    /// The error [`FsNode::create_dirs`] would return, creating nothing.
+    pub fn check_create_dirs(
+        &self,
+        mut path: PathCursor,
+        create_parents: bool,
+    ) -> Result<(), FsNodeCreateDirsError> {
+        let mut node = self;
+        loop {
+            let FsNodeBody::Directory(directory) = &node.body else {
+                return Err(FsNodeCreateDirsError::DirectoryNotExist(
+                    DirectoryNotExist { path },
+                ));
+            };
+            let child = path.next();
+            let Some(next) = directory.nodes().get(path.curr()) else {
+                if child.is_some() && !create_parents {
+                    return Err(FsNodeCreateDirsError::DirectoryNotExist(
+                        DirectoryNotExist { path },
+                    ));
+                }
+                // everything from here down is created
+                return Ok(());
+            };
+            if let FsNodeBody::File(_) = next.body() {
+                return Err(FsNodeCreateDirsError::FileExist(FileExist { path }));
+            }
+            match child {
+                Some(child) => {
+                    node = next;
+                    path = child;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+    pub fn create_dirs(
+        &mut self,
+        mut path: PathCursor,
+        create_parents: bool,
+        mut new_attr: impl FnMut() -> FsNodeAttribute,
+    ) -> Result<bool, FsNodeCreateDirsError> {
+        let mut node = self;
+        loop {
+            let FsNodeBody::Directory(directory) = &mut node.body else {
+                return Err(FsNodeCreateDirsError::DirectoryNotExist(
+                    DirectoryNotExist { path },
+                ));
+            };
+            let child = path.next();
+            let (next, existed) = match directory.nodes_mut().entry(path.curr().clone()) {
+                Entry::Occupied(entry) => (entry.into_mut(), true),
+                Entry::Vacant(entry) => {
+                    if child.is_some() && !create_parents {
+                        return Err(FsNodeCreateDirsError::DirectoryNotExist(
+                            DirectoryNotExist { path },
+                        ));
+                    }
+                    let attr = new_attr();
+                    node.attr.set_mtime(attr.ctime());
+                    let new_node = FsNode::new(
+                        attr,
+                        FsNodeBody::Directory(Directory::new(DirectoryAttribute::new())),
+                    );
+                    (entry.insert(Arc::new(new_node)), false)
+                }
+            };
+            let next = Arc::make_mut(next);
+            if let FsNodeBody::File(_) = next.body() {
+                return Err(FsNodeCreateDirsError::FileExist(FileExist { path }));
+            }
+            match child {
+                Some(child) => {
+                    node = next;
+                    path = child;
+                }
+                None => return Ok(existed),
+            }
+        }
+    }
+    /// Hands back the shared node, so a rename keeps it shared.
+    pub fn remove_node(&mut self, mut path: PathCursor) -> Result<Arc<FsNode>, FsNodeQueryError> {
+        let mut node = self;
+        loop {
+            let FsNodeBody::Directory(directory) = &mut node.body else {
+                return Err(FsNodeQueryError::DirectoryNotExist(DirectoryNotExist {
+                    path,
+                }));
+            };
+            let Some(child) = path.next() else {
+                return match directory.nodes_mut().remove(path.curr()) {
+                    Some(removed) => Ok(removed),
+                    None => Err(FsNodeQueryError::FileNotExist(FileNotExist { path })),
                 };
-                node.list(path.next(), visit)
+            };
+            let Some(next) = directory.nodes_mut().get_mut(path.curr()) else {
+                return Err(FsNodeQueryError::FileNotExist(FileNotExist { path }));
+            };
+            node = Arc::make_mut(next);
+            path = child;
+        }
+    }
+    /// The error [`FsNode::rename`] would return, moving nothing.
+    pub fn check_rename(
+        &self,
+        src: &PathCursor,
+        dst: &PathCursor,
+    ) -> Result<(), FsNodeRenameError> {
+        if dst.path_split().starts_with(src.path_split()) {
+            return Err(FsNodeRenameError::DstUnderSrc);
+        }
+        if let Err(e) = self.get(Some(src.clone())) {
+            return Err(FsNodeRenameError::SrcNotExist(e));
+        }
+        let dst_parent = dst.path_split().parent().and_then(PathCursor::new);
+        match self.get(dst_parent).map(|node| node.body()) {
+            Ok(FsNodeBody::Directory(directory)) => {
+                if directory.nodes().contains_key(dst.last()) {
+                    return Err(FsNodeRenameError::DstExist(FileExist { path: dst.clone() }));
+                }
+            }
+            Ok(FsNodeBody::File(_)) | Err(_) => {
+                return Err(FsNodeRenameError::DstDirectoryNotExist(DirectoryNotExist {
+                    path: dst.clone(),
+                }));
             }
-            FsNodeBody::File(_) => Err(FsNodeQueryError::DirectoryNotExist(DirectoryNotExist {
-                path,
-            })),
         }
+        Ok(())
     }
-    pub fn get(&self, path: Option<PathCursor>) -> Result<&FsNode, FsNodeQueryError> {
-        let Some(path) = path else {
-            return Ok(self);
-        };
-        match &self.body {
+    pub fn rename(&mut self, src: PathCursor, dst: PathCursor) -> Result<(), FsNodeRenameError> {
+        self.check_rename(&src, &dst)?;
+        let node = self.remove_node(src).unwrap();
+        self.create_node(dst, || node).unwrap();
+        Ok(())
+    }
+    pub fn visit_files_mut(
+        &mut self,
+        path: &PathSplit,
+        visit: &mut impl FnMut(&PathSplit, &mut File),
+    ) {
+        match &mut self.body {
             FsNodeBody::Directory(directory) => {
-                let Some(node) = directory.nodes().get(path.curr()) else {
-                    return Err(FsNodeQueryError::FileNotExist(FileNotExist { path }));
-                };
-                node.get(path.next())
+                for (name, node) in directory.nodes_mut() {
+                    Arc::make_mut(node).visit_files_mut(&path.child(name), visit);
+                }
+            }
+            FsNodeBody::File(file) => {
+                visit(path, file);
             }
-            FsNodeBody::File(_) => Err(FsNodeQueryError::DirectoryNotExist(DirectoryNotExist {
-                path,
-            })),
         }
     }
-    pub fn get_mut(&mut self, path: Option<PathCursor>) -> Result<&mut FsNode, FsNodeQueryError> {
-        let Some(path) = path else {
-            return Ok(self);
+    /// The error [`FsNode::create_snapshot`] would return, capturing nothing.
+    pub fn check_create_snapshot(
+        &self,
+        path: Option<PathCursor>,
+        name: &str,
+    ) -> Result<(), SnapshotError> {
+        let node = self.get(path).map_err(|_| SnapshotError::NotFound)?;
+        let FsNodeBody::Directory(directory) = &node.body else {
+            return Err(SnapshotError::NotDirectory);
+        };
+        if let Some(snapshots) = &directory.attr.snapshots {
+            let FsNodeBody::Directory(taken) = &snapshots.body else {
+                unreachable!();
+            };
+            if taken.nodes.contains_key(name) {
+                return Err(SnapshotError::Exists);
+            }
+        }
+        Ok(())
+    }
+    /// Captures the directory at `path` as `.snapshot/<name>`. The copy
+    /// shares every child with the live tree until one side is changed.
+    pub fn create_snapshot(
+        &mut self,
+        path: Option<PathCursor>,
+        name: Arc<str>,
+        time: SystemTime,
+        new_inode: impl FnOnce() -> InodeId,
+    ) -> Result<(), SnapshotError> {
+        let node = self.get_mut(path).map_err(|_| SnapshotError::NotFound)?;
+        let mut attr = node.attr.clone();
+        let FsNodeBody::Directory(directory) = &mut node.body else {
+            return Err(SnapshotError::NotDirectory);
+        };
+        let snapshots = directory.attr.snapshots.get_or_insert_with(|| {
+            Box::new(FsNode::new(
+                FsNodeAttribute::new(new_inode(), time, attr.perm().clone()),
+                FsNodeBody::Directory(Directory::new(DirectoryAttribute::new())),
+            ))
+        });
+        let FsNodeBody::Directory(taken) = &mut snapshots.body else {
+            unreachable!();
+        };
+        let Entry::Vacant(entry) = taken.nodes.entry(name) else {
+            return Err(SnapshotError::Exists);
+        };
+        *snapshots.attr.perm_mut() = attr.perm().clone();
+        snapshots.attr.set_mtime(time);
+        attr.set_mtime(time);
+        let copy = Directory {
+            attr: DirectoryAttribute {
+                quota: directory.attr.quota.clone(),
+                snapshots: None,
+            },
+            nodes: directory.nodes.clone(),
+        };
+        entry.insert(Arc::new(FsNode::new(attr, FsNodeBody::Directory(copy))));
+        Ok(())
+    }
+    /// The error [`FsNode::delete_snapshot`] would return, removing nothing.
+    pub fn check_delete_snapshot(
+        &self,
+        path: Option<PathCursor>,
+        name: &str,
+    ) -> Result<(), SnapshotError> {
+        let node = self.get(path).map_err(|_| SnapshotError::NotFound)?;
+        let FsNodeBody::Directory(directory) = &node.body else {
+            return Err(SnapshotError::NotDirectory);
+        };
+        let Some(snapshots) = &directory.attr.snapshots else {
+            return Err(SnapshotError::NoSuchSnapshot);
+        };
+        let FsNodeBody::Directory(taken) = &snapshots.body else {
+            unreachable!();
+        };
+        if !taken.nodes.contains_key(name) {
+            return Err(SnapshotError::NoSuchSnapshot);
+        }
+        Ok(())
+    }
+    pub fn delete_snapshot(
+        &mut self,
+        path: Option<PathCursor>,
+        name: &str,
+    ) -> Result<Arc<FsNode>, SnapshotError> {
+        let node = self.get_mut(path).map_err(|_| SnapshotError::NotFound)?;
+        let FsNodeBody::Directory(directory) = &mut node.body else {
+            return Err(SnapshotError::NotDirectory);
+        };
+        let Some(snapshots) = &mut directory.attr.snapshots else {
+            return Err(SnapshotError::NoSuchSnapshot);
         };
+        let FsNodeBody::Directory(taken) = &mut snapshots.body else {
+            unreachable!();
+        };
+        let removed = taken
+            .nodes
+            .remove(name)
+            .ok_or(SnapshotError::NoSuchSnapshot)?;
+        if taken.nodes.is_empty() {
+            directory.attr.snapshots = None;
+        }
+        Ok(removed)
+    }
+    /// Usage of the descendants, read from the nearest cached quota.
+    pub fn usage(&self) -> Usage {
+        match &self.body {
+            FsNodeBody::Directory(directory) => match directory.attr().quota() {
+                Some(quota) => quota.usage(),
+                None => directory
+                    .nodes()
+                    .values()
+                    .fold(Usage::default(), |usage, node| usage + node.footprint()),
+            },
+            FsNodeBody::File(file) => Usage {
+                nodes: 0,
+                space: file.space(),
+            },
+        }
+    }
+    /// Walks the whole subtree without recursing, so depth is not bounded by
+    /// the stack.
+    pub fn content_summary(&self) -> ContentSummary {
+        let mut summary = ContentSummary::default();
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            match &node.body {
+                FsNodeBody::Directory(directory) => {
+                    summary.directories += 1;
+                    stack.extend(directory.nodes().values().map(Arc::as_ref));
+                }
+                FsNodeBody::File(file) => {
+                    summary.files += 1;
+                    summary.len += file.len();
+                    summary.space += file.space();
+                }
+            }
+        }
+        summary
+    }
+    /// Usage charged to the ancestors, counting `self` as a node.
+    pub fn footprint(&self) -> Usage {
+        self.usage() + Usage { nodes: 1, space: 0 }
+    }
+    /// Calls `update` on the quota usage of every directory passed through on
+    /// the way to `path`, excluding the node at `path` itself.
+    pub fn update_quota_usage(
+        &mut self,
+        mut path: Option<PathCursor>,
+        update: &mut impl FnMut(&mut Usage),
+    ) {
+        let mut node = self;
+        while let Some(cursor) = path {
+            let FsNodeBody::Directory(directory) = &mut node.body else {
+                return;
+            };
+            if let Some(quota) = directory.attr_mut().quota_mut() {
+                let mut usage = quota.usage();
+                update(&mut usage);
+                quota.set_usage(usage);
+            }
+            let Some(child) = directory.nodes_mut().get_mut(cursor.curr()) else {
+                return;
+            };
+            node = Arc::make_mut(child);
+            path = cursor.next();
+        }
+    }
+    /// Recounts every cached quota usage under `self` and returns how many
+    /// were stale.
+    pub fn refresh_quota_usage(&mut self) -> usize {
+        let mut stale = 0;
+        self.recount_usage(&mut stale);
+        stale
+    }
+    fn recount_usage(&mut self, stale: &mut usize) -> Usage {
         match &mut self.body {
             FsNodeBody::Directory(directory) => {
-                let Some(node) = directory.nodes_mut().get_mut(path.curr()) else {
-                    return Err(FsNodeQueryError::FileNotExist(FileNotExist { path }));
-                };
-                node.get_mut(path.next())
+                let mut usage = Usage::default();
+                for node in directory.nodes_mut().values_mut() {
+                    let child = Arc::make_mut(node).recount_usage(stale);
+                    usage = usage + child + Usage { nodes: 1, space: 0 };
+                }
+                if let Some(quota) = directory.attr_mut().quota_mut() {
+                    if quota.usage() != usage {
+                        *stale += 1;
+                        quota.set_usage(usage);
+                    }
+                }
+                usage
             }
-            FsNodeBody::File(_) => Err(FsNodeQueryError::DirectoryNotExist(DirectoryNotExist {
-                path,
-            })),
+            FsNodeBody::File(file) => Usage {
+                nodes: 0,
+                space: file.space(),
+            },
         }
     }
-    pub fn create_node(
-        &mut self,
-        path: PathCursor,
-        new_node: impl FnOnce() -> FsNode,
-    ) -> Result<(), FsNodeCreateFileError> {
-        let directory = match &mut self.body {
-            FsNodeBody::Directory(directory) => directory,
-            FsNodeBody::File(_) => {
-                return Err(FsNodeCreateFileError::DirectoryNotExist(
-                    DirectoryNotExist { path },
-                ))
+    /// Checks the live tree against itself and against `blocks`. Snapshots
+    /// are frozen copies and are skipped.
+    pub fn validate(&self, blocks: &ReplicatedBlocksMap) -> Vec<InvariantViolation> {
+        let mut violations = vec![];
+        let mut owners = HashMap::new();
+        self.validate_at(
+            &PathSplit::from_uri(""),
+            blocks,
+            &mut owners,
+            &mut violations,
+        );
+        violations
+    }
+    fn validate_at(
+        &self,
+        path: &PathSplit,
+        blocks: &ReplicatedBlocksMap,
+        owners: &mut HashMap<BlockId, PathSplit>,
+        violations: &mut Vec<InvariantViolation>,
+    ) -> Usage {
+        match &self.body {
+            FsNodeBody::Directory(directory) => {
+                let mut usage = Usage::default();
+                for (name, node) in directory.nodes() {
+                    if &**name == SNAPSHOT_DIR || name.contains('/') || check_name(name).is_err() {
+                        violations.push(InvariantViolation::InvalidName {
+                            dir: path.to_uri(),
+                            name: name.to_string(),
+                        });
+                    }
+                    let child = node.validate_at(&path.child(name), blocks, owners, violations);
+                    usage = usage + child + Usage { nodes: 1, space: 0 };
+                }
+                if let Some(quota) = directory.attr().quota() {
+                    if quota.usage() != usage {
+                        violations.push(InvariantViolation::QuotaUsage {
+                            path: path.to_uri(),
+                            cached: quota.usage(),
+                            actual: usage,
+                        });
+                    }
+                }
+                usage
+            }
+            FsNodeBody::File(file) => {
+                let mut expected = 0;
+                for block in file.blocks() {
+                    let (start, end) = block.off_range();
+                    let id = block.id().clone();
+                    if start != expected {
+                        violations.push(InvariantViolation::NonContiguous {
+                            path: path.to_uri(),
+                            block: id.clone(),
+                            expected,
+                            start,
+                        });
+                    }
+                    if end < start {
+                        violations.push(InvariantViolation::Reversed {
+                            path: path.to_uri(),
+                            block: id.clone(),
+                        });
+                    }
+                    expected = end;
+                    match blocks.get(&id) {
+                        None => violations.push(InvariantViolation::Unmapped {
+                            path: path.to_uri(),
+                            block: id.clone(),
+                        }),
+                        Some(mapped) if mapped.inode() != self.attr.inode => {
+                            violations.push(InvariantViolation::WrongOwner {
+                                path: path.to_uri(),
+                                block: id.clone(),
+                                owner: mapped.inode(),
+                            });
+                        }
+                        Some(_) => {}
+                    }
+                    if let Some(first) = owners.insert(id.clone(), path.clone()) {
+                        violations.push(InvariantViolation::SharedBlock {
+                            block: id,
+                            first: first.to_uri(),
+                            second: path.to_uri(),
+                        });
+                    }
+                }
+                Usage {
+                    nodes: 0,
+                    space: file.space(),
+                }
             }
+        }
+    }
+    /// The highest inode in the tree, snapshots included.
+    pub fn max_inode(&self) -> InodeId {
+        let FsNodeBody::Directory(directory) = &self.body else {
+            return self.attr.inode;
         };
-        let child = path.next();
-        match child {
-            Some(child) => {
-                let Some(node) = directory.nodes_mut().get_mut(path.curr()) else {
-                    return Err(FsNodeCreateFileError::DirectoryNotExist(
-                        DirectoryNotExist { path },
-                    ));
-                };
-                node.create_node(child, new_node)
+        let snapshots = directory.attr.snapshots.iter().map(|node| node.max_inode());
+        let children = directory.nodes.values().map(|node| node.max_inode());
+        snapshots
+            .chain(children)
+            .fold(self.attr.inode, InodeId::max)
+    }
+    /// [`FsNode::visit_files`], handing over the file's node.
+    pub fn visit_file_nodes(&self, path: &PathSplit, visit: &mut impl FnMut(&PathSplit, &FsNode)) {
+        match &self.body {
+            FsNodeBody::Directory(directory) => {
+                for (name, node) in directory.nodes() {
+                    node.visit_file_nodes(&path.child(name), visit);
+                }
             }
-            None => {
-                let file_name = path.curr();
-                if directory.nodes_mut().get(file_name).is_some() {
-                    return Err(FsNodeCreateFileError::FileExist(FileExist { path }));
+            FsNodeBody::File(_) => visit(path, self),
+        }
+    }
+    pub fn visit_files(&self, path: &PathSplit, visit: &mut impl FnMut(&PathSplit, &File)) {
+        match &self.body {
+            FsNodeBody::Directory(directory) => {
+                for (name, node) in directory.nodes() {
+                    node.visit_files(&path.child(name), visit);
                 }
-                directory
-                    .nodes_mut()
-                    .insert(path.curr().clone(), new_node());
-                Ok(())
+            }
+            FsNodeBody::File(file) => {
+                visit(path, file);
             }
         }
     }
@@ -237,24 +1026,183 @@ pub enum FsNodeQueryError {
     FileNotExist(FileNotExist),
     DirectoryNotExist(DirectoryNotExist),
 }
+impl FsNodeQueryError {
+    pub fn path(&self) -> &PathCursor {
+        match self {
+            Self::FileNotExist(e) => &e.path,
+            Self::DirectoryNotExist(e) => &e.path,
+        }
+    }
+}
+impl fmt::Display for FsNodeQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileNotExist(e) => e.fmt(f),
+            Self::DirectoryNotExist(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for FsNodeQueryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::FileNotExist(e) => Some(e),
+            Self::DirectoryNotExist(e) => Some(e),
+        }
+    }
+}
 #[derive(Debug, Clone)]
 pub enum FsNodeCreateFileError {
     FileExist(FileExist),
     DirectoryNotExist(DirectoryNotExist),
 }
+impl FsNodeCreateFileError {
+    pub fn path(&self) -> &PathCursor {
+        match self {
+            Self::FileExist(e) => &e.path,
+            Self::DirectoryNotExist(e) => &e.path,
+        }
+    }
+}
+impl fmt::Display for FsNodeCreateFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileExist(e) => e.fmt(f),
+            Self::DirectoryNotExist(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for FsNodeCreateFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::FileExist(e) => Some(e),
+            Self::DirectoryNotExist(e) => Some(e),
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub enum FsNodeCreateDirsError {
+    FileExist(FileExist),
+    DirectoryNotExist(DirectoryNotExist),
+}
+impl FsNodeCreateDirsError {
+    pub fn path(&self) -> &PathCursor {
+        match self {
+            Self::FileExist(e) => &e.path,
+            Self::DirectoryNotExist(e) => &e.path,
+        }
+    }
+}
+impl fmt::Display for FsNodeCreateDirsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileExist(e) => e.fmt(f),
+            Self::DirectoryNotExist(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for FsNodeCreateDirsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::FileExist(e) => Some(e),
+            Self::DirectoryNotExist(e) => Some(e),
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub enum FsNodeRenameError {
+    SrcNotExist(FsNodeQueryError),
+    DstExist(FileExist),
+    DstDirectoryNotExist(DirectoryNotExist),
+    DstUnderSrc,
+}
+impl fmt::Display for FsNodeRenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SrcNotExist(e) => write!(f, "rename source: {e}"),
+            Self::DstExist(e) => write!(f, "rename destination: {e}"),
+            Self::DstDirectoryNotExist(e) => write!(f, "rename destination: {e}"),
+            Self::DstUnderSrc => write!(f, "rename destination is under the source"),
+        }
+    }
+}
+impl std::error::Error for FsNodeRenameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::SrcNotExist(e) => Some(e),
+            Self::DstExist(e) => Some(e),
+            Self::DstDirectoryNotExist(e) => Some(e),
+            Self::DstUnderSrc => None,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FsNodeAttribute {
-    // name: Arc<str>,
+    inode: InodeId,
+    #[serde(with = "super::epoch_millis")]
+    ctime: SystemTime,
+    #[serde(with = "super::epoch_millis")]
+    mtime: SystemTime,
+    #[serde(with = "super::epoch_millis")]
+    atime: SystemTime,
+    perm: Permission,
+    xattrs: HashMap<Arc<str>, Vec<u8>>,
 }
 impl FsNodeAttribute {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(inode: InodeId, now: SystemTime, perm: Permission) -> Self {
+        Self {
+            inode,
+            ctime: now,
+            mtime: now,
+            atime: now,
+            perm,
+            xattrs: HashMap::new(),
+        }
     }
-}
-impl Default for FsNodeAttribute {
-    fn default() -> Self {
-        Self::new()
+    /// Stays with the node across renames; a snapshot's copy keeps the
+    /// inode of the node it was taken from.
+    pub fn inode(&self) -> InodeId {
+        self.inode
+    }
+    pub fn xattrs(&self) -> &HashMap<Arc<str>, Vec<u8>> {
+        &self.xattrs
+    }
+    pub fn xattr(&self, name: &str) -> Option<&[u8]> {
+        self.xattrs.get(name).map(|value| value.as_slice())
+    }
+    pub fn xattrs_size(&self) -> usize {
+        self.xattrs
+            .iter()
+            .map(|(name, value)| xattr_size(name, value.len()))
+            .sum()
+    }
+    pub fn set_xattr(&mut self, name: Arc<str>, value: Vec<u8>) {
+        self.xattrs.insert(name, value);
+    }
+    pub fn remove_xattr(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.xattrs.remove(name)
+    }
+    pub fn perm(&self) -> &Permission {
+        &self.perm
+    }
+    pub fn perm_mut(&mut self) -> &mut Permission {
+        &mut self.perm
+    }
+    pub fn ctime(&self) -> SystemTime {
+        self.ctime
+    }
+    pub fn mtime(&self) -> SystemTime {
+        self.mtime
+    }
+    pub fn set_mtime(&mut self, mtime: SystemTime) {
+        self.mtime = mtime;
+    }
+    /// Only moves when access time tracking is on.
+    pub fn atime(&self) -> SystemTime {
+        self.atime
+    }
+    pub fn set_atime(&mut self, atime: SystemTime) {
+        self.atime = atime;
     }
 }
 
@@ -267,64 +1215,259 @@ pub enum FsNodeBody {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Directory {
     attr: DirectoryAttribute,
-    nodes: HashMap<Arc<str>, FsNode>,
+    nodes: BTreeMap<Arc<str>, Arc<FsNode>>,
 }
 impl Directory {
     pub fn new(attr: DirectoryAttribute) -> Self {
         Self {
             attr,
-            nodes: HashMap::new(),
+            nodes: BTreeMap::new(),
         }
     }
     pub fn insert(&mut self, key: Arc<str>, node: FsNode) -> Result<(), DirectoryInsertError> {
         if self.nodes.contains_key(&key) {
-            return Err(DirectoryInsertError { node });
+            return Err(DirectoryInsertError {
+                node: Box::new(node),
+            });
         }
-        self.nodes.insert(key, node);
+        self.nodes.insert(key, Arc::new(node));
         Ok(())
     }
-    pub fn nodes(&self) -> &HashMap<Arc<str>, FsNode> {
+    pub fn attr(&self) -> &DirectoryAttribute {
+        &self.attr
+    }
+    pub fn attr_mut(&mut self) -> &mut DirectoryAttribute {
+        &mut self.attr
+    }
+    pub fn nodes(&self) -> &BTreeMap<Arc<str>, Arc<FsNode>> {
         &self.nodes
     }
-    pub fn nodes_mut(&mut self) -> &mut HashMap<Arc<str>, FsNode> {
+    /// Children are shared with snapshots, so mutate them through
+    /// `Arc::make_mut`.
+    pub fn nodes_mut(&mut self) -> &mut BTreeMap<Arc<str>, Arc<FsNode>> {
         &mut self.nodes
     }
+    /// Children in name order, starting after `start_after` if given.
+    pub fn nodes_after<'a>(
+        &'a self,
+        start_after: Option<&'a str>,
+    ) -> impl Iterator<Item = (&'a Arc<str>, &'a FsNode)> + 'a {
+        let start = match start_after {
+            Some(name) => Bound::Excluded(name),
+            None => Bound::Unbounded,
+        };
+        self.nodes
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(name, node)| (name, &**node))
+    }
+    /// Resolves `.snapshot` to the snapshots unless the walk is already
+    /// inside one, so snapshots do not nest.
+    fn lookup(&self, name: &str, in_snapshot: &mut bool) -> Option<&FsNode> {
+        if name == SNAPSHOT_DIR && !*in_snapshot {
+            *in_snapshot = true;
+            return self.attr.snapshots.as_deref();
+        }
+        self.nodes.get(name).map(Arc::as_ref)
+    }
 }
 #[derive(Debug, Clone)]
 pub struct DirectoryInsertError {
-    pub node: FsNode,
+    pub node: Box<FsNode>,
+}
+impl fmt::Display for DirectoryInsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "directory already has an entry of that name")
+    }
+}
+impl std::error::Error for DirectoryInsertError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SnapshotError {
+    NotFound,
+    NotDirectory,
+    Exists,
+    NoSuchSnapshot,
 }
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "directory not found"),
+            Self::NotDirectory => write!(f, "not a directory"),
+            Self::Exists => write!(f, "snapshot already exists"),
+            Self::NoSuchSnapshot => write!(f, "no such snapshot"),
+        }
+    }
+}
+impl std::error::Error for SnapshotError {}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DirectoryAttribute {}
+pub struct DirectoryAttribute {
+    quota: Option<Quota>,
+    snapshots: Option<Box<FsNode>>,
+}
+impl DirectoryAttribute {
+    pub fn new() -> Self {
+        Self {
+            quota: None,
+            snapshots: None,
+        }
+    }
+    /// A directory holding one captured copy per snapshot name.
+    pub fn snapshots(&self) -> Option<&FsNode> {
+        self.snapshots.as_deref()
+    }
+    pub fn quota(&self) -> Option<&Quota> {
+        self.quota.as_ref()
+    }
+    pub fn quota_mut(&mut self) -> Option<&mut Quota> {
+        self.quota.as_mut()
+    }
+    pub fn set_quota(&mut self, quota: Option<Quota>) {
+        self.quota = quota;
+    }
+}
+impl Default for DirectoryAttribute {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct File {
     attr: FileAttribute,
     blocks: Vec<FileBlock>,
+    under_construction: bool,
 }
 impl File {
     pub fn new(attr: FileAttribute) -> Self {
         Self {
             attr,
             blocks: vec![],
+            under_construction: false,
         }
     }
     pub fn attr(&self) -> &FileAttribute {
         &self.attr
     }
-    pub fn blocks_mut(&mut self) -> &mut Vec<FileBlock> {
-        &mut self.blocks
+    pub fn attr_mut(&mut self) -> &mut FileAttribute {
+        &mut self.attr
+    }
+    pub fn is_under_construction(&self) -> bool {
+        self.under_construction
+    }
+    pub fn set_under_construction(&mut self, under_construction: bool) {
+        self.under_construction = under_construction;
+    }
+    pub fn len(&self) -> u64 {
+        self.blocks
+            .last()
+            .map(|block| block.off_range().1)
+            .unwrap_or(0)
+    }
+    pub fn space(&self) -> u64 {
+        self.len() * self.attr.replication().get() as u64
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn blocks(&self) -> &[FileBlock] {
+        &self.blocks
+    }
+    pub fn block_mut(&mut self, id: &BlockId) -> Option<&mut FileBlock> {
+        self.blocks.iter_mut().find(|block| block.id() == id)
+    }
+    pub fn last_block_mut(&mut self) -> Option<&mut FileBlock> {
+        self.blocks.last_mut()
     }
+    pub fn pop_block(&mut self) -> Option<FileBlock> {
+        self.blocks.pop()
+    }
+    pub fn block_at_offset(&self, offset: u64) -> Option<&FileBlock> {
+        let i = self
+            .blocks
+            .partition_point(|block| block.off_range().1 <= offset);
+        self.blocks
+            .get(i)
+            .filter(|block| block.off_range().0 <= offset)
+    }
+    /// Drops the blocks past `new_len` and shortens the one it lands in;
+    /// returns the ids of the dropped blocks.
+    pub fn truncate_blocks(&mut self, new_len: u64) -> Vec<BlockId> {
+        let keep = self
+            .blocks
+            .partition_point(|block| block.off_range().0 < new_len);
+        let dropped = self.blocks.drain(keep..).map(|block| block.id).collect();
+        if let Some(last) = self.blocks.last_mut() {
+            let (start, end) = last.off_range();
+            last.set_len(end.min(new_len) - start);
+        }
+        dropped
+    }
+    pub fn check_append(&self, (start, end): (u64, u64)) -> Result<(), FileAppendBlockError> {
+        if end < start {
+            return Err(FileAppendBlockError::Reversed);
+        }
+        if end == start {
+            return Err(FileAppendBlockError::Empty);
+        }
+        match self.blocks.last() {
+            None if start != 0 => Err(FileAppendBlockError::NonZeroStart),
+            Some(last) if start != last.off_range().1 => Err(FileAppendBlockError::Gap {
+                expected: last.off_range().1,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Moves the blocks of `other` onto the end, shifting their ranges to
+    /// start at the current length; a short last block stays short.
+    pub fn concat(&mut self, other: File) {
+        let base = self.len();
+        for mut block in other.blocks {
+            block.off_range.0 += base;
+            block.off_range.1 += base;
+            self.blocks.push(block);
+        }
+    }
+    pub fn append_block(&mut self, block: FileBlock) -> Result<(), FileAppendBlockError> {
+        self.check_append(block.off_range())?;
+        self.blocks.push(block);
+        Ok(())
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAppendBlockError {
+    NonZeroStart,
+    Gap { expected: u64 },
+    Empty,
+    Reversed,
 }
+impl fmt::Display for FileAppendBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NonZeroStart => write!(f, "first block does not start at offset 0"),
+            Self::Gap { expected } => write!(f, "block does not start at offset {expected}"),
+            Self::Empty => write!(f, "block range is empty"),
+            Self::Reversed => write!(f, "block range is reversed"),
+        }
+    }
+}
+impl std::error::Error for FileAppendBlockError {}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileAttribute {
     replication: NonZeroUsize,
+    finalized: bool,
 }
 impl FileAttribute {
     pub fn new(replication: NonZeroUsize) -> Self {
-        Self { replication }
+        Self {
+            replication,
+            finalized: false,
+        }
     }
     pub fn replication(&self) -> NonZeroUsize {
         self.replication
@@ -332,20 +1475,42 @@ impl FileAttribute {
     pub fn set_replication(&mut self, replication: NonZeroUsize) {
         self.replication = replication;
     }
+    pub fn is_finalized(&self) -> bool {
+        self.finalized
+    }
+    pub fn set_finalized(&mut self, finalized: bool) {
+        self.finalized = finalized;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileBlock {
     off_range: (u64, u64),
     id: BlockId,
+    generation: u64,
 }
 impl FileBlock {
     pub fn new(off_range: (u64, u64), id: BlockId) -> Self {
-        Self { off_range, id }
+        Self {
+            off_range,
+            id,
+            generation: 0,
+        }
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
     pub fn off_range(&self) -> (u64, u64) {
         self.off_range
     }
+    pub fn set_len(&mut self, len: u64) {
+        self.off_range.1 = self.off_range.0 + len;
+    }
+
     pub fn id(&self) -> &BlockId {
         &self.id
     }
@@ -369,6 +1534,22 @@ impl PathCursor {
     pub fn curr(&self) -> &Arc<str> {
         &self.path_split.segs()[self.curr]
     }
+    pub fn last(&self) -> &Arc<str> {
+        self.path_split.segs().last().unwrap()
+    }
+    pub fn path_split(&self) -> &PathSplit {
+        &self.path_split
+    }
+    /// The segments before the cursor.
+    pub fn resolved(&self) -> PathSplit {
+        self.path_split.prefix(self.curr)
+    }
+    /// The segment under the cursor and those after it.
+    pub fn remaining(&self) -> PathSplit {
+        PathSplit {
+            segs: self.path_split.segs[self.curr..].into(),
+        }
+    }
     pub fn next(&self) -> Option<Self> {
         if self.curr + 1 == self.path_split.segs().len() {
             return None;
@@ -380,11 +1561,37 @@ impl PathCursor {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Renders the whole path with the segment under the cursor bracketed, as in
+/// `/a/[b]/c`.
+impl fmt::Display for PathCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, seg) in self.path_split.segs().iter().enumerate() {
+            match i == self.curr {
+                true => write!(f, "/[{seg}]")?,
+                false => write!(f, "/{seg}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PathSplit {
+    #[serde(deserialize_with = "super::arc_str::deserialize_slice")]
     segs: Arc<[Arc<str>]>,
 }
 impl PathSplit {
+    /// Validates every name, so this is the one to use on client input.
+    /// Repeated and trailing slashes are collapsed.
+    pub fn parse(path_str: &str) -> Result<Self, PathParseError> {
+        let segs = path_str
+            .split('/')
+            .filter(|seg| !seg.is_empty())
+            .map(|seg| check_name(seg).map(|()| Arc::from(seg)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { segs })
+    }
+    /// Lenient split for trusted paths.
     pub fn from_uri(path_str: &str) -> Self {
         let segs = path_str
             .trim()
@@ -394,39 +1601,168 @@ impl PathSplit {
             .collect();
         Self { segs }
     }
+    /// Trusts `segs` to be valid names.
+    pub fn from_segs(segs: Vec<Arc<str>>) -> Self {
+        Self { segs: segs.into() }
+    }
     pub fn segs(&self) -> &Arc<[Arc<str>]> {
         &self.segs
     }
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.segs.iter().map(|seg| &**seg)
+    }
+    /// Whether the path reaches into a snapshot, which is read-only.
+    pub fn is_in_snapshot(&self) -> bool {
+        self.segs.iter().any(|seg| &**seg == SNAPSHOT_DIR)
+    }
+    pub fn starts_with(&self, prefix: &PathSplit) -> bool {
+        self.segs.starts_with(&prefix.segs)
+    }
+    pub fn to_uri(&self) -> String {
+        self.to_string()
+    }
+    /// `None` for the root.
+    pub fn parent(&self) -> Option<PathSplit> {
+        let len = self.segs.len().checked_sub(1)?;
+        Some(self.prefix(len))
+    }
+    pub fn file_name(&self) -> Option<&Arc<str>> {
+        self.segs.last()
+    }
+    pub fn join(&self, seg: &str) -> Result<PathSplit, PathParseError> {
+        check_name(seg)?;
+        Ok(self.child(&Arc::from(seg)))
+    }
+    pub fn prefix(&self, len: usize) -> PathSplit {
+        Self {
+            segs: self.segs[..len.min(self.segs.len())].into(),
+        }
+    }
+    /// The path below the first `start` segments.
+    pub fn suffix(&self, start: usize) -> PathSplit {
+        Self {
+            segs: self.segs[start.min(self.segs.len())..].into(),
+        }
+    }
+    /// `rest` as a path under `self`.
+    pub fn concat(&self, rest: &PathSplit) -> PathSplit {
+        let segs = self.segs.iter().chain(rest.segs.iter()).cloned().collect();
+        Self { segs }
+    }
+    pub fn child(&self, name: &Arc<str>) -> PathSplit {
+        let segs = self.segs.iter().chain([name]).cloned().collect();
+        Self { segs }
+    }
+}
+
+impl fmt::Display for PathSplit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.segs.is_empty() {
+            return write!(f, "/");
+        }
+        for seg in self.segs.iter() {
+            write!(f, "/{seg}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathParseError {
+    DotName,
+    BlankName,
+    ControlChar,
+    NameTooLong { max: usize },
+    InSnapshot,
+}
+
+impl fmt::Display for PathParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DotName => write!(f, "`.` and `..` are not valid names"),
+            Self::BlankName => write!(f, "name is blank"),
+            Self::ControlChar => write!(f, "name contains a control character"),
+            Self::NameTooLong { max } => write!(f, "name is longer than {max} bytes"),
+            Self::InSnapshot => write!(f, "snapshots are read-only"),
+        }
+    }
+}
+impl std::error::Error for PathParseError {}
+
+fn check_name(name: &str) -> Result<(), PathParseError> {
+    if name == "." || name == ".." {
+        return Err(PathParseError::DotName);
+    }
+    if name.trim().is_empty() {
+        return Err(PathParseError::BlankName);
+    }
+    if name.chars().any(char::is_control) {
+        return Err(PathParseError::ControlChar);
+    }
+    if MAX_NAME_LEN < name.len() {
+        return Err(PathParseError::NameTooLong { max: MAX_NAME_LEN });
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub struct FileExist {
     pub path: PathCursor,
 }
+impl fmt::Display for FileExist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already exists", self.path)
+    }
+}
+impl std::error::Error for FileExist {}
 #[derive(Debug, Clone)]
 pub struct FileNotExist {
     pub path: PathCursor,
 }
+impl fmt::Display for FileNotExist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} does not exist", self.path)
+    }
+}
+impl std::error::Error for FileNotExist {}
 #[derive(Debug, Clone)]
 pub struct DirectoryNotExist {
     pub path: PathCursor,
 }
+impl fmt::Display for DirectoryNotExist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: parent directory does not exist", self.path)
+    }
+}
+impl std::error::Error for DirectoryNotExist {}
 
-pub async fn atomic_persist(path: impl AsRef<Path>, buf: &[u8]) -> io::Result<()> {
+pub async fn atomic_persist(path: impl AsRef<Path>, buf: Vec<u8>) -> io::Result<()> {
+    atomic_persist_with(path, move |file| file.write_all(&buf)).await
+}
+
+pub async fn atomic_persist_with(
+    path: impl AsRef<Path>,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+) -> io::Result<()> {
     let path = path.as_ref().to_path_buf();
-    let buf = unsafe { Arc::from_raw(buf) };
-    spawn_blocking({
-        let buf = buf.clone();
-        move || -> io::Result<()> {
-            let mut file = NamedTempFile::new()?;
-            file.write_all(&buf)?;
-            file.flush()?;
-            file.as_file().sync_all()?;
-            file.persist(path)?;
-            Ok(())
+    spawn_blocking(move || -> io::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut file = NamedTempFile::new_in(&dir)?;
+        {
+            let mut writer = BufWriter::new(file.as_file_mut());
+            write(&mut writer)?;
+            writer.flush()?;
         }
+        file.as_file().sync_all()?;
+        file.persist(&path)?;
+        std::fs::File::open(&dir)?.sync_all()?;
+        Ok(())
     })
-    .await??;
-    drop(buf);
-    Ok(())
+    .await?
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,128 @@
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use super::*;
+
+fn file() -> File {
+    File::new(FileAttribute::new(NonZeroUsize::new(3).unwrap()))
+}
+
+#[test]
+fn first_block_must_start_at_zero() {
+    let mut file = file();
+    let res = file.append_block(FileBlock::new((5, 10), "blk_1".into()));
+    assert_eq!(res, Err(FileAppendBlockError::NonZeroStart));
+    assert!(file.blocks().is_empty());
+    file.append_block(FileBlock::new((0, 10), "blk_1".into()))
+        .unwrap();
+    assert_eq!(file.len(), 10);
+}
+
+#[test]
+fn empty_range_is_rejected() {
+    let mut file = file();
+    let res = file.append_block(FileBlock::new((0, 0), "blk_1".into()));
+    assert_eq!(res, Err(FileAppendBlockError::Empty));
+    file.append_block(FileBlock::new((0, 10), "blk_1".into()))
+        .unwrap();
+    let res = file.append_block(FileBlock::new((10, 10), "blk_2".into()));
+    assert_eq!(res, Err(FileAppendBlockError::Empty));
+    assert_eq!(file.blocks().len(), 1);
+}
+
+#[test]
+fn reversed_range_is_rejected() {
+    let mut file = file();
+    let res = file.append_block(FileBlock::new((10, 0), "blk_1".into()));
+    assert_eq!(res, Err(FileAppendBlockError::Reversed));
+    file.append_block(FileBlock::new((0, 10), "blk_1".into()))
+        .unwrap();
+    let res = file.append_block(FileBlock::new((10, 5), "blk_2".into()));
+    assert_eq!(res, Err(FileAppendBlockError::Reversed));
+    assert_eq!(file.len(), 10);
+}
+
+#[test]
+fn later_block_must_continue_the_last() {
+    let mut file = file();
+    file.append_block(FileBlock::new((0, 10), "blk_1".into()))
+        .unwrap();
+    let res = file.append_block(FileBlock::new((20, 30), "blk_2".into()));
+    assert_eq!(res, Err(FileAppendBlockError::Gap { expected: 10 }));
+    file.append_block(FileBlock::new((10, 30), "blk_2".into()))
+        .unwrap();
+    assert_eq!(file.len(), 30);
+}
+
+fn node(inode: InodeId, body: FsNodeBody) -> FsNode {
+    FsNode::new(
+        FsNodeAttribute::new(inode, SystemTime::UNIX_EPOCH, Permission::default()),
+        body,
+    )
+}
+
+fn dir() -> Directory {
+    Directory::new(DirectoryAttribute::new())
+}
+
+#[test]
+fn content_summary_of_a_large_tree() {
+    let mut inode = 1..;
+    let mut next = || inode.next().unwrap();
+    let mut root = dir();
+    for i in 0..1000 {
+        let mut parent = dir();
+        for j in 0..99 {
+            let mut file = file();
+            let id = format!("blk_{i}_{j}").into();
+            file.append_block(FileBlock::new((0, 10), id)).unwrap();
+            let name = format!("f{j}").into();
+            parent
+                .insert(name, node(next(), FsNodeBody::File(file)))
+                .unwrap();
+        }
+        let name = format!("d{i}").into();
+        let parent = node(next(), FsNodeBody::Directory(parent));
+        root.insert(name, parent).unwrap();
+    }
+    let mut deep = node(next(), FsNodeBody::Directory(dir()));
+    for _ in 1..500 {
+        let mut parent = dir();
+        parent.insert("d".into(), deep).unwrap();
+        deep = node(next(), FsNodeBody::Directory(parent));
+    }
+    root.insert("deep".into(), deep).unwrap();
+    let root = node(next(), FsNodeBody::Directory(root));
+
+    let start = Instant::now();
+    let summary = root.content_summary();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        summary,
+        ContentSummary {
+            files: 99_000,
+            directories: 1 + 1000 + 500,
+            len: 99_000 * 10,
+            space: 99_000 * 30,
+        }
+    );
+}
+
+#[test]
+fn open_table_counts_files_not_holders() {
+    let mut table = OpenFileTable::new();
+    let now = Instant::now();
+    let ttl = Duration::from_secs(60);
+    let f = PathSplit::from_uri("/f");
+    let g = PathSplit::from_uri("/g");
+    assert!(table.is_empty());
+    table.open(f.clone(), "a".into(), false, now, ttl).unwrap();
+    table.open(f.clone(), "b".into(), false, now, ttl).unwrap();
+    table.open(g.clone(), "c".into(), true, now, ttl).unwrap();
+    assert_eq!(table.len(), 2);
+    table.close(&g, &"c".into()).unwrap();
+    table.close(&f, &"a".into()).unwrap();
+    assert_eq!(table.len(), 1);
+}
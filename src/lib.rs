@@ -1,4 +1,9 @@
+pub mod client;
+pub mod clock;
 pub mod fs;
+pub mod metrics;
 pub mod proto;
 pub mod server;
 pub mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
@@ -0,0 +1,211 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Upper bounds of the latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Counters, gauges and latency histograms keyed by name and labels, rendered
+/// in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    series: Mutex<BTreeMap<Key, Value>>,
+}
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.add(name, labels, 1);
+    }
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], n: u64) {
+        let mut series = self.series.lock().unwrap();
+        let value = series
+            .entry(Key::new(name, labels))
+            .or_insert(Value::Counter(0));
+        if let Value::Counter(count) = value {
+            *count += n;
+        }
+    }
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], v: f64) {
+        let mut series = self.series.lock().unwrap();
+        series.insert(Key::new(name, labels), Value::Gauge(v));
+    }
+    pub fn add_gauge(&self, name: &'static str, labels: &[(&'static str, &str)], delta: f64) {
+        let mut series = self.series.lock().unwrap();
+        let value = series
+            .entry(Key::new(name, labels))
+            .or_insert(Value::Gauge(0.0));
+        if let Value::Gauge(v) = value {
+            *v += delta;
+        }
+    }
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], elapsed: Duration) {
+        let mut series = self.series.lock().unwrap();
+        let value = series
+            .entry(Key::new(name, labels))
+            .or_insert_with(|| Value::Histogram {
+                buckets: [0; LATENCY_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        if let Value::Histogram {
+            buckets,
+            sum,
+            count,
+        } = value
+        {
+            let secs = elapsed.as_secs_f64();
+            for (bucket, bound) in buckets.iter_mut().zip(LATENCY_BUCKETS) {
+                if secs <= bound {
+                    *bucket += 1;
+                }
+            }
+            *sum += secs;
+            *count += 1;
+        }
+    }
+    /// Drops every series of `name`, for gauges whose label values come and
+    /// go, such as one per store.
+    pub fn clear(&self, name: &'static str) {
+        self.series
+            .lock()
+            .unwrap()
+            .retain(|key, _| key.name != name);
+    }
+    pub fn get(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Option<f64> {
+        let series = self.series.lock().unwrap();
+        match series.get(&Key::new(name, labels))? {
+            Value::Counter(count) => Some(*count as f64),
+            Value::Gauge(v) => Some(*v),
+            Value::Histogram { count, .. } => Some(*count as f64),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut out = String::new();
+        let mut family = None;
+        for (key, value) in series.iter() {
+            if family != Some(key.name) {
+                family = Some(key.name);
+                let kind = match value {
+                    Value::Counter(_) => "counter",
+                    Value::Gauge(_) => "gauge",
+                    Value::Histogram { .. } => "histogram",
+                };
+                let _ = writeln!(out, "# TYPE {} {kind}", key.name);
+            }
+            match value {
+                Value::Counter(count) => {
+                    let _ = writeln!(out, "{}{} {count}", key.name, key.labels(None));
+                }
+                Value::Gauge(v) => {
+                    let _ = writeln!(out, "{}{} {v}", key.name, key.labels(None));
+                }
+                Value::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    for (bucket, bound) in buckets.iter().zip(LATENCY_BUCKETS) {
+                        let le = bound.to_string();
+                        let labels = key.labels(Some(&le));
+                        let _ = writeln!(out, "{}_bucket{labels} {bucket}", key.name);
+                    }
+                    let labels = key.labels(Some("+Inf"));
+                    let _ = writeln!(out, "{}_bucket{labels} {count}", key.name);
+                    let _ = writeln!(out, "{}_sum{} {sum}", key.name, key.labels(None));
+                    let _ = writeln!(out, "{}_count{} {count}", key.name, key.labels(None));
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    name: &'static str,
+    labels: Vec<(&'static str, String)>,
+}
+impl Key {
+    fn new(name: &'static str, labels: &[(&'static str, &str)]) -> Self {
+        Self {
+            name,
+            labels: labels
+                .iter()
+                .map(|(label, value)| (*label, value.to_string()))
+                .collect(),
+        }
+    }
+    fn labels(&self, le: Option<&str>) -> String {
+        let mut pairs: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+            .collect();
+        if let Some(le) = le {
+            pairs.push(format!("le=\"{le}\""));
+        }
+        match pairs.is_empty() {
+            true => String::new(),
+            false => format!("{{{}}}", pairs.join(",")),
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+    Histogram {
+        buckets: [u64; LATENCY_BUCKETS.len()],
+        sum: f64,
+        count: u64,
+    },
+}
+
+/// Answers `GET /metrics` with [`Metrics::render`] and anything else with a
+/// 404, one request per connection.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> io::Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // the request line fits in the first read; headers are ignored
+            let mut buf = [0; 1024];
+            let Ok(n) = stream.read(&mut buf).await else {
+                return;
+            };
+            let head = String::from_utf8_lossy(&buf[..n]);
+            let (status, body) = match head.lines().next() {
+                Some(line) if line.starts_with("GET /metrics ") => ("200 OK", metrics.render()),
+                _ => ("404 Not Found", String::new()),
+            };
+            let resp = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(resp.as_bytes()).await;
+        });
+    }
+}
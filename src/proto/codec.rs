@@ -0,0 +1,143 @@
+use std::{fmt, io, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder},
+};
+
+use super::{
+    format::{FormatError, WireFormat},
+    handshake::MAX_VERSION,
+};
+
+const LEN_PREFIX: usize = std::mem::size_of::<u32>();
+
+/// u32 LE length prefix followed by a body in the negotiated format; the
+/// same framing as `write_frame_as` and `read_frame_as`.
+#[derive(Debug)]
+pub struct FrameCodec<D, E> {
+    max_frame: usize,
+    version: u16,
+    format: WireFormat,
+    _marker: PhantomData<fn(E) -> D>,
+}
+impl<D, E> FrameCodec<D, E> {
+    pub fn new(max_frame: usize) -> Self {
+        Self {
+            max_frame,
+            version: MAX_VERSION,
+            format: WireFormat::Bincode,
+            _marker: PhantomData,
+        }
+    }
+    /// The version agreed on during the handshake.
+    pub fn with_version(mut self, version: u16) -> Self {
+        self.version = version;
+        self
+    }
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+}
+impl<D, E> Clone for FrameCodec<D, E> {
+    fn clone(&self) -> Self {
+        Self::new(self.max_frame)
+            .with_version(self.version)
+            .with_format(self.format)
+    }
+}
+impl<D: DeserializeOwned, E> Decoder for FrameCodec<D, E> {
+    type Item = D;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, CodecError> {
+        let Some(prefix) = src.first_chunk::<LEN_PREFIX>() else {
+            return Ok(None);
+        };
+        let len = u32::from_le_bytes(*prefix) as usize;
+        if self.max_frame < len {
+            return Err(CodecError::FrameTooLarge {
+                len,
+                max: self.max_frame,
+            });
+        }
+        if src.len() < LEN_PREFIX + len {
+            src.reserve(LEN_PREFIX + len - src.len());
+            return Ok(None);
+        }
+        src.advance(LEN_PREFIX);
+        let frame = src.split_to(len);
+        self.format
+            .decode(&frame)
+            .map(Some)
+            .map_err(CodecError::Malformed)
+    }
+}
+impl<D, E: Serialize> Encoder<E> for FrameCodec<D, E> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let buf = self.format.encode(&item).map_err(CodecError::Malformed)?;
+        if self.max_frame < buf.len() {
+            return Err(CodecError::FrameTooLarge {
+                len: buf.len(),
+                max: self.max_frame,
+            });
+        }
+        dst.reserve(LEN_PREFIX + buf.len());
+        dst.put_u32_le(buf.len() as u32);
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    FrameTooLarge { len: usize, max: usize },
+    Malformed(FormatError),
+}
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::FrameTooLarge { len, max } => {
+                write!(f, "frame of {len} bytes exceeds the {max} byte limit")
+            }
+            Self::Malformed(e) => write!(f, "malformed frame: {e}"),
+        }
+    }
+}
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::FrameTooLarge { .. } => None,
+            Self::Malformed(e) => Some(e),
+        }
+    }
+}
+impl From<CodecError> for io::Error {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -1,26 +1,185 @@
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, SystemTime},
+};
+
 use serde::{Deserialize, Serialize};
 
-use crate::fs::block::{BlockId, BlockReport};
+use crate::{
+    fs::{
+        block::{BlockId, BlockReport},
+        fsck::{FsckFile, FsckSummary},
+        perm::{GroupId, UserId},
+        quota::{ContentSummary, QuotaLimit},
+        snapshot::DiffEntry,
+        virt::{ClientId, PathLimitError, PathParseError},
+    },
+    store::{
+        HostLists, StoreAddr, StoreAdminState, StoreAdmission, StoreConfig, StoreId, StoreLiveness,
+    },
+};
+
+use super::store::{HeartbeatReq, HeartbeatResp, RegisterStoreReq, RegisterStoreResp};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ControlReq {
     OpenReq(OpenReq),
     OpenLeaseReq(OpenLeaseReq),
+    RenewLeasesReq(RenewLeasesReq),
     CloseReq(CloseReq),
     AllocBlockReq(AllocBlockReq),
+    CompleteFileReq(CompleteFileReq),
+    AbandonBlockReq(AbandonBlockReq),
+    GetAdditionalStoreReq(GetAdditionalStoreReq),
+    RegisterStoreReq(RegisterStoreReq),
+    HeartbeatReq(HeartbeatReq),
     BlockReportReq(BlockReportReq),
+    ReplicationFailedReq(ReplicationFailedReq),
+    DeleteFileReq(DeleteFileReq),
+    DeleteDirectoryReq(DeleteDirectoryReq),
+    RenameReq(RenameReq),
+    MkdirReq(MkdirReq),
+    GetBlockLocationsReq(GetBlockLocationsReq),
+    ListReq(ListReq),
+    StatReq(StatReq),
+    SetReplicationReq(SetReplicationReq),
+    SetTimesReq(SetTimesReq),
+    ChownReq(ChownReq),
+    ChmodReq(ChmodReq),
+    SetXattrReq(SetXattrReq),
+    GetXattrReq(GetXattrReq),
+    RemoveXattrReq(RemoveXattrReq),
+    ListXattrsReq(ListXattrsReq),
+    SetQuotaReq(SetQuotaReq),
+    ClearQuotaReq(ClearQuotaReq),
+    ContentSummaryReq(ContentSummaryReq),
+    CreateSnapshotReq(CreateSnapshotReq),
+    DeleteSnapshotReq(DeleteSnapshotReq),
+    SnapshotDiffReq(SnapshotDiffReq),
+    ConcatReq(ConcatReq),
+    GetFileChecksumReq(GetFileChecksumReq),
+    ListOpenFilesReq(ListOpenFilesReq),
+    ForceCloseReq(ForceCloseReq),
+    SafeModeReq(SafeModeReq),
+    DecommissionStoreReq(DecommissionStoreReq),
+    ListStoresReq(ListStoresReq),
+    ClusterStatusReq(ClusterStatusReq),
+    RefreshStoresReq(RefreshStoresReq),
+    EnterMaintenanceReq(EnterMaintenanceReq),
+    ExitMaintenanceReq(ExitMaintenanceReq),
+    ReportBadBlockReq(ReportBadBlockReq),
+    ListCorruptFilesReq(ListCorruptFilesReq),
+    FsckReq(FsckReq),
+    MetaSaveReq(MetaSaveReq),
+    SubscribeEventsReq(SubscribeEventsReq),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlResp {
+    None,
+    InvalidPath(PathParseError),
+    OpenResp(OpenResp),
+    OpenLeaseResp(OpenLeaseResp),
+    RenewLeasesResp(RenewLeasesResp),
+    CloseResp(CloseResp),
+    AllocBlockResp(AllocBlockResp),
+    CompleteFileResp(CompleteFileResp),
+    AbandonBlockResp(AbandonBlockResp),
+    GetAdditionalStoreResp(GetAdditionalStoreResp),
+    RegisterStoreResp(RegisterStoreResp),
+    HeartbeatResp(HeartbeatResp),
+    DeleteFileResp(DeleteFileResp),
+    DeleteDirectoryResp(DeleteDirectoryResp),
+    RenameResp(RenameResp),
+    MkdirResp(MkdirResp),
+    GetBlockLocationsResp(GetBlockLocationsResp),
+    ListResp(ListResp),
+    StatResp(StatResp),
+    BlockReportResp(BlockReportResp),
+    SetReplicationResp(SetReplicationResp),
+    SetTimesResp(SetTimesResp),
+    ChownResp(ChownResp),
+    ChmodResp(ChmodResp),
+    SetXattrResp(SetXattrResp),
+    GetXattrResp(GetXattrResp),
+    RemoveXattrResp(RemoveXattrResp),
+    ListXattrsResp(ListXattrsResp),
+    SetQuotaResp(SetQuotaResp),
+    ClearQuotaResp(ClearQuotaResp),
+    ContentSummaryResp(ContentSummaryResp),
+    CreateSnapshotResp(CreateSnapshotResp),
+    DeleteSnapshotResp(DeleteSnapshotResp),
+    SnapshotDiffResp(SnapshotDiffResp),
+    ConcatResp(ConcatResp),
+    GetFileChecksumResp(GetFileChecksumResp),
+    ListOpenFilesResp(ListOpenFilesResp),
+    ForceCloseResp(ForceCloseResp),
+    SafeModeResp(SafeModeResp),
+    DecommissionStoreResp(DecommissionStoreResp),
+    ListStoresResp(ListStoresResp),
+    ClusterStatusResp(ClusterStatusResp),
+    RefreshStoresResp(RefreshStoresResp),
+    MaintenanceResp(MaintenanceResp),
+    ReportBadBlockResp(ReportBadBlockResp),
+    ListCorruptFilesResp(ListCorruptFilesResp),
+    FsckResp(FsckResp),
+    MetaSaveResp(MetaSaveResp),
+    SubscribeEventsResp(SubscribeEventsResp),
+    /// The request passed its checks but its edit could not be applied.
+    EditFailed(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnresolvedPath {
+    pub resolved: String,
+    pub remaining: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenReq {
+    pub client: ClientId,
     pub write: bool,
+    pub create: bool,
+    pub exclusive: bool,
+    pub append: bool,
+    pub overwrite: bool,
     pub path: String,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct OpenResp {}
+pub enum OpenResp {
+    Ok(OpenRespOk),
+    Rejected(OpenRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenRespOk {
+    pub len: u64,
+    pub finalized: bool,
+    pub last_block: Option<LastBlock>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastBlock {
+    pub block: BlockId,
+    pub generation: u64,
+    pub size: u64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OpenRejected {
+    Conflict { held_for_write: bool },
+    ParentMissing(UnresolvedPath),
+    NotFound(UnresolvedPath),
+    IsDirectory,
+    InvalidPath,
+    FileExists,
+    InvalidMode,
+    SafeMode,
+    PermissionDenied,
+    QuotaExceeded(QuotaExceeded),
+    PathLimit(PathLimitError),
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenLeaseReq {
+    pub client: ClientId,
     pub path: String,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,37 +187,963 @@ pub struct OpenLeaseResp {
     pub permitted: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenewLeasesReq {
+    pub client: ClientId,
+    pub paths: Vec<String>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenewLeasesResp {
+    pub lost: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CloseReq {
+    pub client: ClientId,
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloseResp {
+    pub permitted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteFileReq {
+    pub path: String,
+    pub skip_trash: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DeleteFileResp {
+    Ok,
+    Rejected,
+    SafeMode,
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteDirectoryReq {
+    pub path: String,
+    pub recursive: bool,
+    pub skip_trash: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DeleteDirectoryResp {
+    Ok,
+    Rejected,
+    SafeMode,
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenameReq {
+    pub src: String,
+    pub dst: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RenameResp {
+    Ok,
+    Rejected(RenameRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RenameRejected {
+    SrcNotExist,
+    DstExist,
+    DstParentNotDirectory,
+    DstUnderSrc,
+    SrcOpen,
+    SafeMode,
+    PermissionDenied,
+    QuotaExceeded(QuotaExceeded),
+    PathLimit(PathLimitError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConcatReq {
+    pub target: String,
+    pub sources: Vec<String>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConcatResp {
+    Ok,
+    Rejected(ConcatRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConcatRejected {
+    NoSources,
+    DuplicateSource(String),
+    NotFound(String),
+    NotFile(String),
+    Open(String),
+    NotFinalized(String),
+    ReplicationMismatch(String),
+    SafeMode,
+    PermissionDenied,
+    QuotaExceeded(QuotaExceeded),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetFileChecksumReq {
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GetFileChecksumResp {
+    Ok(GetFileChecksumRespOk),
+    Rejected(GetFileChecksumRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetFileChecksumRespOk {
+    pub algorithm: String,
+    pub checksum: Vec<u8>,
+    pub blocks: usize,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum GetFileChecksumRejected {
+    NotFound,
+    NotFile,
+    NotFinalized,
+    MissingChecksums { missing: usize, blocks: usize },
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MkdirReq {
+    pub path: String,
+    pub create_parents: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MkdirResp {
+    Ok(MkdirRespOk),
+    Rejected(MkdirRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MkdirRespOk {
+    pub existed: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MkdirRejected {
+    ParentNotExist(UnresolvedPath),
+    NotDirectory(UnresolvedPath),
+    SafeMode,
+    PermissionDenied,
+    QuotaExceeded(QuotaExceeded),
+    PathLimit(PathLimitError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListReq {
+    pub path: String,
+    pub start_after: Option<String>,
+    pub limit: Option<NonZeroUsize>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ListResp {
+    Ok(ListRespOk),
+    Rejected(ListRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListRespOk {
+    pub entries: Vec<ListEntry>,
+    pub has_more: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub replication: Option<NonZeroUsize>,
+    pub len: u64,
+    #[serde(with = "crate::fs::epoch_millis")]
+    pub mtime: SystemTime,
+    pub owner: UserId,
+    pub group: GroupId,
+    pub mode: u16,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ListRejected {
+    NotFound(UnresolvedPath),
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatReq {
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StatResp {
+    Ok(FileStatus),
+    Rejected(StatRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileStatus {
+    pub inode: u64,
+    pub is_dir: bool,
+    pub len: u64,
+    pub replication: Option<NonZeroUsize>,
+    pub block_count: usize,
+    pub children: usize,
+    #[serde(with = "crate::fs::epoch_millis")]
+    pub ctime: SystemTime,
+    #[serde(with = "crate::fs::epoch_millis")]
+    pub mtime: SystemTime,
+    #[serde(with = "crate::fs::epoch_millis")]
+    pub atime: SystemTime,
+    pub owner: UserId,
+    pub group: GroupId,
+    pub mode: u16,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StatRejected {
+    NotFound(UnresolvedPath),
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetReplicationReq {
+    pub path: String,
+    pub replication: NonZeroUsize,
+    pub recursive: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SetReplicationResp {
+    Ok(SetReplicationRespOk),
+    Rejected(SetReplicationRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetReplicationRespOk {
+    pub pending_blocks: usize,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SetReplicationRejected {
+    NotFound,
+    IsDirectory,
+    SafeMode,
+    PermissionDenied,
+    QuotaExceeded(QuotaExceeded),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetTimesReq {
+    pub path: String,
+    #[serde(with = "crate::fs::epoch_millis::option")]
+    pub mtime: Option<SystemTime>,
+    #[serde(with = "crate::fs::epoch_millis::option")]
+    pub atime: Option<SystemTime>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SetTimesResp {
+    Ok,
+    Rejected(SetTimesRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SetTimesRejected {
+    NotFound,
+    SafeMode,
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChownReq {
+    pub path: String,
+    pub owner: Option<UserId>,
+    pub group: Option<GroupId>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChownResp {
+    Ok,
+    Rejected(ChownRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ChownRejected {
+    NotFound,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChmodReq {
+    pub path: String,
+    pub mode: u16,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChmodResp {
+    Ok,
+    Rejected(ChmodRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ChmodRejected {
+    NotFound,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetXattrReq {
+    pub path: String,
+    pub name: String,
+    pub value: Vec<u8>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SetXattrResp {
+    Ok,
+    Rejected(SetXattrRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SetXattrRejected {
+    NotFound,
+    TooLarge,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetXattrReq {
+    pub path: String,
+    pub name: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GetXattrResp {
+    Ok(GetXattrRespOk),
+    Rejected(GetXattrRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetXattrRespOk {
+    pub value: Vec<u8>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum GetXattrRejected {
+    NotFound,
+    NoSuchAttr,
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveXattrReq {
+    pub path: String,
+    pub name: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RemoveXattrResp {
+    Ok,
+    Rejected(RemoveXattrRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum RemoveXattrRejected {
+    NotFound,
+    NoSuchAttr,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListXattrsReq {
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ListXattrsResp {
+    Ok(ListXattrsRespOk),
+    Rejected(ListXattrsRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListXattrsRespOk {
+    pub names: Vec<String>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ListXattrsRejected {
+    NotFound,
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaExceeded {
+    pub dir: String,
+    pub limit: QuotaLimit,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetQuotaReq {
+    pub path: String,
+    pub max_nodes: Option<u64>,
+    pub max_space: Option<u64>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SetQuotaResp {
+    Ok,
+    Rejected(SetQuotaRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SetQuotaRejected {
+    NotFound,
+    NotDirectory,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClearQuotaReq {
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClearQuotaResp {
+    Ok,
+    Rejected(ClearQuotaRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ClearQuotaRejected {
+    NotFound,
+    NotDirectory,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentSummaryReq {
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ContentSummaryResp {
+    Ok(ContentSummaryRespOk),
+    Rejected(ContentSummaryRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentSummaryRespOk {
+    pub summary: ContentSummary,
+    pub max_nodes: Option<u64>,
+    pub max_space: Option<u64>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ContentSummaryRejected {
+    NotFound,
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSnapshotReq {
     pub path: String,
+    pub name: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CreateSnapshotResp {
+    Ok,
+    Rejected(CreateSnapshotRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum CreateSnapshotRejected {
+    NotFound,
+    NotDirectory,
+    Exists,
+    InvalidName(PathParseError),
+    PermissionDenied,
+    SafeMode,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeleteFile {
+pub struct DeleteSnapshotReq {
     pub path: String,
+    pub name: String,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeleteDirectory {
+pub enum DeleteSnapshotResp {
+    Ok,
+    Rejected(DeleteSnapshotRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum DeleteSnapshotRejected {
+    NotFound,
+    NotDirectory,
+    NoSuchSnapshot,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotDiffReq {
     pub path: String,
+    pub from: String,
+    pub to: Option<String>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SnapshotDiffResp {
+    Ok(SnapshotDiffRespOk),
+    Rejected(SnapshotDiffRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotDiffRespOk {
+    pub entries: Vec<DiffEntry>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SnapshotDiffRejected {
+    NotFound,
+    NotDirectory,
+    NoSuchSnapshot,
+    PermissionDenied,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AllocBlockReq {
+    pub client: ClientId,
     pub path: String,
     pub off_range: (u64, u64),
+    pub request_id: Option<u64>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AllocBlockResp {
     Ok(AllocBlockRespOk),
-    Rejected,
+    Rejected(AllocBlockRejected),
 }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AllocBlockRejected {
+    FileNotExist,
+    NotFile,
+    OffsetGap { expected: u64 },
+    NonZeroStart,
+    EmptyRange,
+    ReversedRange,
+
+    NoStore,
+    NoLease,
+    BlockTooLarge { max: u64 },
+    SafeMode,
+    QuotaExceeded(QuotaExceeded),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AllocBlockRespOk {
     pub block: BlockId,
-    pub store_addr: String,
+    pub generation: u64,
+
+    pub targets: Vec<StoreAddr>,
+    /// The stores behind `targets`, in the same order.
+    #[serde(default)]
+    pub stores: Vec<StoreId>,
+    pub max_block_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAdditionalStoreReq {
+    pub client: ClientId,
+    pub path: String,
+    pub block: BlockId,
+    pub existing: Vec<StoreId>,
+    /// Pipeline members that failed, never picked as the replacement.
+    #[serde(default)]
+    pub excluded: Vec<StoreId>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GetAdditionalStoreResp {
+    Ok(GetAdditionalStoreRespOk),
+    Rejected(GetAdditionalStoreRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAdditionalStoreRespOk {
+    pub generation: u64,
+    pub store: StoreId,
+    pub addr: StoreAddr,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum GetAdditionalStoreRejected {
+    FileNotExist,
+    NotFile,
+    NoLease,
+    NotLastBlock,
+    NoStore,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbandonBlockReq {
+    pub client: ClientId,
+    pub path: String,
+    pub block: BlockId,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AbandonBlockResp {
+    Ok,
+    Rejected(AbandonBlockRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum AbandonBlockRejected {
+    FileNotExist,
+    NotFile,
+    NoLease,
+    NotLastBlock,
+    AlreadyReported,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompleteFileReq {
+    pub client: ClientId,
+    pub path: String,
+    pub len: u64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CompleteFileResp {
+    Ok,
+    Retry,
+    Rejected(CompleteFileRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum CompleteFileRejected {
+    FileNotExist,
+    NotFile,
+    NoLease,
+    LengthMismatch { len: u64 },
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetBlockLocationsReq {
+    pub path: String,
+    pub off_range: Option<(u64, u64)>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GetBlockLocationsResp {
+    Ok(GetBlockLocationsRespOk),
+    Rejected(GetBlockLocationsRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetBlockLocationsRespOk {
+    pub blocks: Vec<LocatedBlock>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocatedBlock {
+    pub block: BlockId,
+    pub generation: u64,
+    pub off_range: (u64, u64),
+    pub store_addrs: Vec<StoreAddr>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum GetBlockLocationsRejected {
+    FileNotExist,
+    NotFile,
+    PermissionDenied,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockReportReq {
-    report: BlockReport,
+    pub store: StoreId,
+    pub report: BlockReport,
+    pub chunk: Option<ReportChunk>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ReportChunk {
+    pub epoch: u64,
+    pub seq: u32,
+    pub last: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockReportResp {
+    pub corrupted: Vec<BlockId>,
+    pub resend: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationFailedReq {
+    pub store: StoreId,
+    pub block: BlockId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListOpenFilesReq {
+    pub prefix: Option<String>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ListOpenFilesResp {
+    Ok(ListOpenFilesRespOk),
+    Rejected(ListOpenFilesRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ListOpenFilesRejected {
+    PermissionDenied,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListOpenFilesRespOk {
+    pub files: Vec<OpenFileSummary>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenFileSummary {
+    pub path: String,
+    pub write: bool,
+    pub holders: usize,
+    pub secs_since_lease: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForceCloseReq {
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ForceCloseResp {
+    Ok,
+    Rejected(ForceCloseRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ForceCloseRejected {
+    PermissionDenied,
+    NotOpen,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafeModeReq {
+    pub action: SafeModeAction,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SafeModeAction {
+    Get,
+    Enter,
+    Leave,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SafeModeResp {
+    Ok(SafeModeStatus),
+    Rejected(SafeModeRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SafeModeRejected {
+    PermissionDenied,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafeModeStatus {
+    pub on: bool,
+    pub manual: bool,
+    pub threshold: f64,
+    pub reported_blocks: usize,
+    pub total_blocks: usize,
+    pub invariant_violations: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecommissionStoreReq {
+    pub store: StoreId,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DecommissionStoreResp {
+    Ok,
+    Rejected(DecommissionStoreRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum DecommissionStoreRejected {
+    PermissionDenied,
+    NotFound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListStoresReq {}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListStoresResp {
+    pub stores: Vec<StoreSummary>,
+    pub missing_in_maintenance: Vec<BlockId>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreSummary {
+    pub store: StoreId,
+    pub addr: StoreAddr,
+    pub alive: bool,
+    pub admin_state: StoreAdminState,
+    pub admission: StoreAdmission,
+    pub pending_deletions: usize,
+
+    pub in_maintenance: bool,
+    pub remaining: u64,
+    pub block_count: usize,
+    pub failed_volumes: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterStatusReq {}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterStatusResp {
+    pub stores: Vec<StoreReport>,
+    pub capacity: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub files: u64,
+    pub directories: u64,
+    pub blocks: usize,
+    pub under_replicated: usize,
+    pub corrupt: usize,
+    pub safe_mode: SafeModeStatus,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreReport {
+    pub store: StoreId,
+    pub addr: StoreAddr,
+    pub liveness: StoreLiveness,
+    pub admin_state: StoreAdminState,
+    pub capacity: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub block_count: usize,
+    pub last_heartbeat_age: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshStoresReq {
+    pub stores: Vec<StoreConfig>,
+    pub hosts: HostLists,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RefreshStoresResp {
+    Ok(RefreshStoresRespOk),
+    Rejected(RefreshStoresRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum RefreshStoresRejected {
+    PermissionDenied,
+}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RefreshStoresRespOk {
+    pub added: Vec<StoreId>,
+    pub removed: Vec<StoreId>,
+    pub changed: Vec<StoreId>,
+    pub excluded: Vec<StoreId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnterMaintenanceReq {
+    pub store: StoreId,
+    pub until: SystemTime,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitMaintenanceReq {
+    pub store: StoreId,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MaintenanceResp {
+    Ok,
+    Rejected(MaintenanceRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum MaintenanceRejected {
+    PermissionDenied,
+    NotFound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportBadBlockReq {
+    pub block: BlockId,
+    pub store_addr: StoreAddr,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReportBadBlockResp {
+    Ok,
+    Rejected(ReportBadBlockRejected),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ReportBadBlockRejected {
+    BlockNotExist,
+    StoreNotExist,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListCorruptFilesReq {
+    pub prefix: Option<String>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListCorruptFilesResp {
+    pub files: Vec<CorruptFile>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorruptFile {
+    pub path: String,
+    pub corrupt: Vec<BlockId>,
+    pub missing: Vec<BlockId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsckReq {
+    pub path: String,
+    pub list_corrupt: bool,
+    pub delete_orphans: bool,
+    pub start_after: Option<String>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FsckResp {
+    Ok(FsckRespOk),
+    Rejected(FsckRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsckRespOk {
+    pub summary: FsckSummary,
+    pub files: Vec<FsckFile>,
+    pub orphans: Vec<BlockId>,
+    pub next: Option<String>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum FsckRejected {
+    NotFound,
+    PermissionDenied,
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetaSaveReq {
+    pub path: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MetaSaveResp {
+    Ok,
+    Rejected(MetaSaveRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MetaSaveRejected {
+    PermissionDenied,
+    /// Sent to a handler directly rather than through the control server.
+    Unsupported,
+    Io(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeEventsReq {
+    pub path_prefix: String,
+    pub after_txid: u64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SubscribeEventsResp {
+    Ok(SubscribeEventsRespOk),
+    Rejected(SubscribeEventsRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeEventsRespOk {
+    pub events: Vec<NamespaceEvent>,
+    /// The `after_txid` to ask with next; past `events` when the rest of
+    /// the log did not match the prefix.
+    pub last_txid: u64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SubscribeEventsRejected {
+    PermissionDenied,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamespaceEvent {
+    pub txid: u64,
+    pub kind: NamespaceEventKind,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NamespaceEventKind {
+    Create {
+        path: String,
+        is_dir: bool,
+    },
+    Close {
+        path: String,
+    },
+    Delete {
+        path: String,
+    },
+    Rename {
+        src: String,
+        dst: String,
+    },
+    SetReplication {
+        path: String,
+        replication: NonZeroUsize,
+    },
+    Concat {
+        target: String,
+        sources: Vec<String>,
+    },
+    /// Events up to `txid` are no longer retained; whatever the subscriber
+    /// mirrors has to be listed again.
+    Missed,
 }
@@ -0,0 +1,76 @@
+use std::{fmt, io, str::FromStr};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireFormat {
+    Bincode,
+    Json,
+}
+impl WireFormat {
+    pub const ALL: &'static [WireFormat] = &[WireFormat::Bincode, WireFormat::Json];
+
+    pub fn encode<T: Serialize>(&self, msg: &T) -> Result<Vec<u8>, FormatError> {
+        match self {
+            WireFormat::Bincode => bincode::serialize(msg).map_err(FormatError::Bincode),
+            WireFormat::Json => serde_json::to_vec(msg).map_err(FormatError::Json),
+        }
+    }
+    pub fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> Result<T, FormatError> {
+        match self {
+            WireFormat::Bincode => bincode::deserialize(buf).map_err(FormatError::Bincode),
+            WireFormat::Json => serde_json::from_slice(buf).map_err(FormatError::Json),
+        }
+    }
+}
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireFormat::Bincode => write!(f, "bincode"),
+            WireFormat::Json => write!(f, "json"),
+        }
+    }
+}
+impl FromStr for WireFormat {
+    type Err = io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(WireFormat::Bincode),
+            "json" => Ok(WireFormat::Json),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown wire format `{s}`"),
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+}
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bincode(e) => e.fmt(f),
+            Self::Json(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Bincode(e) => Some(e),
+            Self::Json(e) => Some(e),
+        }
+    }
+}
+impl From<FormatError> for io::Error {
+    fn from(e: FormatError) -> Self {
+        match e {
+            FormatError::Bincode(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            FormatError::Json(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::{fmt, io};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::store::StoreAddr;
+
+use super::{format::WireFormat, read_frame, write_frame};
+
+pub const MAGIC: u32 = u32::from_le_bytes(*b"DFSP");
+pub const MIN_VERSION: u16 = 1;
+pub const MAX_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub magic: u32,
+    pub min_version: u16,
+    pub max_version: u16,
+    pub formats: Vec<WireFormat>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HelloResp {
+    Accepted(Negotiated),
+    Rejected(HandshakeRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HandshakeRejected {
+    BadMagic,
+    Unsupported { min_version: u16, max_version: u16 },
+    NoCommonFormat { formats: Vec<WireFormat> },
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Negotiated {
+    pub version: u16,
+    pub format: WireFormat,
+}
+
+pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<(TcpStream, Negotiated)> {
+    connect_with(addr, &[WireFormat::Bincode]).await
+}
+
+/// `formats` is in order of preference; the peer picks the first one it
+/// also speaks.
+pub async fn connect_with(
+    addr: impl ToSocketAddrs,
+    formats: &[WireFormat],
+) -> io::Result<(TcpStream, Negotiated)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let negotiated = offer(&mut stream, MIN_VERSION, MAX_VERSION, formats).await?;
+    Ok((stream, negotiated))
+}
+
+pub async fn connect_store(addr: &StoreAddr) -> io::Result<(TcpStream, Negotiated)> {
+    connect(addr.resolve().await?).await
+}
+
+pub async fn offer(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    min_version: u16,
+    max_version: u16,
+    formats: &[WireFormat],
+) -> Result<Negotiated, HandshakeError> {
+    let hello = Hello {
+        magic: MAGIC,
+        min_version,
+        max_version,
+        formats: formats.to_vec(),
+    };
+    write_frame(stream, &hello).await?;
+    match read_frame(stream).await? {
+        HelloResp::Accepted(negotiated)
+            if (min_version..=max_version).contains(&negotiated.version)
+                && formats.contains(&negotiated.format) =>
+        {
+            Ok(negotiated)
+        }
+        HelloResp::Accepted(negotiated) if !formats.contains(&negotiated.format) => {
+            Err(HandshakeError::NoCommonFormat {
+                ours: formats.to_vec(),
+                theirs: vec![negotiated.format],
+            })
+        }
+        HelloResp::Accepted(Negotiated { version, .. }) => Err(HandshakeError::Unsupported {
+            ours: (min_version, max_version),
+            theirs: (version, version),
+        }),
+        HelloResp::Rejected(HandshakeRejected::BadMagic) => Err(HandshakeError::BadMagic),
+        HelloResp::Rejected(HandshakeRejected::Unsupported {
+            min_version: their_min,
+            max_version: their_max,
+        }) => Err(HandshakeError::Unsupported {
+            ours: (min_version, max_version),
+            theirs: (their_min, their_max),
+        }),
+        HelloResp::Rejected(HandshakeRejected::NoCommonFormat { formats: theirs }) => {
+            Err(HandshakeError::NoCommonFormat {
+                ours: formats.to_vec(),
+                theirs,
+            })
+        }
+    }
+}
+
+/// `formats` are the ones this side speaks; the first one the peer listed
+/// that is among them wins.
+pub async fn accept(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    formats: &[WireFormat],
+) -> Result<Negotiated, HandshakeError> {
+    let hello: Hello = read_frame(stream).await?;
+    if hello.magic != MAGIC {
+        let resp = HelloResp::Rejected(HandshakeRejected::BadMagic);
+        write_frame(stream, &resp).await?;
+        return Err(HandshakeError::BadMagic);
+    }
+    let version = MAX_VERSION.min(hello.max_version);
+    if version < MIN_VERSION.max(hello.min_version) {
+        let resp = HelloResp::Rejected(HandshakeRejected::Unsupported {
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+        });
+        write_frame(stream, &resp).await?;
+        return Err(HandshakeError::Unsupported {
+            ours: (MIN_VERSION, MAX_VERSION),
+            theirs: (hello.min_version, hello.max_version),
+        });
+    }
+    let Some(&format) = hello.formats.iter().find(|f| formats.contains(f)) else {
+        let resp = HelloResp::Rejected(HandshakeRejected::NoCommonFormat {
+            formats: formats.to_vec(),
+        });
+        write_frame(stream, &resp).await?;
+        return Err(HandshakeError::NoCommonFormat {
+            ours: formats.to_vec(),
+            theirs: hello.formats,
+        });
+    };
+    let negotiated = Negotiated { version, format };
+    write_frame(stream, &HelloResp::Accepted(negotiated)).await?;
+    Ok(negotiated)
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    BadMagic,
+    Unsupported {
+        ours: (u16, u16),
+        theirs: (u16, u16),
+    },
+    NoCommonFormat {
+        ours: Vec<WireFormat>,
+        theirs: Vec<WireFormat>,
+    },
+}
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "handshake failed: {e}"),
+            Self::BadMagic => write!(f, "peer does not speak the dfs protocol"),
+            Self::Unsupported { ours, theirs } => write!(
+                f,
+                "no common protocol version: we support {}..={}, peer supports {}..={}",
+                ours.0, ours.1, theirs.0, theirs.1
+            ),
+            Self::NoCommonFormat { ours, theirs } => write!(
+                f,
+                "no common wire format: we support {ours:?}, peer supports {theirs:?}"
+            ),
+        }
+    }
+}
+impl std::error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        let kind = match e {
+            HandshakeError::Io(e) => return e,
+            HandshakeError::BadMagic => io::ErrorKind::InvalidData,
+            HandshakeError::Unsupported { .. } | HandshakeError::NoCommonFormat { .. } => {
+                io::ErrorKind::Unsupported
+            }
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    async fn handshake(
+        min_version: u16,
+        max_version: u16,
+        offered: &[WireFormat],
+        spoken: &[WireFormat],
+    ) -> (
+        Result<Negotiated, HandshakeError>,
+        Result<Negotiated, HandshakeError>,
+    ) {
+        let (mut client, mut server) = duplex(1024);
+        tokio::join!(
+            offer(&mut client, min_version, max_version, offered),
+            accept(&mut server, spoken),
+        )
+    }
+
+    #[tokio::test]
+    async fn future_client_is_rejected_cleanly() {
+        let (client, server) = handshake(
+            MAX_VERSION + 1,
+            MAX_VERSION + 3,
+            &[WireFormat::Bincode],
+            WireFormat::ALL,
+        )
+        .await;
+        let client = io::Error::from(client.unwrap_err());
+        assert_eq!(client.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            client.to_string(),
+            format!(
+                "no common protocol version: we support {}..={}, peer supports {MIN_VERSION}..={MAX_VERSION}",
+                MAX_VERSION + 1,
+                MAX_VERSION + 3,
+            )
+        );
+        assert!(matches!(
+            server,
+            Err(HandshakeError::Unsupported { theirs, .. }) if theirs == (MAX_VERSION + 1, MAX_VERSION + 3)
+        ));
+    }
+
+    #[tokio::test]
+    async fn highest_common_version_and_first_common_format_win() {
+        let (client, server) = handshake(
+            MIN_VERSION,
+            MAX_VERSION + 5,
+            &[WireFormat::Json, WireFormat::Bincode],
+            &[WireFormat::Bincode, WireFormat::Json],
+        )
+        .await;
+        let expected = Negotiated {
+            version: MAX_VERSION,
+            format: WireFormat::Json,
+        };
+        assert_eq!(client.unwrap(), expected);
+        assert_eq!(server.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn no_common_format_is_rejected() {
+        let (client, server) = handshake(
+            MIN_VERSION,
+            MAX_VERSION,
+            &[WireFormat::Json],
+            &[WireFormat::Bincode],
+        )
+        .await;
+        assert!(matches!(client, Err(HandshakeError::NoCommonFormat { .. })));
+        assert!(matches!(server, Err(HandshakeError::NoCommonFormat { .. })));
+    }
+
+    #[tokio::test]
+    async fn wrong_magic_is_rejected() {
+        let (mut client, mut server) = duplex(1024);
+        let hello = Hello {
+            magic: u32::from_le_bytes(*b"HTTP"),
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+            formats: vec![WireFormat::Bincode],
+        };
+        let (resp, server) = tokio::join!(
+            async {
+                write_frame(&mut client, &hello).await.unwrap();
+                read_frame::<HelloResp>(&mut client).await.unwrap()
+            },
+            accept(&mut server, WireFormat::ALL),
+        );
+        assert!(matches!(
+            resp,
+            HelloResp::Rejected(HandshakeRejected::BadMagic)
+        ));
+        assert!(matches!(server, Err(HandshakeError::BadMagic)));
+    }
+}
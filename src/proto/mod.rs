@@ -1,2 +1,272 @@
+use std::{io, sync::Arc};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::fs::perm::Caller;
+
+use format::WireFormat;
+
+pub mod codec;
 pub mod control;
+pub mod format;
+pub mod handshake;
 pub mod store;
+
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+pub type TraceId = Arc<str>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub req_id: u64,
+    pub trace_id: Option<TraceId>,
+    pub caller: Option<Caller>,
+    pub msg: T,
+}
+
+pub async fn write_frame<T: Serialize>(
+    w: &mut (impl AsyncWrite + Unpin),
+    msg: &T,
+) -> io::Result<()> {
+    write_frame_as(w, WireFormat::Bincode, msg).await
+}
+
+pub async fn write_frame_as<T: Serialize>(
+    w: &mut (impl AsyncWrite + Unpin),
+    format: WireFormat,
+    msg: &T,
+) -> io::Result<()> {
+    let buf = format.encode(msg)?;
+    let len = u32::try_from(buf.len()).map_err(io::Error::other)?;
+    w.write_all(&len.to_le_bytes()).await?;
+    w.write_all(&buf).await?;
+    w.flush().await
+}
+
+pub async fn read_frame<T: DeserializeOwned>(r: &mut (impl AsyncRead + Unpin)) -> io::Result<T> {
+    read_frame_as(r, WireFormat::Bincode).await
+}
+
+pub async fn read_frame_as<T: DeserializeOwned>(
+    r: &mut (impl AsyncRead + Unpin),
+    format: WireFormat,
+) -> io::Result<T> {
+    let len = r.read_u32_le().await? as usize;
+    if MAX_FRAME < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds {MAX_FRAME}"),
+        ));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf).await?;
+    Ok(format.decode(&buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use crate::{
+        clock::ManualClock,
+        fs::block::{BlockBody, BlockList, BlockReport, BlockReportType, ReportedBlock},
+        store::StoreAddr,
+        testing::{self, superuser},
+    };
+
+    use super::{control::*, store::*, *};
+
+    /// Decoding what was encoded and encoding it again gives the same bytes,
+    /// in every wire format.
+    fn assert_round_trips<T: Serialize + DeserializeOwned + Debug>(msg: &T) {
+        for format in WireFormat::ALL {
+            let buf = format
+                .encode(msg)
+                .unwrap_or_else(|e| panic!("{format}: {e}: {msg:?}"));
+            let decoded: T = format
+                .decode(&buf)
+                .unwrap_or_else(|e| panic!("{format}: {e}: {msg:?}"));
+            assert_eq!(format.encode(&decoded).unwrap(), buf, "{format}: {msg:?}");
+        }
+    }
+
+    #[test]
+    fn control_messages_round_trip() {
+        let clock = ManualClock::new();
+        let mut handler = testing::handler(&clock);
+        let reqs = vec![
+            ControlReq::RegisterStoreReq(RegisterStoreReq {
+                store: "store-0".into(),
+                addr: addr(),
+                rack: Some("r".into()),
+                capacity: 1 << 40,
+                cluster_id: None,
+            }),
+            ControlReq::HeartbeatReq(HeartbeatReq {
+                store: "store-0".into(),
+                capacity: 100,
+                used: 10,
+                remaining: 90,
+                block_count: 3,
+                failed_volumes: 0,
+            }),
+            ControlReq::SafeModeReq(SafeModeReq {
+                action: SafeModeAction::Leave,
+            }),
+            ControlReq::MkdirReq(MkdirReq {
+                path: "/a/b".into(),
+                create_parents: true,
+            }),
+            ControlReq::OpenReq(OpenReq {
+                client: "c".into(),
+                write: true,
+                create: true,
+                exclusive: true,
+                append: false,
+                overwrite: false,
+                path: "/a/f".into(),
+            }),
+            ControlReq::SetXattrReq(SetXattrReq {
+                path: "/a/f".into(),
+                name: "user.k".into(),
+                value: vec![0, 1, 255],
+            }),
+            ControlReq::GetXattrReq(GetXattrReq {
+                path: "/a/f".into(),
+                name: "user.k".into(),
+            }),
+            ControlReq::CompleteFileReq(CompleteFileReq {
+                client: "c".into(),
+                path: "/a/f".into(),
+                len: 0,
+            }),
+            ControlReq::StatReq(StatReq {
+                path: "/a/f".into(),
+            }),
+            ControlReq::ListReq(ListReq {
+                path: "/a".into(),
+                start_after: None,
+                limit: None,
+            }),
+            ControlReq::ContentSummaryReq(ContentSummaryReq { path: "/".into() }),
+            ControlReq::CreateSnapshotReq(CreateSnapshotReq {
+                path: "/a".into(),
+                name: "s".into(),
+            }),
+            ControlReq::SnapshotDiffReq(SnapshotDiffReq {
+                path: "/a".into(),
+                from: "s".into(),
+                to: None,
+            }),
+            ControlReq::ClusterStatusReq(ClusterStatusReq {}),
+            ControlReq::FsckReq(FsckReq {
+                path: "/".into(),
+                list_corrupt: true,
+                delete_orphans: false,
+                start_after: None,
+            }),
+            ControlReq::StatReq(StatReq {
+                path: "/missing".into(),
+            }),
+        ];
+        for req in reqs {
+            assert_round_trips(&req);
+            let resp = handler.handle_req(&superuser(), req);
+            assert_round_trips(&resp);
+        }
+        let report = BlockReportReq {
+            store: "store-0".into(),
+            report: BlockReport::new(BlockReportType::Full, blocks()),
+            chunk: Some(ReportChunk {
+                epoch: 1,
+                seq: 2,
+                last: true,
+            }),
+        };
+        assert_round_trips(&ControlReq::BlockReportReq(report));
+        let alloc = AllocBlockRespOk {
+            block: "blk_1".into(),
+            generation: 3,
+            targets: vec![addr(), StoreAddr::Socket(([127, 0, 0, 1], 9000).into())],
+            stores: vec!["store-0".into(), "store-1".into()],
+            max_block_size: 1 << 27,
+        };
+        assert_round_trips(&ControlResp::AllocBlockResp(AllocBlockResp::Ok(alloc)));
+    }
+
+    #[test]
+    fn store_messages_round_trip() {
+        let msgs = vec![
+            StoreProto::OpenBlockReq(OpenBlockReq {
+                client: "c".into(),
+                block: "blk_1".into(),
+                generation: 2,
+                write: true,
+                targets: vec![addr()],
+                resume: Some(ResumeBlock {
+                    from_generation: 1,
+                    offset: 10,
+                }),
+                trace_id: Some("t".into()),
+            }),
+            StoreProto::BlockChunk(BlockChunk {
+                offset: 10,
+                data: vec![1, 2, 3],
+                last: true,
+            }),
+            StoreProto::ChunkAck(ChunkAck {
+                offset: 10,
+                status: ChunkAckStatus::DownstreamFailed { target: addr() },
+            }),
+        ];
+        for msg in msgs {
+            assert_round_trips(&msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_carry_an_envelope_in_every_format() {
+        for &format in WireFormat::ALL {
+            let envelope = Envelope {
+                req_id: 7,
+                trace_id: Some("t".into()),
+                caller: Some(superuser()),
+                msg: ControlReq::StatReq(StatReq { path: "/".into() }),
+            };
+            let mut buf = vec![];
+            write_frame_as(&mut buf, format, &envelope).await.unwrap();
+            let read: Envelope<ControlReq> =
+                read_frame_as(&mut buf.as_slice(), format).await.unwrap();
+            assert_eq!(read.req_id, 7);
+            assert_eq!(format!("{read:?}"), format!("{envelope:?}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_refused() {
+        let mut buf = (MAX_FRAME as u32 + 1).to_le_bytes().to_vec();
+        buf.extend([0; 16]);
+        let err = read_frame::<StoreProto>(&mut buf.as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn addr() -> StoreAddr {
+        StoreAddr::Host {
+            host: "store-0.dfs".into(),
+            port: 9000,
+        }
+    }
+
+    fn blocks() -> BlockList {
+        let mut blocks = BlockList::new();
+        blocks.push(ReportedBlock::new("blk_1".into(), BlockBody::new(10, 0)));
+        blocks.push(ReportedBlock::new(
+            "blk_2".into(),
+            BlockBody::with_checksum(20, 0xdead_beef, 1),
+        ));
+        blocks
+    }
+}
@@ -1,38 +1,117 @@
 use serde::{Deserialize, Serialize};
 
-use crate::fs::block::{BlockId, BlockReport};
+use crate::{
+    fs::{
+        block::{BlockBody, BlockId, BlockMeta, BlockReport},
+        virt::ClientId,
+    },
+    store::{ClusterId, StoreAddr, StoreId},
+};
+
+use super::TraceId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum StoreProto {
     OpenBlockReq(OpenBlockReq),
     OpenBlockResp(OpenBlockResp),
+    BlockChunk(BlockChunk),
+    ChunkAck(ChunkAck),
+    TransferPartialBlockReq(TransferPartialBlockReq),
+    TransferPartialBlockResp(TransferPartialBlockResp),
+    ReadBlockReq(ReadBlockReq),
+    ReadBlockResp(ReadBlockResp),
     ReplicateBlockReq(ReplicateBlockReq),
     ReplicateBlockResp(ReplicateBlockResp),
     RemoveBlockReq(RemoveBlockReq),
     RemoveBlockResp(RemoveBlockResp),
-    HeartbeatReq(HeartbeatReq),
-    HeartbeatResp(HeartbeatResp),
     FullBlockReportReq(FullBlockReportReq),
     FullBlockReportResp(FullBlockReportResp),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenBlockReq {
+    pub client: ClientId,
     pub block: BlockId,
+    pub generation: u64,
     pub write: bool,
+    pub targets: Vec<StoreAddr>,
+    pub resume: Option<ResumeBlock>,
+    pub trace_id: Option<TraceId>,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ResumeBlock {
+    pub from_generation: u64,
+    pub offset: u64,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenBlockResp {
     pub permitted: bool,
+    pub failed_target: Option<StoreAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockChunk {
+    pub offset: u64,
+    pub data: Vec<u8>,
+    pub last: bool,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkAck {
+    pub offset: u64,
+    pub status: ChunkAckStatus,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChunkAckStatus {
+    Ok,
+    LocalFailed,
+    DownstreamFailed { target: StoreAddr },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferPartialBlockReq {
+    pub client: ClientId,
+    pub block: BlockId,
+    pub generation: u64,
+    pub new_generation: u64,
+    pub offset: u64,
+    pub target: StoreAddr,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TransferPartialBlockResp {
+    Ok,
+    Failed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReplicateBlockReq {
     pub block: BlockId,
-    pub store_addr: String,
+    pub store_addr: StoreAddr,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ReplicateBlockResp {}
+pub enum ReplicateBlockResp {
+    Ok,
+    Failed(ReplicateBlockFailure),
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ReplicateBlockFailure {
+    Busy,
+    SourceUnavailable,
+    NotFound,
+    ChecksumMismatch,
+    Io,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadBlockReq {
+    pub block: BlockId,
+    pub offset: u64,
+    pub trace_id: Option<TraceId>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadBlockResp {
+    pub body: Option<BlockBody>,
+    pub meta: Option<BlockMeta>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoveBlockReq {
@@ -42,13 +121,70 @@ pub struct RemoveBlockReq {
 pub struct RemoveBlockResp {}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HeartbeatReq {}
+pub struct RegisterStoreReq {
+    pub store: StoreId,
+    pub addr: StoreAddr,
+    pub rack: Option<String>,
+    pub capacity: u64,
+
+    pub cluster_id: Option<ClusterId>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RegisterStoreResp {
+    Ok { cluster_id: ClusterId },
+    Rejected(RegisterStoreRejected),
+}
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HeartbeatResp {}
+pub enum RegisterStoreRejected {
+    ClusterMismatch { expected: ClusterId },
+    NotIncluded,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FullBlockReportReq {}
+pub struct HeartbeatReq {
+    pub store: StoreId,
+    pub capacity: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub block_count: u64,
+    pub failed_volumes: u32,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HeartbeatResp {
+    Ok(HeartbeatRespOk),
+    Rejected(HeartbeatRejected),
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeartbeatRespOk {
+    pub commands: Vec<StoreCommand>,
+    /// The control node holds no full report from this store, as after
+    /// either of them restarts.
+    pub full_report: bool,
+}
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum HeartbeatRejected {
+    NotIncluded,
+    /// The store has to register again first.
+    Unregistered,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StoreCommand {
+    DeleteBlocks(Vec<BlockId>),
+    ReplicateBlock {
+        block: BlockId,
+        targets: Vec<StoreAddr>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FullBlockReportReq {
+    pub cursor: Option<BlockId>,
+    pub max_entries: usize,
+}
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FullBlockReportResp {
+    pub epoch: u64,
     pub report: BlockReport,
+    pub cursor: Option<BlockId>,
+    pub last: bool,
 }
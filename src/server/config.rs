@@ -1,9 +1,292 @@
+use std::{fmt, io, path::Path};
+
 use serde::{Deserialize, Serialize};
 
 use super::{control::config::ControlNodeConfig, store::config::StoreNodeConfig};
 
+/// Filled in with defaults by round-tripping through [`Config`].
+const EXAMPLE_BASE: &str = r#"
+[control]
+meta_dir = "/var/lib/dfs/meta"
+stores = [{ addr = "127.0.0.1:9001" }]
+
+[store]
+config = { addr = "127.0.0.1:9001" }
+data_dirs = [{ path = "/var/lib/dfs/data", capacity = 1099511627776 }]
+"#;
+
+/// Comments written above each field by [`Config::example`].
+const FIELD_DOCS: &[(&str, &str)] = &[
+    (
+        "control",
+        "Settings of the control node; omit on store-only hosts.",
+    ),
+    ("control.addr", "Address clients and stores connect to."),
+    (
+        "control.meta_dir",
+        "Holds the fsimage and edit log; unset keeps the namespace in memory only.",
+    ),
+    (
+        "control.checkpoint_interval_secs",
+        "How often the namespace is saved and the edit log emptied.",
+    ),
+    (
+        "control.placement",
+        "RoundRobin, Random, AvailableSpace or RackAware.",
+    ),
+    ("control.track_atime", "Update access times on reads."),
+    (
+        "control.allow_invariant_violations",
+        "Leave safe mode even if the loaded image fails validation.",
+    ),
+    (
+        "control.lease_ttl_secs",
+        "Other clients may recover a write lease after this long.",
+    ),
+    (
+        "control.lease_hard_ttl_secs",
+        "The control node recovers abandoned leases after this long.",
+    ),
+    (
+        "control.default_replication",
+        "Replication of files created without one.",
+    ),
+    (
+        "control.heartbeat_interval_secs",
+        "How often stores are expected to heartbeat.",
+    ),
+    (
+        "control.store_dead_ttl_secs",
+        "Stores silent for longer are treated as dead.",
+    ),
+    (
+        "control.block_size",
+        "Upper bound on the size of a block, in bytes.",
+    ),
+    (
+        "control.include_hosts",
+        "Hosts allowed to register, inline or as a file path; empty admits all.",
+    ),
+    (
+        "control.exclude_hosts",
+        "Hosts to decommission, inline or as a file path.",
+    ),
+    ("control.stores", "Stores the control node knows about."),
+    ("control.stores.addr", "ip:port or host:port."),
+    ("control.stores.rack", "Used by RackAware placement."),
+    (
+        "store",
+        "Settings of a store node; omit on control-only hosts.",
+    ),
+    (
+        "store.max_volume_io_errors",
+        "A volume is failed after this many I/O errors.",
+    ),
+    (
+        "store.full_report_chunk",
+        "Blocks per message of a full block report.",
+    ),
+    (
+        "store.max_replications",
+        "Concurrent outgoing block replications.",
+    ),
+    (
+        "store.scrub_bytes_per_sec",
+        "Rate limit of the background block scrubber.",
+    ),
+    ("store.config", "Address this store is reached at."),
+    (
+        "store.control_addr",
+        "Address of the control node to register with.",
+    ),
+    (
+        "store.heartbeat_interval_secs",
+        "How often to heartbeat and report new or removed blocks.",
+    ),
+    ("store.data_dirs", "One entry per volume."),
+    ("store.data_dirs.capacity", "Bytes this volume may hold."),
+    (
+        "store.tmp_max_age",
+        "Unfinished block files older than this are removed.",
+    ),
+    (
+        "store.block_lease_timeout",
+        "Writes idle for longer are abandoned.",
+    ),
+];
+
+/// Commented-out lines for fields that are unset by default.
+const UNSET_EXAMPLES: &[(&str, &str, &str)] = &[
+    (
+        "control.stores",
+        "Id the store registers with; defaults to addr.",
+        "id = \"store-1\"",
+    ),
+    (
+        "control",
+        "User that skips every permission check.",
+        "superuser = \"dfs\"",
+    ),
+    (
+        "control",
+        "Masks the mode of new files and directories.",
+        "umask = 18",
+    ),
+    (
+        "control",
+        "Deleted nodes stay in /.trash this long; unset deletes immediately.",
+        "trash_retention_secs = 86400",
+    ),
+    (
+        "control",
+        "Namespace events kept for subscribers that fall behind.",
+        "event_window = 100000",
+    ),
+    (
+        "control",
+        "Serves Prometheus metrics at /metrics; unset leaves it off.",
+        "metrics_addr = \"0.0.0.0:9100\"",
+    ),
+    (
+        "control",
+        "Shards of the block map; unset uses one per CPU.",
+        "block_map_shards = 16",
+    ),
+    (
+        "store",
+        "Serves Prometheus metrics at /metrics; unset leaves it off.",
+        "metrics_addr = \"0.0.0.0:9101\"",
+    ),
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub control: Option<ControlNodeConfig>,
     pub store: Option<StoreNodeConfig>,
 }
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config: Config = toml::from_str(&text).map_err(ConfigError::Parse)?;
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(ConfigError::Invalid(errors));
+        }
+        Ok(config)
+    }
+
+    /// Reports every problem rather than stopping at the first.
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+        if self.control.is_none() && self.store.is_none() {
+            errors.push(FieldError::new(
+                "control",
+                "at least one of control or store must be set",
+            ));
+        }
+        if let Some(Err(e)) = self.control.as_ref().map(|control| control.settings()) {
+            errors.extend(e);
+        }
+        if let Some(store) = &self.store {
+            errors.extend(store.validate());
+        }
+        let control_metrics = self.control.as_ref().and_then(|c| c.metrics_addr());
+        let store_metrics = self.store.as_ref().and_then(|s| s.metrics_addr);
+        if control_metrics.is_some() && control_metrics == store_metrics {
+            errors.push(FieldError::new(
+                "store.metrics_addr",
+                "must differ from control.metrics_addr",
+            ));
+        }
+        errors
+    }
+
+    /// A config for a host running both nodes, with every field at its
+    /// default and commented.
+    pub fn example() -> String {
+        let config: Config = toml::from_str(EXAMPLE_BASE).unwrap();
+        let text = toml::to_string(&config).unwrap();
+        let mut out = String::new();
+        let mut section = String::new();
+        for line in text.lines() {
+            if line.starts_with('[') {
+                let header = line.trim_start_matches('[').trim_end_matches(']');
+                flush_unset_examples(&section, &mut out);
+                section = header.to_string();
+                if let Some(doc) = field_doc(&section) {
+                    out.push_str(&format!("\n# {doc}\n"));
+                } else {
+                    out.push('\n');
+                }
+            } else if let Some((key, _)) = line.split_once(" = ") {
+                if let Some(doc) = field_doc(&format!("{section}.{key}")) {
+                    out.push_str(&format!("# {doc}\n"));
+                }
+            }
+            if !line.is_empty() {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        flush_unset_examples(&section, &mut out);
+        out.trim_start().to_string()
+    }
+}
+
+fn field_doc(path: &str) -> Option<&'static str> {
+    FIELD_DOCS
+        .iter()
+        .find(|(field, _)| *field == path)
+        .map(|(_, doc)| *doc)
+}
+
+fn flush_unset_examples(section: &str, out: &mut String) {
+    for (_, doc, line) in UNSET_EXAMPLES.iter().filter(|(s, ..)| *s == section) {
+        out.push_str(&format!("# {doc}\n# {line}\n"));
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// Includes malformed values such as store addresses that do not parse.
+    Parse(toml::de::Error),
+    Invalid(Vec<FieldError>),
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read config: {e}"),
+            Self::Parse(e) => write!(f, "failed to parse config: {e}"),
+            Self::Invalid(errors) => {
+                write!(f, "invalid config:")?;
+                for e in errors {
+                    write!(f, "\n  {e}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+/// An invalid value, named by its dotted path from the top of the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub reason: String,
+}
+impl FieldError {
+    pub fn new(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+}
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.reason)
+    }
+}
+impl std::error::Error for FieldError {}
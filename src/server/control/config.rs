@@ -1,8 +1,300 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use serde::{Deserialize, Serialize};
 
-use crate::store::StoreConfig;
+use crate::{
+    fs::{
+        block::default_shards,
+        perm::{DEFAULT_SUPERUSER, DEFAULT_UMASK},
+        virt::LeaseLimits,
+    },
+    server::config::FieldError,
+    store::{HostLists, StoreConfig},
+};
+
+use super::placement::PlacementPolicyKind;
+
+const DEFAULT_REPLICATION: usize = 3;
+const HEARTBEAT_INTERVAL_SECS: u64 = 3;
+const STORE_DEAD_TTL_SECS: u64 = 30;
+const BLOCK_SIZE: u64 = 128 * 1024 * 1024;
+const ADDR: &str = "0.0.0.0:9000";
+const CHECKPOINT_INTERVAL_SECS: u64 = 60 * 60;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ControlNodeConfig {
+    #[serde(default = "default_addr")]
+    addr: SocketAddr,
+    #[serde(default)]
+    meta_dir: Option<PathBuf>,
+    #[serde(default = "default_checkpoint_interval_secs")]
+    checkpoint_interval_secs: u64,
+    #[serde(default)]
+    edit_sync_interval_ms: u64,
     stores: Vec<StoreConfig>,
+    #[serde(default)]
+    placement: PlacementPolicyKind,
+    #[serde(default)]
+    track_atime: bool,
+    #[serde(default)]
+    allow_invariant_violations: bool,
+    #[serde(default)]
+    superuser: Option<String>,
+    #[serde(default)]
+    umask: Option<u16>,
+    #[serde(default)]
+    trash_retention_secs: Option<u64>,
+    #[serde(default)]
+    event_window: Option<usize>,
+    #[serde(default = "default_lease_ttl_secs")]
+    lease_ttl_secs: u64,
+    #[serde(default = "default_lease_hard_ttl_secs")]
+    lease_hard_ttl_secs: u64,
+    #[serde(default = "default_replication")]
+    default_replication: usize,
+    #[serde(default = "default_heartbeat_interval_secs")]
+    heartbeat_interval_secs: u64,
+    #[serde(default = "default_store_dead_ttl_secs")]
+    store_dead_ttl_secs: u64,
+    #[serde(default = "default_block_size")]
+    block_size: u64,
+    #[serde(default)]
+    include_hosts: HostList,
+    #[serde(default)]
+    exclude_hosts: HostList,
+    #[serde(default)]
+    metrics_addr: Option<SocketAddr>,
+    #[serde(default)]
+    block_map_shards: Option<usize>,
+}
+impl ControlNodeConfig {
+    /// Where clients and stores reach the control node.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Holds the fsimage and edit log; `None` keeps the namespace in memory
+    /// only.
+    pub fn meta_dir(&self) -> Option<&Path> {
+        self.meta_dir.as_deref()
+    }
+    pub fn checkpoint_interval(&self) -> Duration {
+        Duration::from_secs(self.checkpoint_interval_secs)
+    }
+    /// How long a logged edit may wait to be synced; zero syncs every edit
+    /// before its request is answered.
+    pub fn edit_sync_interval(&self) -> Duration {
+        Duration::from_millis(self.edit_sync_interval_ms)
+    }
+    pub fn stores(&self) -> &[StoreConfig] {
+        &self.stores
+    }
+    pub fn placement(&self) -> PlacementPolicyKind {
+        self.placement
+    }
+    pub fn track_atime(&self) -> bool {
+        self.track_atime
+    }
+    pub fn allow_invariant_violations(&self) -> bool {
+        self.allow_invariant_violations
+    }
+    pub fn superuser(&self) -> &str {
+        self.superuser.as_deref().unwrap_or(DEFAULT_SUPERUSER)
+    }
+    pub fn umask(&self) -> u16 {
+        self.umask.unwrap_or(DEFAULT_UMASK)
+    }
+    /// Where to serve `GET /metrics`; `None` leaves it off.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+    /// `None` leaves the trash off and deletes immediately.
+    pub fn trash_retention(&self) -> Option<Duration> {
+        self.trash_retention_secs.map(Duration::from_secs)
+    }
+    /// Namespace events kept for subscribers to catch up from; `None`
+    /// keeps the handler's default.
+    pub fn event_window(&self) -> Option<usize> {
+        self.event_window
+    }
+    /// Reports every invalid field, qualified by its path under `control`.
+    pub fn settings(&self) -> Result<ControlSettings, Vec<FieldError>> {
+        let mut errors = vec![];
+        let mut addrs = HashMap::new();
+        let mut ids = HashMap::new();
+        for (i, store) in self.stores.iter().enumerate() {
+            if let Some(first) = addrs.insert(store.addr(), i) {
+                let field = format!("control.stores[{i}].addr");
+                let reason = format!("duplicate of control.stores[{first}].addr");
+                errors.push(FieldError::new(field, reason));
+            }
+            if let Some(first) = ids.insert(store.id(), i) {
+                let field = format!("control.stores[{i}].id");
+                let reason = format!("duplicate of control.stores[{first}].id");
+                errors.push(FieldError::new(field, reason));
+            }
+        }
+        let mut check = |ok: bool, field: &str, reason: &str| {
+            if !ok {
+                errors.push(FieldError::new(format!("control.{field}"), reason));
+            }
+        };
+        check(self.umask() <= 0o777, "umask", "must be at most 0o777");
+        check(
+            self.lease_ttl_secs > 0,
+            "lease_ttl_secs",
+            "must be positive",
+        );
+        check(
+            self.lease_hard_ttl_secs >= self.lease_ttl_secs,
+            "lease_hard_ttl_secs",
+            "must be at least lease_ttl_secs",
+        );
+        check(
+            self.heartbeat_interval_secs > 0,
+            "heartbeat_interval_secs",
+            "must be positive",
+        );
+        check(
+            self.store_dead_ttl_secs > self.heartbeat_interval_secs,
+            "store_dead_ttl_secs",
+            "must be greater than heartbeat_interval_secs",
+        );
+        check(self.block_size > 0, "block_size", "must be positive");
+        check(
+            self.checkpoint_interval_secs > 0,
+            "checkpoint_interval_secs",
+            "must be positive",
+        );
+        check(
+            self.block_map_shards != Some(0),
+            "block_map_shards",
+            "must be at least 1",
+        );
+        let default_replication = NonZeroUsize::new(self.default_replication);
+        check(
+            default_replication.is_some(),
+            "default_replication",
+            "must be at least 1",
+        );
+        let mut load = |list: &HostList, field: &str| match list.load() {
+            Ok(hosts) => hosts,
+            Err(e) => {
+                errors.push(FieldError::new(format!("control.{field}"), e.to_string()));
+                HashSet::new()
+            }
+        };
+        let hosts = HostLists {
+            include: load(&self.include_hosts, "include_hosts"),
+            exclude: load(&self.exclude_hosts, "exclude_hosts"),
+        };
+        let default_replication = match default_replication {
+            Some(replication) if errors.is_empty() => replication,
+            _ => return Err(errors),
+        };
+        Ok(ControlSettings {
+            lease_limits: LeaseLimits {
+                soft: Duration::from_secs(self.lease_ttl_secs),
+                hard: Duration::from_secs(self.lease_hard_ttl_secs),
+            },
+            default_replication,
+            heartbeat_interval: Duration::from_secs(self.heartbeat_interval_secs),
+            store_dead_ttl: Duration::from_secs(self.store_dead_ttl_secs),
+            block_size: self.block_size,
+            block_map_shards: self
+                .block_map_shards
+                .and_then(NonZeroUsize::new)
+                .unwrap_or_else(default_shards),
+            hosts,
+        })
+    }
+}
+
+/// Runtime parameters that [`ControlNodeConfig::settings`] has validated.
+#[derive(Debug, Clone)]
+pub struct ControlSettings {
+    pub lease_limits: LeaseLimits,
+    /// Replication of files created without one.
+    pub default_replication: NonZeroUsize,
+    /// How often stores are expected to heartbeat.
+    pub heartbeat_interval: Duration,
+    /// Stores silent for longer are treated as dead.
+    pub store_dead_ttl: Duration,
+    /// Upper bound on the size of a single block.
+    pub block_size: u64,
+    /// Shards of the block map, each with its own store index.
+    pub block_map_shards: NonZeroUsize,
+    pub hosts: HostLists,
+}
+impl Default for ControlSettings {
+    fn default() -> Self {
+        Self {
+            lease_limits: LeaseLimits::default(),
+            default_replication: NonZeroUsize::new(DEFAULT_REPLICATION).unwrap(),
+            heartbeat_interval: Duration::from_secs(HEARTBEAT_INTERVAL_SECS),
+            store_dead_ttl: Duration::from_secs(STORE_DEAD_TTL_SECS),
+            block_size: BLOCK_SIZE,
+            block_map_shards: default_shards(),
+            hosts: HostLists::default(),
+        }
+    }
+}
+
+/// Either the hosts themselves or a file naming one per line, where blank
+/// lines and `#` comments are skipped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HostList {
+    Inline(Vec<String>),
+    File(PathBuf),
+}
+impl HostList {
+    pub fn load(&self) -> io::Result<HashSet<String>> {
+        let text = match self {
+            HostList::Inline(hosts) => return Ok(hosts.iter().cloned().collect()),
+            HostList::File(path) => std::fs::read_to_string(path)?,
+        };
+        Ok(text
+            .lines()
+            .map(|line| line.split('#').next().unwrap().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect())
+    }
+}
+impl Default for HostList {
+    fn default() -> Self {
+        HostList::Inline(vec![])
+    }
+}
+
+fn default_addr() -> SocketAddr {
+    ADDR.parse().unwrap()
+}
+fn default_checkpoint_interval_secs() -> u64 {
+    CHECKPOINT_INTERVAL_SECS
+}
+fn default_lease_ttl_secs() -> u64 {
+    LeaseLimits::default().soft.as_secs()
+}
+fn default_lease_hard_ttl_secs() -> u64 {
+    LeaseLimits::default().hard.as_secs()
+}
+fn default_replication() -> usize {
+    DEFAULT_REPLICATION
+}
+fn default_heartbeat_interval_secs() -> u64 {
+    HEARTBEAT_INTERVAL_SECS
+}
+fn default_store_dead_ttl_secs() -> u64 {
+    STORE_DEAD_TTL_SECS
+}
+fn default_block_size() -> u64 {
+    BLOCK_SIZE
 }
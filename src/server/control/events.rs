@@ -0,0 +1,207 @@
+use std::{collections::VecDeque, iter, sync::Arc};
+
+use tokio::sync::Notify;
+
+use crate::{
+    fs::{edit_log::EditRecord, virt::PathSplit},
+    proto::control::{NamespaceEvent, NamespaceEventKind, SubscribeEventsRespOk},
+};
+
+use super::handler::TRASH_DIR;
+
+/// The most recent namespace events, numbered by the edit log records they
+/// came from.
+#[derive(Debug)]
+pub struct EventLog {
+    events: VecDeque<RecordedEvent>,
+    capacity: usize,
+    /// Events up to this txid may have been dropped.
+    retained_after: u64,
+    notify: Arc<Notify>,
+}
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            capacity,
+            retained_after: 0,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim();
+    }
+    /// Drops every event; none up to `txid` can be replayed any more.
+    pub fn reset(&mut self, txid: u64) {
+        self.events.clear();
+        self.retained_after = txid;
+    }
+    /// Woken whenever an event is recorded.
+    pub fn notify(&self) -> &Arc<Notify> {
+        &self.notify
+    }
+    /// Records the event of an applied edit, if it is one subscribers see.
+    pub fn record(&mut self, txid: u64, record: &EditRecord) {
+        let Some((kind, paths)) = event_kind(record) else {
+            return;
+        };
+        let event = NamespaceEvent { txid, kind };
+        self.events.push_back(RecordedEvent { event, paths });
+        self.trim();
+        self.notify.notify_waiters();
+    }
+    fn trim(&mut self) {
+        while self.events.len() > self.capacity {
+            let dropped = self.events.pop_front().unwrap();
+            self.retained_after = dropped.event.txid;
+        }
+    }
+    /// Up to `limit` events after `after_txid` that touch `prefix`, led by
+    /// [`NamespaceEventKind::Missed`] if some of them are gone.
+    pub fn after(
+        &self,
+        after_txid: u64,
+        last_txid: u64,
+        prefix: &PathSplit,
+        limit: usize,
+    ) -> SubscribeEventsRespOk {
+        let mut events = vec![];
+        // a txid we never wrote means the subscriber followed some other
+        // history
+        let missed = if after_txid > last_txid {
+            Some(last_txid)
+        } else if after_txid < self.retained_after {
+            Some(self.retained_after)
+        } else {
+            None
+        };
+        if let Some(txid) = missed {
+            events.push(NamespaceEvent {
+                txid,
+                kind: NamespaceEventKind::Missed,
+            });
+        }
+        let after_txid = missed.unwrap_or(after_txid);
+        let start = self
+            .events
+            .partition_point(|recorded| recorded.event.txid <= after_txid);
+        let mut scanned = after_txid;
+        for recorded in self.events.range(start..) {
+            if events.len() == limit {
+                return SubscribeEventsRespOk {
+                    events,
+                    last_txid: scanned,
+                };
+            }
+            if recorded.paths.iter().any(|path| path.starts_with(prefix)) {
+                events.push(recorded.event.clone());
+            }
+            scanned = recorded.event.txid;
+        }
+        SubscribeEventsRespOk { events, last_txid }
+    }
+}
+
+#[derive(Debug)]
+struct RecordedEvent {
+    event: NamespaceEvent,
+    /// Those of `event`, kept parsed for matching prefixes.
+    paths: Vec<PathSplit>,
+}
+
+/// The event and the paths it touches.
+fn event_kind(record: &EditRecord) -> Option<(NamespaceEventKind, Vec<PathSplit>)> {
+    Some(match record {
+        EditRecord::CreateFile { path, .. } => (
+            NamespaceEventKind::Create {
+                path: path.to_uri(),
+                is_dir: false,
+            },
+            vec![path.clone()],
+        ),
+        EditRecord::Mkdir { path, .. } => (
+            NamespaceEventKind::Create {
+                path: path.to_uri(),
+                is_dir: true,
+            },
+            vec![path.clone()],
+        ),
+        EditRecord::CompleteFile { path } => (
+            NamespaceEventKind::Close {
+                path: path.to_uri(),
+            },
+            vec![path.clone()],
+        ),
+        EditRecord::Delete { path } => (
+            NamespaceEventKind::Delete {
+                path: path.to_uri(),
+            },
+            vec![path.clone()],
+        ),
+        // to whoever watches the source, a move to the trash is a delete
+        EditRecord::Rename { src, dst } if is_in_trash(dst) && !is_in_trash(src) => (
+            NamespaceEventKind::Delete { path: src.to_uri() },
+            vec![src.clone()],
+        ),
+        EditRecord::Rename { src, dst } => (
+            NamespaceEventKind::Rename {
+                src: src.to_uri(),
+                dst: dst.to_uri(),
+            },
+            vec![src.clone(), dst.clone()],
+        ),
+        EditRecord::SetReplication { path, replication } => (
+            NamespaceEventKind::SetReplication {
+                path: path.to_uri(),
+                replication: *replication,
+            },
+            vec![path.clone()],
+        ),
+        EditRecord::Concat { target, sources } => (
+            NamespaceEventKind::Concat {
+                target: target.to_uri(),
+                sources: sources.iter().map(|source| source.to_uri()).collect(),
+            },
+            iter::once(target).chain(sources).cloned().collect(),
+        ),
+        _ => return None,
+    })
+}
+
+fn is_in_trash(path: &PathSplit) -> bool {
+    path.segs().first().map(|seg| &**seg) == Some(TRASH_DIR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rename(src: &str, dst: &str) -> EditRecord {
+        EditRecord::Rename {
+            src: PathSplit::from_uri(src),
+            dst: PathSplit::from_uri(dst),
+        }
+    }
+    fn txids(ok: &SubscribeEventsRespOk) -> Vec<u64> {
+        ok.events.iter().map(|event| event.txid).collect()
+    }
+
+    #[test]
+    fn renames_match_either_side_and_dropped_events_are_missed() {
+        let mut log = EventLog::new(3);
+        log.record(1, &rename("/a/x", "/b/x"));
+        log.record(2, &rename("/b/y", "/a/y"));
+        log.record(3, &rename("/c/z", "/.trash/u/1/c/z"));
+        let a = PathSplit::from_uri("/a");
+        assert_eq!(txids(&log.after(0, 3, &a, 10)), [1, 2]);
+        let ok = log.after(0, 3, &PathSplit::from_uri("/.trash"), 10);
+        assert!(ok.events.is_empty());
+        assert_eq!(ok.last_txid, 3);
+
+        log.record(4, &rename("/a/w", "/d/w"));
+        let ok = log.after(0, 4, &a, 10);
+        assert!(matches!(ok.events[0].kind, NamespaceEventKind::Missed));
+        assert_eq!(txids(&ok), [1, 2, 4]);
+    }
+}
@@ -1,29 +1,157 @@
 use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    io, iter, mem,
     num::NonZeroUsize,
-    time::{Duration, Instant},
+    ops::ControlFlow,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
 };
 
+use tokio::sync::Notify;
+use tracing::{error, warn};
+
 use crate::{
+    clock::Clock,
     fs::{
-        block::ReplicatedBlocksMap,
+        block::{
+            composite_checksum, shard_of, BlockBody, BlockId, BlockIdGenerator, BlockList,
+            BlockReport, BlockReportType, BlockShards, CorruptReason, PushReplicaError,
+            ReplicatedBlock, ReplicatedBlocksMap, ReportedBlock, StoreIndex, COMPOSITE_CRC32C,
+        },
+        edit_log::{EditEntry, EditLog, EditRecord},
+        epoch_millis::to_millis,
+        fsck::{FsckFile, FsckProblem, FsckSummary},
+        image::FsImage,
+        inode::{InodeIdGenerator, InodeTable},
+        invariant::InvariantViolation,
+        perm::{
+            Caller, Permission, UserId, DEFAULT_SUPERUSER, DEFAULT_UMASK, EXECUTE, READ, WRITE,
+        },
+        quota::{Quota, Usage},
+        replication::{CorruptReplicas, ExcessReplicas, InvalidateQueue, ReplicationQueue},
+        snapshot,
         virt::{
-            File, FileAttribute, FileBlock, FsNode, FsNodeAttribute, FsNodeBody,
-            FsNodeCreateFileError, OpenFileTable, PathCursor, PathSplit,
+            atomic_persist_with, File, FileAppendBlockError, FileAttribute, FileBlock, FsNode,
+            FsNodeAttribute, FsNodeBody, FsNodeCreateDirsError, FsNodeCreateFileError,
+            FsNodeQueryError, FsNodeRenameError, LeaseLimits, OpenFileTable, PathCursor,
+            PathLimits, PathParseError, PathSplit, SnapshotError, XattrLimits, SNAPSHOT_DIR,
+        },
+    },
+    metrics::Metrics,
+    proto::{
+        control::{
+            AbandonBlockRejected, AbandonBlockResp, AllocBlockRejected, AllocBlockResp,
+            AllocBlockRespOk, BlockReportReq, BlockReportResp, ChmodRejected, ChmodResp,
+            ChownRejected, ChownResp, ClearQuotaRejected, ClearQuotaResp, CloseResp,
+            ClusterStatusResp, CompleteFileRejected, CompleteFileResp, ConcatRejected, ConcatResp,
+            ContentSummaryRejected, ContentSummaryResp, ContentSummaryRespOk, ControlReq,
+            ControlResp, CorruptFile, CreateSnapshotRejected, CreateSnapshotResp,
+            DecommissionStoreRejected, DecommissionStoreResp, DeleteDirectoryResp, DeleteFileResp,
+            DeleteSnapshotRejected, DeleteSnapshotResp, FileStatus, ForceCloseRejected,
+            ForceCloseResp, FsckRejected, FsckResp, FsckRespOk, GetAdditionalStoreRejected,
+            GetAdditionalStoreResp, GetAdditionalStoreRespOk, GetBlockLocationsRejected,
+            GetBlockLocationsResp, GetBlockLocationsRespOk, GetFileChecksumRejected,
+            GetFileChecksumResp, GetFileChecksumRespOk, GetXattrRejected, GetXattrResp,
+            GetXattrRespOk, LastBlock, ListCorruptFilesResp, ListEntry, ListOpenFilesRejected,
+            ListOpenFilesResp, ListOpenFilesRespOk, ListRejected, ListResp, ListRespOk,
+            ListStoresResp, ListXattrsRejected, ListXattrsResp, ListXattrsRespOk, LocatedBlock,
+            MaintenanceRejected, MaintenanceResp, MetaSaveRejected, MetaSaveReq, MetaSaveResp,
+            MkdirRejected, MkdirResp, MkdirRespOk, OpenFileSummary, OpenLeaseResp, OpenRejected,
+            OpenResp, OpenRespOk, QuotaExceeded, RefreshStoresRejected, RefreshStoresReq,
+            RefreshStoresResp, RefreshStoresRespOk, RemoveXattrRejected, RemoveXattrResp,
+            RenameRejected, RenameResp, RenewLeasesResp, ReportBadBlockRejected,
+            ReportBadBlockResp, SafeModeAction, SafeModeRejected, SafeModeResp, SafeModeStatus,
+            SetQuotaRejected, SetQuotaResp, SetReplicationRejected, SetReplicationResp,
+            SetReplicationRespOk, SetTimesRejected, SetTimesResp, SetXattrRejected, SetXattrResp,
+            SnapshotDiffRejected, SnapshotDiffResp, SnapshotDiffRespOk, StatRejected, StatResp,
+            StoreReport, StoreSummary, SubscribeEventsRejected, SubscribeEventsResp,
+            UnresolvedPath,
+        },
+        store::{
+            HeartbeatRejected, HeartbeatReq, HeartbeatResp, HeartbeatRespOk, RegisterStoreRejected,
+            RegisterStoreReq, RegisterStoreResp, StoreCommand,
         },
     },
-    proto::control::{AllocBlockResp, AllocBlockRespOk, ControlReq, OpenLeaseResp, OpenResp},
-    store::StoreStatusesMap,
+    store::{
+        new_cluster_id, ClusterId, HostLists, StoreAdminState, StoreAdmission, StoreConfig,
+        StoreId, StoreStatusesMap, StoreUsage,
+    },
+};
+
+use super::{
+    config::ControlSettings,
+    events::EventLog,
+    metasave::MetaSave,
+    placement::{PlacementPolicy, PlacementPolicyKind},
+    report::PartialReports,
+    retry_cache::RetryCache,
+    safe_mode::SafeMode,
 };
 
-const OPEN_LEASE_TTL: Duration = Duration::from_secs(60);
-const REPLICATION: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(3) };
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const SAFE_MODE_THRESHOLD: f64 = 0.999;
+const DELETE_BATCH: usize = 1000;
+const MAX_PARTIAL_REPORTS: usize = 16;
+const RETRY_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+const MAX_LIST_ENTRIES: NonZeroUsize = NonZeroUsize::new(1000).unwrap();
+const EVENT_WINDOW: usize = 100_000;
+pub const TRASH_DIR: &str = ".trash";
+/// Segments in `/.trash/<user>/<millis>`, below which the original path
+/// resumes.
+const TRASH_DEPTH: usize = 3;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Handler {
     virt_fs: FsNode,
-    open_table: OpenFileTable,
-    store_statuses: StoreStatusesMap,
+    /// Locked on its own, so lease renewals need only the shared lock.
+    open_table: Mutex<OpenFileTable>,
+    /// Locked by heartbeats, which run under the shared handler lock.
+    stores: Mutex<StoreState>,
     replicated_blocks: ReplicatedBlocksMap,
+    block_ids: BlockIdGenerator,
+    inode_ids: InodeIdGenerator,
+    /// Where each inode of the live tree is, for the block map's owners.
+    inodes: InodeTable,
+    replication_queue: ReplicationQueue,
+    excess_replicas: ExcessReplicas,
+    edit_log: Option<EditLog>,
+    last_txid: u64,
+    events: EventLog,
+    lease_limits: LeaseLimits,
+    xattr_limits: XattrLimits,
+    path_limits: PathLimits,
+    min_replication: NonZeroUsize,
+    default_replication: NonZeroUsize,
+    heartbeat_interval: Duration,
+    store_dead_ttl: Duration,
+    max_block_size: u64,
+    block_map_shards: NonZeroUsize,
+    max_list_entries: NonZeroUsize,
+    safe_mode: SafeMode,
+    cluster_id: ClusterId,
+    placement: Box<dyn PlacementPolicy>,
+    partial_reports: PartialReports,
+    corrupt_replicas: CorruptReplicas,
+    retry_cache: RetryCache,
+    track_atime: bool,
+    superuser: UserId,
+    umask: u16,
+    trash_retention: Option<Duration>,
+    /// Stores named by the last [`Handler::refresh_stores`].
+    configured_stores: HashSet<StoreId>,
+    hosts: HostLists,
+    /// How many snapshots reference each block.
+    snapshot_blocks: HashMap<BlockId, usize>,
+    /// Blocks gone from the live tree that snapshots still hold.
+    deferred_blocks: HashSet<BlockId>,
+    metrics: Arc<Metrics>,
+    clock: Arc<dyn Clock>,
+    /// Found by [`Handler::validate`] after the last image load.
+    invariant_violations: usize,
+    allow_invariant_violations: bool,
 }
 impl Handler {
     pub fn new(
@@ -31,108 +159,4110 @@ impl Handler {
         open_table: OpenFileTable,
         store_statuses: StoreStatusesMap,
         replicated_blocks: ReplicatedBlocksMap,
+        block_ids: BlockIdGenerator,
+        settings: ControlSettings,
+        clock: Arc<dyn Clock>,
     ) -> Self {
+        let inodes = InodeTable::build(&virt_fs);
+        let inode_ids = InodeIdGenerator::from_high_water_mark(virt_fs.max_inode() + 1);
         Self {
             virt_fs,
-            open_table,
-            store_statuses,
+            open_table: Mutex::new(open_table),
+            stores: Mutex::new(StoreState {
+                statuses: store_statuses,
+                commands: HashMap::new(),
+                invalidate_queue: InvalidateQueue::new(),
+            }),
             replicated_blocks,
+            block_ids,
+            inode_ids,
+            inodes,
+            replication_queue: ReplicationQueue::new(),
+            excess_replicas: ExcessReplicas::new(),
+            edit_log: None,
+            last_txid: 0,
+            events: EventLog::new(EVENT_WINDOW),
+            lease_limits: settings.lease_limits,
+            xattr_limits: XattrLimits::default(),
+            path_limits: PathLimits::default(),
+            min_replication: NonZeroUsize::MIN,
+            default_replication: settings.default_replication,
+            heartbeat_interval: settings.heartbeat_interval,
+            store_dead_ttl: settings.store_dead_ttl,
+            max_block_size: settings.block_size,
+            block_map_shards: settings.block_map_shards,
+            max_list_entries: MAX_LIST_ENTRIES,
+            safe_mode: SafeMode::new(SAFE_MODE_THRESHOLD),
+            cluster_id: new_cluster_id(),
+            placement: PlacementPolicyKind::default().build(),
+            partial_reports: PartialReports::new(MAX_PARTIAL_REPORTS),
+            corrupt_replicas: CorruptReplicas::new(),
+            retry_cache: RetryCache::new(RETRY_CACHE_TTL),
+            track_atime: false,
+            superuser: DEFAULT_SUPERUSER.into(),
+            umask: DEFAULT_UMASK,
+            trash_retention: None,
+            configured_stores: HashSet::new(),
+            hosts: settings.hosts,
+            snapshot_blocks: HashMap::new(),
+            deferred_blocks: HashSet::new(),
+            metrics: Arc::new(Metrics::new()),
+            clock,
+            invariant_violations: 0,
+            allow_invariant_violations: false,
+        }
+    }
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+    /// Lets several nodes in one process share a registry.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+    /// Lets safe mode be left even though the loaded image failed
+    /// [`Handler::validate`].
+    pub fn set_allow_invariant_violations(&mut self, allow: bool) {
+        self.allow_invariant_violations = allow;
+    }
+    pub fn set_track_atime(&mut self, track_atime: bool) {
+        self.track_atime = track_atime;
+    }
+    /// Skips every permission check; ownership changes are limited to
+    /// the owner and this user.
+    pub fn set_superuser(&mut self, superuser: UserId) {
+        self.superuser = superuser;
+    }
+    pub fn set_umask(&mut self, umask: u16) {
+        self.umask = umask;
+    }
+    /// Deletes move nodes into `/.trash` until `retention` passes; `None`
+    /// deletes immediately.
+    pub fn set_trash_retention(&mut self, retention: Option<Duration>) {
+        self.trash_retention = retention;
+    }
+    pub fn set_retry_cache_ttl(&mut self, ttl: Duration) {
+        self.retry_cache.set_ttl(ttl);
+    }
+    pub fn set_placement_policy(&mut self, placement: Box<dyn PlacementPolicy>) {
+        self.placement = placement;
+    }
+    pub fn cluster_id(&self) -> &ClusterId {
+        &self.cluster_id
+    }
+    pub fn block_ids(&self) -> &BlockIdGenerator {
+        &self.block_ids
+    }
+    pub fn set_xattr_limits(&mut self, xattr_limits: XattrLimits) {
+        self.xattr_limits = xattr_limits;
+    }
+    pub fn set_path_limits(&mut self, path_limits: PathLimits) {
+        self.path_limits = path_limits;
+    }
+    pub fn set_min_replication(&mut self, min_replication: NonZeroUsize) {
+        self.min_replication = min_replication;
+    }
+    pub fn set_max_list_entries(&mut self, max: NonZeroUsize) {
+        self.max_list_entries = max;
+    }
+    /// Allocations of larger blocks are refused with the limit, which
+    /// writers then adopt.
+    pub fn set_max_block_size(&mut self, max: u64) {
+        self.max_block_size = max;
+    }
+    pub fn set_max_partial_reports(&mut self, max: usize) {
+        self.partial_reports.set_max(max);
+    }
+    pub fn safe_mode(&self) -> &SafeMode {
+        &self.safe_mode
+    }
+    pub fn set_safe_mode_threshold(&mut self, threshold: f64) {
+        self.safe_mode.set_threshold(threshold);
+    }
+    pub fn set_edit_log(&mut self, edit_log: EditLog) {
+        self.edit_log = Some(edit_log);
+    }
+    /// How many namespace events stay around for subscribers that fall
+    /// behind.
+    pub fn set_event_window(&mut self, capacity: usize) {
+        self.events.set_capacity(capacity);
+    }
+    pub fn event_notify(&self) -> &Arc<Notify> {
+        self.events.notify()
+    }
+    pub async fn save_image(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let image = FsImage::new(
+            self.cluster_id.clone(),
+            self.virt_fs.clone(),
+            self.block_ids.high_water_mark(),
+            self.inode_ids.high_water_mark(),
+            self.last_txid,
+        );
+        atomic_persist_with(path, move |w| image.write_to(w)).await
+    }
+    /// Copies the state to dump while borrowed; the returned future writes it
+    /// without holding on to the handler.
+    pub fn metasave(
+        &self,
+        caller: &Caller,
+        req: MetaSaveReq,
+    ) -> impl Future<Output = MetaSaveResp> + 'static {
+        let view = self.is_superuser(caller).then(|| {
+            let state = self.lock_stores();
+            let stores = state
+                .statuses
+                .iter()
+                .map(|(store, _)| {
+                    let commands = state.commands.get(store).map_or(0, |c| c.len());
+                    let invalidations = state.invalidate_queue.len_of(store);
+                    (store.clone(), commands, invalidations)
+                })
+                .collect();
+            MetaSave::new(
+                self.virt_fs.clone(),
+                self.inodes.clone(),
+                self.replicated_blocks.clone(),
+                self.replication_queue.clone(),
+                state.invalidate_queue.clone(),
+                stores,
+                self.lock_open_table().clone(),
+                self.clock.now(),
+            )
+        });
+        async move {
+            let Some(view) = view else {
+                return MetaSaveResp::Rejected(MetaSaveRejected::PermissionDenied);
+            };
+            match atomic_persist_with(req.path, move |w| view.write_to(w)).await {
+                Ok(()) => MetaSaveResp::Ok,
+                Err(e) => MetaSaveResp::Rejected(MetaSaveRejected::Io(e.to_string())),
+            }
+        }
+    }
+    pub async fn load_image(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let buf = tokio::fs::read(path).await?;
+        let image = FsImage::decode(&buf)?;
+        self.cluster_id = image.cluster_id().clone();
+        self.block_ids = BlockIdGenerator::from_high_water_mark(image.next_block_id());
+        self.last_txid = image.last_txid();
+        self.events.reset(self.last_txid);
+        self.inode_ids = InodeIdGenerator::from_high_water_mark(image.next_inode());
+        self.virt_fs = image.into_root();
+        self.inode_ids.observe(self.virt_fs.max_inode());
+        self.inodes = InodeTable::build(&self.virt_fs);
+        let cached = quota_usages(&self.virt_fs);
+        self.virt_fs.refresh_quota_usage();
+        for (origin, usage) in self.trash_charges(&PathSplit::from_uri(TRASH_DIR)) {
+            self.charge(&origin, |quota| quota + usage);
+        }
+        let stale = cached
+            .into_iter()
+            .zip(quota_usages(&self.virt_fs))
+            .filter(|(cached, usage)| cached != usage)
+            .count();
+        if stale != 0 {
+            warn!("corrected cached usage of {stale} quota directories");
+        }
+        self.rebuild_block_map();
+        let violations = self.validate();
+        for violation in &violations {
+            warn!("namespace invariant violated: {violation}");
+        }
+        self.invariant_violations = violations.len();
+        if !violations.is_empty() && !self.allow_invariant_violations {
+            self.safe_mode.enter();
+        }
+        Ok(())
+    }
+    /// [`FsNode::validate`] on the namespace, crediting each quota with what
+    /// its trashed descendants still charge it.
+    pub fn validate(&self) -> Vec<InvariantViolation> {
+        let mut trashed: HashMap<PathSplit, Usage> = HashMap::new();
+        for (origin, usage) in self.trash_charges(&PathSplit::from_uri(TRASH_DIR)) {
+            for len in 1..origin.segs().len() {
+                let charged = trashed.entry(origin.prefix(len)).or_default();
+                *charged = *charged + usage;
+            }
+        }
+        let mut violations = self.virt_fs.validate(&self.replicated_blocks);
+        violations.retain(|violation| {
+            let InvariantViolation::QuotaUsage {
+                path,
+                cached,
+                actual,
+            } = violation
+            else {
+                return true;
+            };
+            let charged = trashed.get(&PathSplit::from_uri(path));
+            *cached != *actual + charged.copied().unwrap_or_default()
+        });
+        violations
+    }
+    pub fn replay_edits(&mut self, entries: impl IntoIterator<Item = EditEntry>) {
+        for entry in entries {
+            if entry.txid() <= self.last_txid {
+                continue;
+            }
+            if self.apply_edit(entry.record(), entry.time()).is_ok() {
+                self.events.record(entry.txid(), entry.record());
+            }
+            self.last_txid = entry.txid();
+        }
+    }
+    pub async fn checkpoint(&mut self, image_path: impl AsRef<Path>) -> io::Result<()> {
+        if let Some(edit_log) = &mut self.edit_log {
+            edit_log.sync(self.clock.now())?;
+        }
+        self.save_image(image_path).await?;
+        if let Some(edit_log) = &mut self.edit_log {
+            edit_log.truncate(self.clock.now())?;
+        }
+        Ok(())
+    }
+    /// Runs the periodic upkeep; an edit log that fails to sync puts the node
+    /// in safe mode and is returned once the rest of the tick has run.
+    pub fn handle_timer(&mut self) -> io::Result<()> {
+        let now = self.clock.now();
+        let expired = self
+            .open_table
+            .get_mut()
+            .unwrap()
+            .clear_timeout(self.lease_limits.hard, now);
+        let mut recovered = Ok(());
+        for lease in expired {
+            warn!(path = %lease.path, client = %lease.client, write = lease.write, "lease expired");
+            self.metrics.inc("dfs_control_lease_expirations_total", &[]);
+            if lease.write {
+                // a failed append has already put the node in safe mode
+                if let Err(EditApplyError::Log(e)) = self.recover_lease(&lease.path) {
+                    recovered = recovered.and(Err(e));
+                }
+            }
+        }
+        let mut res = match &mut self.edit_log {
+            Some(edit_log) => recovered.and(edit_log.sync_if_due(now)),
+            None => recovered,
+        };
+        if let Err(e) = &res {
+            self.edit_log_failed(e);
+        }
+        self.sweep_dead_stores(now);
+        self.retry_cache.clear_timeout(now);
+        self.check_safe_mode();
+        if !self.safe_mode.is_on() {
+            if let Err(e) = self.purge_trash() {
+                res = res.and(Err(e));
+            }
+            self.schedule_replication(now);
+            self.remove_excess_replicas(now);
+            self.release_corrupt_replicas();
+            self.update_decommissioning();
+        }
+        self.update_gauges(now);
+        res
+    }
+
+    fn update_gauges(&self, now: Instant) {
+        let metrics = &self.metrics;
+        metrics.set(
+            "dfs_control_open_files",
+            &[],
+            self.lock_open_table().len() as f64,
+        );
+        let queued = self.replication_queue.queued_len();
+        let pending = self.replication_queue.pending_len();
+        // the queue is ordered by live replicas, so blocks with none come first
+        let missing = self
+            .replication_queue
+            .iter_queued()
+            .take_while(|(live, _)| *live == 0)
+            .count();
+        metrics.set(
+            "dfs_control_blocks",
+            &[],
+            self.replicated_blocks.len() as f64,
+        );
+        metrics.set(
+            "dfs_control_under_replicated_blocks",
+            &[],
+            (queued + pending) as f64,
+        );
+        metrics.set("dfs_control_missing_blocks", &[], missing as f64);
+        metrics.set(
+            "dfs_control_corrupt_blocks",
+            &[],
+            self.corrupt_replicas.blocks().count() as f64,
+        );
+        metrics.set(
+            "dfs_control_replication_queue",
+            &[("state", "queued")],
+            queued as f64,
+        );
+        metrics.set(
+            "dfs_control_replication_queue",
+            &[("state", "pending")],
+            pending as f64,
+        );
+        let stores = self.lock_stores();
+        metrics.set(
+            "dfs_control_invalidation_queue",
+            &[],
+            stores.invalidate_queue.len() as f64,
+        );
+        metrics.set(
+            "dfs_control_safe_mode",
+            &[],
+            if self.safe_mode.is_on() { 1.0 } else { 0.0 },
+        );
+        metrics.clear("dfs_control_store_heartbeat_age_seconds");
+        for (store, status) in stores.statuses.iter() {
+            if let Some(last) = status.last_heartbeat() {
+                let age = now.duration_since(last).as_secs_f64();
+                metrics.set(
+                    "dfs_control_store_heartbeat_age_seconds",
+                    &[("store", store)],
+                    age,
+                );
+            }
+        }
+    }
+    pub fn handle_register(&mut self, req: RegisterStoreReq) -> RegisterStoreResp {
+        if req.cluster_id.is_some_and(|id| id != self.cluster_id) {
+            return RegisterStoreResp::Rejected(RegisterStoreRejected::ClusterMismatch {
+                expected: self.cluster_id.clone(),
+            });
+        }
+        let admission = self.hosts.admission(&req.addr);
+        if admission == StoreAdmission::NotIncluded {
+            return RegisterStoreResp::Rejected(RegisterStoreRejected::NotIncluded);
+        }
+        let statuses = &mut self.stores.get_mut().unwrap().statuses;
+        statuses.upsert(req.store.clone(), StoreConfig::new(req.addr, req.rack));
+
+        let status = statuses.get_mut(&req.store).unwrap();
+        status.set_usage(StoreUsage {
+            capacity: req.capacity,
+            remaining: req.capacity,
+            ..Default::default()
+        });
+        if admission == StoreAdmission::Excluded {
+            self.decommission(&req.store);
+        }
+        RegisterStoreResp::Ok {
+            cluster_id: self.cluster_id.clone(),
+        }
+    }
+    /// Takes `&self` so heartbeats only hold the handler lock shared.
+    pub fn handle_heartbeat(&self, req: HeartbeatReq) -> HeartbeatResp {
+        let now = self.clock.now();
+        let mut state = self.lock_stores();
+        let state = &mut *state;
+        let Some(status) = state.statuses.get_mut(&req.store) else {
+            return HeartbeatResp::Rejected(HeartbeatRejected::Unregistered);
+        };
+        if self.hosts.admission(status.config().addr()) == StoreAdmission::NotIncluded {
+            return HeartbeatResp::Rejected(HeartbeatRejected::NotIncluded);
+        }
+        status.beat(now);
+        status.set_usage(StoreUsage {
+            capacity: req.capacity,
+            used: req.used,
+            remaining: req.remaining,
+            block_count: req.block_count,
+            failed_volumes: req.failed_volumes,
+        });
+        let full_report = status.awaiting_full_report();
+        let mut commands = state.commands.remove(&req.store).unwrap_or_default();
+        let delete = state.invalidate_queue.drain(&req.store, DELETE_BATCH);
+        if !delete.is_empty() {
+            commands.push(StoreCommand::DeleteBlocks(delete));
+        }
+        HeartbeatResp::Ok(HeartbeatRespOk {
+            commands,
+            full_report,
+        })
+    }
+    /// Diffs `stores` against the previous list. Stores dropped from it, or
+    /// now matching an excluded host, are decommissioned rather than
+    /// forgotten so their blocks are copied off first.
+    pub fn refresh_stores(
+        &mut self,
+        stores: &[StoreConfig],
+        hosts: HostLists,
+    ) -> RefreshStoresRespOk {
+        self.hosts = hosts;
+        let mut resp = RefreshStoresRespOk::default();
+        let configured: HashSet<StoreId> = stores.iter().map(|config| config.id()).collect();
+        let statuses = &mut self.stores.get_mut().unwrap().statuses;
+        for config in stores {
+            let store = config.id();
+            match statuses.get_mut(&store) {
+                None => {
+                    statuses.insert(store.clone(), config.clone());
+                    resp.added.push(store);
+                }
+                Some(status)
+                    if status.config().addr() != config.addr()
+                        || status.config().rack() != config.rack() =>
+                {
+                    status.set_config(config.clone());
+                    resp.changed.push(store);
+                }
+                Some(_) => (),
+            }
+        }
+        let previous = mem::replace(&mut self.configured_stores, configured);
+        for store in previous {
+            if self.configured_stores.contains(&store) {
+                continue;
+            }
+            if self
+                .stores
+                .get_mut()
+                .unwrap()
+                .statuses
+                .get(&store)
+                .is_some()
+            {
+                self.decommission(&store);
+            }
+            resp.removed.push(store);
+        }
+        let excluded: Vec<StoreId> = self
+            .stores
+            .get_mut()
+            .unwrap()
+            .statuses
+            .iter()
+            .filter(|(_, status)| status.admin_state() == StoreAdminState::Normal)
+            .filter(|(_, status)| {
+                self.hosts.admission(status.config().addr()) == StoreAdmission::Excluded
+            })
+            .map(|(store, _)| store.clone())
+            .collect();
+        for store in &excluded {
+            self.decommission(store);
+        }
+        resp.excluded = excluded;
+        resp
+    }
+    fn fsck_file(&self, file: &File, summary: &mut FsckSummary) -> Vec<FsckProblem> {
+        let mut problems = vec![];
+        let target = file.attr().replication().get();
+        let mut expected = 0;
+        summary.files += 1;
+        for block in file.blocks() {
+            let id = block.id();
+            summary.blocks += 1;
+            let (start, end) = block.off_range();
+            if start != expected {
+                summary.non_contiguous += 1;
+                problems.push(FsckProblem::NonContiguous {
+                    block: id.clone(),
+                    expected,
+                    start,
+                });
+            }
+            expected = end;
+            if !self.replicated_blocks.contains(id) {
+                summary.unmapped += 1;
+                problems.push(FsckProblem::Unmapped(id.clone()));
+                continue;
+            }
+            let live = self.compliant_replicas(id);
+            if self.replicated_blocks.stores(id).is_empty() {
+                if self.corrupt_replicas.get(id).is_empty() {
+                    summary.missing += 1;
+                    problems.push(FsckProblem::Missing(id.clone()));
+                } else {
+                    summary.corrupt += 1;
+                    problems.push(FsckProblem::Corrupt(id.clone()));
+                }
+            } else if live < target {
+                summary.under_replicated += 1;
+                problems.push(FsckProblem::UnderReplicated {
+                    block: id.clone(),
+                    live,
+                    target,
+                });
+            } else {
+                summary.healthy += 1;
+            }
+        }
+        problems
+    }
+    /// Blocks whose file no longer lists them and that no snapshot holds,
+    /// among those owned by a file under `path`; blocks whose file is gone
+    /// count only under the root.
+    fn orphaned_blocks(&self, path: &PathSplit) -> Vec<BlockId> {
+        let mut orphans: Vec<BlockId> = vec![];
+        self.replicated_blocks.visit(&mut |id, block| {
+            if self.snapshot_blocks.contains_key(id) {
+                return;
+            }
+            let orphaned = match self.inodes.path(block.inode()) {
+                None => path.segs().is_empty(),
+                Some(owner) if !owner.starts_with(path) => false,
+                Some(owner) => {
+                    let node = self.virt_fs.get_by_segs(owner.names());
+                    !matches!(
+                        node.map(|node| node.body()),
+                        Some(FsNodeBody::File(file)) if file.blocks().iter().any(|b| b.id() == id)
+                    )
+                }
+            };
+            if orphaned {
+                orphans.push(id.clone());
+            }
+        });
+        orphans.sort();
+        orphans
+    }
+    fn safe_mode_status(&self) -> SafeModeStatus {
+        SafeModeStatus {
+            on: self.safe_mode.is_on(),
+            manual: self.safe_mode.is_manual(),
+            threshold: self.safe_mode.threshold(),
+            reported_blocks: self.replicated_blocks.reported_len(),
+            total_blocks: self.replicated_blocks.len(),
+            invariant_violations: self.invariant_violations,
+        }
+    }
+    fn decommission(&mut self, store: &StoreId) {
+        let status = self
+            .stores
+            .get_mut()
+            .unwrap()
+            .statuses
+            .get_mut(store)
+            .unwrap();
+        if status.admin_state() != StoreAdminState::Normal {
+            return;
+        }
+        status.set_admin_state(StoreAdminState::Decommissioning);
+        let blocks = self.replicated_blocks.blocks_on(store);
+        for block in blocks {
+            self.update_replication(block);
         }
     }
-    pub fn handle_timer(&mut self) {
-        let now = Instant::now();
-        self.open_table.clear_timeout(OPEN_LEASE_TTL, now);
-        todo!()
+    pub fn push_store_command(&mut self, store: StoreId, command: StoreCommand) {
+        self.stores
+            .get_mut()
+            .unwrap()
+            .commands
+            .entry(store)
+            .or_default()
+            .push(command);
     }
-    pub fn handle_req(&mut self, msg: ControlReq) -> Resp {
-        let now = Instant::now();
+    pub fn handle_req(&mut self, caller: &Caller, msg: ControlReq) -> ControlResp {
+        let msg = match self.handle_read_req(caller, msg) {
+            Ok(resp) => return resp,
+            Err(msg) => msg,
+        };
+        let now = self.clock.now();
+        // every request that logs an edit is one safe mode refuses, so an edit
+        // log failure, which enters safe mode, is answered the same way
+        let if_log_fails = safe_mode_rejection(&msg);
+        // validated once here so the arms below can split paths infallibly
+        for path in request_paths(&msg) {
+            let path = match PathSplit::parse(path) {
+                Ok(path) => path,
+                Err(e) => return ControlResp::InvalidPath(e),
+            };
+            // whatever safe mode would refuse is a write
+            if path.is_in_snapshot() && safe_mode_rejection(&msg).is_some() {
+                return ControlResp::InvalidPath(PathParseError::InSnapshot);
+            }
+        }
+        if self.safe_mode.is_on() {
+            if let Some(resp) = safe_mode_rejection(&msg) {
+                return resp;
+            }
+        }
         match msg {
+            ControlReq::DecommissionStoreReq(decommission_store_req) => {
+                let store = decommission_store_req.store;
+                let reject =
+                    |r| ControlResp::DecommissionStoreResp(DecommissionStoreResp::Rejected(r));
+                if !self.is_superuser(caller) {
+                    return reject(DecommissionStoreRejected::PermissionDenied);
+                }
+                if self
+                    .stores
+                    .get_mut()
+                    .unwrap()
+                    .statuses
+                    .get(&store)
+                    .is_none()
+                {
+                    return reject(DecommissionStoreRejected::NotFound);
+                }
+                self.decommission(&store);
+                ControlResp::DecommissionStoreResp(DecommissionStoreResp::Ok)
+            }
+            // The control server calls `metasave` instead so the dump is
+            // written after the lock is released.
+            ControlReq::MetaSaveReq(_) => {
+                ControlResp::MetaSaveResp(MetaSaveResp::Rejected(MetaSaveRejected::Unsupported))
+            }
+            ControlReq::RefreshStoresReq(refresh_stores_req) => {
+                if !self.is_superuser(caller) {
+                    return ControlResp::RefreshStoresResp(RefreshStoresResp::Rejected(
+                        RefreshStoresRejected::PermissionDenied,
+                    ));
+                }
+                let RefreshStoresReq { stores, hosts } = refresh_stores_req;
+                let ok = self.refresh_stores(&stores, hosts);
+                ControlResp::RefreshStoresResp(RefreshStoresResp::Ok(ok))
+            }
+            ControlReq::EnterMaintenanceReq(enter_maintenance_req) => {
+                let reject = |r| ControlResp::MaintenanceResp(MaintenanceResp::Rejected(r));
+                if !self.is_superuser(caller) {
+                    return reject(MaintenanceRejected::PermissionDenied);
+                }
+                let Some(status) = self
+                    .stores
+                    .get_mut()
+                    .unwrap()
+                    .statuses
+                    .get_mut(&enter_maintenance_req.store)
+                else {
+                    return reject(MaintenanceRejected::NotFound);
+                };
+                let remaining = enter_maintenance_req
+                    .until
+                    .duration_since(self.clock.system_now())
+                    .unwrap_or_default();
+                status.enter_maintenance(now + remaining);
+                ControlResp::MaintenanceResp(MaintenanceResp::Ok)
+            }
+            ControlReq::ExitMaintenanceReq(exit_maintenance_req) => {
+                let reject = |r| ControlResp::MaintenanceResp(MaintenanceResp::Rejected(r));
+                if !self.is_superuser(caller) {
+                    return reject(MaintenanceRejected::PermissionDenied);
+                }
+                let Some(status) = self
+                    .stores
+                    .get_mut()
+                    .unwrap()
+                    .statuses
+                    .get_mut(&exit_maintenance_req.store)
+                else {
+                    return reject(MaintenanceRejected::NotFound);
+                };
+                status.exit_maintenance();
+                ControlResp::MaintenanceResp(MaintenanceResp::Ok)
+            }
+            ControlReq::ReportBadBlockReq(report_bad_block_req) => {
+                let block = report_bad_block_req.block;
+                let reject = |r| ControlResp::ReportBadBlockResp(ReportBadBlockResp::Rejected(r));
+                if !self.replicated_blocks.contains(&block) {
+                    return reject(ReportBadBlockRejected::BlockNotExist);
+                }
+                let Some(store) = self
+                    .stores
+                    .get_mut()
+                    .unwrap()
+                    .statuses
+                    .iter()
+                    .find(|(_, status)| *status.config().addr() == report_bad_block_req.store_addr)
+                    .map(|(store, _)| store.clone())
+                else {
+                    return reject(ReportBadBlockRejected::StoreNotExist);
+                };
+                warn!(%block, %store, "client reported a corrupt replica");
+                self.replicated_blocks.remove_store(&block, &store);
+                self.excess_replicas.remove(&block, &store);
+                self.corrupt_replicas.insert(
+                    block.clone(),
+                    store,
+                    CorruptReason::ClientReported,
+                    now,
+                );
+                self.update_replication(block);
+                ControlResp::ReportBadBlockResp(ReportBadBlockResp::Ok)
+            }
+            ControlReq::FsckReq(fsck_req) => {
+                let path = PathSplit::from_uri(&fsck_req.path);
+                let reject = |r| ControlResp::FsckResp(FsckResp::Rejected(r));
+                if fsck_req.delete_orphans && !self.is_superuser(caller) {
+                    return reject(FsckRejected::PermissionDenied);
+                }
+                let start_after = fsck_req.start_after.as_deref().map(PathSplit::from_uri);
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(FsckRejected::NotFound);
+                };
+                let mut summary = FsckSummary::default();
+                let mut files = vec![];
+                let mut checked = 0;
+                let mut next = None;
+                let open_table = self.lock_open_table();
+                node.visit_files(&path, &mut |file_path, file| {
+                    if next.is_some()
+                        || start_after
+                            .as_ref()
+                            .is_some_and(|start| file_path.segs() <= start.segs())
+                    {
+                        return;
+                    }
+                    // blocks still being written are not reported yet
+                    if open_table.get(file_path).is_some_and(|attr| attr.write()) {
+                        return;
+                    }
+                    let problems = self.fsck_file(file, &mut summary);
+                    if fsck_req.list_corrupt && !problems.is_empty() {
+                        files.push(FsckFile {
+                            path: file_path.to_uri(),
+                            problems,
+                        });
+                    }
+                    checked += 1;
+                    if checked == self.max_list_entries.get() {
+                        next = Some(file_path.to_uri());
+                    }
+                });
+                drop(open_table);
+                // the block map is only walked once per run
+                let mut orphans = vec![];
+                if start_after.is_none() {
+                    orphans = self.orphaned_blocks(&path);
+                    summary.orphaned = orphans.len() as u64;
+                    if fsck_req.delete_orphans {
+                        for block in &orphans {
+                            self.invalidate_block(block);
+                        }
+                    }
+                }
+                ControlResp::FsckResp(FsckResp::Ok(FsckRespOk {
+                    summary,
+                    files,
+                    orphans,
+                    next,
+                }))
+            }
+            ControlReq::SafeModeReq(safe_mode_req) => {
+                let manual = !matches!(safe_mode_req.action, SafeModeAction::Get);
+                if manual && !self.is_superuser(caller) {
+                    return ControlResp::SafeModeResp(SafeModeResp::Rejected(
+                        SafeModeRejected::PermissionDenied,
+                    ));
+                }
+                match safe_mode_req.action {
+                    SafeModeAction::Get => (),
+                    SafeModeAction::Enter => self.safe_mode.enter(),
+                    SafeModeAction::Leave
+                        if self.invariant_violations != 0 && !self.allow_invariant_violations => {}
+                    SafeModeAction::Leave => self.safe_mode.leave(),
+                }
+                ControlResp::SafeModeResp(SafeModeResp::Ok(self.safe_mode_status()))
+            }
             ControlReq::OpenReq(open_req) => {
                 let path = PathSplit::from_uri(&open_req.path);
                 let path_cursor = PathCursor::new(path.clone());
+                let reject = |r| ControlResp::OpenResp(OpenResp::Rejected(r));
+                let modifiers =
+                    open_req.create || open_req.append || open_req.overwrite || open_req.exclusive;
+                if modifiers && !open_req.write {
+                    return reject(OpenRejected::InvalidMode);
+                }
+                if open_req.append && (open_req.overwrite || open_req.exclusive) {
+                    return reject(OpenRejected::InvalidMode);
+                }
+                // exclusive only qualifies a create and contradicts overwrite
+                if open_req.exclusive && (!open_req.create || open_req.overwrite) {
+                    return reject(OpenRejected::InvalidMode);
+                }
+                let exists = self.virt_fs.get_by_segs(path.names()).is_some();
+                let permitted = match (open_req.write, exists) {
+                    (false, _) => self.permits(caller, &path, READ),
+                    (true, true) => self.permits(caller, &path, WRITE),
+                    (true, false) => self.permits(caller, &parent_dir(&path), WRITE | EXECUTE),
+                };
+                if !permitted {
+                    return reject(OpenRejected::PermissionDenied);
+                }
+                let mut truncate = false;
                 if open_req.write {
-                    let Some(path_cursor) = path_cursor else {
-                        return Resp::OpenResp(OpenResp {});
-                    };
-                    let res = self.virt_fs.create_node(path_cursor, || {
-                        FsNode::new(
-                            FsNodeAttribute::new(),
-                            FsNodeBody::File(File::new(FileAttribute::new(REPLICATION))),
-                        )
-                    });
-                    match res {
-                        Ok(_) => (),
-                        Err(e) => match e {
-                            FsNodeCreateFileError::FileExist(_) => (),
-                            FsNodeCreateFileError::DirectoryNotExist(_) => {
-                                return Resp::OpenResp(OpenResp {});
+                    if path_cursor.is_none() {
+                        return reject(OpenRejected::InvalidPath);
+                    }
+                    match self.virt_fs.get(path_cursor).map(|node| node.body()) {
+                        Ok(FsNodeBody::File(_)) => {
+                            if open_req.exclusive || !(open_req.append || open_req.overwrite) {
+                                return reject(OpenRejected::FileExists);
+                            }
+                            truncate = open_req.overwrite;
+                        }
+                        Ok(FsNodeBody::Directory(_)) => return reject(OpenRejected::IsDirectory),
+                        Err(FsNodeQueryError::FileNotExist(e)) if !open_req.create => {
+                            return reject(OpenRejected::NotFound(unresolved(&e.path)));
+                        }
+                        Err(FsNodeQueryError::DirectoryNotExist(e)) if !open_req.create => {
+                            return reject(OpenRejected::ParentMissing(unresolved(&e.path)));
+                        }
+                        Err(_) => {
+                            if let Err(e) = self.path_limits.check(&path) {
+                                return reject(OpenRejected::PathLimit(e));
+                            }
+                            let delta = Usage { nodes: 1, space: 0 };
+                            if let Err(e) = self.check_quota(&path, 0, delta) {
+                                return reject(OpenRejected::QuotaExceeded(e));
                             }
-                        },
+                            let res = self.log_and_apply(EditRecord::CreateFile {
+                                path: path.clone(),
+                                replication: self.default_replication,
+                                perm: self.new_perm(caller, 0o666),
+                            });
+                            match res {
+                                Ok(()) => (),
+                                Err(EditApplyError::CreateFile(e)) => {
+                                    return reject(OpenRejected::ParentMissing(unresolved(
+                                        e.path(),
+                                    )));
+                                }
+                                Err(e @ EditApplyError::Log(_)) => {
+                                    return edit_failed(e, if_log_fails);
+                                }
+                                Err(_) => return reject(OpenRejected::InvalidPath),
+                            }
+                        }
                     }
                 } else {
-                    let Ok(node) = self.virt_fs.get(path_cursor) else {
-                        return Resp::OpenResp(OpenResp {});
+                    let node = match self.virt_fs.get(path_cursor) {
+                        Ok(node) => node,
+                        Err(FsNodeQueryError::FileNotExist(e)) => {
+                            return reject(OpenRejected::NotFound(unresolved(&e.path)));
+                        }
+                        Err(FsNodeQueryError::DirectoryNotExist(e)) => {
+                            return reject(OpenRejected::ParentMissing(unresolved(&e.path)));
+                        }
                     };
                     let FsNodeBody::File(_) = node.body() else {
-                        return Resp::OpenResp(OpenResp {});
+                        return reject(OpenRejected::IsDirectory);
                     };
+                    // every read becomes an edit, so this is opt-in
+                    if self.track_atime && !self.safe_mode.is_on() && !path.is_in_snapshot() {
+                        // a lost atime is no reason to refuse the read, and the
+                        // failure has already put the node in safe mode
+                        let _ = self.log_and_apply(EditRecord::SetTimes {
+                            path: path.clone(),
+                            mtime: None,
+                            atime: Some(self.clock.system_now()),
+                        });
+                    }
                 }
-                let res = self.open_table.open(path, open_req.write, now);
+                let res = self.open_table.get_mut().unwrap().open(
+                    path.clone(),
+                    open_req.client.clone(),
+                    open_req.write,
+                    now,
+                    self.lease_limits.soft,
+                );
                 match res {
-                    Ok(_) => Resp::None,
-                    Err(_) => Resp::OpenResp(OpenResp {}),
+                    Ok(taken_over) => {
+                        if taken_over.is_some() {
+                            if let Err(e) = self.recover_lease(&path) {
+                                self.open_table
+                                    .get_mut()
+                                    .unwrap()
+                                    .close(&path, &open_req.client)
+                                    .unwrap();
+                                return edit_failed(e, if_log_fails);
+                            }
+                        }
+                        // only now that the lease is ours, so an open refused
+                        // for a conflict leaves the file as it was
+                        let edit = if truncate {
+                            Some(EditRecord::Truncate { path: path.clone() })
+                        } else if open_req.append {
+                            Some(EditRecord::Reopen { path: path.clone() })
+                        } else {
+                            None
+                        };
+                        if let Some(edit) = edit {
+                            if let Err(e) = self.log_and_apply(edit) {
+                                // refused, so the lease must not outlive the request
+                                self.open_table
+                                    .get_mut()
+                                    .unwrap()
+                                    .close(&path, &open_req.client)
+                                    .unwrap();
+                                return edit_failed(e, if_log_fails);
+                            }
+                        }
+                        let node = self.virt_fs.get_mut(PathCursor::new(path)).unwrap();
+                        let FsNodeBody::File(file) = node.body_mut() else {
+                            unreachable!();
+                        };
+                        if open_req.write {
+                            file.set_under_construction(true);
+                        }
+                        let last_block = file.blocks().last().map(|block| {
+                            let (start, end) = block.off_range();
+                            LastBlock {
+                                block: block.id().clone(),
+                                generation: block.generation(),
+                                size: end - start,
+                            }
+                        });
+                        ControlResp::OpenResp(OpenResp::Ok(OpenRespOk {
+                            len: file.len(),
+                            finalized: file.attr().is_finalized(),
+                            last_block,
+                        }))
+                    }
+                    Err(e) => reject(OpenRejected::Conflict {
+                        held_for_write: e.held_for_write,
+                    }),
                 }
             }
             ControlReq::OpenLeaseReq(open_lease_req) => {
                 let path = PathSplit::from_uri(&open_lease_req.path);
-                let res = self.open_table.lease(&path, now);
+                let res =
+                    self.open_table
+                        .get_mut()
+                        .unwrap()
+                        .lease(&path, &open_lease_req.client, now);
                 match res {
-                    Ok(_) => Resp::OpenLeaseResp(OpenLeaseResp { permitted: true }),
-                    Err(_) => Resp::OpenLeaseResp(OpenLeaseResp { permitted: false }),
+                    Ok(_) => ControlResp::OpenLeaseResp(OpenLeaseResp { permitted: true }),
+                    Err(_) => ControlResp::OpenLeaseResp(OpenLeaseResp { permitted: false }),
                 }
             }
             ControlReq::CloseReq(close_req) => {
                 let path = PathSplit::from_uri(&close_req.path);
-                self.open_table.close(&path);
-                Resp::None
+                let write = self
+                    .open_table
+                    .get_mut()
+                    .unwrap()
+                    .get(&path)
+                    .is_some_and(|attr| attr.write());
+                if self
+                    .open_table
+                    .get_mut()
+                    .unwrap()
+                    .close(&path, &close_req.client)
+                    .is_err()
+                {
+                    return ControlResp::CloseResp(CloseResp { permitted: false });
+                }
+                let is_file = self
+                    .virt_fs
+                    .get_by_segs(path.names())
+                    .is_some_and(|node| matches!(node.body(), FsNodeBody::File(_)));
+                if write && is_file && !self.open_table.get_mut().unwrap().is_open(&path) {
+                    if let Err(e) = self.log_and_apply(EditRecord::CloseFile { path }) {
+                        return edit_failed(e, None);
+                    }
+                }
+                ControlResp::CloseResp(CloseResp { permitted: true })
+            }
+            ControlReq::AbandonBlockReq(abandon_block_req) => {
+                let path = PathSplit::from_uri(&abandon_block_req.path);
+                let reject = |r| ControlResp::AbandonBlockResp(AbandonBlockResp::Rejected(r));
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(AbandonBlockRejected::FileNotExist);
+                };
+                let FsNodeBody::File(file) = node.body() else {
+                    return reject(AbandonBlockRejected::NotFile);
+                };
+                let has_lease = self
+                    .open_table
+                    .get_mut()
+                    .unwrap()
+                    .get(&path)
+                    .is_some_and(|attr| attr.write() && attr.is_held_by(&abandon_block_req.client));
+                if !has_lease {
+                    return reject(AbandonBlockRejected::NoLease);
+                }
+                let is_last = file
+                    .blocks()
+                    .last()
+                    .is_some_and(|last| *last.id() == abandon_block_req.block);
+                if !is_last {
+                    return reject(AbandonBlockRejected::NotLastBlock);
+                }
+                if !self
+                    .replicated_blocks
+                    .stores(&abandon_block_req.block)
+                    .is_empty()
+                {
+                    return reject(AbandonBlockRejected::AlreadyReported);
+                }
+                let res = self.log_and_apply(EditRecord::AbandonBlock {
+                    path,
+                    block: abandon_block_req.block,
+                });
+                if let Err(e) = res {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::AbandonBlockResp(AbandonBlockResp::Ok)
+            }
+            ControlReq::GetAdditionalStoreReq(get_additional_store_req) => {
+                let path = PathSplit::from_uri(&get_additional_store_req.path);
+                let block = get_additional_store_req.block;
+                let existing = get_additional_store_req.existing;
+                let excluded = get_additional_store_req.excluded;
+                let reject =
+                    |r| ControlResp::GetAdditionalStoreResp(GetAdditionalStoreResp::Rejected(r));
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(GetAdditionalStoreRejected::FileNotExist);
+                };
+                let FsNodeBody::File(file) = node.body() else {
+                    return reject(GetAdditionalStoreRejected::NotFile);
+                };
+                let has_lease = self
+                    .open_table
+                    .get_mut()
+                    .unwrap()
+                    .get(&path)
+                    .is_some_and(|attr| {
+                        attr.write() && attr.is_held_by(&get_additional_store_req.client)
+                    });
+                if !has_lease {
+                    return reject(GetAdditionalStoreRejected::NoLease);
+                }
+                let is_last = file.blocks().last().is_some_and(|last| *last.id() == block);
+                if !is_last {
+                    return reject(GetAdditionalStoreRejected::NotLastBlock);
+                }
+                let exclude: Vec<StoreId> = existing.iter().chain(&excluded).cloned().collect();
+                let Some(store) = self.select_stores(1, &exclude, now).into_iter().next() else {
+                    return reject(GetAdditionalStoreRejected::NoStore);
+                };
+                let previous = self.replicated_blocks.stores(&block).to_vec();
+                let res = self.log_and_apply(EditRecord::BumpGeneration {
+                    path,
+                    block: block.clone(),
+                });
+                if let Err(e) = res {
+                    return edit_failed(e, if_log_fails);
+                }
+                for old in previous {
+                    if !existing.contains(&old) {
+                        self.stores
+                            .get_mut()
+                            .unwrap()
+                            .invalidate_queue
+                            .push(old, block.clone());
+                    }
+                }
+                let generation = self
+                    .replicated_blocks
+                    .get(&block)
+                    .unwrap()
+                    .body()
+                    .generation();
+                let addr = self
+                    .stores
+                    .get_mut()
+                    .unwrap()
+                    .statuses
+                    .get(&store)
+                    .unwrap()
+                    .config()
+                    .addr()
+                    .clone();
+                ControlResp::GetAdditionalStoreResp(GetAdditionalStoreResp::Ok(
+                    GetAdditionalStoreRespOk {
+                        generation,
+                        store,
+                        addr,
+                    },
+                ))
+            }
+            ControlReq::CompleteFileReq(complete_file_req) => {
+                let path = PathSplit::from_uri(&complete_file_req.path);
+                let reject = |r| ControlResp::CompleteFileResp(CompleteFileResp::Rejected(r));
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(CompleteFileRejected::FileNotExist);
+                };
+                let FsNodeBody::File(file) = node.body() else {
+                    return reject(CompleteFileRejected::NotFile);
+                };
+                let has_lease = self
+                    .open_table
+                    .get_mut()
+                    .unwrap()
+                    .get(&path)
+                    .is_some_and(|attr| attr.write() && attr.is_held_by(&complete_file_req.client));
+                if !has_lease {
+                    return reject(CompleteFileRejected::NoLease);
+                }
+                if file.len() != complete_file_req.len {
+                    return reject(CompleteFileRejected::LengthMismatch { len: file.len() });
+                }
+                let replicated = file.blocks().iter().all(|block| {
+                    self.min_replication.get() <= self.replicated_blocks.stores(block.id()).len()
+                });
+                if !replicated {
+                    return ControlResp::CompleteFileResp(CompleteFileResp::Retry);
+                }
+                if let Err(e) = self.log_and_apply(EditRecord::CompleteFile { path: path.clone() })
+                {
+                    return edit_failed(e, if_log_fails);
+                }
+                self.open_table
+                    .get_mut()
+                    .unwrap()
+                    .close(&path, &complete_file_req.client)
+                    .unwrap();
+                ControlResp::CompleteFileResp(CompleteFileResp::Ok)
             }
             ControlReq::AllocBlockReq(alloc_block_req) => {
                 let path = PathSplit::from_uri(&alloc_block_req.path);
-                let path = PathCursor::new(path);
-                let res = self.virt_fs.get_mut(path);
-                let node = match res {
-                    Ok(fs_node) => fs_node,
-                    Err(_) => return Resp::AllocBlockResp(AllocBlockResp::Rejected),
-                };
-                let file = match node.body_mut() {
-                    FsNodeBody::Directory(_) => {
-                        return Resp::AllocBlockResp(AllocBlockResp::Rejected);
+                let reject = |r| ControlResp::AllocBlockResp(AllocBlockResp::Rejected(r));
+                if let Some(request_id) = alloc_block_req.request_id {
+                    let cached = self.retry_cache.get(&alloc_block_req.client, request_id);
+                    if let Some(cached) = cached {
+                        return ControlResp::AllocBlockResp(AllocBlockResp::Ok(cached.clone()));
                     }
+                }
+                let has_lease = self
+                    .open_table
+                    .get_mut()
+                    .unwrap()
+                    .get(&path)
+                    .is_some_and(|attr| attr.write() && attr.is_held_by(&alloc_block_req.client));
+                if !has_lease {
+                    return reject(AllocBlockRejected::NoLease);
+                }
+
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(AllocBlockRejected::FileNotExist);
+                };
+                let file = match node.body() {
+                    FsNodeBody::Directory(_) => return reject(AllocBlockRejected::NotFile),
                     FsNodeBody::File(file) => file,
                 };
                 let off_range = alloc_block_req.off_range;
-                if let Some(last) = file.blocks_mut().last() {
-                    let (_, last) = last.off_range();
-                    if off_range.0 != last {
-                        return Resp::AllocBlockResp(AllocBlockResp::Rejected);
-                    }
+                if let Err(e) = file.check_append(off_range) {
+                    return reject(match e {
+                        FileAppendBlockError::NonZeroStart => AllocBlockRejected::NonZeroStart,
+                        FileAppendBlockError::Gap { expected } => {
+                            AllocBlockRejected::OffsetGap { expected }
+                        }
+                        FileAppendBlockError::Empty => AllocBlockRejected::EmptyRange,
+                        FileAppendBlockError::Reversed => AllocBlockRejected::ReversedRange,
+                    });
+                }
+                let size = block_size(off_range).unwrap();
+                if self.max_block_size < size {
+                    return reject(AllocBlockRejected::BlockTooLarge {
+                        max: self.max_block_size,
+                    });
+                }
+                let replication = file.attr().replication();
+                let delta = Usage {
+                    nodes: 0,
+                    space: size * replication.get() as u64,
+                };
+                if let Err(e) = self.check_quota(&path, 0, delta) {
+                    return reject(AllocBlockRejected::QuotaExceeded(e));
                 }
-                let id: std::sync::Arc<str> = todo!();
-                let block = FileBlock::new(off_range, id.clone());
-                file.blocks_mut().push(block);
-                Resp::AllocBlockResp(AllocBlockResp::Ok(AllocBlockRespOk {
+                let stores = self.select_stores(replication.get(), &[], now);
+                if stores.is_empty() {
+                    return reject(AllocBlockRejected::NoStore);
+                }
+                let id = self.block_ids.next_id();
+                self.metrics.inc("dfs_control_blocks_allocated_total", &[]);
+                let res = self.log_and_apply(EditRecord::AllocBlock {
+                    path,
+                    off_range,
+                    block: id.clone(),
+                });
+                if let Err(e) = res {
+                    return edit_failed(e, if_log_fails);
+                }
+                if stores.len() < replication.get() {
+                    self.replication_queue.push(id.clone(), stores.len());
+                }
+                let targets = stores
+                    .iter()
+                    .map(|store| {
+                        self.stores
+                            .get_mut()
+                            .unwrap()
+                            .statuses
+                            .get(store)
+                            .unwrap()
+                            .config()
+                            .addr()
+                            .clone()
+                    })
+                    .collect();
+                let ok = AllocBlockRespOk {
                     block: id,
-                    store_addr: todo!(),
+                    generation: 0,
+                    targets,
+                    stores,
+                    max_block_size: self.max_block_size,
+                };
+                if let Some(request_id) = alloc_block_req.request_id {
+                    self.retry_cache
+                        .insert(alloc_block_req.client, request_id, ok.clone(), now);
+                }
+                ControlResp::AllocBlockResp(AllocBlockResp::Ok(ok))
+            }
+            ControlReq::ForceCloseReq(force_close_req) => {
+                let path = PathSplit::from_uri(&force_close_req.path);
+                let reject = |r| ControlResp::ForceCloseResp(ForceCloseResp::Rejected(r));
+                // breaks a lease some other client holds
+                if !self.is_superuser(caller) {
+                    return reject(ForceCloseRejected::PermissionDenied);
+                }
+                let Some(attr) = self.open_table.get_mut().unwrap().force_close(&path) else {
+                    return reject(ForceCloseRejected::NotOpen);
+                };
+                if attr.write() {
+                    if let Err(e) = self.recover_lease(&path) {
+                        return edit_failed(e, if_log_fails);
+                    }
+                }
+                ControlResp::ForceCloseResp(ForceCloseResp::Ok)
+            }
+            ControlReq::SetReplicationReq(set_replication_req) => {
+                let path = PathSplit::from_uri(&set_replication_req.path);
+                let reject = |r| ControlResp::SetReplicationResp(SetReplicationResp::Rejected(r));
+                if !self.permits(caller, &path, WRITE) {
+                    return reject(SetReplicationRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(SetReplicationRejected::NotFound);
+                };
+                if let FsNodeBody::Directory(_) = node.body() {
+                    if !set_replication_req.recursive {
+                        return reject(SetReplicationRejected::IsDirectory);
+                    }
+                }
+                let growth = replication_growth(node, &path, set_replication_req.replication)
+                    .and_then(|space| {
+                        let delta = Usage { nodes: 0, space };
+                        self.check_quota(&path, 0, delta)
+                    });
+                if let Err(e) = growth {
+                    return reject(SetReplicationRejected::QuotaExceeded(e));
+                }
+                let mut blocks = vec![];
+                node.visit_files(&path, &mut |_, file| {
+                    blocks.extend(file.blocks().iter().map(|block| block.id().clone()));
+                });
+                let res = self.log_and_apply(EditRecord::SetReplication {
+                    path,
+                    replication: set_replication_req.replication,
+                });
+                if let Err(e) = res {
+                    return edit_failed(e, if_log_fails);
+                }
+                let mut pending_blocks = 0;
+                for block in blocks {
+                    self.update_replication(block.clone());
+                    let deficit = self.replication_deficit(&block);
+                    if deficit.is_some() || !self.excess_replicas.stores(&block).is_empty() {
+                        pending_blocks += 1;
+                    }
+                }
+                ControlResp::SetReplicationResp(SetReplicationResp::Ok(SetReplicationRespOk {
+                    pending_blocks,
                 }))
             }
-            ControlReq::BlockReportReq(block_report_req) => todo!(),
-        }
-    }
-}
-
-pub enum Resp {
-    None,
-    OpenResp(OpenResp),
-    OpenLeaseResp(OpenLeaseResp),
-    AllocBlockResp(AllocBlockResp),
-}
+            ControlReq::SetTimesReq(set_times_req) => {
+                let path = PathSplit::from_uri(&set_times_req.path);
+                let reject = |r| ControlResp::SetTimesResp(SetTimesResp::Rejected(r));
+                if !self.is_owner(caller, &path) {
+                    return reject(SetTimesRejected::PermissionDenied);
+                }
+                let res = self.log_and_apply(EditRecord::SetTimes {
+                    path,
+                    mtime: set_times_req.mtime,
+                    atime: set_times_req.atime,
+                });
+                match res {
+                    Ok(()) => ControlResp::SetTimesResp(SetTimesResp::Ok),
+                    Err(e @ EditApplyError::Log(_)) => edit_failed(e, if_log_fails),
+                    Err(_) => reject(SetTimesRejected::NotFound),
+                }
+            }
+            ControlReq::ChownReq(chown_req) => {
+                let path = PathSplit::from_uri(&chown_req.path);
+                let reject = |r| ControlResp::ChownResp(ChownResp::Rejected(r));
+                if !self.is_owner(caller, &path) {
+                    return reject(ChownRejected::PermissionDenied);
+                }
+                let res = self.log_and_apply(EditRecord::SetOwner {
+                    path,
+                    owner: chown_req.owner,
+                    group: chown_req.group,
+                });
+                match res {
+                    Ok(()) => ControlResp::ChownResp(ChownResp::Ok),
+                    Err(e @ EditApplyError::Log(_)) => edit_failed(e, if_log_fails),
+                    Err(_) => reject(ChownRejected::NotFound),
+                }
+            }
+            ControlReq::ChmodReq(chmod_req) => {
+                let path = PathSplit::from_uri(&chmod_req.path);
+                let reject = |r| ControlResp::ChmodResp(ChmodResp::Rejected(r));
+                if !self.is_owner(caller, &path) {
+                    return reject(ChmodRejected::PermissionDenied);
+                }
+                let res = self.log_and_apply(EditRecord::SetMode {
+                    path,
+                    mode: chmod_req.mode,
+                });
+                match res {
+                    Ok(()) => ControlResp::ChmodResp(ChmodResp::Ok),
+                    Err(e @ EditApplyError::Log(_)) => edit_failed(e, if_log_fails),
+                    Err(_) => reject(ChmodRejected::NotFound),
+                }
+            }
+            ControlReq::SetQuotaReq(set_quota_req) => {
+                let path = PathSplit::from_uri(&set_quota_req.path);
+                let reject = |r| ControlResp::SetQuotaResp(SetQuotaResp::Rejected(r));
+                if !self.is_superuser(caller) {
+                    return reject(SetQuotaRejected::PermissionDenied);
+                }
+                match self
+                    .virt_fs
+                    .get(PathCursor::new(path.clone()))
+                    .map(|node| node.body())
+                {
+                    Ok(FsNodeBody::Directory(_)) => (),
+                    Ok(FsNodeBody::File(_)) => return reject(SetQuotaRejected::NotDirectory),
+                    Err(_) => return reject(SetQuotaRejected::NotFound),
+                }
+                let res = self.log_and_apply(EditRecord::SetQuota {
+                    path,
+                    max_nodes: set_quota_req.max_nodes,
+                    max_space: set_quota_req.max_space,
+                });
+                if let Err(e) = res {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::SetQuotaResp(SetQuotaResp::Ok)
+            }
+            ControlReq::ClearQuotaReq(clear_quota_req) => {
+                let path = PathSplit::from_uri(&clear_quota_req.path);
+                let reject = |r| ControlResp::ClearQuotaResp(ClearQuotaResp::Rejected(r));
+                if !self.is_superuser(caller) {
+                    return reject(ClearQuotaRejected::PermissionDenied);
+                }
+                match self
+                    .virt_fs
+                    .get(PathCursor::new(path.clone()))
+                    .map(|node| node.body())
+                {
+                    Ok(FsNodeBody::Directory(_)) => (),
+                    Ok(FsNodeBody::File(_)) => return reject(ClearQuotaRejected::NotDirectory),
+                    Err(_) => return reject(ClearQuotaRejected::NotFound),
+                }
+                if let Err(e) = self.log_and_apply(EditRecord::ClearQuota { path }) {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::ClearQuotaResp(ClearQuotaResp::Ok)
+            }
+            ControlReq::CreateSnapshotReq(create_snapshot_req) => {
+                let path = PathSplit::from_uri(&create_snapshot_req.path);
+                let reject = |r| ControlResp::CreateSnapshotResp(CreateSnapshotResp::Rejected(r));
+                if let Err(e) = PathSplit::from_uri("").join(&create_snapshot_req.name) {
+                    return reject(CreateSnapshotRejected::InvalidName(e));
+                }
+                if !self.is_owner(caller, &path) {
+                    return reject(CreateSnapshotRejected::PermissionDenied);
+                }
+                let res = self.log_and_apply(EditRecord::CreateSnapshot {
+                    path,
+                    name: create_snapshot_req.name.into(),
+                });
+                match res {
+                    Ok(()) => ControlResp::CreateSnapshotResp(CreateSnapshotResp::Ok),
+                    Err(EditApplyError::Snapshot(e)) => reject(match e {
+                        SnapshotError::NotFound => CreateSnapshotRejected::NotFound,
+                        SnapshotError::NotDirectory => CreateSnapshotRejected::NotDirectory,
+                        SnapshotError::Exists | SnapshotError::NoSuchSnapshot => {
+                            CreateSnapshotRejected::Exists
+                        }
+                    }),
+                    Err(e @ EditApplyError::Log(_)) => edit_failed(e, if_log_fails),
+                    Err(_) => reject(CreateSnapshotRejected::NotFound),
+                }
+            }
+            ControlReq::DeleteSnapshotReq(delete_snapshot_req) => {
+                let path = PathSplit::from_uri(&delete_snapshot_req.path);
+                let reject = |r| ControlResp::DeleteSnapshotResp(DeleteSnapshotResp::Rejected(r));
+                if !self.is_owner(caller, &path) {
+                    return reject(DeleteSnapshotRejected::PermissionDenied);
+                }
+                let res = self.log_and_apply(EditRecord::DeleteSnapshot {
+                    path,
+                    name: delete_snapshot_req.name.into(),
+                });
+                match res {
+                    Ok(()) => ControlResp::DeleteSnapshotResp(DeleteSnapshotResp::Ok),
+                    Err(EditApplyError::Snapshot(e)) => reject(match e {
+                        SnapshotError::NotFound => DeleteSnapshotRejected::NotFound,
+                        SnapshotError::NotDirectory => DeleteSnapshotRejected::NotDirectory,
+                        SnapshotError::Exists | SnapshotError::NoSuchSnapshot => {
+                            DeleteSnapshotRejected::NoSuchSnapshot
+                        }
+                    }),
+                    Err(e @ EditApplyError::Log(_)) => edit_failed(e, if_log_fails),
+                    Err(_) => reject(DeleteSnapshotRejected::NotFound),
+                }
+            }
+            // xattrs are metadata, so they stay writable while another client
+            // holds the file open for write
+            ControlReq::SetXattrReq(set_xattr_req) => {
+                let path = PathSplit::from_uri(&set_xattr_req.path);
+                let reject = |r| ControlResp::SetXattrResp(SetXattrResp::Rejected(r));
+                if !self.permits(caller, &path, WRITE) {
+                    return reject(SetXattrRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(SetXattrRejected::NotFound);
+                };
+                let permitted = self.xattr_limits.permits(
+                    node.attr(),
+                    &set_xattr_req.name,
+                    set_xattr_req.value.len(),
+                );
+                if !permitted {
+                    return reject(SetXattrRejected::TooLarge);
+                }
+                let res = self.log_and_apply(EditRecord::SetXattr {
+                    path,
+                    name: set_xattr_req.name.into(),
+                    value: set_xattr_req.value,
+                });
+                if let Err(e) = res {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::SetXattrResp(SetXattrResp::Ok)
+            }
+            ControlReq::RemoveXattrReq(remove_xattr_req) => {
+                let path = PathSplit::from_uri(&remove_xattr_req.path);
+                let reject = |r| ControlResp::RemoveXattrResp(RemoveXattrResp::Rejected(r));
+                if !self.permits(caller, &path, WRITE) {
+                    return reject(RemoveXattrRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(RemoveXattrRejected::NotFound);
+                };
+                if node.attr().xattr(&remove_xattr_req.name).is_none() {
+                    return reject(RemoveXattrRejected::NoSuchAttr);
+                }
+                let res = self.log_and_apply(EditRecord::RemoveXattr {
+                    path,
+                    name: remove_xattr_req.name.into(),
+                });
+                if let Err(e) = res {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::RemoveXattrResp(RemoveXattrResp::Ok)
+            }
+            ControlReq::RegisterStoreReq(register_store_req) => {
+                ControlResp::RegisterStoreResp(self.handle_register(register_store_req))
+            }
+            ControlReq::HeartbeatReq(heartbeat_req) => {
+                ControlResp::HeartbeatResp(self.handle_heartbeat(heartbeat_req))
+            }
+            ControlReq::BlockReportReq(block_report_req) => {
+                ControlResp::BlockReportResp(self.handle_block_report(block_report_req, None))
+            }
+            ControlReq::ReplicationFailedReq(replication_failed_req) => {
+                let block = replication_failed_req.block;
+                self.replication_queue
+                    .fail(&block, &replication_failed_req.store);
+                self.update_replication(block);
+                ControlResp::None
+            }
+            ControlReq::DeleteFileReq(delete_file_req) => {
+                let path = PathSplit::from_uri(&delete_file_req.path);
+                if !self.permits_delete(caller, &path) {
+                    return ControlResp::DeleteFileResp(DeleteFileResp::PermissionDenied);
+                }
+                if self.open_table.get_mut().unwrap().is_open(&path) {
+                    return ControlResp::DeleteFileResp(DeleteFileResp::Rejected);
+                }
+                if path.segs().is_empty() {
+                    return ControlResp::DeleteFileResp(DeleteFileResp::Rejected);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return ControlResp::DeleteFileResp(DeleteFileResp::Rejected);
+                };
+                let FsNodeBody::File(_) = node.body() else {
+                    return ControlResp::DeleteFileResp(DeleteFileResp::Rejected);
+                };
+                if let Err(e) = self.delete(caller, path, delete_file_req.skip_trash) {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::DeleteFileResp(DeleteFileResp::Ok)
+            }
+            ControlReq::DeleteDirectoryReq(delete_directory_req) => {
+                let path = PathSplit::from_uri(&delete_directory_req.path);
+                if !self.permits_delete(caller, &path) {
+                    return ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::PermissionDenied);
+                }
+                if path.segs().is_empty() {
+                    return ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Rejected);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Rejected);
+                };
+                let FsNodeBody::Directory(directory) = node.body() else {
+                    return ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Rejected);
+                };
+                if !directory.nodes().is_empty() && !delete_directory_req.recursive {
+                    return ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Rejected);
+                }
+                if self.open_table.get_mut().unwrap().is_any_open_under(&path) {
+                    return ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Rejected);
+                }
+                if let Err(e) = self.delete(caller, path, delete_directory_req.skip_trash) {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::Ok)
+            }
+            ControlReq::RenameReq(rename_req) => {
+                let src = PathSplit::from_uri(&rename_req.src);
+                let dst = PathSplit::from_uri(&rename_req.dst);
+                let reject = |r| ControlResp::RenameResp(RenameResp::Rejected(r));
+                if !self.permits(caller, &parent_dir(&src), WRITE | EXECUTE)
+                    || !self.permits(caller, &parent_dir(&dst), WRITE | EXECUTE)
+                {
+                    return reject(RenameRejected::PermissionDenied);
+                }
+                if self.open_table.get_mut().unwrap().is_any_open_under(&src) {
+                    return reject(RenameRejected::SrcOpen);
+                }
+                if src.segs().is_empty() {
+                    return reject(RenameRejected::SrcNotExist);
+                }
+                if dst.segs().is_empty() {
+                    return reject(RenameRejected::DstExist);
+                }
+                if let Err(e) = self.path_limits.check(&dst) {
+                    return reject(RenameRejected::PathLimit(e));
+                }
+                // directories above both ends see no net change
+                let shared = parent_dir(&src)
+                    .segs()
+                    .iter()
+                    .zip(parent_dir(&dst).segs().iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if self.has_quota_above(&dst, shared + 1) {
+                    if let Err(e) = self.check_quota(&dst, shared + 1, self.footprint(&src)) {
+                        return reject(RenameRejected::QuotaExceeded(e));
+                    }
+                }
+                let res = self.log_and_apply(EditRecord::Rename { src, dst });
+                match res {
+                    Ok(()) => ControlResp::RenameResp(RenameResp::Ok),
+                    Err(EditApplyError::Rename(e)) => reject(match e {
+                        FsNodeRenameError::SrcNotExist(_) => RenameRejected::SrcNotExist,
+                        FsNodeRenameError::DstExist(_) => RenameRejected::DstExist,
+                        FsNodeRenameError::DstDirectoryNotExist(_) => {
+                            RenameRejected::DstParentNotDirectory
+                        }
+                        FsNodeRenameError::DstUnderSrc => RenameRejected::DstUnderSrc,
+                    }),
+                    Err(e) => edit_failed(e, if_log_fails),
+                }
+            }
+            ControlReq::ConcatReq(concat_req) => {
+                let target = PathSplit::from_uri(&concat_req.target);
+                let sources: Vec<PathSplit> = concat_req
+                    .sources
+                    .iter()
+                    .map(|source| PathSplit::from_uri(source))
+                    .collect();
+                let reject = |r| ControlResp::ConcatResp(ConcatResp::Rejected(r));
+                if sources.is_empty() {
+                    return reject(ConcatRejected::NoSources);
+                }
+                let mut seen = HashSet::from([&target]);
+                if let Some(dup) = sources.iter().find(|source| !seen.insert(*source)) {
+                    return reject(ConcatRejected::DuplicateSource(dup.to_uri()));
+                }
+                let permitted = self.permits(caller, &target, WRITE)
+                    && sources.iter().all(|source| {
+                        self.permits(caller, source, READ) && self.permits_delete(caller, source)
+                    });
+                if !permitted {
+                    return reject(ConcatRejected::PermissionDenied);
+                }
+                let mut replication = None;
+                let mut space = 0;
+                for path in iter::once(&target).chain(&sources) {
+                    let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                        return reject(ConcatRejected::NotFound(path.to_uri()));
+                    };
+                    let FsNodeBody::File(file) = node.body() else {
+                        return reject(ConcatRejected::NotFile(path.to_uri()));
+                    };
+                    if self.open_table.get_mut().unwrap().is_open(path) {
+                        return reject(ConcatRejected::Open(path.to_uri()));
+                    }
+                    if !file.attr().is_finalized() {
+                        return reject(ConcatRejected::NotFinalized(path.to_uri()));
+                    }
+                    let expected = *replication.get_or_insert(file.attr().replication());
+                    if file.attr().replication() != expected {
+                        return reject(ConcatRejected::ReplicationMismatch(path.to_uri()));
+                    }
+                    if path != &target {
+                        space += file.space();
+                    }
+                }
+                // directories above the target and every source see no net
+                // change
+                let shared = sources
+                    .iter()
+                    .map(|source| {
+                        parent_dir(source)
+                            .segs()
+                            .iter()
+                            .zip(parent_dir(&target).segs().iter())
+                            .take_while(|(a, b)| a == b)
+                            .count()
+                    })
+                    .min()
+                    .unwrap_or_default();
+                let delta = Usage { nodes: 0, space };
+                if let Err(e) = self.check_quota(&target, shared + 1, delta) {
+                    return reject(ConcatRejected::QuotaExceeded(e));
+                }
+                if let Err(e) = self.log_and_apply(EditRecord::Concat { target, sources }) {
+                    return edit_failed(e, if_log_fails);
+                }
+                ControlResp::ConcatResp(ConcatResp::Ok)
+            }
+            ControlReq::MkdirReq(mkdir_req) => {
+                let path = PathSplit::from_uri(&mkdir_req.path);
+                if let Ok(FsNodeBody::Directory(_)) = self
+                    .virt_fs
+                    .get(PathCursor::new(path.clone()))
+                    .map(|node| node.body())
+                {
+                    return ControlResp::MkdirResp(MkdirResp::Ok(MkdirRespOk { existed: true }));
+                }
+                // new directories land in the deepest one that already exists
+                let ancestors = self.virt_fs.ancestors(PathCursor::new(path.clone()));
+                let permitted = self.is_superuser(caller)
+                    || ancestors
+                        .iter()
+                        .all(|dir| dir.attr().perm().allows(caller, EXECUTE))
+                        && ancestors
+                            .last()
+                            .is_none_or(|dir| dir.attr().perm().allows(caller, WRITE));
+                if !permitted {
+                    return ControlResp::MkdirResp(MkdirResp::Rejected(
+                        MkdirRejected::PermissionDenied,
+                    ));
+                }
+                if let Err(e) = self.path_limits.check(&path) {
+                    return ControlResp::MkdirResp(MkdirResp::Rejected(MkdirRejected::PathLimit(
+                        e,
+                    )));
+                }
+                let missing = match mkdir_req.create_parents {
+                    true => path.segs().len() + 1 - ancestors.len(),
+                    false => 1,
+                };
+                let delta = Usage {
+                    nodes: missing as u64,
+                    space: 0,
+                };
+                if let Err(e) = self.check_quota(&path, 0, delta) {
+                    return ControlResp::MkdirResp(MkdirResp::Rejected(
+                        MkdirRejected::QuotaExceeded(e),
+                    ));
+                }
+                let res = self.log_and_apply(EditRecord::Mkdir {
+                    path,
+                    create_parents: mkdir_req.create_parents,
+                    perm: self.new_perm(caller, 0o777),
+                });
+                match res {
+                    Ok(()) => ControlResp::MkdirResp(MkdirResp::Ok(MkdirRespOk { existed: false })),
+                    Err(EditApplyError::CreateDirs(e)) => {
+                        let rejected = match e {
+                            FsNodeCreateDirsError::FileExist(e) => {
+                                MkdirRejected::NotDirectory(unresolved(&e.path))
+                            }
+                            FsNodeCreateDirsError::DirectoryNotExist(e) => {
+                                MkdirRejected::ParentNotExist(unresolved(&e.path))
+                            }
+                        };
+                        ControlResp::MkdirResp(MkdirResp::Rejected(rejected))
+                    }
+                    Err(e) => edit_failed(e, if_log_fails),
+                }
+            }
+            ControlReq::ListCorruptFilesReq(_)
+            | ControlReq::ListStoresReq(_)
+            | ControlReq::ClusterStatusReq(_)
+            | ControlReq::GetBlockLocationsReq(_)
+            | ControlReq::ListReq(_)
+            | ControlReq::ListOpenFilesReq(_)
+            | ControlReq::RenewLeasesReq(_)
+            | ControlReq::StatReq(_)
+            | ControlReq::SnapshotDiffReq(_)
+            | ControlReq::ContentSummaryReq(_)
+            | ControlReq::GetXattrReq(_)
+            | ControlReq::ListXattrsReq(_)
+            | ControlReq::GetFileChecksumReq(_)
+            | ControlReq::SubscribeEventsReq(_) => {
+                unreachable!("answered by handle_read_req")
+            }
+        }
+    }
+
+    /// `diff` is the [`FullReportDiff`] of an unchunked full report, worked
+    /// out beforehand without the handler; without one it is worked out
+    /// here.
+    pub fn handle_block_report(
+        &mut self,
+        req: BlockReportReq,
+        diff: Option<FullReportDiff>,
+    ) -> BlockReportResp {
+        let now = self.clock.now();
+        let store = req.store;
+        let mut report = req.report;
+        let mut corrupted = vec![];
+        if self
+            .stores
+            .get_mut()
+            .unwrap()
+            .statuses
+            .get(&store)
+            .is_none()
+        {
+            return BlockReportResp {
+                corrupted,
+                resend: false,
+            };
+        }
+        if let (BlockReportType::Full, Some(chunk)) = (report.ty(), req.chunk) {
+            match self.partial_reports.push(&store, chunk, report.body()) {
+                Ok(Some(blocks)) => report = BlockReport::new(BlockReportType::Full, blocks),
+                Ok(None) => {
+                    return BlockReportResp {
+                        corrupted,
+                        resend: false,
+                    }
+                }
+                Err(_) => {
+                    return BlockReportResp {
+                        corrupted,
+                        resend: true,
+                    }
+                }
+            }
+        }
+        let mut touched: Vec<BlockId> = report
+            .body()
+            .blocks()
+            .iter()
+            .map(|b| b.id().clone())
+            .collect();
+        let status = self
+            .stores
+            .get_mut()
+            .unwrap()
+            .statuses
+            .get_mut(&store)
+            .unwrap();
+        match report.ty() {
+            BlockReportType::Full => status.set_awaiting_full_report(false),
+            BlockReportType::Add | BlockReportType::Remove => {
+                if status.awaiting_full_report() {
+                    return BlockReportResp {
+                        corrupted,
+                        resend: false,
+                    };
+                }
+            }
+        }
+        match report.ty() {
+            BlockReportType::Add => {
+                for block in report.body().blocks() {
+                    self.accept_replica(&store, block, now, &mut corrupted);
+                }
+            }
+            BlockReportType::Remove => {
+                for block in report.body().blocks() {
+                    self.replicated_blocks.remove_store(block.id(), &store);
+                    self.excess_replicas.remove(block.id(), &store);
+                    self.corrupt_replicas.remove(block.id(), &store);
+                }
+            }
+            BlockReportType::Full => {
+                let diff = diff
+                    .filter(|diff| diff.store == store)
+                    .unwrap_or_else(|| self.diff_full_report(&store, report.body()));
+                // a store has few queued deletions and corrupt replicas, so
+                // these are looked up here rather than in the diff
+                let stale_invalidations: Vec<BlockId> = self
+                    .stores
+                    .get_mut()
+                    .unwrap()
+                    .invalidate_queue
+                    .blocks_of(&store)
+                    .filter(|id| !diff.was_reported(id))
+                    .cloned()
+                    .collect();
+                for id in &stale_invalidations {
+                    self.stores
+                        .get_mut()
+                        .unwrap()
+                        .invalidate_queue
+                        .remove(&store, id);
+                }
+                let vanished_corrupt: Vec<BlockId> = self
+                    .corrupt_replicas
+                    .blocks()
+                    .filter(|id| {
+                        self.corrupt_replicas.contains(id, &store) && !diff.was_reported(id)
+                    })
+                    .cloned()
+                    .collect();
+                for id in &vanished_corrupt {
+                    self.corrupt_replicas.remove(id, &store);
+                }
+                for id in diff.missing {
+                    self.replicated_blocks.remove_store(&id, &store);
+                    self.excess_replicas.remove(&id, &store);
+                    touched.push(id);
+                }
+                // rechecked, as an add report may have landed since the diff
+                for block in &diff.unrecorded {
+                    if self.replicated_blocks.stores(block.id()).contains(&store) {
+                        continue;
+                    }
+                    self.accept_replica(&store, block, now, &mut corrupted);
+                }
+            }
+        }
+        for block in touched {
+            self.update_replication(block);
+        }
+        self.check_safe_mode();
+        BlockReportResp {
+            corrupted,
+            resend: false,
+        }
+    }
+    fn diff_full_report(&self, store: &StoreId, blocks: &BlockList) -> FullReportDiff {
+        let index = self.store_index(store);
+        FullReportDiff::new(self.block_shards(), store.clone(), index, blocks)
+    }
+    /// What [`FullReportDiff::new`] compares a report against, to be held
+    /// on to after the handler lock is released.
+    pub fn block_shards(&self) -> &BlockShards {
+        self.replicated_blocks.shards()
+    }
+    pub fn store_index(&self, store: &StoreId) -> Option<StoreIndex> {
+        self.replicated_blocks.store_table().index(store)
+    }
+
+    /// Answers the requests that only read the namespace, so the control
+    /// server can run them under a shared lock; anything else is handed back.
+    pub fn handle_read_req(
+        &self,
+        caller: &Caller,
+        msg: ControlReq,
+    ) -> Result<ControlResp, ControlReq> {
+        if !is_read_only(&msg) {
+            return Err(msg);
+        }
+        for path in request_paths(&msg) {
+            if let Err(e) = PathSplit::parse(path) {
+                return Ok(ControlResp::InvalidPath(e));
+            }
+        }
+        Ok(self.answer_read(caller, msg))
+    }
+    fn answer_read(&self, caller: &Caller, msg: ControlReq) -> ControlResp {
+        let now = self.clock.now();
+        match msg {
+            ControlReq::ListCorruptFilesReq(list_corrupt_files_req) => {
+                let prefix = list_corrupt_files_req
+                    .prefix
+                    .as_deref()
+                    .map(PathSplit::from_uri)
+                    .unwrap_or_else(|| PathSplit::from_uri(""));
+                let mut files = vec![];
+                if let Some(node) = self.virt_fs.get_by_segs(prefix.names()) {
+                    let open_table = self.lock_open_table();
+                    node.visit_files(&prefix, &mut |path, file| {
+                        if open_table.get(path).is_some_and(|attr| attr.write()) {
+                            return;
+                        }
+                        let mut corrupt = vec![];
+                        let mut missing = vec![];
+                        for block in file.blocks() {
+                            if !self.corrupt_replicas.get(block.id()).is_empty() {
+                                corrupt.push(block.id().clone());
+                            } else if self.replicated_blocks.stores(block.id()).is_empty() {
+                                missing.push(block.id().clone());
+                            }
+                        }
+                        if corrupt.is_empty() && missing.is_empty() {
+                            return;
+                        }
+                        files.push(CorruptFile {
+                            path: path.to_uri(),
+                            corrupt,
+                            missing,
+                        });
+                    });
+                }
+                ControlResp::ListCorruptFilesResp(ListCorruptFilesResp { files })
+            }
+            ControlReq::ListStoresReq(_) => {
+                let state = self.lock_stores();
+                let stores = state
+                    .statuses
+                    .iter()
+                    .map(|(store, status)| StoreSummary {
+                        store: store.clone(),
+                        addr: status.config().addr().clone(),
+                        alive: status.is_alive(self.store_dead_ttl, now),
+                        admin_state: status.admin_state(),
+                        admission: self.hosts.admission(status.config().addr()),
+                        pending_deletions: state.invalidate_queue.len_of(store),
+                        in_maintenance: status.in_maintenance(now),
+                        remaining: status.remaining(),
+                        block_count: self.replicated_blocks.count_on(store),
+                        failed_volumes: status.usage().failed_volumes,
+                    })
+                    .collect();
+                let mut missing_in_maintenance: Vec<BlockId> = state
+                    .statuses
+                    .iter()
+                    .filter(|(_, status)| status.in_maintenance(now))
+                    .flat_map(|(store, _)| self.replicated_blocks.blocks_on(store))
+                    .filter(|block| {
+                        self.replicated_blocks.stores(block).iter().all(|store| {
+                            state
+                                .statuses
+                                .get(store)
+                                .is_none_or(|status| !status.is_alive(self.store_dead_ttl, now))
+                        })
+                    })
+                    .collect();
+                missing_in_maintenance.sort();
+                missing_in_maintenance.dedup();
+                ControlResp::ListStoresResp(ListStoresResp {
+                    stores,
+                    missing_in_maintenance,
+                })
+            }
+            ControlReq::ClusterStatusReq(_) => {
+                let state = self.lock_stores();
+                let mut stores: Vec<StoreReport> = state
+                    .statuses
+                    .iter()
+                    .map(|(store, status)| StoreReport {
+                        store: store.clone(),
+                        addr: status.config().addr().clone(),
+                        liveness: status.liveness(
+                            self.heartbeat_interval,
+                            self.store_dead_ttl,
+                            now,
+                        ),
+                        admin_state: status.admin_state(),
+                        capacity: status.usage().capacity,
+                        used: status.usage().used,
+                        remaining: status.remaining(),
+                        block_count: self.replicated_blocks.count_on(store),
+                        last_heartbeat_age: status
+                            .last_heartbeat()
+                            .map(|last| now.duration_since(last)),
+                    })
+                    .collect();
+                stores.sort_by(|a, b| a.store.cmp(&b.store));
+                let summary = self.virt_fs.content_summary();
+                ControlResp::ClusterStatusResp(ClusterStatusResp {
+                    capacity: stores.iter().map(|store| store.capacity).sum(),
+                    used: stores.iter().map(|store| store.used).sum(),
+                    remaining: stores.iter().map(|store| store.remaining).sum(),
+                    stores,
+                    files: summary.files,
+                    directories: summary.directories,
+                    blocks: self.replicated_blocks.len(),
+                    under_replicated: self.replication_queue.queued_len()
+                        + self.replication_queue.pending_len(),
+                    corrupt: self.corrupt_replicas.blocks().count(),
+                    safe_mode: self.safe_mode_status(),
+                })
+            }
+            ControlReq::GetBlockLocationsReq(get_block_locations_req) => {
+                let path = PathSplit::from_uri(&get_block_locations_req.path);
+                let reject =
+                    |r| ControlResp::GetBlockLocationsResp(GetBlockLocationsResp::Rejected(r));
+                if !self.permits(caller, &path, READ) {
+                    return reject(GetBlockLocationsRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(GetBlockLocationsRejected::FileNotExist);
+                };
+                let FsNodeBody::File(file) = node.body() else {
+                    return reject(GetBlockLocationsRejected::NotFile);
+                };
+                let state = self.lock_stores();
+                let overlaps = |(start, end): (u64, u64)| {
+                    let Some((req_start, req_end)) = get_block_locations_req.off_range else {
+                        return true;
+                    };
+                    start < req_end && req_start < end
+                };
+                let blocks = file
+                    .blocks()
+                    .iter()
+                    .filter(|block| overlaps(block.off_range()))
+                    .map(|block| {
+                        let store_addrs = self
+                            .replicated_blocks
+                            .stores(block.id())
+                            .iter()
+                            .filter(|store| !self.corrupt_replicas.contains(block.id(), store))
+                            .filter_map(|store| state.statuses.get(store))
+                            .filter(|status| status.is_alive(self.store_dead_ttl, now))
+                            .map(|status| status.config().addr().clone())
+                            .collect();
+                        LocatedBlock {
+                            block: block.id().clone(),
+                            generation: block.generation(),
+                            off_range: block.off_range(),
+                            store_addrs,
+                        }
+                    })
+                    .collect();
+                ControlResp::GetBlockLocationsResp(GetBlockLocationsResp::Ok(
+                    GetBlockLocationsRespOk { blocks },
+                ))
+            }
+            ControlReq::ListReq(list_req) => {
+                let path = PathSplit::from_uri(&list_req.path);
+                if !self.permits(caller, &path, READ) {
+                    return ControlResp::ListResp(ListResp::Rejected(
+                        ListRejected::PermissionDenied,
+                    ));
+                }
+                let limit = list_req
+                    .limit
+                    .map_or(self.max_list_entries, |limit| {
+                        limit.min(self.max_list_entries)
+                    })
+                    .get();
+                let mut entries = vec![];
+                let mut has_more = false;
+                let start_after = list_req.start_after.as_deref();
+                let res = self
+                    .virt_fs
+                    .list(PathCursor::new(path), start_after, |name, node| {
+                        if entries.len() == limit {
+                            has_more = true;
+                            return ControlFlow::Break(());
+                        }
+                        let mtime = node.attr().mtime();
+                        let perm = node.attr().perm();
+                        let entry = match node.body() {
+                            FsNodeBody::Directory(_) => ListEntry {
+                                name: name.to_string(),
+                                is_dir: true,
+                                replication: None,
+                                len: 0,
+                                mtime,
+                                owner: perm.owner().clone(),
+                                group: perm.group().clone(),
+                                mode: perm.mode(),
+                            },
+                            FsNodeBody::File(file) => ListEntry {
+                                name: name.to_string(),
+                                is_dir: false,
+                                replication: Some(file.attr().replication()),
+                                len: file.len(),
+                                mtime,
+                                owner: perm.owner().clone(),
+                                group: perm.group().clone(),
+                                mode: perm.mode(),
+                            },
+                        };
+                        entries.push(entry);
+                        ControlFlow::Continue(())
+                    });
+                match res {
+                    Ok(()) => ControlResp::ListResp(ListResp::Ok(ListRespOk { entries, has_more })),
+                    Err(e) => ControlResp::ListResp(ListResp::Rejected(ListRejected::NotFound(
+                        unresolved(e.path()),
+                    ))),
+                }
+            }
+            // touches only the open-file table, which has a lock of its own
+            ControlReq::RenewLeasesReq(renew_leases_req) => {
+                let mut open_table = self.lock_open_table();
+                let lost = renew_leases_req
+                    .paths
+                    .into_iter()
+                    .filter(|path| {
+                        let path = PathSplit::from_uri(path);
+                        open_table
+                            .lease(&path, &renew_leases_req.client, now)
+                            .is_err()
+                    })
+                    .collect();
+                ControlResp::RenewLeasesResp(RenewLeasesResp { lost })
+            }
+            ControlReq::ListOpenFilesReq(list_open_files_req) => {
+                if !self.is_superuser(caller) {
+                    return ControlResp::ListOpenFilesResp(ListOpenFilesResp::Rejected(
+                        ListOpenFilesRejected::PermissionDenied,
+                    ));
+                }
+                let prefix = list_open_files_req
+                    .prefix
+                    .as_deref()
+                    .map(PathSplit::from_uri)
+                    .unwrap_or_else(|| PathSplit::from_uri(""));
+                let files = self
+                    .lock_open_table()
+                    .iter()
+                    .filter(|(path, _)| path.starts_with(&prefix))
+                    .map(|(path, attr)| OpenFileSummary {
+                        path: path.to_uri(),
+                        write: attr.write(),
+                        holders: attr.holders().count(),
+                        secs_since_lease: attr
+                            .since_latest_lease(now)
+                            .unwrap_or_default()
+                            .as_secs(),
+                    })
+                    .collect();
+                ControlResp::ListOpenFilesResp(ListOpenFilesResp::Ok(ListOpenFilesRespOk { files }))
+            }
+            ControlReq::StatReq(stat_req) => {
+                let path = PathSplit::from_uri(&stat_req.path);
+                if !self.permits(caller, &parent_dir(&path), EXECUTE) {
+                    return ControlResp::StatResp(StatResp::Rejected(
+                        StatRejected::PermissionDenied,
+                    ));
+                }
+                let node = match self.virt_fs.get(PathCursor::new(path)) {
+                    Ok(node) => node,
+                    Err(e) => {
+                        return ControlResp::StatResp(StatResp::Rejected(StatRejected::NotFound(
+                            unresolved(e.path()),
+                        )));
+                    }
+                };
+                let attr = node.attr();
+                let status = match node.body() {
+                    FsNodeBody::Directory(directory) => FileStatus {
+                        inode: attr.inode(),
+                        is_dir: true,
+                        len: 0,
+                        replication: None,
+                        block_count: 0,
+                        children: directory.nodes().len(),
+                        ctime: attr.ctime(),
+                        mtime: attr.mtime(),
+                        atime: attr.atime(),
+                        owner: attr.perm().owner().clone(),
+                        group: attr.perm().group().clone(),
+                        mode: attr.perm().mode(),
+                    },
+                    FsNodeBody::File(file) => FileStatus {
+                        inode: attr.inode(),
+                        is_dir: false,
+                        len: file.len(),
+                        replication: Some(file.attr().replication()),
+                        block_count: file.block_count(),
+                        children: 0,
+                        ctime: attr.ctime(),
+                        mtime: attr.mtime(),
+                        atime: attr.atime(),
+                        owner: attr.perm().owner().clone(),
+                        group: attr.perm().group().clone(),
+                        mode: attr.perm().mode(),
+                    },
+                };
+                ControlResp::StatResp(StatResp::Ok(status))
+            }
+            ControlReq::SnapshotDiffReq(snapshot_diff_req) => {
+                let path = PathSplit::from_uri(&snapshot_diff_req.path);
+                let reject = |r| ControlResp::SnapshotDiffResp(SnapshotDiffResp::Rejected(r));
+                if !self.permits(caller, &path, READ) {
+                    return reject(SnapshotDiffRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(SnapshotDiffRejected::NotFound);
+                };
+                let FsNodeBody::Directory(directory) = node.body() else {
+                    return reject(SnapshotDiffRejected::NotDirectory);
+                };
+                let snapshot = |name: &str| {
+                    let FsNodeBody::Directory(snapshots) = directory.attr().snapshots()?.body()
+                    else {
+                        return None;
+                    };
+                    snapshots.nodes().get(name).map(Arc::as_ref)
+                };
+                let Some(from) = snapshot(&snapshot_diff_req.from) else {
+                    return reject(SnapshotDiffRejected::NoSuchSnapshot);
+                };
+                let to = match &snapshot_diff_req.to {
+                    Some(name) => match snapshot(name) {
+                        Some(to) => to,
+                        None => return reject(SnapshotDiffRejected::NoSuchSnapshot),
+                    },
+                    None => node,
+                };
+                let entries = snapshot::diff(from, to);
+                ControlResp::SnapshotDiffResp(SnapshotDiffResp::Ok(SnapshotDiffRespOk { entries }))
+            }
+            ControlReq::ContentSummaryReq(content_summary_req) => {
+                let path = PathSplit::from_uri(&content_summary_req.path);
+                let reject = |r| ControlResp::ContentSummaryResp(ContentSummaryResp::Rejected(r));
+                if !self.permits(caller, &path, READ) {
+                    return reject(ContentSummaryRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(ContentSummaryRejected::NotFound);
+                };
+                let quota = match node.body() {
+                    FsNodeBody::Directory(directory) => directory.attr().quota(),
+                    FsNodeBody::File(_) => None,
+                };
+                let ok = ContentSummaryRespOk {
+                    summary: node.content_summary(),
+                    max_nodes: quota.and_then(|quota| quota.max_nodes()),
+                    max_space: quota.and_then(|quota| quota.max_space()),
+                };
+                ControlResp::ContentSummaryResp(ContentSummaryResp::Ok(ok))
+            }
+            ControlReq::GetXattrReq(get_xattr_req) => {
+                let path = PathSplit::from_uri(&get_xattr_req.path);
+                let reject = |r| ControlResp::GetXattrResp(GetXattrResp::Rejected(r));
+                if !self.permits(caller, &path, READ) {
+                    return reject(GetXattrRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(GetXattrRejected::NotFound);
+                };
+                match node.attr().xattr(&get_xattr_req.name) {
+                    Some(value) => ControlResp::GetXattrResp(GetXattrResp::Ok(GetXattrRespOk {
+                        value: value.to_vec(),
+                    })),
+                    None => reject(GetXattrRejected::NoSuchAttr),
+                }
+            }
+            ControlReq::ListXattrsReq(list_xattrs_req) => {
+                let path = PathSplit::from_uri(&list_xattrs_req.path);
+                let reject = |r| ControlResp::ListXattrsResp(ListXattrsResp::Rejected(r));
+                if !self.permits(caller, &path, READ) {
+                    return reject(ListXattrsRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(ListXattrsRejected::NotFound);
+                };
+                let mut names: Vec<String> = node
+                    .attr()
+                    .xattrs()
+                    .keys()
+                    .map(|name| name.to_string())
+                    .collect();
+                names.sort();
+                ControlResp::ListXattrsResp(ListXattrsResp::Ok(ListXattrsRespOk { names }))
+            }
+            ControlReq::GetFileChecksumReq(get_file_checksum_req) => {
+                let path = PathSplit::from_uri(&get_file_checksum_req.path);
+                let reject = |r| ControlResp::GetFileChecksumResp(GetFileChecksumResp::Rejected(r));
+                if !self.permits(caller, &path, READ) {
+                    return reject(GetFileChecksumRejected::PermissionDenied);
+                }
+                let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+                    return reject(GetFileChecksumRejected::NotFound);
+                };
+                let FsNodeBody::File(file) = node.body() else {
+                    return reject(GetFileChecksumRejected::NotFile);
+                };
+                if self.lock_open_table().is_open(&path) || !file.attr().is_finalized() {
+                    return reject(GetFileChecksumRejected::NotFinalized);
+                }
+                // a checksum from an older generation no longer describes the
+                // block's contents
+                let checksums: Vec<Option<(u64, u32)>> = file
+                    .blocks()
+                    .iter()
+                    .map(|block| {
+                        let replicated = self.replicated_blocks.get(block.id())?;
+                        let body = replicated.body();
+                        if body.generation() != block.generation() {
+                            return None;
+                        }
+                        let (start, end) = block.off_range();
+                        Some((end - start, body.checksum()?))
+                    })
+                    .collect();
+                let missing = checksums.iter().filter(|c| c.is_none()).count();
+                if missing != 0 {
+                    return reject(GetFileChecksumRejected::MissingChecksums {
+                        missing,
+                        blocks: checksums.len(),
+                    });
+                }
+                let checksums: Vec<(u64, u32)> = checksums.into_iter().flatten().collect();
+                let ok = GetFileChecksumRespOk {
+                    algorithm: COMPOSITE_CRC32C.to_string(),
+                    checksum: composite_checksum(&checksums),
+                    blocks: checksums.len(),
+                };
+                ControlResp::GetFileChecksumResp(GetFileChecksumResp::Ok(ok))
+            }
+            ControlReq::SubscribeEventsReq(subscribe_events_req) => {
+                if !self.is_superuser(caller) {
+                    return ControlResp::SubscribeEventsResp(SubscribeEventsResp::Rejected(
+                        SubscribeEventsRejected::PermissionDenied,
+                    ));
+                }
+                let ok = self.events.after(
+                    subscribe_events_req.after_txid,
+                    self.last_txid,
+                    &PathSplit::from_uri(&subscribe_events_req.path_prefix),
+                    self.max_list_entries.get(),
+                );
+                ControlResp::SubscribeEventsResp(SubscribeEventsResp::Ok(ok))
+            }
+            msg => unreachable!("{} is not read-only", request_name(&msg)),
+        }
+    }
+
+    /// For `&self` paths, which may run alongside a heartbeat.
+    fn lock_stores(&self) -> MutexGuard<'_, StoreState> {
+        self.stores.lock().unwrap()
+    }
+
+    /// For `&self` paths, which may run alongside a lease renewal.
+    fn lock_open_table(&self) -> MutexGuard<'_, OpenFileTable> {
+        self.open_table.lock().unwrap()
+    }
+
+    /// Checked before it is logged, so the log never holds an edit that
+    /// fails to apply on replay.
+    fn log_and_apply(&mut self, record: EditRecord) -> Result<(), EditApplyError> {
+        self.check_record(&record)?;
+        let txid = self.last_txid + 1;
+        let time = self.clock.system_now();
+        let entry = EditEntry::new(txid, time, record);
+        if let Some(edit_log) = &mut self.edit_log {
+            if let Err(e) = edit_log.append(&entry, self.clock.now()) {
+                self.edit_log_failed(&e);
+                return Err(EditApplyError::Log(e));
+            }
+        }
+        self.last_txid = txid;
+        self.apply_edit(entry.record(), time)?;
+        self.events.record(txid, entry.record());
+        Ok(())
+    }
+
+    /// Nothing more can be made durable, so the namespace stops changing
+    /// until an admin has fixed the disk and left safe mode.
+    fn edit_log_failed(&mut self, e: &io::Error) {
+        error!("edit log failed, entering safe mode: {e}");
+        self.metrics.inc("dfs_control_edit_log_failures_total", &[]);
+        self.safe_mode.enter();
+    }
+
+    fn apply_edit(&mut self, record: &EditRecord, time: SystemTime) -> Result<(), EditApplyError> {
+        // subtrees whose footprint the record may change
+        let roots = match record {
+            EditRecord::CreateFile { path, .. }
+            | EditRecord::Delete { path }
+            | EditRecord::Truncate { path }
+            | EditRecord::CompleteFile { path }
+            | EditRecord::RecoverLease { path, .. }
+            | EditRecord::AllocBlock { path, .. }
+            | EditRecord::AbandonBlock { path, .. }
+            | EditRecord::SetReplication { path, .. } => vec![path.clone()],
+            EditRecord::Mkdir { path, .. } => {
+                let existing = self.virt_fs.ancestors(PathCursor::new(path.clone())).len();
+                vec![path.prefix(existing)]
+            }
+            EditRecord::Rename { src, dst } => vec![src.clone(), dst.clone()],
+            EditRecord::Concat { target, sources } => {
+                iter::once(target).chain(sources).cloned().collect()
+            }
+            _ => vec![],
+        };
+        // walking a subtree for its footprint only pays off if some directory
+        // above it keeps count
+        let charged: Vec<(&PathSplit, Usage)> = roots
+            .iter()
+            .filter(|root| self.has_quota_above(root, 0))
+            .map(|root| (root, self.footprint(root)))
+            .collect();
+        let trashed: Vec<_> = roots
+            .iter()
+            .flat_map(|root| self.trash_charges(root))
+            .collect();
+        let res = self.apply_record(record, time);
+        if let EditRecord::SetReplication { path, .. } = record {
+            if let Ok(node) = self.virt_fs.get_mut(PathCursor::new(path.clone())) {
+                node.refresh_quota_usage();
+            }
+        }
+        for (root, before) in charged {
+            self.recharge(root, before);
+        }
+        for (origin, usage) in trashed {
+            self.charge(&origin, |quota| quota - usage);
+        }
+        for root in &roots {
+            for (origin, usage) in self.trash_charges(root) {
+                self.charge(&origin, |quota| quota + usage);
+            }
+        }
+        res
+    }
+
+    fn apply_record(
+        &mut self,
+        record: &EditRecord,
+        time: SystemTime,
+    ) -> Result<(), EditApplyError> {
+        match record {
+            EditRecord::CreateFile {
+                path,
+                replication,
+                perm,
+            } => {
+                let cursor = PathCursor::new(path.clone()).ok_or(EditApplyError::InvalidPath)?;
+                let inode_ids = &mut self.inode_ids;
+                self.virt_fs
+                    .create_node(cursor, || {
+                        FsNode::new(
+                            FsNodeAttribute::new(inode_ids.next_id(), time, perm.clone()),
+                            FsNodeBody::File(File::new(FileAttribute::new(*replication))),
+                        )
+                    })
+                    .map_err(EditApplyError::CreateFile)?;
+                self.index_path(path);
+                self.touch(&parent_dir(path), time);
+                Ok(())
+            }
+            EditRecord::Mkdir {
+                path,
+                create_parents,
+                perm,
+            } => {
+                let cursor = PathCursor::new(path.clone()).ok_or(EditApplyError::InvalidPath)?;
+                let inode_ids = &mut self.inode_ids;
+                let res = self.virt_fs.create_dirs(cursor, *create_parents, || {
+                    FsNodeAttribute::new(inode_ids.next_id(), time, perm.clone())
+                });
+                // parents may have been created even if the last one failed
+                self.index_path(path);
+                res.map(|_| ()).map_err(EditApplyError::CreateDirs)
+            }
+            EditRecord::Delete { path } => {
+                let cursor = PathCursor::new(path.clone()).ok_or(EditApplyError::InvalidPath)?;
+                let node = self
+                    .virt_fs
+                    .remove_node(cursor)
+                    .map_err(|_| EditApplyError::NotFound)?;
+                self.inodes.remove_subtree(&node);
+                self.mark_blocks_removing(&node);
+                self.touch(&parent_dir(path), time);
+                Ok(())
+            }
+            EditRecord::Truncate { path } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::File(file) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                file.attr_mut().set_finalized(false);
+                let dropped = file.truncate_blocks(0);
+                node.attr_mut().set_mtime(time);
+                for block in dropped {
+                    self.invalidate_block(&block);
+                }
+                Ok(())
+            }
+            EditRecord::Reopen { path } | EditRecord::CompleteFile { path } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::File(file) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                let finalized = matches!(record, EditRecord::CompleteFile { .. });
+                file.attr_mut().set_finalized(finalized);
+                if finalized {
+                    file.set_under_construction(false);
+                    node.attr_mut().set_mtime(time);
+                }
+
+                Ok(())
+            }
+            EditRecord::CloseFile { path } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::File(file) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                file.set_under_construction(false);
+                Ok(())
+            }
+            EditRecord::RecoverLease {
+                path,
+                last_block_len,
+            } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::File(file) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                file.set_under_construction(false);
+                if let (Some(len), Some(last)) = (last_block_len, file.last_block_mut()) {
+                    last.set_len(*len);
+                }
+                Ok(())
+            }
+            EditRecord::Concat { target, sources } => {
+                for path in iter::once(target).chain(sources) {
+                    let node = self
+                        .virt_fs
+                        .get(PathCursor::new(path.clone()))
+                        .map_err(|_| EditApplyError::NotFound)?;
+                    let FsNodeBody::File(_) = node.body() else {
+                        return Err(EditApplyError::InvalidPath);
+                    };
+                }
+                let mut files = vec![];
+                for source in sources {
+                    let cursor =
+                        PathCursor::new(source.clone()).ok_or(EditApplyError::InvalidPath)?;
+                    let node = self.virt_fs.remove_node(cursor).unwrap();
+                    self.inodes.remove_subtree(&node);
+                    let FsNodeBody::File(file) = node.body() else {
+                        unreachable!();
+                    };
+                    files.push(file.clone());
+                    self.touch(&parent_dir(source), time);
+                }
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(target.clone()))
+                    .unwrap();
+                node.attr_mut().set_mtime(time);
+                let inode = node.attr().inode();
+                let FsNodeBody::File(target_file) = node.body_mut() else {
+                    unreachable!();
+                };
+                for file in files {
+                    target_file.concat(file);
+                }
+                for block in target_file.blocks() {
+                    self.replicated_blocks.set_inode(block.id(), inode);
+                }
+                Ok(())
+            }
+            EditRecord::Rename { src, dst } => {
+                let src_cursor = PathCursor::new(src.clone()).ok_or(EditApplyError::InvalidPath)?;
+                let dst_cursor = PathCursor::new(dst.clone()).ok_or(EditApplyError::InvalidPath)?;
+                self.virt_fs
+                    .rename(src_cursor, dst_cursor)
+                    .map_err(EditApplyError::Rename)?;
+                self.touch(&parent_dir(src), time);
+                self.touch(&parent_dir(dst), time);
+                self.index_path(dst);
+                Ok(())
+            }
+            EditRecord::AllocBlock {
+                path,
+                off_range,
+                block,
+            } => {
+                let size = block_size(*off_range).ok_or(EditApplyError::InvalidPath)?;
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::File(file) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                file.append_block(FileBlock::new(*off_range, block.clone()))
+                    .map_err(|_| EditApplyError::InvalidPath)?;
+                node.attr_mut().set_mtime(time);
+                let inode = node.attr().inode();
+
+                self.block_ids.observe(block);
+                // already seeded from the image when replaying
+                let _ = self.replicated_blocks.insert(
+                    block.clone(),
+                    ReplicatedBlock::new(BlockBody::new(size, 0), inode),
+                );
+                Ok(())
+            }
+            EditRecord::BumpGeneration { path, block } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::File(file) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                let file_block = file.block_mut(block).ok_or(EditApplyError::NotFound)?;
+                let generation = self
+                    .replicated_blocks
+                    .bump_generation(block)
+                    .ok_or(EditApplyError::NotFound)?;
+                file_block.set_generation(generation);
+                Ok(())
+            }
+            EditRecord::AbandonBlock { path, block } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::File(file) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                if file.blocks().last().map(|last| last.id()) != Some(block) {
+                    return Err(EditApplyError::NotFound);
+                }
+                self.replicated_blocks
+                    .remove(block)
+                    .map_err(|_| EditApplyError::NotFound)?;
+                file.pop_block();
+                self.replication_queue.remove(block);
+                Ok(())
+            }
+            EditRecord::SetReplication { path, replication } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                node.visit_files_mut(path, &mut |_, file| {
+                    file.attr_mut().set_replication(*replication);
+                });
+                Ok(())
+            }
+            EditRecord::SetOwner { path, owner, group } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                if let Some(owner) = owner {
+                    node.attr_mut().perm_mut().set_owner(owner.clone());
+                }
+                if let Some(group) = group {
+                    node.attr_mut().perm_mut().set_group(group.clone());
+                }
+                Ok(())
+            }
+            EditRecord::SetMode { path, mode } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                node.attr_mut().perm_mut().set_mode(*mode);
+                Ok(())
+            }
+            EditRecord::SetXattr { path, name, value } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                node.attr_mut().set_xattr(name.clone(), value.clone());
+                Ok(())
+            }
+            EditRecord::RemoveXattr { path, name } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                node.attr_mut().remove_xattr(name);
+                Ok(())
+            }
+            EditRecord::SetQuota {
+                path,
+                max_nodes,
+                max_space,
+            } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let usage = node.usage();
+                let FsNodeBody::Directory(directory) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                let quota = Quota::new(*max_nodes, *max_space, usage);
+                directory.attr_mut().set_quota(Some(quota));
+                Ok(())
+            }
+            EditRecord::ClearQuota { path } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                let FsNodeBody::Directory(directory) = node.body_mut() else {
+                    return Err(EditApplyError::InvalidPath);
+                };
+                directory.attr_mut().set_quota(None);
+                Ok(())
+            }
+            EditRecord::CreateSnapshot { path, name } => {
+                self.virt_fs
+                    .create_snapshot(PathCursor::new(path.clone()), name.clone(), time, || {
+                        self.inode_ids.next_id()
+                    })
+                    .map_err(EditApplyError::Snapshot)?;
+                let snapshot = path.child(&SNAPSHOT_DIR.into()).child(name);
+                let node = self.virt_fs.get_by_segs(snapshot.names()).unwrap();
+                node.visit_files(path, &mut |_, file| {
+                    for block in file.blocks() {
+                        *self.snapshot_blocks.entry(block.id().clone()).or_default() += 1;
+                    }
+                });
+                Ok(())
+            }
+            EditRecord::DeleteSnapshot { path, name } => {
+                let removed = self
+                    .virt_fs
+                    .delete_snapshot(PathCursor::new(path.clone()), name)
+                    .map_err(EditApplyError::Snapshot)?;
+                let mut released = vec![];
+                removed.visit_files(path, &mut |_, file| {
+                    for block in file.blocks() {
+                        let Some(count) = self.snapshot_blocks.get_mut(block.id()) else {
+                            continue;
+                        };
+                        *count -= 1;
+                        if *count == 0 {
+                            self.snapshot_blocks.remove(block.id());
+                            released.push(block.id().clone());
+                        }
+                    }
+                });
+                for block in released {
+                    if self.deferred_blocks.remove(&block) {
+                        self.invalidate_block(&block);
+                    }
+                }
+                Ok(())
+            }
+            EditRecord::SetTimes { path, mtime, atime } => {
+                let node = self
+                    .virt_fs
+                    .get_mut(PathCursor::new(path.clone()))
+                    .map_err(|_| EditApplyError::NotFound)?;
+                if let Some(mtime) = mtime {
+                    node.attr_mut().set_mtime(*mtime);
+                }
+                if let Some(atime) = atime {
+                    node.attr_mut().set_atime(*atime);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The error [`Handler::apply_record`] would return, changing nothing.
+    fn check_record(&self, record: &EditRecord) -> Result<(), EditApplyError> {
+        let node = |path: &PathSplit| {
+            self.virt_fs
+                .get(PathCursor::new(path.clone()))
+                .map_err(|_| EditApplyError::NotFound)
+        };
+        let file = |path: &PathSplit| match node(path)?.body() {
+            FsNodeBody::File(file) => Ok(file),
+            FsNodeBody::Directory(_) => Err(EditApplyError::InvalidPath),
+        };
+        let cursor =
+            |path: &PathSplit| PathCursor::new(path.clone()).ok_or(EditApplyError::InvalidPath);
+        match record {
+            EditRecord::CreateFile { path, .. } => self
+                .virt_fs
+                .check_create_node(cursor(path)?)
+                .map_err(EditApplyError::CreateFile),
+            EditRecord::Mkdir {
+                path,
+                create_parents,
+                ..
+            } => self
+                .virt_fs
+                .check_create_dirs(cursor(path)?, *create_parents)
+                .map_err(EditApplyError::CreateDirs),
+            EditRecord::Delete { path } => {
+                cursor(path)?;
+                node(path).map(|_| ())
+            }
+            EditRecord::Truncate { path }
+            | EditRecord::Reopen { path }
+            | EditRecord::CompleteFile { path }
+            | EditRecord::CloseFile { path }
+            | EditRecord::RecoverLease { path, .. } => file(path).map(|_| ()),
+            EditRecord::Concat { target, sources } => {
+                for path in iter::once(target).chain(sources) {
+                    file(path)?;
+                }
+                for source in sources {
+                    cursor(source)?;
+                }
+                Ok(())
+            }
+            EditRecord::Rename { src, dst } => self
+                .virt_fs
+                .check_rename(&cursor(src)?, &cursor(dst)?)
+                .map_err(EditApplyError::Rename),
+            EditRecord::AllocBlock {
+                path, off_range, ..
+            } => {
+                block_size(*off_range).ok_or(EditApplyError::InvalidPath)?;
+                file(path)?
+                    .check_append(*off_range)
+                    .map_err(|_| EditApplyError::InvalidPath)
+            }
+            EditRecord::BumpGeneration { path, block } => {
+                let held = file(path)?.blocks().iter().any(|b| b.id() == block);
+                if !held || !self.replicated_blocks.contains(block) {
+                    return Err(EditApplyError::NotFound);
+                }
+                Ok(())
+            }
+            EditRecord::AbandonBlock { path, block } => {
+                let last = file(path)?.blocks().last().map(|last| last.id());
+                if last != Some(block) || !self.replicated_blocks.contains(block) {
+                    return Err(EditApplyError::NotFound);
+                }
+                Ok(())
+            }
+            EditRecord::SetReplication { path, .. }
+            | EditRecord::SetOwner { path, .. }
+            | EditRecord::SetMode { path, .. }
+            | EditRecord::SetXattr { path, .. }
+            | EditRecord::RemoveXattr { path, .. }
+            | EditRecord::SetTimes { path, .. } => node(path).map(|_| ()),
+            EditRecord::SetQuota { path, .. } | EditRecord::ClearQuota { path } => {
+                match node(path)?.body() {
+                    FsNodeBody::Directory(_) => Ok(()),
+                    FsNodeBody::File(_) => Err(EditApplyError::InvalidPath),
+                }
+            }
+            EditRecord::CreateSnapshot { path, name } => self
+                .virt_fs
+                .check_create_snapshot(PathCursor::new(path.clone()), name)
+                .map_err(EditApplyError::Snapshot),
+            EditRecord::DeleteSnapshot { path, name } => self
+                .virt_fs
+                .check_delete_snapshot(PathCursor::new(path.clone()), name)
+                .map_err(EditApplyError::Snapshot),
+        }
+    }
+
+    fn is_superuser(&self, caller: &Caller) -> bool {
+        caller.user == self.superuser
+    }
+    /// Execute on every directory leading to `path` and `access` on `path`
+    /// itself; a missing node is left for the request to report.
+    fn permits(&self, caller: &Caller, path: &PathSplit, access: u16) -> bool {
+        if self.is_superuser(caller) {
+            return true;
+        }
+        let mut traversable = true;
+        let node = self.virt_fs.walk_segs(path.names(), |dir| {
+            traversable &= dir.attr().perm().allows(caller, EXECUTE);
+        });
+        traversable && node.is_none_or(|node| node.attr().perm().allows(caller, access))
+    }
+    fn permits_delete(&self, caller: &Caller, path: &PathSplit) -> bool {
+        self.permits(caller, path, WRITE)
+            && self.permits(caller, &parent_dir(path), WRITE | EXECUTE)
+    }
+    fn is_owner(&self, caller: &Caller, path: &PathSplit) -> bool {
+        if self.is_superuser(caller) {
+            return true;
+        }
+        if !self.permits(caller, &parent_dir(path), EXECUTE) {
+            return false;
+        }
+        self.virt_fs
+            .get_by_segs(path.names())
+            .is_none_or(|node| *node.attr().perm().owner() == caller.user)
+    }
+    fn new_perm(&self, caller: &Caller, mode: u16) -> Permission {
+        Permission::new(
+            caller.user.clone(),
+            caller.primary_group().clone(),
+            mode & !self.umask,
+        )
+    }
+
+    /// Whether a directory above `path`, past the first `skip`, has a quota.
+    fn has_quota_above(&self, path: &PathSplit, skip: usize) -> bool {
+        let ancestors = self.virt_fs.ancestors(PathCursor::new(path.clone()));
+        ancestors
+            .into_iter()
+            .skip(skip)
+            .any(|dir| match dir.body() {
+                FsNodeBody::Directory(directory) => directory.attr().quota().is_some(),
+                FsNodeBody::File(_) => false,
+            })
+    }
+    fn footprint(&self, path: &PathSplit) -> Usage {
+        if path.segs().is_empty() {
+            // nothing above the root to charge
+            return Usage::default();
+        }
+        self.virt_fs
+            .get(PathCursor::new(path.clone()))
+            .map(|node| node.footprint())
+            .unwrap_or_default()
+    }
+    fn recharge(&mut self, path: &PathSplit, before: Usage) {
+        let after = self.footprint(path);
+        if after == before {
+            return;
+        }
+        self.virt_fs
+            .update_quota_usage(PathCursor::new(path.clone()), &mut |usage| {
+                *usage = *usage + after - before;
+            });
+    }
+    /// Trashed nodes under `path`, each as a charge against the directories
+    /// above its original location, so `/.trash/<user>/<millis>/a/b` counts
+    /// against `/a` until it is purged. The directories recreated to hold a
+    /// trashed path count too.
+    fn trash_charges(&self, path: &PathSplit) -> Vec<(PathSplit, Usage)> {
+        if path.segs().first().map(|seg| &**seg) != Some(TRASH_DIR) {
+            return vec![];
+        }
+        let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+            return vec![];
+        };
+        let mut charges = vec![];
+        let mut stack = vec![(path.clone(), node)];
+        while let Some((path, node)) = stack.pop() {
+            match node.body() {
+                FsNodeBody::Directory(directory) => {
+                    let children = directory.nodes().iter();
+                    stack.extend(children.map(|(name, node)| (path.child(name), &**node)));
+                    if path.segs().len() > TRASH_DEPTH {
+                        let usage = Usage { nodes: 1, space: 0 };
+                        charges.push((path.suffix(TRASH_DEPTH), usage));
+                    }
+                }
+                FsNodeBody::File(file) => {
+                    if path.segs().len() > TRASH_DEPTH {
+                        let usage = Usage {
+                            nodes: 1,
+                            space: file.space(),
+                        };
+                        charges.push((path.suffix(TRASH_DEPTH), usage));
+                    }
+                }
+            }
+        }
+        charges
+    }
+    /// Updates the quotas above `origin`, leaving out the root, which already
+    /// counts the trash as it stands.
+    fn charge(&mut self, origin: &PathSplit, update: impl Fn(Usage) -> Usage) {
+        let Ok(top) = self.virt_fs.get_mut(PathCursor::new(origin.prefix(1))) else {
+            return;
+        };
+        top.update_quota_usage(PathCursor::new(origin.suffix(1)), &mut |usage| {
+            *usage = update(*usage);
+        });
+    }
+    fn delete(
+        &mut self,
+        caller: &Caller,
+        path: PathSplit,
+        skip_trash: bool,
+    ) -> Result<(), EditApplyError> {
+        let in_trash = path.segs().first().map(|seg| &**seg) == Some(TRASH_DIR);
+        if skip_trash || in_trash || self.trash_retention.is_none() {
+            return self.log_and_apply(EditRecord::Delete { path });
+        }
+        let trash = PathSplit::from_uri(TRASH_DIR);
+        if self.virt_fs.get_by_segs(trash.names()).is_none() {
+            let perm = Permission::new(self.superuser.clone(), self.superuser.clone(), 0o755);
+            self.log_and_apply(EditRecord::Mkdir {
+                path: trash.clone(),
+                create_parents: false,
+                perm,
+            })?;
+        }
+        // one checkpoint per delete, so restoring never has to untangle two
+        let user = trash.child(&caller.user);
+        let mut millis = to_millis(self.clock.system_now());
+        let checkpoint = loop {
+            let checkpoint = user.child(&Arc::from(millis.to_string()));
+            if self
+                .virt_fs
+                .get(PathCursor::new(checkpoint.clone()))
+                .is_err()
+            {
+                break checkpoint;
+            }
+            millis += 1;
+        };
+        let dst = checkpoint.concat(&path);
+        self.log_and_apply(EditRecord::Mkdir {
+            path: parent_dir(&dst),
+            create_parents: true,
+            perm: Permission::new(caller.user.clone(), caller.primary_group().clone(), 0o700),
+        })?;
+        self.log_and_apply(EditRecord::Rename { src: path, dst })
+    }
+    fn purge_trash(&mut self) -> io::Result<()> {
+        let Some(retention) = self.trash_retention else {
+            return Ok(());
+        };
+        let now = to_millis(self.clock.system_now());
+        let trash = PathSplit::from_uri(TRASH_DIR);
+        let Some(node) = self.virt_fs.get_by_segs(trash.names()) else {
+            return Ok(());
+        };
+        let FsNodeBody::Directory(users) = node.body() else {
+            return Ok(());
+        };
+        let mut expired = vec![];
+        for (user, node) in users.nodes() {
+            let FsNodeBody::Directory(checkpoints) = node.body() else {
+                continue;
+            };
+            for name in checkpoints.nodes().keys() {
+                let Ok(millis) = name.parse::<u64>() else {
+                    continue;
+                };
+                if now.saturating_sub(millis) >= retention.as_millis() as u64 {
+                    expired.push(trash.child(user).child(name));
+                }
+            }
+        }
+        for path in expired {
+            if self.open_table.get_mut().unwrap().is_any_open_under(&path) {
+                continue;
+            }
+            match self.log_and_apply(EditRecord::Delete { path }) {
+                Ok(()) => (),
+                Err(EditApplyError::Log(e)) => return Err(e),
+                Err(e) => unreachable!("validated edit failed: {e:?}"),
+            }
+        }
+        Ok(())
+    }
+    /// Checks the quotas of the directories above `path`, skipping the first
+    /// `skip` of them counting from the root.
+    fn check_quota(
+        &self,
+        path: &PathSplit,
+        skip: usize,
+        delta: Usage,
+    ) -> Result<(), QuotaExceeded> {
+        let ancestors = self.virt_fs.ancestors(PathCursor::new(path.clone()));
+        for (depth, dir) in ancestors.into_iter().enumerate().skip(skip) {
+            let FsNodeBody::Directory(directory) = dir.body() else {
+                continue;
+            };
+            let Some(quota) = directory.attr().quota() else {
+                continue;
+            };
+            if let Err(limit) = quota.check(delta) {
+                return Err(QuotaExceeded {
+                    dir: path.prefix(depth).to_uri(),
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Records where each node along `path` sits, after it was created or
+    /// moved there.
+    fn index_path(&mut self, path: &PathSplit) {
+        let mut node = &self.virt_fs;
+        for name in path.segs().iter() {
+            let Some(child) = node.get_by_segs([&**name]) else {
+                return;
+            };
+            let parent = node.attr().inode();
+            self.inodes
+                .insert(child.attr().inode(), parent, name.clone());
+            node = child;
+        }
+    }
+    fn touch(&mut self, dir: &PathSplit, time: SystemTime) {
+        if let Ok(node) = self.virt_fs.get_mut(PathCursor::new(dir.clone())) {
+            node.attr_mut().set_mtime(time);
+        }
+    }
+
+    fn rebuild_block_map(&mut self) {
+        self.replicated_blocks = ReplicatedBlocksMap::with_shards(self.block_map_shards);
+        self.virt_fs
+            .visit_file_nodes(&PathSplit::from_uri(""), &mut |_, node| {
+                let FsNodeBody::File(file) = node.body() else {
+                    return;
+                };
+                for block in file.blocks() {
+                    let Some(size) = block_size(block.off_range()) else {
+                        continue;
+                    };
+                    // a block claimed by two files keeps its first owner
+                    let _ = self.replicated_blocks.insert(
+                        block.id().clone(),
+                        ReplicatedBlock::new(
+                            BlockBody::new(size, block.generation()),
+                            node.attr().inode(),
+                        ),
+                    );
+                }
+            });
+        self.snapshot_blocks.clear();
+        self.deferred_blocks.clear();
+        let mut stack = vec![(PathSplit::from_uri(""), &self.virt_fs)];
+        while let Some((path, node)) = stack.pop() {
+            let FsNodeBody::Directory(directory) = node.body() else {
+                continue;
+            };
+            let children = directory.nodes().iter();
+            stack.extend(children.map(|(name, node)| (path.child(name), &**node)));
+            let Some(snapshots) = directory.attr().snapshots() else {
+                continue;
+            };
+            let path = path.child(&SNAPSHOT_DIR.into());
+            snapshots.visit_file_nodes(&path, &mut |_, node| {
+                let FsNodeBody::File(file) = node.body() else {
+                    return;
+                };
+                for block in file.blocks() {
+                    *self.snapshot_blocks.entry(block.id().clone()).or_default() += 1;
+                    if self.replicated_blocks.get(block.id()).is_some() {
+                        continue;
+                    }
+                    let Some(size) = block_size(block.off_range()) else {
+                        continue;
+                    };
+                    self.deferred_blocks.insert(block.id().clone());
+                    let _ = self.replicated_blocks.insert(
+                        block.id().clone(),
+                        ReplicatedBlock::new(
+                            BlockBody::new(size, block.generation()),
+                            node.attr().inode(),
+                        ),
+                    );
+                }
+            });
+        }
+    }
+
+    fn sweep_dead_stores(&mut self, now: Instant) {
+        let statuses = &mut self.stores.get_mut().unwrap().statuses;
+        let maintenance_over: Vec<StoreId> = statuses
+            .iter()
+            .filter(|(_, status)| status.maintenance_until().is_some_and(|until| until <= now))
+            .map(|(store, _)| store.clone())
+            .collect();
+        for store in maintenance_over {
+            statuses.get_mut(&store).unwrap().exit_maintenance();
+        }
+        let dead: Vec<StoreId> = statuses
+            .dead(self.store_dead_ttl, now)
+            .filter(|store| !statuses.get(store).unwrap().awaiting_full_report())
+            .cloned()
+            .collect();
+        for store in &dead {
+            statuses
+                .get_mut(store)
+                .unwrap()
+                .set_awaiting_full_report(true);
+        }
+        for store in dead {
+            self.partial_reports.remove(&store);
+            self.corrupt_replicas.remove_store(&store);
+            let blocks = self.replicated_blocks.remove_all_of_store(&store);
+            warn!(%store, blocks = blocks.len(), "store is dead");
+            self.metrics.inc("dfs_control_dead_stores_total", &[]);
+            for block in blocks {
+                self.update_replication(block);
+            }
+        }
+    }
+
+    pub fn replication_queue(&self) -> &ReplicationQueue {
+        &self.replication_queue
+    }
+
+    fn update_replication(&mut self, block: BlockId) {
+        if self.replication_deficit(&block).is_none() {
+            self.replication_queue.remove(&block);
+            self.choose_excess_replicas(&block);
+            return;
+        }
+        let live = self.compliant_replicas(&block);
+        self.replication_queue.push(block, live);
+    }
+
+    fn compliant_replicas(&self, block: &BlockId) -> usize {
+        self.replicated_blocks
+            .stores(block)
+            .iter()
+            .filter(|store| self.is_compliant(store))
+            .count()
+    }
+
+    fn is_compliant(&self, store: &StoreId) -> bool {
+        self.lock_stores()
+            .statuses
+            .get(store)
+            .is_none_or(|status| status.admin_state() == StoreAdminState::Normal)
+    }
+
+    fn replication_deficit(&self, block: &BlockId) -> Option<usize> {
+        let target =
+            self.replicated_blocks
+                .replication_target(block, &self.virt_fs, &self.inodes)?;
+        let deficit = target.saturating_sub(self.compliant_replicas(block));
+        if deficit == 0 && self.single_rack(block).is_some() {
+            return Some(1);
+        }
+        (deficit != 0).then_some(deficit)
+    }
+
+    fn single_rack(&self, block: &BlockId) -> Option<Option<String>> {
+        let stores = self.replicated_blocks.stores(block);
+        if stores.len() < 2 {
+            return None;
+        }
+        let state = self.lock_stores();
+        let racks: HashSet<Option<&str>> = stores
+            .iter()
+            .filter_map(|store| state.statuses.get(store))
+            .map(|status| status.config().rack())
+            .collect();
+        let [rack] = racks.into_iter().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        let elsewhere = state
+            .statuses
+            .iter()
+            .any(|(_, status)| status.config().rack() != rack);
+        elsewhere.then(|| rack.map(str::to_owned))
+    }
+
+    fn update_decommissioning(&mut self) {
+        let draining: Vec<StoreId> = self
+            .stores
+            .get_mut()
+            .unwrap()
+            .statuses
+            .iter()
+            .filter(|(_, status)| status.admin_state() == StoreAdminState::Decommissioning)
+            .map(|(store, _)| store.clone())
+            .collect();
+        for store in draining {
+            let drained = self
+                .replicated_blocks
+                .blocks_on(&store)
+                .iter()
+                .all(|block| self.replication_deficit(block).is_none());
+            if drained {
+                self.stores
+                    .get_mut()
+                    .unwrap()
+                    .statuses
+                    .get_mut(&store)
+                    .unwrap()
+                    .set_admin_state(StoreAdminState::Decommissioned);
+            }
+        }
+    }
+
+    fn choose_excess_replicas(&mut self, block: &BlockId) {
+        let Some(target) =
+            self.replicated_blocks
+                .replication_target(block, &self.virt_fs, &self.inodes)
+        else {
+            self.excess_replicas.remove_block(block);
+            return;
+        };
+        let pending = self.excess_replicas.stores(block);
+        let compliant: Vec<StoreId> = self
+            .replicated_blocks
+            .stores(block)
+            .iter()
+            .filter(|store| !pending.contains(store))
+            .filter(|store| self.is_compliant(store))
+            .cloned()
+            .collect();
+        let statuses = &self.stores.get_mut().unwrap().statuses;
+        let mut kept: Vec<(&StoreId, u64)> = compliant
+            .iter()
+            .map(|store| {
+                let remaining = statuses
+                    .get(store)
+                    .map(|status| status.remaining())
+                    .unwrap_or(0);
+                (store, remaining)
+            })
+            .collect();
+        if kept.len() <= target {
+            return;
+        }
+        let rack_of = |store: &StoreId| {
+            statuses
+                .get(store)
+                .and_then(|status| status.config().rack())
+        };
+        let mut rack_replicas: HashMap<Option<&str>, usize> = HashMap::new();
+        for (store, _) in &kept {
+            *rack_replicas.entry(rack_of(store)).or_default() += 1;
+        }
+        // trim from crowded racks first so the survivors stay spread out
+        kept.sort_by_key(|(store, remaining)| (rack_replicas[&rack_of(store)] == 1, *remaining));
+        let excess: Vec<StoreId> = kept[..kept.len() - target]
+            .iter()
+            .map(|(store, _)| (*store).clone())
+            .collect();
+        for store in excess {
+            self.excess_replicas.insert(block.clone(), store);
+        }
+    }
+
+    fn remove_excess_replicas(&mut self, now: Instant) {
+        let blocks: Vec<BlockId> = self.excess_replicas.blocks().cloned().collect();
+        for block in blocks {
+            let Some(target) =
+                self.replicated_blocks
+                    .replication_target(&block, &self.virt_fs, &self.inodes)
+            else {
+                self.excess_replicas.remove_block(&block);
+                continue;
+            };
+            let excess = self.excess_replicas.stores(&block).to_vec();
+            let confirmed = self
+                .replicated_blocks
+                .stores(&block)
+                .iter()
+                .filter(|store| !excess.contains(store))
+                .filter(|store| self.is_compliant(store))
+                .filter(|store| {
+                    self.lock_stores()
+                        .statuses
+                        .get(store)
+                        .is_some_and(|status| status.is_alive(self.store_dead_ttl, now))
+                })
+                .count();
+            if confirmed < target {
+                continue;
+            }
+            for store in excess {
+                self.excess_replicas.remove(&block, &store);
+                self.replicated_blocks.remove_store(&block, &store);
+                self.stores
+                    .get_mut()
+                    .unwrap()
+                    .invalidate_queue
+                    .push(store, block.clone());
+            }
+        }
+    }
+
+    fn schedule_replication(&mut self, now: Instant) {
+        for block in self.replication_queue.expire(now) {
+            self.metrics
+                .inc("dfs_control_replication_timeouts_total", &[]);
+            self.update_replication(block);
+        }
+        let mut deferred = vec![];
+        while let Some(block) = self.replication_queue.pop() {
+            let Some(deficit) = self.replication_deficit(&block) else {
+                continue;
+            };
+            let holders = self.replicated_blocks.stores(&block).to_vec();
+            let source = holders.iter().find(|store| {
+                self.stores
+                    .get_mut()
+                    .unwrap()
+                    .statuses
+                    .get(store)
+                    .is_some_and(|status| status.is_alive(self.store_dead_ttl, now))
+            });
+            let Some(source) = source.cloned() else {
+                deferred.push((block, holders.len()));
+                continue;
+            };
+            let mut exclude = holders.clone();
+            if let Some(rack) = self.single_rack(&block) {
+                exclude.extend(
+                    self.stores
+                        .get_mut()
+                        .unwrap()
+                        .statuses
+                        .iter()
+                        .filter(|(_, status)| status.config().rack() == rack.as_deref())
+                        .map(|(store, _)| store.clone()),
+                );
+            }
+            let targets = self.select_stores(deficit, &exclude, now);
+
+            if targets.is_empty() {
+                deferred.push((block, holders.len()));
+                continue;
+            }
+            let target_addrs = targets
+                .iter()
+                .map(|store| {
+                    self.stores
+                        .get_mut()
+                        .unwrap()
+                        .statuses
+                        .get(store)
+                        .unwrap()
+                        .config()
+                        .addr()
+                        .clone()
+                })
+                .collect();
+            self.push_store_command(
+                source,
+                StoreCommand::ReplicateBlock {
+                    block: block.clone(),
+                    targets: target_addrs,
+                },
+            );
+            self.metrics
+                .inc("dfs_control_replications_scheduled_total", &[]);
+            self.replication_queue
+                .start(block, targets, now + REPLICATION_TIMEOUT);
+        }
+        for (block, live) in deferred {
+            self.replication_queue.push(block, live);
+        }
+    }
+
+    fn select_stores(&mut self, count: usize, exclude: &[StoreId], now: Instant) -> Vec<StoreId> {
+        let ineligible = self
+            .stores
+            .get_mut()
+            .unwrap()
+            .statuses
+            .iter()
+            .filter(|(_, status)| {
+                status.admin_state() != StoreAdminState::Normal
+                    || status.in_maintenance(now)
+                    || !status.is_alive(self.store_dead_ttl, now)
+            })
+            .map(|(store, _)| store.clone());
+        let exclude: Vec<StoreId> = exclude.iter().cloned().chain(ineligible).collect();
+        let chosen =
+            self.placement
+                .choose(count, &self.stores.get_mut().unwrap().statuses, &exclude);
+        self.metrics.inc("dfs_control_placements_total", &[]);
+        if chosen.len() < count {
+            self.metrics
+                .inc("dfs_control_placement_shortfalls_total", &[]);
+        }
+        chosen
+    }
+
+    fn recover_lease(&mut self, path: &PathSplit) -> Result<(), EditApplyError> {
+        let Some(node) = self.virt_fs.get_by_segs(path.names()) else {
+            return Ok(());
+        };
+        let FsNodeBody::File(file) = node.body() else {
+            return Ok(());
+        };
+        let last_block_len = file
+            .blocks()
+            .last()
+            .and_then(|last| self.replicated_blocks.get(last.id()))
+            .filter(|reported| !reported.stores().is_empty())
+            .map(|reported| reported.body().size());
+        self.log_and_apply(EditRecord::RecoverLease {
+            path: path.clone(),
+            last_block_len,
+        })
+    }
+
+    fn accept_replica(
+        &mut self,
+        store: &StoreId,
+        block: &ReportedBlock,
+        now: Instant,
+        corrupted: &mut Vec<BlockId>,
+    ) {
+        if self.corrupt_replicas.contains(block.id(), store) {
+            return;
+        }
+        let res = self
+            .replicated_blocks
+            .push_store(store.clone(), block.clone());
+        match res {
+            Ok(()) => (),
+            Err(PushReplicaError::Unknown { .. }) => corrupted.push(block.id().clone()),
+            Err(PushReplicaError::Corrupted { store, reason }) => {
+                self.corrupt_replicas
+                    .insert(block.id().clone(), store, reason, now);
+            }
+            Err(PushReplicaError::Stale { store }) => {
+                self.stores
+                    .get_mut()
+                    .unwrap()
+                    .invalidate_queue
+                    .push(store, block.id().clone());
+            }
+        }
+    }
+
+    fn release_corrupt_replicas(&mut self) {
+        let blocks: Vec<BlockId> = self.corrupt_replicas.blocks().cloned().collect();
+        for block in blocks {
+            if let Some(target) =
+                self.replicated_blocks
+                    .replication_target(&block, &self.virt_fs, &self.inodes)
+            {
+                if self.compliant_replicas(&block) < target {
+                    continue;
+                }
+            }
+            for replica in self.corrupt_replicas.remove_block(&block) {
+                self.stores
+                    .get_mut()
+                    .unwrap()
+                    .invalidate_queue
+                    .push(replica.store().clone(), block.clone());
+            }
+        }
+    }
+
+    fn check_safe_mode(&mut self) {
+        self.safe_mode.check(
+            self.replicated_blocks.reported_len(),
+            self.replicated_blocks.len(),
+        );
+    }
+
+    fn mark_blocks_removing(&mut self, node: &FsNode) {
+        node.visit_files(&PathSplit::from_uri(""), &mut |_, file| {
+            for block in file.blocks() {
+                self.invalidate_block(block.id());
+            }
+        });
+    }
+
+    fn invalidate_block(&mut self, id: &BlockId) {
+        // a snapshot still reads it; released with the last one
+        if self.snapshot_blocks.contains_key(id) {
+            self.deferred_blocks.insert(id.clone());
+            return;
+        }
+        let stores = self.replicated_blocks.stores(id).to_vec();
+        if self.replicated_blocks.remove(id).is_err() {
+            return;
+        }
+        let queue = &mut self.stores.get_mut().unwrap().invalidate_queue;
+        for store in stores {
+            queue.push(store, id.clone());
+        }
+        for replica in self.corrupt_replicas.remove_block(id) {
+            queue.push(replica.store().clone(), id.clone());
+        }
+        self.replication_queue.remove(id);
+        self.excess_replicas.remove_block(id);
+    }
+}
+
+/// Cached quota usages in a fixed walk order, for spotting changes.
+fn quota_usages(root: &FsNode) -> Vec<Usage> {
+    let mut usages = vec![];
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        let FsNodeBody::Directory(directory) = node.body() else {
+            continue;
+        };
+        if let Some(quota) = directory.attr().quota() {
+            usages.push(quota.usage());
+        }
+        stack.extend(directory.nodes().values().map(Arc::as_ref));
+    }
+    usages
+}
+
+fn unresolved(path: &PathCursor) -> UnresolvedPath {
+    UnresolvedPath {
+        resolved: path.resolved().to_uri(),
+        remaining: path.remaining().segs().join("/"),
+    }
+}
+
+/// The root stands in for its own parent.
+fn parent_dir(path: &PathSplit) -> PathSplit {
+    path.parent().unwrap_or_else(|| path.clone())
+}
+
+/// Requests [`Handler::handle_read_req`] answers.
+pub fn is_read_only(msg: &ControlReq) -> bool {
+    matches!(
+        msg,
+        ControlReq::ListCorruptFilesReq(_)
+            | ControlReq::ListStoresReq(_)
+            | ControlReq::ClusterStatusReq(_)
+            | ControlReq::GetBlockLocationsReq(_)
+            | ControlReq::ListReq(_)
+            | ControlReq::ListOpenFilesReq(_)
+            | ControlReq::RenewLeasesReq(_)
+            | ControlReq::StatReq(_)
+            | ControlReq::SnapshotDiffReq(_)
+            | ControlReq::ContentSummaryReq(_)
+            | ControlReq::GetXattrReq(_)
+            | ControlReq::ListXattrsReq(_)
+            | ControlReq::GetFileChecksumReq(_)
+            | ControlReq::SubscribeEventsReq(_)
+    )
+}
+
+/// The variant name in snake case, for labelling logs.
+pub fn request_name(msg: &ControlReq) -> &'static str {
+    match msg {
+        ControlReq::OpenReq(_) => "open",
+        ControlReq::OpenLeaseReq(_) => "open_lease",
+        ControlReq::RenewLeasesReq(_) => "renew_leases",
+        ControlReq::CloseReq(_) => "close",
+        ControlReq::AllocBlockReq(_) => "alloc_block",
+        ControlReq::CompleteFileReq(_) => "complete_file",
+        ControlReq::AbandonBlockReq(_) => "abandon_block",
+        ControlReq::GetAdditionalStoreReq(_) => "get_additional_store",
+        ControlReq::RegisterStoreReq(_) => "register_store",
+        ControlReq::HeartbeatReq(_) => "heartbeat",
+        ControlReq::BlockReportReq(_) => "block_report",
+        ControlReq::ReplicationFailedReq(_) => "replication_failed",
+        ControlReq::DeleteFileReq(_) => "delete_file",
+        ControlReq::DeleteDirectoryReq(_) => "delete_directory",
+        ControlReq::RenameReq(_) => "rename",
+        ControlReq::MkdirReq(_) => "mkdir",
+        ControlReq::GetBlockLocationsReq(_) => "get_block_locations",
+        ControlReq::ListReq(_) => "list",
+        ControlReq::StatReq(_) => "stat",
+        ControlReq::SetReplicationReq(_) => "set_replication",
+        ControlReq::SetTimesReq(_) => "set_times",
+        ControlReq::ChownReq(_) => "chown",
+        ControlReq::ChmodReq(_) => "chmod",
+        ControlReq::SetXattrReq(_) => "set_xattr",
+        ControlReq::GetXattrReq(_) => "get_xattr",
+        ControlReq::RemoveXattrReq(_) => "remove_xattr",
+        ControlReq::ListXattrsReq(_) => "list_xattrs",
+        ControlReq::SetQuotaReq(_) => "set_quota",
+        ControlReq::ClearQuotaReq(_) => "clear_quota",
+        ControlReq::ContentSummaryReq(_) => "content_summary",
+        ControlReq::CreateSnapshotReq(_) => "create_snapshot",
+        ControlReq::DeleteSnapshotReq(_) => "delete_snapshot",
+        ControlReq::SnapshotDiffReq(_) => "snapshot_diff",
+        ControlReq::ConcatReq(_) => "concat",
+        ControlReq::GetFileChecksumReq(_) => "get_file_checksum",
+        ControlReq::ListOpenFilesReq(_) => "list_open_files",
+        ControlReq::ForceCloseReq(_) => "force_close",
+        ControlReq::SafeModeReq(_) => "safe_mode",
+        ControlReq::DecommissionStoreReq(_) => "decommission_store",
+        ControlReq::ListStoresReq(_) => "list_stores",
+        ControlReq::ClusterStatusReq(_) => "cluster_status",
+        ControlReq::RefreshStoresReq(_) => "refresh_stores",
+        ControlReq::EnterMaintenanceReq(_) => "enter_maintenance",
+        ControlReq::ExitMaintenanceReq(_) => "exit_maintenance",
+        ControlReq::ReportBadBlockReq(_) => "report_bad_block",
+        ControlReq::ListCorruptFilesReq(_) => "list_corrupt_files",
+        ControlReq::FsckReq(_) => "fsck",
+        ControlReq::MetaSaveReq(_) => "meta_save",
+        ControlReq::SubscribeEventsReq(_) => "subscribe_events",
+    }
+}
+
+pub fn request_client(msg: &ControlReq) -> Option<&str> {
+    Some(match msg {
+        ControlReq::OpenReq(req) => &req.client,
+        ControlReq::OpenLeaseReq(req) => &req.client,
+        ControlReq::RenewLeasesReq(req) => &req.client,
+        ControlReq::CloseReq(req) => &req.client,
+        ControlReq::AllocBlockReq(req) => &req.client,
+        ControlReq::GetAdditionalStoreReq(req) => &req.client,
+        ControlReq::AbandonBlockReq(req) => &req.client,
+        ControlReq::CompleteFileReq(req) => &req.client,
+        _ => return None,
+    })
+}
+
+pub fn rejection(resp: &ControlResp) -> Option<String> {
+    Some(match resp {
+        ControlResp::InvalidPath(e) => e.to_string(),
+        ControlResp::EditFailed(e) => e.clone(),
+        ControlResp::OpenResp(OpenResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::AllocBlockResp(AllocBlockResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::CompleteFileResp(CompleteFileResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::AbandonBlockResp(AbandonBlockResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::GetAdditionalStoreResp(GetAdditionalStoreResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::RegisterStoreResp(RegisterStoreResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::HeartbeatResp(HeartbeatResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::DeleteFileResp(
+            resp @ (DeleteFileResp::Rejected
+            | DeleteFileResp::SafeMode
+            | DeleteFileResp::PermissionDenied),
+        ) => format!("{resp:?}"),
+        ControlResp::DeleteDirectoryResp(
+            resp @ (DeleteDirectoryResp::Rejected
+            | DeleteDirectoryResp::SafeMode
+            | DeleteDirectoryResp::PermissionDenied),
+        ) => format!("{resp:?}"),
+        ControlResp::RenameResp(RenameResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::MkdirResp(MkdirResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::GetBlockLocationsResp(GetBlockLocationsResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::ListResp(ListResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::StatResp(StatResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::SetReplicationResp(SetReplicationResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::SetTimesResp(SetTimesResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::ChownResp(ChownResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::ChmodResp(ChmodResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::SetXattrResp(SetXattrResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::GetXattrResp(GetXattrResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::RemoveXattrResp(RemoveXattrResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::ListXattrsResp(ListXattrsResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::SetQuotaResp(SetQuotaResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::ClearQuotaResp(ClearQuotaResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::ContentSummaryResp(ContentSummaryResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::CreateSnapshotResp(CreateSnapshotResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::DeleteSnapshotResp(DeleteSnapshotResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::SnapshotDiffResp(SnapshotDiffResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::ConcatResp(ConcatResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::GetFileChecksumResp(GetFileChecksumResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::ForceCloseResp(ForceCloseResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::DecommissionStoreResp(DecommissionStoreResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::MaintenanceResp(MaintenanceResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::ReportBadBlockResp(ReportBadBlockResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::FsckResp(FsckResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::ListOpenFilesResp(ListOpenFilesResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::SafeModeResp(SafeModeResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::RefreshStoresResp(RefreshStoresResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        ControlResp::MetaSaveResp(MetaSaveResp::Rejected(rejected)) => format!("{rejected:?}"),
+        ControlResp::SubscribeEventsResp(SubscribeEventsResp::Rejected(rejected)) => {
+            format!("{rejected:?}")
+        }
+        _ => return None,
+    })
+}
+
+pub fn request_paths(msg: &ControlReq) -> Vec<&str> {
+    match msg {
+        ControlReq::OpenReq(req) => vec![&req.path],
+        ControlReq::OpenLeaseReq(req) => vec![&req.path],
+        ControlReq::RenewLeasesReq(req) => req.paths.iter().map(|path| path.as_str()).collect(),
+        ControlReq::CloseReq(req) => vec![&req.path],
+        ControlReq::AllocBlockReq(req) => vec![&req.path],
+        ControlReq::CompleteFileReq(req) => vec![&req.path],
+        ControlReq::AbandonBlockReq(req) => vec![&req.path],
+        ControlReq::GetAdditionalStoreReq(req) => vec![&req.path],
+        ControlReq::DeleteFileReq(req) => vec![&req.path],
+        ControlReq::DeleteDirectoryReq(req) => vec![&req.path],
+        ControlReq::RenameReq(req) => vec![&req.src, &req.dst],
+        ControlReq::MkdirReq(req) => vec![&req.path],
+        ControlReq::GetBlockLocationsReq(req) => vec![&req.path],
+        ControlReq::ListReq(req) => vec![&req.path],
+        ControlReq::StatReq(req) => vec![&req.path],
+        ControlReq::SetReplicationReq(req) => vec![&req.path],
+        ControlReq::SetTimesReq(req) => vec![&req.path],
+        ControlReq::ChownReq(req) => vec![&req.path],
+        ControlReq::ChmodReq(req) => vec![&req.path],
+        ControlReq::SetXattrReq(req) => vec![&req.path],
+        ControlReq::GetXattrReq(req) => vec![&req.path],
+        ControlReq::RemoveXattrReq(req) => vec![&req.path],
+        ControlReq::ListXattrsReq(req) => vec![&req.path],
+        ControlReq::SetQuotaReq(req) => vec![&req.path],
+        ControlReq::ClearQuotaReq(req) => vec![&req.path],
+        ControlReq::ContentSummaryReq(req) => vec![&req.path],
+        ControlReq::CreateSnapshotReq(req) => vec![&req.path],
+        ControlReq::DeleteSnapshotReq(req) => vec![&req.path],
+        ControlReq::SnapshotDiffReq(req) => vec![&req.path],
+        ControlReq::ConcatReq(req) => iter::once(&req.target)
+            .chain(&req.sources)
+            .map(|path| path.as_str())
+            .collect(),
+        ControlReq::GetFileChecksumReq(req) => vec![&req.path],
+        ControlReq::ForceCloseReq(req) => vec![&req.path],
+        ControlReq::SubscribeEventsReq(req) => vec![&req.path_prefix],
+        ControlReq::ListOpenFilesReq(req) => req.prefix.iter().map(|path| path.as_str()).collect(),
+        ControlReq::ListCorruptFilesReq(req) => {
+            req.prefix.iter().map(|path| path.as_str()).collect()
+        }
+        ControlReq::FsckReq(req) => iter::once(&req.path)
+            .chain(&req.start_after)
+            .map(|path| path.as_str())
+            .collect(),
+        ControlReq::RegisterStoreReq(_)
+        | ControlReq::HeartbeatReq(_)
+        | ControlReq::BlockReportReq(_)
+        | ControlReq::ReplicationFailedReq(_)
+        | ControlReq::SafeModeReq(_)
+        | ControlReq::DecommissionStoreReq(_)
+        | ControlReq::ListStoresReq(_)
+        | ControlReq::ClusterStatusReq(_)
+        | ControlReq::RefreshStoresReq(_)
+        | ControlReq::MetaSaveReq(_)
+        | ControlReq::EnterMaintenanceReq(_)
+        | ControlReq::ExitMaintenanceReq(_)
+        | ControlReq::ReportBadBlockReq(_) => vec![],
+    }
+}
+
+fn safe_mode_rejection(msg: &ControlReq) -> Option<ControlResp> {
+    Some(match msg {
+        ControlReq::OpenReq(open_req) if open_req.write => {
+            ControlResp::OpenResp(OpenResp::Rejected(OpenRejected::SafeMode))
+        }
+        ControlReq::AllocBlockReq(_) => {
+            ControlResp::AllocBlockResp(AllocBlockResp::Rejected(AllocBlockRejected::SafeMode))
+        }
+        ControlReq::GetAdditionalStoreReq(_) => ControlResp::GetAdditionalStoreResp(
+            GetAdditionalStoreResp::Rejected(GetAdditionalStoreRejected::SafeMode),
+        ),
+        ControlReq::AbandonBlockReq(_) => ControlResp::AbandonBlockResp(
+            AbandonBlockResp::Rejected(AbandonBlockRejected::SafeMode),
+        ),
+        ControlReq::CompleteFileReq(_) => ControlResp::CompleteFileResp(
+            CompleteFileResp::Rejected(CompleteFileRejected::SafeMode),
+        ),
+        ControlReq::DeleteFileReq(_) => ControlResp::DeleteFileResp(DeleteFileResp::SafeMode),
+        ControlReq::DeleteDirectoryReq(_) => {
+            ControlResp::DeleteDirectoryResp(DeleteDirectoryResp::SafeMode)
+        }
+        ControlReq::RenameReq(_) => {
+            ControlResp::RenameResp(RenameResp::Rejected(RenameRejected::SafeMode))
+        }
+        ControlReq::MkdirReq(_) => {
+            ControlResp::MkdirResp(MkdirResp::Rejected(MkdirRejected::SafeMode))
+        }
+        ControlReq::SetReplicationReq(_) => ControlResp::SetReplicationResp(
+            SetReplicationResp::Rejected(SetReplicationRejected::SafeMode),
+        ),
+        ControlReq::SetTimesReq(_) => {
+            ControlResp::SetTimesResp(SetTimesResp::Rejected(SetTimesRejected::SafeMode))
+        }
+        ControlReq::ChownReq(_) => {
+            ControlResp::ChownResp(ChownResp::Rejected(ChownRejected::SafeMode))
+        }
+        ControlReq::ChmodReq(_) => {
+            ControlResp::ChmodResp(ChmodResp::Rejected(ChmodRejected::SafeMode))
+        }
+        ControlReq::SetQuotaReq(_) => {
+            ControlResp::SetQuotaResp(SetQuotaResp::Rejected(SetQuotaRejected::SafeMode))
+        }
+        ControlReq::ClearQuotaReq(_) => {
+            ControlResp::ClearQuotaResp(ClearQuotaResp::Rejected(ClearQuotaRejected::SafeMode))
+        }
+        ControlReq::SetXattrReq(_) => {
+            ControlResp::SetXattrResp(SetXattrResp::Rejected(SetXattrRejected::SafeMode))
+        }
+        ControlReq::RemoveXattrReq(_) => {
+            ControlResp::RemoveXattrResp(RemoveXattrResp::Rejected(RemoveXattrRejected::SafeMode))
+        }
+        ControlReq::ConcatReq(_) => {
+            ControlResp::ConcatResp(ConcatResp::Rejected(ConcatRejected::SafeMode))
+        }
+        ControlReq::FsckReq(fsck_req) if fsck_req.delete_orphans => {
+            ControlResp::FsckResp(FsckResp::Rejected(FsckRejected::SafeMode))
+        }
+        ControlReq::CreateSnapshotReq(_) => ControlResp::CreateSnapshotResp(
+            CreateSnapshotResp::Rejected(CreateSnapshotRejected::SafeMode),
+        ),
+        ControlReq::DeleteSnapshotReq(_) => ControlResp::DeleteSnapshotResp(
+            DeleteSnapshotResp::Rejected(DeleteSnapshotRejected::SafeMode),
+        ),
+        // breaking a write lease recovers the file, which is an edit
+        ControlReq::ForceCloseReq(_) => {
+            ControlResp::ForceCloseResp(ForceCloseResp::Rejected(ForceCloseRejected::SafeMode))
+        }
+        _ => return None,
+    })
+}
+
+/// Answers a request whose edit failed after validation: a failed append
+/// the way safe mode would have, anything else with the edit's error.
+fn edit_failed(e: EditApplyError, if_log_fails: Option<ControlResp>) -> ControlResp {
+    match (e, if_log_fails) {
+        (EditApplyError::Log(_), Some(resp)) => resp,
+        (e, _) => {
+            error!("validated edit failed: {e}");
+            ControlResp::EditFailed(e.to_string())
+        }
+    }
+}
+
+/// Space the subtree grows by at `replication`, checked against the quotas
+/// inside it.
+fn replication_growth(
+    node: &FsNode,
+    path: &PathSplit,
+    replication: NonZeroUsize,
+) -> Result<u64, QuotaExceeded> {
+    match node.body() {
+        FsNodeBody::Directory(directory) => {
+            let mut growth = 0;
+            for (name, child) in directory.nodes() {
+                growth += replication_growth(child, &path.child(name), replication)?;
+            }
+            if let Some(quota) = directory.attr().quota() {
+                let delta = Usage {
+                    nodes: 0,
+                    space: growth,
+                };
+                quota.check(delta).map_err(|limit| QuotaExceeded {
+                    dir: path.to_uri(),
+                    limit,
+                })?;
+            }
+            Ok(growth)
+        }
+        FsNodeBody::File(file) => {
+            let added = replication
+                .get()
+                .saturating_sub(file.attr().replication().get());
+            Ok(file.len() * added as u64)
+        }
+    }
+}
+
+fn block_size((start, end): (u64, u64)) -> Option<u64> {
+    end.checked_sub(start)
+}
+
+/// What a heartbeat changes.
+#[derive(Debug)]
+struct StoreState {
+    statuses: StoreStatusesMap,
+    /// Sent with the next heartbeat.
+    commands: HashMap<StoreId, Vec<StoreCommand>>,
+    invalidate_queue: InvalidateQueue,
+}
+
+/// What a full block report changes in the block map.
+#[derive(Debug, Clone)]
+pub struct FullReportDiff {
+    store: StoreId,
+    /// The reported ids, split by shard.
+    reported: Vec<HashSet<BlockId>>,
+    /// Recorded on the store but not reported.
+    missing: Vec<BlockId>,
+    /// Reported but not recorded on the store.
+    unrecorded: Vec<ReportedBlock>,
+}
+impl FullReportDiff {
+    /// Splits `blocks` by shard once, then compares each part under its
+    /// own shard's lock alone, so the handler lock is not needed and no
+    /// other shard is held up meanwhile. `index` is the store's in the
+    /// block map, if it has one yet.
+    pub fn new(
+        shards: &BlockShards,
+        store: StoreId,
+        index: Option<StoreIndex>,
+        blocks: &BlockList,
+    ) -> Self {
+        let mut diff = Self {
+            store,
+            reported: vec![],
+            missing: vec![],
+            unrecorded: vec![],
+        };
+        for (i, part) in shards.partition(blocks.blocks()).into_iter().enumerate() {
+            let reported: HashSet<BlockId> = part.iter().map(|block| block.id().clone()).collect();
+            let shard = shards.read(i);
+            if let Some(index) = index {
+                let missing = shard.blocks_on(index).filter(|id| !reported.contains(*id));
+                diff.missing.extend(missing.cloned());
+            }
+            let unrecorded = part.into_iter().filter(|block| {
+                !index.is_some_and(|index| shard.stores(block.id()).contains(&index))
+            });
+            diff.unrecorded.extend(unrecorded.cloned());
+            drop(shard);
+            diff.reported.push(reported);
+        }
+        diff
+    }
+    fn was_reported(&self, id: &BlockId) -> bool {
+        self.reported[shard_of(id, self.reported.len())].contains(id)
+    }
+}
+
+#[derive(Debug)]
+enum EditApplyError {
+    InvalidPath,
+    CreateFile(FsNodeCreateFileError),
+    CreateDirs(FsNodeCreateDirsError),
+    NotFound,
+    Rename(FsNodeRenameError),
+    Snapshot(SnapshotError),
+    Log(io::Error),
+}
+impl fmt::Display for EditApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPath => write!(f, "invalid path"),
+            Self::CreateFile(e) => e.fmt(f),
+            Self::CreateDirs(e) => e.fmt(f),
+            Self::NotFound => write!(f, "not found"),
+            Self::Rename(e) => e.fmt(f),
+            Self::Snapshot(e) => e.fmt(f),
+            Self::Log(e) => write!(f, "edit log: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
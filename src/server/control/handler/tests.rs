@@ -0,0 +1,1289 @@
+use std::time::{Duration, SystemTime};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    clock::ManualClock,
+    fs::virt::PathLimitError,
+    proto::{
+        control::*,
+        store::{HeartbeatReq, RegisterStoreReq},
+    },
+    store::StoreAddr,
+    testing::{self, superuser},
+};
+
+use super::*;
+
+const STORE: &str = "store-0";
+
+fn heartbeat(handler: &mut Handler) {
+    heartbeat_from(handler, STORE);
+}
+fn heartbeat_from(handler: &mut Handler, store: &str) -> HeartbeatResp {
+    handler.handle_heartbeat(HeartbeatReq {
+        store: store.into(),
+        capacity: 1 << 30,
+        used: 0,
+        remaining: 1 << 30,
+        block_count: 0,
+        failed_volumes: 0,
+    })
+}
+
+fn register(handler: &mut Handler, store: &str, port: u16) {
+    handler.handle_register(RegisterStoreReq {
+        store: store.into(),
+        addr: store_addr(port),
+        rack: None,
+        capacity: 1 << 30,
+        cluster_id: None,
+    });
+}
+fn store_addr(port: u16) -> StoreAddr {
+    StoreAddr::Socket(([127, 0, 0, 1], port).into())
+}
+
+fn user() -> Caller {
+    Caller::new("alice".into(), vec!["alice".into()])
+}
+
+/// A handler out of safe mode with one registered store.
+fn handler() -> Handler {
+    handler_on(&ManualClock::new())
+}
+fn handler_on(clock: &ManualClock) -> Handler {
+    let mut handler = testing::handler(clock);
+    register(&mut handler, STORE, 1);
+    heartbeat(&mut handler);
+    let req = SafeModeReq {
+        action: SafeModeAction::Leave,
+    };
+    handler.handle_req(&superuser(), ControlReq::SafeModeReq(req));
+    handler
+}
+
+/// Each admin request, whether a user's is refused, and whether the
+/// superuser's gets past the guard.
+type AdminCase = (
+    ControlReq,
+    fn(&ControlResp) -> bool,
+    fn(&ControlResp) -> bool,
+);
+
+fn admin_cases() -> Vec<AdminCase> {
+    vec![
+        (
+            ControlReq::DecommissionStoreReq(DecommissionStoreReq {
+                store: STORE.into(),
+            }),
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::DecommissionStoreResp(DecommissionStoreResp::Rejected(
+                        DecommissionStoreRejected::PermissionDenied
+                    ))
+                )
+            },
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::DecommissionStoreResp(DecommissionStoreResp::Ok)
+                )
+            },
+        ),
+        (
+            ControlReq::RefreshStoresReq(RefreshStoresReq {
+                stores: vec![],
+                hosts: Default::default(),
+            }),
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::RefreshStoresResp(RefreshStoresResp::Rejected(
+                        RefreshStoresRejected::PermissionDenied
+                    ))
+                )
+            },
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::RefreshStoresResp(RefreshStoresResp::Ok(_))
+                )
+            },
+        ),
+        (
+            ControlReq::EnterMaintenanceReq(EnterMaintenanceReq {
+                store: STORE.into(),
+                until: SystemTime::now() + Duration::from_secs(3600),
+            }),
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::MaintenanceResp(MaintenanceResp::Rejected(
+                        MaintenanceRejected::PermissionDenied
+                    ))
+                )
+            },
+            |resp| matches!(resp, ControlResp::MaintenanceResp(MaintenanceResp::Ok)),
+        ),
+        (
+            ControlReq::ExitMaintenanceReq(ExitMaintenanceReq {
+                store: STORE.into(),
+            }),
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::MaintenanceResp(MaintenanceResp::Rejected(
+                        MaintenanceRejected::PermissionDenied
+                    ))
+                )
+            },
+            |resp| matches!(resp, ControlResp::MaintenanceResp(MaintenanceResp::Ok)),
+        ),
+        (
+            ControlReq::ForceCloseReq(ForceCloseReq { path: "/f".into() }),
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::ForceCloseResp(ForceCloseResp::Rejected(
+                        ForceCloseRejected::PermissionDenied
+                    ))
+                )
+            },
+            // past the guard, nothing is open
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::ForceCloseResp(ForceCloseResp::Rejected(
+                        ForceCloseRejected::NotOpen
+                    ))
+                )
+            },
+        ),
+        (
+            ControlReq::ListOpenFilesReq(ListOpenFilesReq { prefix: None }),
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::ListOpenFilesResp(ListOpenFilesResp::Rejected(
+                        ListOpenFilesRejected::PermissionDenied
+                    ))
+                )
+            },
+            |resp| {
+                matches!(
+                    resp,
+                    ControlResp::ListOpenFilesResp(ListOpenFilesResp::Ok(_))
+                )
+            },
+        ),
+    ]
+}
+
+#[test]
+fn admin_requests_need_superuser() {
+    for (req, denied, permitted) in admin_cases() {
+        let mut handler = handler();
+        let resp = handler.handle_req(&user(), req.clone());
+        assert!(denied(&resp), "{req:?} not refused: {resp:?}");
+        // read-only ones are guarded on the shared path too
+        if is_read_only(&req) {
+            let resp = handler.handle_read_req(&user(), req.clone()).unwrap();
+            assert!(denied(&resp), "{req:?} not refused: {resp:?}");
+        }
+        let resp = handler.handle_req(&superuser(), req.clone());
+        assert!(permitted(&resp), "{req:?} refused: {resp:?}");
+    }
+}
+
+#[test]
+fn enter_and_leave_safe_mode_need_superuser() {
+    let mut handler = handler();
+    for action in [SafeModeAction::Enter, SafeModeAction::Leave] {
+        let req = ControlReq::SafeModeReq(SafeModeReq { action });
+        let resp = handler.handle_req(&user(), req);
+        assert!(matches!(
+            resp,
+            ControlResp::SafeModeResp(SafeModeResp::Rejected(SafeModeRejected::PermissionDenied))
+        ));
+    }
+    // anyone may ask whether it is on
+    let req = ControlReq::SafeModeReq(SafeModeReq {
+        action: SafeModeAction::Get,
+    });
+    let resp = handler.handle_req(&user(), req);
+    assert!(matches!(
+        resp,
+        ControlResp::SafeModeResp(SafeModeResp::Ok(_))
+    ));
+}
+
+fn mkdir(handler: &mut Handler, path: &str) {
+    let req = MkdirReq {
+        path: path.into(),
+        create_parents: true,
+    };
+    handler.handle_req(&superuser(), ControlReq::MkdirReq(req));
+}
+
+fn rename(handler: &mut Handler, src: &str, dst: &str) -> ControlResp {
+    let req = RenameReq {
+        src: src.into(),
+        dst: dst.into(),
+    };
+    handler.handle_req(&superuser(), ControlReq::RenameReq(req))
+}
+
+#[test]
+fn quota_usage_follows_edits_in_and_out_of_the_quota() {
+    let mut handler = handler();
+    mkdir(&mut handler, "/q");
+    let req = SetQuotaReq {
+        path: "/q".into(),
+        max_nodes: Some(2),
+        max_space: None,
+    };
+    handler.handle_req(&superuser(), ControlReq::SetQuotaReq(req));
+    mkdir(&mut handler, "/q/a/b");
+    // outside any quota, so nothing is charged
+    mkdir(&mut handler, "/x/y");
+    assert!(handler.validate().is_empty());
+
+    let resp = rename(&mut handler, "/x", "/q/x");
+    assert!(matches!(
+        resp,
+        ControlResp::RenameResp(RenameResp::Rejected(RenameRejected::QuotaExceeded(_)))
+    ));
+    let resp = rename(&mut handler, "/q/a/b", "/b");
+    assert!(matches!(resp, ControlResp::RenameResp(RenameResp::Ok)));
+    let req = DeleteDirectoryReq {
+        path: "/x/y".into(),
+        recursive: false,
+        skip_trash: true,
+    };
+    handler.handle_req(&superuser(), ControlReq::DeleteDirectoryReq(req));
+    let resp = rename(&mut handler, "/x", "/q/x");
+    assert!(matches!(resp, ControlResp::RenameResp(RenameResp::Ok)));
+    assert!(handler.validate().is_empty());
+
+    let resp = rename(&mut handler, "/b", "/q/b");
+    assert!(matches!(
+        resp,
+        ControlResp::RenameResp(RenameResp::Rejected(RenameRejected::QuotaExceeded(_)))
+    ));
+}
+
+/// A closed file at `path` holding one block of 10 bytes.
+fn file_with_block(handler: &mut Handler, path: &str) {
+    file_with_blocks(handler, path, 1, 1);
+}
+
+/// A closed file at `path` holding `blocks` blocks of 10 bytes each.
+fn file_with_blocks(
+    handler: &mut Handler,
+    path: &str,
+    replication: usize,
+    blocks: u64,
+) -> Vec<BlockId> {
+    let path = PathSplit::from_uri(path);
+    let ids: Vec<BlockId> = (0..blocks)
+        .map(|i| format!("blk_{}_{i}", path.to_uri()).into())
+        .collect();
+    let create = EditRecord::CreateFile {
+        path: path.clone(),
+        replication: NonZeroUsize::new(replication).unwrap(),
+        perm: Permission::default(),
+    };
+    let allocs = ids.iter().zip(0..).map(|(id, i)| EditRecord::AllocBlock {
+        path: path.clone(),
+        off_range: (i * 10, (i + 1) * 10),
+        block: id.clone(),
+    });
+    let complete = EditRecord::CompleteFile { path: path.clone() };
+    for record in iter::once(create).chain(allocs).chain([complete]) {
+        handler.log_and_apply(record).unwrap();
+    }
+    ids
+}
+
+fn open(handler: &mut Handler, client: &str, path: &str, flags: [bool; 4]) -> OpenResp {
+    let [create, exclusive, append, overwrite] = flags;
+    let req = OpenReq {
+        client: client.into(),
+        write: true,
+        create,
+        exclusive,
+        append,
+        overwrite,
+        path: path.into(),
+    };
+    let ControlResp::OpenResp(resp) = handler.handle_req(&superuser(), ControlReq::OpenReq(req))
+    else {
+        panic!("not an open response");
+    };
+    resp
+}
+
+#[test]
+fn open_for_write_under_every_flag_combination() {
+    for bits in 0..16u8 {
+        let flags = [0, 1, 2, 3].map(|i| bits & (1 << i) != 0);
+        let [create, exclusive, append, overwrite] = flags;
+        let invalid = (append && (overwrite || exclusive)) || (exclusive && (!create || overwrite));
+
+        let mut handler = handler();
+        file_with_block(&mut handler, "/f");
+        let resp = open(&mut handler, "c", "/f", flags);
+        match resp {
+            OpenResp::Rejected(OpenRejected::InvalidMode) => assert!(invalid, "{flags:?}"),
+            OpenResp::Rejected(OpenRejected::FileExists) => {
+                assert!(
+                    !invalid && (exclusive || !(append || overwrite)),
+                    "{flags:?}"
+                );
+            }
+            OpenResp::Ok(ok) => {
+                assert!(!invalid && (append || overwrite), "{flags:?}");
+                assert_eq!(ok.len, if overwrite { 0 } else { 10 }, "{flags:?}");
+            }
+            resp => panic!("{flags:?}: {resp:?}"),
+        }
+
+        let resp = open(&mut handler, "c", "/g", flags);
+        match resp {
+            OpenResp::Rejected(OpenRejected::InvalidMode) => assert!(invalid, "{flags:?}"),
+            OpenResp::Rejected(OpenRejected::NotFound(_)) => {
+                assert!(!invalid && !create, "{flags:?}");
+            }
+            OpenResp::Ok(ok) => {
+                assert!(!invalid && create, "{flags:?}");
+                assert_eq!(ok.len, 0);
+            }
+            resp => panic!("{flags:?}: {resp:?}"),
+        }
+        let created = handler
+            .virt_fs
+            .get_by_segs(PathSplit::from_uri("/g").names());
+        assert_eq!(created.is_some(), !invalid && create, "{flags:?}");
+        assert!(handler.validate().is_empty());
+    }
+}
+
+#[test]
+fn modifiers_on_a_read_are_invalid() {
+    let mut handler = handler();
+    file_with_block(&mut handler, "/f");
+    let req = OpenReq {
+        client: "c".into(),
+        write: false,
+        create: false,
+        exclusive: false,
+        append: false,
+        overwrite: true,
+        path: "/f".into(),
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::OpenReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::OpenResp(OpenResp::Rejected(OpenRejected::InvalidMode))
+    ));
+}
+
+#[test]
+fn conflicting_overwrite_leaves_the_file_alone() {
+    let mut handler = handler();
+    file_with_block(&mut handler, "/f");
+    let append = [false, false, true, false];
+    let overwrite = [true, false, false, true];
+    assert!(matches!(
+        open(&mut handler, "a", "/f", append),
+        OpenResp::Ok(_)
+    ));
+    assert!(matches!(
+        open(&mut handler, "b", "/f", overwrite),
+        OpenResp::Rejected(OpenRejected::Conflict {
+            held_for_write: true
+        })
+    ));
+    let file = handler
+        .virt_fs
+        .get_by_segs(PathSplit::from_uri("/f").names());
+    let FsNodeBody::File(file) = file.unwrap().body() else {
+        panic!("not a file");
+    };
+    assert_eq!(file.len(), 10);
+}
+
+fn alloc(handler: &mut Handler, client: &str, path: &str, off_range: (u64, u64)) -> AllocBlockResp {
+    let req = AllocBlockReq {
+        client: client.into(),
+        path: path.into(),
+        off_range,
+        request_id: None,
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::AllocBlockReq(req));
+    let ControlResp::AllocBlockResp(resp) = resp else {
+        panic!("not an alloc response");
+    };
+    resp
+}
+
+fn alloc_id(handler: &mut Handler, client: &str, path: &str, off_range: (u64, u64)) -> BlockId {
+    match alloc(handler, client, path, off_range) {
+        AllocBlockResp::Ok(ok) => ok.block,
+        AllocBlockResp::Rejected(e) => panic!("{e:?}"),
+    }
+}
+
+const CREATE: [bool; 4] = [true, true, false, false];
+const APPEND: [bool; 4] = [false, false, true, false];
+
+#[tokio::test]
+async fn block_ids_stay_unique_across_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = dir.path().join("image");
+    let edits = dir.path().join("edits");
+    let clock = ManualClock::new();
+    let mut ids = vec![];
+
+    let mut before = handler_on(&clock);
+    let edit_log = EditLog::open(&edits, Duration::ZERO, clock.now()).unwrap();
+    before.set_edit_log(edit_log);
+    assert!(matches!(
+        open(&mut before, "c", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    ids.push(alloc_id(&mut before, "c", "/f", (0, 10)));
+    before.checkpoint(&image).await.unwrap();
+    // only in the edit log
+    ids.push(alloc_id(&mut before, "c", "/f", (10, 20)));
+    drop(before);
+
+    let mut after = handler_on(&clock);
+    after.load_image(&image).await.unwrap();
+    after.replay_edits(EditLog::read(&edits).unwrap());
+    assert!(matches!(
+        open(&mut after, "c", "/f", APPEND),
+        OpenResp::Ok(_)
+    ));
+    ids.push(alloc_id(&mut after, "c", "/f", (20, 30)));
+    ids.push(alloc_id(&mut after, "c", "/f", (30, 40)));
+
+    let unique: HashSet<_> = ids.iter().collect();
+    assert_eq!(unique.len(), ids.len(), "{ids:?}");
+}
+
+#[test]
+fn failed_edit_log_enters_safe_mode_and_refuses_the_edit() {
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    // every write to it fails with ENOSPC
+    let edit_log = EditLog::open("/dev/full", Duration::ZERO, clock.now()).unwrap();
+    handler.set_edit_log(edit_log);
+
+    let req = MkdirReq {
+        path: "/a".into(),
+        create_parents: false,
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::MkdirReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::MkdirResp(MkdirResp::Rejected(MkdirRejected::SafeMode))
+    ));
+    assert!(handler.safe_mode.is_manual());
+    let req = StatReq { path: "/a".into() };
+    let resp = handler.handle_req(&superuser(), ControlReq::StatReq(req));
+    assert!(matches!(resp, ControlResp::StatResp(StatResp::Rejected(_))));
+}
+
+#[test]
+fn only_the_lease_holder_allocates() {
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    assert!(matches!(
+        open(&mut handler, "a", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    assert!(matches!(
+        alloc(&mut handler, "b", "/f", (0, 10)),
+        AllocBlockResp::Rejected(AllocBlockRejected::NoLease)
+    ));
+    alloc_id(&mut handler, "a", "/f", (0, 10));
+    // nobody ever opened this one
+    file_with_block(&mut handler, "/g");
+    assert!(matches!(
+        alloc(&mut handler, "a", "/g", (10, 20)),
+        AllocBlockResp::Rejected(AllocBlockRejected::NoLease)
+    ));
+
+    clock.advance(LeaseLimits::default().hard + Duration::from_secs(1));
+    heartbeat(&mut handler);
+    handler.handle_timer().unwrap();
+    for client in ["a", "b"] {
+        assert!(matches!(
+            alloc(&mut handler, client, "/f", (10, 20)),
+            AllocBlockResp::Rejected(AllocBlockRejected::NoLease)
+        ));
+    }
+    assert!(matches!(
+        open(&mut handler, "b", "/f", APPEND),
+        OpenResp::Ok(_)
+    ));
+    alloc_id(&mut handler, "b", "/f", (10, 20));
+}
+
+fn report(handler: &mut Handler, ty: BlockReportType, blocks: &[BlockId]) {
+    report_from(handler, STORE, ty, blocks);
+}
+fn report_from(handler: &mut Handler, store: &str, ty: BlockReportType, blocks: &[BlockId]) {
+    let mut body = BlockList::new();
+    for id in blocks {
+        body.push(ReportedBlock::new(id.clone(), BlockBody::new(10, 0)));
+    }
+    let req = BlockReportReq {
+        store: store.into(),
+        report: BlockReport::new(ty, body),
+        chunk: None,
+    };
+    handler.handle_block_report(req, None);
+}
+
+#[test]
+fn heartbeat_asks_for_registration_and_a_full_report() {
+    let mut handler = testing::handler(&ManualClock::new());
+    assert!(matches!(
+        heartbeat_from(&mut handler, STORE),
+        HeartbeatResp::Rejected(HeartbeatRejected::Unregistered)
+    ));
+    register(&mut handler, STORE, 1);
+    let HeartbeatResp::Ok(ok) = heartbeat_from(&mut handler, STORE) else {
+        panic!("registered store refused");
+    };
+    assert!(ok.full_report);
+    report(&mut handler, BlockReportType::Full, &[]);
+    let HeartbeatResp::Ok(ok) = heartbeat_from(&mut handler, STORE) else {
+        panic!("registered store refused");
+    };
+    assert!(!ok.full_report);
+}
+
+#[test]
+fn full_report_drops_a_vanished_replica() {
+    let mut handler = handler();
+    let ids = file_with_blocks(&mut handler, "/f", 1, 3);
+    report(&mut handler, BlockReportType::Full, &ids);
+    for id in &ids {
+        assert_eq!(handler.replicated_blocks.get(id).unwrap().stores().len(), 1);
+    }
+    assert_eq!(handler.replication_queue().clone().pop(), None);
+
+    report(&mut handler, BlockReportType::Full, &ids[..2]);
+    for id in &ids[..2] {
+        assert_eq!(handler.replicated_blocks.get(id).unwrap().stores().len(), 1);
+    }
+    assert!(handler
+        .replicated_blocks
+        .get(&ids[2])
+        .unwrap()
+        .stores()
+        .is_empty());
+    let held = handler.replicated_blocks.blocks_on(&STORE.into());
+    assert_eq!(held.len(), 2);
+    assert_eq!(
+        handler.replication_queue().clone().pop(),
+        Some(ids[2].clone())
+    );
+}
+
+fn stat(handler: &mut Handler, path: &str) -> FileStatus {
+    let req = StatReq { path: path.into() };
+    match handler.handle_req(&superuser(), ControlReq::StatReq(req)) {
+        ControlResp::StatResp(StatResp::Ok(status)) => status,
+        resp => panic!("{resp:?}"),
+    }
+}
+
+fn open_for_read(handler: &mut Handler, client: &str, path: &str) {
+    let req = OpenReq {
+        client: client.into(),
+        write: false,
+        create: false,
+        exclusive: false,
+        append: false,
+        overwrite: false,
+        path: path.into(),
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::OpenReq(req));
+    assert!(matches!(resp, ControlResp::OpenResp(OpenResp::Ok(_))));
+}
+
+#[test]
+fn timestamps_follow_the_clock() {
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    let tick = || {
+        clock.advance(Duration::from_secs(1));
+        clock.system_now()
+    };
+
+    let created = tick();
+    mkdir(&mut handler, "/d");
+    assert_eq!(stat(&mut handler, "/d").ctime, created);
+
+    let opened = tick();
+    assert!(matches!(
+        open(&mut handler, "c1", "/d/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    let file = stat(&mut handler, "/d/f");
+    assert_eq!((file.ctime, file.mtime), (opened, opened));
+    assert_eq!(stat(&mut handler, "/d").mtime, opened);
+
+    // writing to a file leaves its directory alone
+    let written = tick();
+    heartbeat(&mut handler);
+    let block = alloc_id(&mut handler, "c1", "/d/f", (0, 10));
+    report(&mut handler, BlockReportType::Full, &[block]);
+    assert_eq!(stat(&mut handler, "/d/f").mtime, written);
+    assert_eq!(stat(&mut handler, "/d").mtime, opened);
+
+    let completed = tick();
+    let req = CompleteFileReq {
+        client: "c1".into(),
+        path: "/d/f".into(),
+        len: 10,
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::CompleteFileReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::CompleteFileResp(CompleteFileResp::Ok)
+    ));
+    let file = stat(&mut handler, "/d/f");
+    assert_eq!((file.ctime, file.mtime), (opened, completed));
+
+    // reads only move atime once tracking is on
+    tick();
+    open_for_read(&mut handler, "c2", "/d/f");
+    assert_eq!(stat(&mut handler, "/d/f").atime, opened);
+    handler.set_track_atime(true);
+    let read = tick();
+    open_for_read(&mut handler, "c3", "/d/f");
+    let file = stat(&mut handler, "/d/f");
+    assert_eq!((file.mtime, file.atime), (completed, read));
+
+    let preserved = created - Duration::from_secs(3600);
+    let req = SetTimesReq {
+        path: "/d/f".into(),
+        mtime: Some(preserved),
+        atime: None,
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::SetTimesReq(req));
+    assert!(matches!(resp, ControlResp::SetTimesResp(SetTimesResp::Ok)));
+    let file = stat(&mut handler, "/d/f");
+    assert_eq!((file.mtime, file.atime), (preserved, read));
+
+    // readers still hold /d/f open, so move a closed file instead
+    mkdir(&mut handler, "/e");
+    file_with_block(&mut handler, "/d/g");
+    let written = stat(&mut handler, "/d/g").mtime;
+    let renamed = tick();
+    let resp = rename(&mut handler, "/d/g", "/e/g");
+    assert!(matches!(resp, ControlResp::RenameResp(RenameResp::Ok)));
+    assert_eq!(stat(&mut handler, "/d").mtime, renamed);
+    assert_eq!(stat(&mut handler, "/e").mtime, renamed);
+    assert_eq!(stat(&mut handler, "/e/g").mtime, written);
+
+    let deleted = tick();
+    let req = DeleteFileReq {
+        path: "/e/g".into(),
+        skip_trash: true,
+    };
+    handler.handle_req(&superuser(), ControlReq::DeleteFileReq(req));
+    assert_eq!(stat(&mut handler, "/e").mtime, deleted);
+    assert_eq!(stat(&mut handler, "/d").mtime, renamed);
+}
+
+fn set_xattr(handler: &mut Handler, path: &str, name: &str, value: &[u8]) -> SetXattrResp {
+    let req = SetXattrReq {
+        path: path.into(),
+        name: name.into(),
+        value: value.to_vec(),
+    };
+    match handler.handle_req(&superuser(), ControlReq::SetXattrReq(req)) {
+        ControlResp::SetXattrResp(resp) => resp,
+        resp => panic!("{resp:?}"),
+    }
+}
+
+fn get_xattr(handler: &mut Handler, path: &str, name: &str) -> GetXattrResp {
+    let req = GetXattrReq {
+        path: path.into(),
+        name: name.into(),
+    };
+    match handler.handle_req(&superuser(), ControlReq::GetXattrReq(req)) {
+        ControlResp::GetXattrResp(resp) => resp,
+        resp => panic!("{resp:?}"),
+    }
+}
+
+fn xattr_names(handler: &mut Handler, path: &str) -> Vec<String> {
+    let req = ListXattrsReq { path: path.into() };
+    match handler.handle_req(&superuser(), ControlReq::ListXattrsReq(req)) {
+        ControlResp::ListXattrsResp(ListXattrsResp::Ok(ok)) => {
+            let mut names = ok.names;
+            names.sort();
+            names
+        }
+        resp => panic!("{resp:?}"),
+    }
+}
+
+#[tokio::test]
+async fn xattrs_survive_rename_and_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = dir.path().join("image");
+    let mut handler = handler();
+    handler.set_xattr_limits(XattrLimits {
+        max_entry: 16,
+        max_per_node: 24,
+    });
+    file_with_block(&mut handler, "/f");
+
+    assert!(matches!(
+        set_xattr(&mut handler, "/f", "hash", b"abc"),
+        SetXattrResp::Ok
+    ));
+    assert!(matches!(
+        set_xattr(&mut handler, "/f", "pipeline", b"42"),
+        SetXattrResp::Ok
+    ));
+    assert!(matches!(
+        set_xattr(&mut handler, "/f", "big", &[0; 16]),
+        SetXattrResp::Rejected(SetXattrRejected::TooLarge)
+    ));
+    // 7 + 10 bytes already taken, so this one tips the node over
+    assert!(matches!(
+        set_xattr(&mut handler, "/f", "more", b"1234"),
+        SetXattrResp::Rejected(SetXattrRejected::TooLarge)
+    ));
+    // replacing a value only counts the difference
+    assert!(matches!(
+        set_xattr(&mut handler, "/f", "hash", b"abcd"),
+        SetXattrResp::Ok
+    ));
+    assert!(matches!(
+        set_xattr(&mut handler, "/missing", "hash", b"abc"),
+        SetXattrResp::Rejected(SetXattrRejected::NotFound)
+    ));
+
+    assert!(matches!(
+        rename(&mut handler, "/f", "/g"),
+        ControlResp::RenameResp(RenameResp::Ok)
+    ));
+    assert_eq!(xattr_names(&mut handler, "/g"), ["hash", "pipeline"]);
+    let req = RemoveXattrReq {
+        path: "/g".into(),
+        name: "pipeline".into(),
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::RemoveXattrReq(req.clone()));
+    assert!(matches!(
+        resp,
+        ControlResp::RemoveXattrResp(RemoveXattrResp::Ok)
+    ));
+    let resp = handler.handle_req(&superuser(), ControlReq::RemoveXattrReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::RemoveXattrResp(RemoveXattrResp::Rejected(RemoveXattrRejected::NoSuchAttr))
+    ));
+
+    handler.checkpoint(&image).await.unwrap();
+    let mut restarted = handler_on(&ManualClock::new());
+    restarted.load_image(&image).await.unwrap();
+    assert_eq!(xattr_names(&mut restarted, "/g"), ["hash"]);
+    let GetXattrResp::Ok(ok) = get_xattr(&mut restarted, "/g", "hash") else {
+        panic!("hash is gone");
+    };
+    assert_eq!(ok.value, b"abcd");
+    assert!(matches!(
+        get_xattr(&mut restarted, "/g", "pipeline"),
+        GetXattrResp::Rejected(GetXattrRejected::NoSuchAttr)
+    ));
+}
+
+#[test]
+fn xattrs_are_settable_on_a_file_open_for_write() {
+    let mut handler = handler();
+    assert!(matches!(
+        open(&mut handler, "writer", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    // metadata, not data, so the writer's lease does not guard it
+    assert!(matches!(
+        set_xattr(&mut handler, "/f", "hash", b"abc"),
+        SetXattrResp::Ok
+    ));
+    assert_eq!(xattr_names(&mut handler, "/f"), ["hash"]);
+}
+
+#[test]
+fn hundred_thousand_segment_path_is_refused() {
+    let mut handler = handler();
+    mkdir(&mut handler, "/a/a");
+    let path = "/a".repeat(100_000);
+
+    let req = MkdirReq {
+        path: path.clone(),
+        create_parents: true,
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::MkdirReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::MkdirResp(MkdirResp::Rejected(MkdirRejected::PathLimit(
+            PathLimitError::PathTooDeep { .. }
+        )))
+    ));
+    let resp = open(&mut handler, "c", &path, CREATE);
+    assert!(matches!(
+        resp,
+        OpenResp::Rejected(OpenRejected::PathLimit(PathLimitError::PathTooDeep { .. }))
+    ));
+    let resp = rename(&mut handler, "/a/a", &path);
+    assert!(matches!(
+        resp,
+        ControlResp::RenameResp(RenameResp::Rejected(RenameRejected::PathLimit(_)))
+    ));
+
+    // lookups walk the path in a loop, so they fail without overflowing
+    let req = StatReq { path: path.clone() };
+    let resp = handler.handle_req(&superuser(), ControlReq::StatReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::StatResp(StatResp::Rejected(StatRejected::NotFound(_)))
+    ));
+    let req = ListReq {
+        path,
+        start_after: None,
+        limit: None,
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::ListReq(req));
+    assert!(matches!(resp, ControlResp::ListResp(ListResp::Rejected(_))));
+}
+
+fn open_files(handler: &mut Handler) -> Vec<String> {
+    let req = ListOpenFilesReq { prefix: None };
+    match handler.handle_req(&superuser(), ControlReq::ListOpenFilesReq(req)) {
+        ControlResp::ListOpenFilesResp(ListOpenFilesResp::Ok(ok)) => {
+            ok.files.into_iter().map(|file| file.path).collect()
+        }
+        resp => panic!("{resp:?}"),
+    }
+}
+
+#[test]
+fn expired_lease_is_released_by_the_timer() {
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    assert!(matches!(
+        open(&mut handler, "c1", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    let block = alloc_id(&mut handler, "c1", "/f", (0, 10));
+    report(&mut handler, BlockReportType::Full, &[block]);
+
+    clock.advance(Duration::from_secs(59));
+    assert!(matches!(
+        open(&mut handler, "c2", "/f", APPEND),
+        OpenResp::Rejected(_)
+    ));
+    heartbeat(&mut handler);
+    handler.handle_timer().unwrap();
+    assert_eq!(open_files(&mut handler), ["/f"]);
+
+    clock.advance(LeaseLimits::default().hard);
+    heartbeat(&mut handler);
+    handler.handle_timer().unwrap();
+    assert!(open_files(&mut handler).is_empty());
+    assert_eq!(stat(&mut handler, "/f").len, 10);
+    assert!(matches!(
+        open(&mut handler, "c2", "/f", APPEND),
+        OpenResp::Ok(_)
+    ));
+}
+
+#[test]
+fn stale_writer_is_taken_over_after_the_soft_limit() {
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    assert!(matches!(
+        open(&mut handler, "c1", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    let block = alloc_id(&mut handler, "c1", "/f", (0, 10));
+    report(&mut handler, BlockReportType::Full, &[block]);
+
+    clock.advance(LeaseLimits::default().soft + Duration::from_secs(1));
+    assert!(matches!(
+        open(&mut handler, "c2", "/f", APPEND),
+        OpenResp::Ok(_)
+    ));
+    assert_eq!(stat(&mut handler, "/f").len, 10);
+    heartbeat(&mut handler);
+    assert!(matches!(
+        alloc(&mut handler, "c1", "/f", (10, 20)),
+        AllocBlockResp::Rejected(AllocBlockRejected::NoLease)
+    ));
+}
+
+#[test]
+fn leases_are_renewed_under_the_shared_lock() {
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    assert!(matches!(
+        open(&mut handler, "c1", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    let renew = |handler: &Handler, client: &str| {
+        let req = ControlReq::RenewLeasesReq(RenewLeasesReq {
+            client: client.into(),
+            paths: vec!["/f".into()],
+        });
+        let Ok(ControlResp::RenewLeasesResp(resp)) = handler.handle_read_req(&user(), req) else {
+            panic!("not answered under the shared lock");
+        };
+        resp.lost
+    };
+
+    clock.advance(LeaseLimits::default().hard - Duration::from_secs(1));
+    assert!(renew(&handler, "c1").is_empty());
+    assert_eq!(renew(&handler, "c2"), ["/f"]);
+    clock.advance(Duration::from_secs(2));
+    heartbeat(&mut handler);
+    handler.handle_timer().unwrap();
+    assert_eq!(open_files(&mut handler), ["/f"]);
+}
+
+#[test]
+fn recovered_lease_replays_to_the_same_namespace() {
+    let dir = tempfile::tempdir().unwrap();
+    let edits = dir.path().join("edits");
+    let clock = ManualClock::new();
+    let mut before = handler_on(&clock);
+    let edit_log = EditLog::open(&edits, Duration::ZERO, clock.now()).unwrap();
+    before.set_edit_log(edit_log);
+    assert!(matches!(
+        open(&mut before, "c", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    let block = alloc_id(&mut before, "c", "/f", (0, 10));
+    report(&mut before, BlockReportType::Full, &[block]);
+    let req = ForceCloseReq { path: "/f".into() };
+    let resp = before.handle_req(&superuser(), ControlReq::ForceCloseReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::ForceCloseResp(ForceCloseResp::Ok)
+    ));
+
+    let entries = EditLog::read(&edits).unwrap();
+    assert!(matches!(
+        entries.last().unwrap().record(),
+        EditRecord::RecoverLease {
+            last_block_len: Some(10),
+            ..
+        }
+    ));
+    let mut after = handler_on(&clock);
+    after.replay_edits(entries);
+    for handler in [&mut before, &mut after] {
+        let status = stat(handler, "/f");
+        assert_eq!((status.len, status.block_count), (10, 1));
+        let node = handler
+            .virt_fs
+            .get_by_segs(PathSplit::from_uri("/f").names());
+        let FsNodeBody::File(file) = node.unwrap().body() else {
+            panic!("not a file");
+        };
+        assert!(!file.is_under_construction());
+    }
+}
+
+#[test]
+fn refused_edit_is_not_logged() {
+    let dir = tempfile::tempdir().unwrap();
+    let edits = dir.path().join("edits");
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    let edit_log = EditLog::open(&edits, Duration::ZERO, clock.now()).unwrap();
+    handler.set_edit_log(edit_log);
+    file_with_block(&mut handler, "/f");
+    let logged = EditLog::read(&edits).unwrap().len();
+
+    assert!(matches!(
+        rename(&mut handler, "/missing", "/g"),
+        ControlResp::RenameResp(RenameResp::Rejected(RenameRejected::SrcNotExist))
+    ));
+    let req = MkdirReq {
+        path: "/f/d".into(),
+        create_parents: true,
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::MkdirReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::MkdirResp(MkdirResp::Rejected(MkdirRejected::NotDirectory(_)))
+    ));
+    assert!(matches!(
+        handler.log_and_apply(EditRecord::AbandonBlock {
+            path: PathSplit::from_uri("/f"),
+            block: "blk_0".into(),
+        }),
+        Err(EditApplyError::NotFound)
+    ));
+    assert_eq!(EditLog::read(&edits).unwrap().len(), logged);
+    assert_eq!(handler.last_txid, logged as u64);
+}
+
+#[test]
+fn close_by_the_last_writer_is_logged() {
+    let dir = tempfile::tempdir().unwrap();
+    let edits = dir.path().join("edits");
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    let edit_log = EditLog::open(&edits, Duration::ZERO, clock.now()).unwrap();
+    handler.set_edit_log(edit_log);
+    assert!(matches!(
+        open(&mut handler, "c", "/f", CREATE),
+        OpenResp::Ok(_)
+    ));
+    let req = CloseReq {
+        client: "c".into(),
+        path: "/f".into(),
+    };
+    let resp = handler.handle_req(&superuser(), ControlReq::CloseReq(req));
+    assert!(matches!(
+        resp,
+        ControlResp::CloseResp(CloseResp { permitted: true })
+    ));
+    let entries = EditLog::read(&edits).unwrap();
+    assert!(matches!(
+        entries.last().unwrap().record(),
+        EditRecord::CloseFile { .. }
+    ));
+}
+
+#[test]
+fn edit_failing_after_its_checks_is_answered() {
+    let refused = ControlResp::MkdirResp(MkdirResp::Rejected(MkdirRejected::SafeMode));
+    let log = || EditApplyError::Log(io::Error::other("disk gone"));
+    assert!(matches!(
+        edit_failed(log(), Some(refused)),
+        ControlResp::MkdirResp(MkdirResp::Rejected(MkdirRejected::SafeMode))
+    ));
+    assert!(matches!(
+        edit_failed(log(), None),
+        ControlResp::EditFailed(_)
+    ));
+    assert!(matches!(
+        edit_failed(EditApplyError::NotFound, None),
+        ControlResp::EditFailed(_)
+    ));
+}
+
+#[test]
+fn dead_store_replicas_are_copied_from_a_survivor() {
+    let clock = ManualClock::new();
+    let mut handler = handler_on(&clock);
+    register(&mut handler, "store-1", 2);
+    register(&mut handler, "store-2", 3);
+    let beat_survivors = |handler: &mut Handler| {
+        heartbeat_from(handler, STORE);
+        heartbeat_from(handler, "store-2")
+    };
+    heartbeat_from(&mut handler, "store-1");
+    beat_survivors(&mut handler);
+    let ids = file_with_blocks(&mut handler, "/f", 2, 1);
+    report_from(&mut handler, STORE, BlockReportType::Full, &ids);
+    report_from(&mut handler, "store-1", BlockReportType::Full, &ids);
+    report_from(&mut handler, "store-2", BlockReportType::Full, &[]);
+
+    clock.advance(Duration::from_secs(29));
+    beat_survivors(&mut handler);
+    handler.handle_timer().unwrap();
+    assert_eq!(
+        handler
+            .replicated_blocks
+            .get(&ids[0])
+            .unwrap()
+            .stores()
+            .len(),
+        2
+    );
+    let HeartbeatResp::Ok(ok) = heartbeat_from(&mut handler, STORE) else {
+        panic!("heartbeat rejected");
+    };
+    assert!(ok.commands.is_empty());
+
+    clock.advance(Duration::from_secs(2));
+    beat_survivors(&mut handler);
+    handler.handle_timer().unwrap();
+    assert_eq!(
+        handler
+            .replicated_blocks
+            .get(&ids[0])
+            .unwrap()
+            .stores()
+            .len(),
+        1
+    );
+    let stores: Vec<_> = handler.replicated_blocks.stores(&ids[0]).iter().collect();
+    assert_eq!(stores, [&StoreId::from(STORE)]);
+    let HeartbeatResp::Ok(ok) = heartbeat_from(&mut handler, STORE) else {
+        panic!("heartbeat rejected");
+    };
+    assert!(matches!(
+        &ok.commands[..],
+        [StoreCommand::ReplicateBlock { block, targets }]
+            if *block == ids[0] && *targets == [store_addr(3)]
+    ));
+}
+
+fn exists(handler: &mut Handler, path: &str) -> bool {
+    let req = StatReq { path: path.into() };
+    matches!(
+        handler.handle_req(&superuser(), ControlReq::StatReq(req)),
+        ControlResp::StatResp(StatResp::Ok(_))
+    )
+}
+
+#[test]
+fn random_namespace_edits_keep_the_invariants() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut handler = handler();
+    let mut dirs = vec![String::new()];
+    let mut files: Vec<String> = vec![];
+    for n in 0..500 {
+        let dir = dirs[rng.gen_range(0..dirs.len())].clone();
+        let req = match rng.gen_range(0..6) {
+            0 => {
+                let path = format!("{dir}/d{n}");
+                mkdir(&mut handler, &path);
+                dirs.push(path);
+                continue;
+            }
+            1 if dir.is_empty() || exists(&mut handler, &dir) => {
+                let path = format!("{dir}/f{n}");
+                let blocks = rng.gen_range(0..3);
+                file_with_blocks(&mut handler, &path, rng.gen_range(1..4), blocks);
+                files.push(path);
+                continue;
+            }
+            2 => {
+                let pool = if rng.gen() { &dirs } else { &files };
+                let Some(src) = pool.get(rng.gen_range(0..pool.len().max(1))) else {
+                    continue;
+                };
+                ControlReq::RenameReq(RenameReq {
+                    src: src.clone(),
+                    dst: format!("{dir}/r{n}"),
+                })
+            }
+            3 if !files.is_empty() => ControlReq::DeleteFileReq(DeleteFileReq {
+                path: files[rng.gen_range(0..files.len())].clone(),
+                skip_trash: rng.gen(),
+            }),
+            4 if !dir.is_empty() => match rng.gen_range(0..3) {
+                0 => ControlReq::DeleteDirectoryReq(DeleteDirectoryReq {
+                    path: dir,
+                    recursive: true,
+                    skip_trash: rng.gen(),
+                }),
+                1 => ControlReq::SetQuotaReq(SetQuotaReq {
+                    path: dir,
+                    max_nodes: Some(rng.gen_range(5..50)),
+                    max_space: Some(rng.gen_range(50..500)),
+                }),
+                _ => ControlReq::ClearQuotaReq(ClearQuotaReq { path: dir }),
+            },
+            _ => ControlReq::SetReplicationReq(SetReplicationReq {
+                path: dir,
+                replication: NonZeroUsize::new(rng.gen_range(1..4)).unwrap(),
+                recursive: true,
+            }),
+        };
+        handler.handle_req(&superuser(), req);
+        assert_eq!(handler.validate(), [], "after edit {n}");
+    }
+}
+
+#[tokio::test]
+async fn image_with_a_violation_stays_in_safe_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = dir.path().join("image");
+    let mut handler = handler();
+    mkdir(&mut handler, "/d");
+    let bad = handler
+        .virt_fs
+        .get(PathCursor::new(PathSplit::from_uri("/d")));
+    let bad = bad.unwrap().clone();
+    let FsNodeBody::Directory(root) = handler.virt_fs.body_mut() else {
+        panic!("root is not a directory");
+    };
+    root.nodes_mut().insert("a/b".into(), Arc::new(bad));
+    assert!(matches!(
+        &handler.validate()[..],
+        [InvariantViolation::InvalidName { .. }]
+    ));
+    handler.checkpoint(&image).await.unwrap();
+
+    let leave = || {
+        ControlReq::SafeModeReq(SafeModeReq {
+            action: SafeModeAction::Leave,
+        })
+    };
+    let mut strict = testing::handler(&ManualClock::new());
+    strict.load_image(&image).await.unwrap();
+    strict.handle_req(&superuser(), leave());
+    assert!(strict.safe_mode.is_on());
+
+    let mut lenient = testing::handler(&ManualClock::new());
+    lenient.set_allow_invariant_violations(true);
+    lenient.load_image(&image).await.unwrap();
+    lenient.handle_req(&superuser(), leave());
+    assert!(!lenient.safe_mode.is_on());
+}
+
+#[test]
+fn heartbeats_share_the_handler_with_reads() {
+    let mut handler = handler();
+    for i in 0..8 {
+        register(&mut handler, &format!("s{i}"), 10 + i);
+    }
+    let handler = &handler;
+    std::thread::scope(|s| {
+        for i in 0..8 {
+            s.spawn(move || {
+                let list = ControlReq::ListStoresReq(ListStoresReq {});
+                assert!(handler.handle_read_req(&superuser(), list).is_ok());
+                let resp = handler.handle_heartbeat(HeartbeatReq {
+                    store: format!("s{i}").into(),
+                    capacity: 1 << 30,
+                    used: 0,
+                    remaining: i,
+                    block_count: 0,
+                    failed_volumes: 0,
+                });
+                assert!(matches!(resp, HeartbeatResp::Ok(_)));
+            });
+        }
+    });
+    let list = ControlReq::ListStoresReq(ListStoresReq {});
+    let Ok(ControlResp::ListStoresResp(resp)) = handler.handle_read_req(&superuser(), list) else {
+        panic!("stores not listed");
+    };
+    for i in 0..8 {
+        let store = resp
+            .stores
+            .iter()
+            .find(|s| s.store == format!("s{i}").into());
+        assert_eq!(store.unwrap().remaining, i);
+    }
+}
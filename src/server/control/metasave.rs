@@ -0,0 +1,136 @@
+use std::{
+    io::{self, Write},
+    time::Instant,
+};
+
+use crate::{
+    fs::{
+        block::{BlockId, ReplicatedBlocksMap},
+        inode::InodeTable,
+        replication::{InvalidateQueue, ReplicationQueue},
+        virt::{FsNode, OpenFileTable},
+    },
+    store::StoreId,
+};
+
+/// A copy of the block map, queues and open-file table, taken under the
+/// handler lock so the dump itself can be written after releasing it.
+#[derive(Debug, Clone)]
+pub struct MetaSave {
+    virt_fs: FsNode,
+    inodes: InodeTable,
+    blocks: ReplicatedBlocksMap,
+    replication_queue: ReplicationQueue,
+    invalidate_queue: InvalidateQueue,
+    /// Store, queued commands, queued invalidations.
+    stores: Vec<(StoreId, usize, usize)>,
+    open_table: OpenFileTable,
+    now: Instant,
+}
+impl MetaSave {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        virt_fs: FsNode,
+        inodes: InodeTable,
+        blocks: ReplicatedBlocksMap,
+        replication_queue: ReplicationQueue,
+        invalidate_queue: InvalidateQueue,
+        stores: Vec<(StoreId, usize, usize)>,
+        open_table: OpenFileTable,
+        now: Instant,
+    ) -> Self {
+        Self {
+            virt_fs,
+            inodes,
+            blocks,
+            replication_queue,
+            invalidate_queue,
+            stores,
+            open_table,
+            now,
+        }
+    }
+
+    /// One line per entry, so the output is never held in memory as a whole.
+    pub fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut ids: Vec<BlockId> = vec![];
+        self.blocks.visit(&mut |id, _| ids.push(id.clone()));
+        ids.sort();
+        writeln!(w, "blocks: {}", ids.len())?;
+        for id in ids {
+            let block = self.blocks.get(&id).unwrap();
+            let expected = match self
+                .blocks
+                .replication_target(&id, &self.virt_fs, &self.inodes)
+            {
+                Some(target) => target.to_string(),
+                None => "-".to_string(),
+            };
+            let path = match self.inodes.path(block.inode()) {
+                Some(path) => path.to_string(),
+                None => "-".to_string(),
+            };
+            writeln!(
+                w,
+                "{id} size={} gen={} expected={expected} live={} stores=[{}] inode={} path={path}",
+                block.body().size(),
+                block.body().generation(),
+                block.stores().len(),
+                self.blocks.stores(&id).to_vec().join(", "),
+                block.inode(),
+            )?;
+        }
+
+        writeln!(
+            w,
+            "\nreplication queued: {}",
+            self.replication_queue.queued_len()
+        )?;
+        for (live, block) in self.replication_queue.iter_queued() {
+            writeln!(w, "{block} live={live}")?;
+        }
+        writeln!(
+            w,
+            "\nreplication pending: {}",
+            self.replication_queue.pending_len()
+        )?;
+        for (block, pending) in self.replication_queue.iter_pending() {
+            writeln!(
+                w,
+                "{block} targets=[{}] deadline_in={:?}",
+                pending.targets().join(", "),
+                pending.deadline().saturating_duration_since(self.now),
+            )?;
+        }
+
+        writeln!(w, "\ninvalidation queue: {}", self.invalidate_queue.len())?;
+        for (store, blocks) in self.invalidate_queue.iter() {
+            for block in blocks {
+                writeln!(w, "{store} {block}")?;
+            }
+        }
+
+        writeln!(w, "\nstores: {}", self.stores.len())?;
+        for (store, commands, invalidations) in &self.stores {
+            writeln!(
+                w,
+                "{store} commands={commands} invalidations={invalidations}"
+            )?;
+        }
+
+        let mut open: Vec<_> = self.open_table.iter().collect();
+        open.sort_by_cached_key(|(path, _)| path.to_uri());
+        writeln!(w, "\nopen files: {}", open.len())?;
+        for (path, attr) in open {
+            let holders: Vec<&str> = attr.holders().map(|client| client.as_ref()).collect();
+            writeln!(
+                w,
+                "{path} write={} holders=[{}] since_lease={:?}",
+                attr.write(),
+                holders.join(", "),
+                attr.since_latest_lease(self.now).unwrap_or_default(),
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,10 @@
 pub mod config;
+pub mod events;
 pub mod handler;
+pub mod metasave;
+pub mod node;
+pub mod placement;
+pub mod report;
+pub mod retry_cache;
+pub mod safe_mode;
+pub mod server;
@@ -0,0 +1,323 @@
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{net::TcpListener, sync::RwLock, task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    fs::{
+        block::{BlockIdGenerator, ReplicatedBlocksMap},
+        edit_log::EditLog,
+        inode::ROOT_INODE,
+        perm::Permission,
+        virt::{Directory, DirectoryAttribute, FsNode, FsNodeAttribute, FsNodeBody, OpenFileTable},
+    },
+    metrics,
+    proto::{control::RefreshStoresRespOk, MAX_FRAME},
+    store::StoreStatusesMap,
+};
+
+use super::{
+    config::{ControlNodeConfig, ControlSettings},
+    handler::Handler,
+    server,
+};
+
+const IMAGE_FILE: &str = "fsimage";
+const EDITS_FILE: &str = "edits";
+
+/// A control node serving on its configured address, with the handler's
+/// timer and checkpoints running in the background.
+#[derive(Debug)]
+pub struct ControlNode {
+    handler: Arc<RwLock<Handler>>,
+    addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
+    image: Option<PathBuf>,
+    tasks: Vec<JoinHandle<()>>,
+}
+impl ControlNode {
+    /// Picks up the namespace from `meta_dir`, if set, where the last run
+    /// left it.
+    pub async fn start(config: &ControlNodeConfig) -> io::Result<Self> {
+        let settings = settings(config)?;
+        let timer_interval = settings.heartbeat_interval;
+        let mut handler = handler(config, settings);
+        let image = match config.meta_dir() {
+            Some(meta_dir) => {
+                Some(restore(&mut handler, meta_dir, config.edit_sync_interval()).await?)
+            }
+            None => {
+                warn!("no meta_dir; the namespace is lost on restart");
+                None
+            }
+        };
+        let handler = Arc::new(RwLock::new(handler));
+        let listener = TcpListener::bind(config.addr()).await?;
+        let addr = listener.local_addr()?;
+        info!(%addr, "control node listening");
+        let serve = {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server::serve(listener, handler, MAX_FRAME).await {
+                    warn!("control listener failed: {e}");
+                }
+            })
+        };
+        let timer = {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(timer_interval);
+                loop {
+                    interval.tick().await;
+                    // the handler is in safe mode by now, so keep ticking
+                    if let Err(e) = handler.write().await.handle_timer() {
+                        warn!("timer tick failed: {e}");
+                    }
+                }
+            })
+        };
+        let mut tasks = vec![serve, timer];
+        let mut metrics_addr = None;
+        if let Some(addr) = config.metrics_addr() {
+            let listener = TcpListener::bind(addr).await?;
+            let addr = listener.local_addr()?;
+            info!(%addr, "serving metrics");
+            metrics_addr = Some(addr);
+            let metrics = handler.read().await.metrics().clone();
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = metrics::serve(listener, metrics).await {
+                    warn!("metrics listener failed: {e}");
+                }
+            }));
+        }
+        if let Some(image) = image.clone() {
+            let handler = handler.clone();
+            let period = config.checkpoint_interval();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = time::interval_at(time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = handler.write().await.checkpoint(&image).await {
+                        warn!("checkpoint failed: {e}");
+                    }
+                }
+            }));
+        }
+        Ok(Self {
+            handler,
+            addr,
+            metrics_addr,
+            image,
+            tasks,
+        })
+    }
+    /// Stops serving, then checkpoints so the next start has no edits to
+    /// replay.
+    pub async fn shutdown(self) -> io::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        if let Some(image) = &self.image {
+            self.handler.write().await.checkpoint(image).await?;
+            info!("namespace saved");
+        }
+        Ok(())
+    }
+    /// Applies the store and host lists of `config`, as reloaded from the
+    /// config file; every other setting takes a restart.
+    pub async fn refresh_stores(
+        &self,
+        config: &ControlNodeConfig,
+    ) -> io::Result<RefreshStoresRespOk> {
+        let hosts = settings(config)?.hosts;
+        let mut handler = self.handler.write().await;
+        Ok(handler.refresh_stores(config.stores(), hosts))
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+    pub fn handler(&self) -> &Arc<RwLock<Handler>> {
+        &self.handler
+    }
+}
+impl Drop for ControlNode {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// [`Config::load`](crate::server::config::Config::load) has already
+/// checked these, so an error here means the config was built by hand.
+fn settings(config: &ControlNodeConfig) -> io::Result<ControlSettings> {
+    config.settings().map_err(|errors| {
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        io::Error::new(io::ErrorKind::InvalidInput, errors.join("; "))
+    })
+}
+
+/// Loads the image and replays the edits logged since, then logs further
+/// edits next to them, synced every `sync_interval`. Returns where the
+/// image goes.
+async fn restore(
+    handler: &mut Handler,
+    meta_dir: &Path,
+    sync_interval: Duration,
+) -> io::Result<PathBuf> {
+    tokio::fs::create_dir_all(meta_dir).await?;
+    let image = meta_dir.join(IMAGE_FILE);
+    let edits = meta_dir.join(EDITS_FILE);
+    match handler.load_image(&image).await {
+        Ok(()) => info!(image = %image.display(), "namespace loaded"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!(image = %image.display(), "no image; starting empty")
+        }
+        Err(e) => return Err(e),
+    }
+    let entries = EditLog::read(&edits)?;
+    if !entries.is_empty() {
+        info!(edits = entries.len(), "replaying edit log");
+    }
+    handler.replay_edits(entries);
+    handler.set_edit_log(EditLog::open(&edits, sync_interval, Instant::now())?);
+    Ok(image)
+}
+
+/// An empty namespace, set up as `config` says.
+fn handler(config: &ControlNodeConfig, settings: ControlSettings) -> Handler {
+    let clock = Arc::new(SystemClock);
+    let root = FsNode::new(
+        FsNodeAttribute::new(ROOT_INODE, clock.system_now(), Permission::default()),
+        FsNodeBody::Directory(Directory::new(DirectoryAttribute::new())),
+    );
+    let hosts = settings.hosts.clone();
+    let blocks = ReplicatedBlocksMap::with_shards(settings.block_map_shards);
+    let mut handler = Handler::new(
+        root,
+        OpenFileTable::new(),
+        StoreStatusesMap::new(),
+        blocks,
+        BlockIdGenerator::new(),
+        settings,
+        clock,
+    );
+    handler.set_placement_policy(config.placement().build());
+    handler.set_track_atime(config.track_atime());
+    handler.set_allow_invariant_violations(config.allow_invariant_violations());
+    handler.set_superuser(config.superuser().into());
+    handler.set_umask(config.umask());
+    handler.set_trash_retention(config.trash_retention());
+    if let Some(window) = config.event_window() {
+        handler.set_event_window(window);
+    }
+    handler.refresh_stores(config.stores(), hosts);
+    handler
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        fs::perm::Caller,
+        proto::control::{
+            ControlReq, ControlResp, MkdirReq, SafeModeAction, SafeModeReq, StatReq, StatResp,
+        },
+    };
+
+    use super::*;
+
+    fn config(meta_dir: &Path) -> ControlNodeConfig {
+        let text = format!("addr = \"127.0.0.1:0\"\nmeta_dir = {meta_dir:?}\nstores = []\n");
+        toml::from_str(&text).unwrap()
+    }
+    fn superuser(config: &ControlNodeConfig) -> Caller {
+        Caller::new(config.superuser().into(), vec![])
+    }
+    async fn mkdir(node: &ControlNode, caller: &Caller, path: &str) {
+        let mut handler = node.handler().write().await;
+        let req = SafeModeReq {
+            action: SafeModeAction::Leave,
+        };
+        handler.handle_req(caller, ControlReq::SafeModeReq(req));
+        let req = MkdirReq {
+            path: path.into(),
+            create_parents: true,
+        };
+        handler.handle_req(caller, ControlReq::MkdirReq(req));
+    }
+    async fn exists(node: &ControlNode, caller: &Caller, path: &str) -> bool {
+        let req = StatReq { path: path.into() };
+        let resp = node
+            .handler()
+            .write()
+            .await
+            .handle_req(caller, ControlReq::StatReq(req));
+        matches!(resp, ControlResp::StatResp(StatResp::Ok(_)))
+    }
+
+    #[tokio::test]
+    async fn namespace_survives_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let caller = superuser(&config);
+
+        // a crash leaves only the edit log
+        let node = ControlNode::start(&config).await.unwrap();
+        mkdir(&node, &caller, "/a").await;
+        drop(node);
+        assert!(!dir.path().join(IMAGE_FILE).exists());
+
+        let node = ControlNode::start(&config).await.unwrap();
+        assert!(exists(&node, &caller, "/a").await);
+        mkdir(&node, &caller, "/b").await;
+        node.shutdown().await.unwrap();
+        assert!(dir.path().join(IMAGE_FILE).exists());
+
+        let node = ControlNode::start(&config).await.unwrap();
+        assert!(exists(&node, &caller, "/a").await);
+        assert!(exists(&node, &caller, "/b").await);
+    }
+
+    #[tokio::test]
+    async fn edit_sync_interval_is_configurable() {
+        let dir = tempfile::tempdir().unwrap();
+        // unset, every edit is synced before its request is answered
+        assert_eq!(config(dir.path()).edit_sync_interval(), Duration::ZERO);
+        let text = format!(
+            "addr = \"127.0.0.1:0\"\nmeta_dir = {:?}\nstores = []\nedit_sync_interval_ms = 250\n",
+            dir.path()
+        );
+        let config: ControlNodeConfig = toml::from_str(&text).unwrap();
+        assert_eq!(config.edit_sync_interval(), Duration::from_millis(250));
+        let caller = superuser(&config);
+        let node = ControlNode::start(&config).await.unwrap();
+        mkdir(&node, &caller, "/a").await;
+        node.shutdown().await.unwrap();
+        let node = ControlNode::start(&config).await.unwrap();
+        assert!(exists(&node, &caller, "/a").await);
+    }
+
+    #[tokio::test]
+    async fn reloaded_config_refreshes_the_store_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = ControlNode::start(&config(dir.path())).await.unwrap();
+        let text = format!(
+            "addr = \"127.0.0.1:0\"\nmeta_dir = {:?}\nstores = [{{ addr = \"127.0.0.1:9001\", id = \"s1\" }}]\nexclude_hosts = [\"127.0.0.1\"]\n",
+            dir.path()
+        );
+        let reloaded: ControlNodeConfig = toml::from_str(&text).unwrap();
+        let ok = node.refresh_stores(&reloaded).await.unwrap();
+        assert_eq!(ok.added, ["s1".into()]);
+        assert_eq!(ok.excluded, ["s1".into()]);
+    }
+}
@@ -0,0 +1,357 @@
+use std::fmt::Debug;
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{StoreId, StoreStatusesMap};
+
+pub trait PlacementPolicy: Debug + Send + Sync {
+    fn choose(
+        &mut self,
+        needed: usize,
+        stores: &StoreStatusesMap,
+        exclude: &[StoreId],
+    ) -> Vec<StoreId>;
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum PlacementPolicyKind {
+    RoundRobin,
+    Random,
+    #[default]
+    AvailableSpace,
+    RackAware,
+}
+impl PlacementPolicyKind {
+    pub fn build(self) -> Box<dyn PlacementPolicy> {
+        match self {
+            PlacementPolicyKind::RoundRobin => Box::new(RoundRobinPolicy::new()),
+            PlacementPolicyKind::Random => Box::new(RandomPolicy::new()),
+            PlacementPolicyKind::AvailableSpace => Box::new(AvailableSpacePolicy::new()),
+            PlacementPolicyKind::RackAware => Box::new(RackAwarePolicy::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RoundRobinPolicy {
+    next: usize,
+}
+impl RoundRobinPolicy {
+    pub fn new() -> Self {
+        Self { next: 0 }
+    }
+}
+impl Default for RoundRobinPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PlacementPolicy for RoundRobinPolicy {
+    fn choose(
+        &mut self,
+        needed: usize,
+        stores: &StoreStatusesMap,
+        exclude: &[StoreId],
+    ) -> Vec<StoreId> {
+        let mut candidates = candidates(stores, exclude);
+        if candidates.is_empty() {
+            return vec![];
+        }
+        candidates.sort();
+        let start = self.next % candidates.len();
+        self.next = self.next.wrapping_add(1);
+        candidates
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(needed.min(candidates.len()))
+            .map(|store| (*store).clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RandomPolicy {
+    rng: StdRng,
+}
+impl RandomPolicy {
+    pub fn new() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+        }
+    }
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+impl Default for RandomPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PlacementPolicy for RandomPolicy {
+    fn choose(
+        &mut self,
+        needed: usize,
+        stores: &StoreStatusesMap,
+        exclude: &[StoreId],
+    ) -> Vec<StoreId> {
+        let mut candidates = candidates(stores, exclude);
+        candidates.sort();
+        candidates
+            .choose_multiple(&mut self.rng, needed)
+            .map(|store| (*store).clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AvailableSpacePolicy {
+    rng: StdRng,
+}
+impl AvailableSpacePolicy {
+    pub fn new() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+        }
+    }
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+impl Default for AvailableSpacePolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PlacementPolicy for AvailableSpacePolicy {
+    fn choose(
+        &mut self,
+        needed: usize,
+        stores: &StoreStatusesMap,
+        exclude: &[StoreId],
+    ) -> Vec<StoreId> {
+        let mut candidates = candidates(stores, exclude);
+        candidates.sort();
+        // weight by free space; the +1 keeps full stores pickable as a last resort
+        let mut pool: Vec<(&StoreId, u128)> = candidates
+            .into_iter()
+            .map(|store| {
+                let remaining = stores.get(store).unwrap().remaining();
+                (store, u128::from(remaining) + 1)
+            })
+            .collect();
+        let mut chosen = vec![];
+        while chosen.len() < needed && !pool.is_empty() {
+            let total: u128 = pool.iter().map(|(_, weight)| weight).sum();
+            let mut pick = self.rng.gen_range(0..total);
+            let i = pool
+                .iter()
+                .position(|(_, weight)| {
+                    if pick < *weight {
+                        return true;
+                    }
+                    pick -= weight;
+                    false
+                })
+                .unwrap();
+            let (store, _) = pool.swap_remove(i);
+            chosen.push(store.clone());
+        }
+        chosen
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RackAwarePolicy {
+    rng: StdRng,
+}
+impl RackAwarePolicy {
+    pub fn new() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+        }
+    }
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+impl Default for RackAwarePolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PlacementPolicy for RackAwarePolicy {
+    fn choose(
+        &mut self,
+        needed: usize,
+        stores: &StoreStatusesMap,
+        exclude: &[StoreId],
+    ) -> Vec<StoreId> {
+        let mut pool = candidates(stores, exclude);
+        pool.sort();
+        pool.shuffle(&mut self.rng);
+        let rack_of = |store: &StoreId| stores.get(store).unwrap().config().rack();
+        let mut chosen: Vec<StoreId> = vec![];
+        while chosen.len() < needed && !pool.is_empty() {
+            // second replica off the first one's rack, third beside the second
+            let preferred = match chosen.len() {
+                1 => pool
+                    .iter()
+                    .position(|store| rack_of(store) != rack_of(&chosen[0])),
+                2 => pool
+                    .iter()
+                    .position(|store| rack_of(store) == rack_of(&chosen[1])),
+                _ => None,
+            };
+            let store = pool.swap_remove(preferred.unwrap_or(0));
+            chosen.push(store.clone());
+        }
+        chosen
+    }
+}
+
+fn candidates<'a>(stores: &'a StoreStatusesMap, exclude: &[StoreId]) -> Vec<&'a StoreId> {
+    stores
+        .iter()
+        .map(|(store, _)| store)
+        .filter(|store| !exclude.contains(store))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use crate::store::{StoreAddr, StoreConfig, StoreUsage};
+
+    use super::*;
+
+    const PICKS: usize = 10_000;
+
+    /// `(id, rack, remaining)` per store.
+    fn stores(specs: &[(&str, &str, u64)]) -> StoreStatusesMap {
+        let mut stores = StoreStatusesMap::new();
+        for (i, (id, rack, remaining)) in specs.iter().enumerate() {
+            let addr = StoreAddr::Socket(([127, 0, 0, 1], 1 + i as u16).into());
+            stores.insert((*id).into(), StoreConfig::new(addr, Some(rack.to_string())));
+            stores
+                .get_mut(&(*id).into())
+                .unwrap()
+                .set_usage(StoreUsage {
+                    remaining: *remaining,
+                    ..Default::default()
+                });
+        }
+        stores
+    }
+
+    /// How often each store comes up over [`PICKS`] single-store choices.
+    fn shares(policy: &mut dyn PlacementPolicy, stores: &StoreStatusesMap) -> HashMap<String, f64> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..PICKS {
+            for store in policy.choose(1, stores, &[]) {
+                *counts.entry(store.to_string()).or_default() += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .map(|(store, count)| (store, count as f64 / PICKS as f64))
+            .collect()
+    }
+
+    fn policies() -> Vec<Box<dyn PlacementPolicy>> {
+        vec![
+            Box::new(RoundRobinPolicy::new()),
+            Box::new(RandomPolicy::from_seed(1)),
+            Box::new(AvailableSpacePolicy::from_seed(1)),
+            Box::new(RackAwarePolicy::from_seed(1)),
+        ]
+    }
+
+    #[test]
+    fn weighted_prefers_emptier_stores() {
+        let stores = stores(&[("a", "r", 900), ("b", "r", 100)]);
+        let shares = shares(&mut AvailableSpacePolicy::from_seed(1), &stores);
+        assert!((0.87..0.93).contains(&shares["a"]), "{shares:?}");
+    }
+
+    #[test]
+    fn weighted_still_picks_full_stores_last() {
+        let stores = stores(&[("a", "r", 0), ("b", "r", 0), ("c", "r", 1000)]);
+        let mut policy = AvailableSpacePolicy::from_seed(1);
+        for _ in 0..100 {
+            let chosen = policy.choose(3, &stores, &[]);
+            assert_eq!(chosen.len(), 3);
+        }
+        let shares = shares(&mut policy, &stores);
+        assert!(shares["c"] > 0.99, "{shares:?}");
+    }
+
+    #[test]
+    fn random_is_roughly_uniform() {
+        let stores = stores(&[
+            ("a", "r", 1),
+            ("b", "r", 1000),
+            ("c", "r", 1),
+            ("d", "r", 1),
+        ]);
+        let shares = shares(&mut RandomPolicy::from_seed(1), &stores);
+        for share in shares.values() {
+            assert!((0.22..0.28).contains(share), "{shares:?}");
+        }
+    }
+
+    #[test]
+    fn round_robin_is_exactly_even() {
+        let stores = stores(&[("a", "r", 1), ("b", "r", 1), ("c", "r", 1), ("d", "r", 1)]);
+        let shares = shares(&mut RoundRobinPolicy::new(), &stores);
+        for share in shares.values() {
+            assert_eq!(*share, 0.25);
+        }
+    }
+
+    #[test]
+    fn rack_aware_puts_the_second_replica_off_rack() {
+        let stores = stores(&[
+            ("a1", "a", 1),
+            ("a2", "a", 1),
+            ("b1", "b", 1),
+            ("b2", "b", 1),
+        ]);
+        let mut policy = RackAwarePolicy::from_seed(1);
+        for _ in 0..1000 {
+            let chosen = policy.choose(3, &stores, &[]);
+            let rack = |i: usize| stores.get(&chosen[i]).unwrap().config().rack();
+            assert_ne!(rack(0), rack(1));
+            assert_eq!(rack(1), rack(2));
+        }
+    }
+
+    #[test]
+    fn every_policy_honors_exclude_and_never_repeats() {
+        let stores = stores(&[
+            ("a", "a", 10),
+            ("b", "b", 20),
+            ("c", "a", 30),
+            ("d", "b", 40),
+        ]);
+        let exclude: Vec<StoreId> = vec!["b".into()];
+        for mut policy in policies() {
+            for needed in 0..6 {
+                let chosen = policy.choose(needed, &stores, &exclude);
+                assert_eq!(chosen.len(), needed.min(3), "{policy:?}");
+                assert!(!chosen.contains(&exclude[0]), "{policy:?}");
+                let unique: HashSet<_> = chosen.iter().collect();
+                assert_eq!(unique.len(), chosen.len(), "{policy:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{fs::block::BlockList, proto::control::ReportChunk, store::StoreId};
+
+#[derive(Debug, Clone)]
+pub struct PartialReports {
+    map: HashMap<StoreId, PartialReport>,
+    max: usize,
+}
+impl PartialReports {
+    pub fn new(max: usize) -> Self {
+        Self {
+            map: HashMap::new(),
+            max,
+        }
+    }
+    pub fn set_max(&mut self, max: usize) {
+        self.max = max;
+    }
+    pub fn push(
+        &mut self,
+        store: &StoreId,
+        chunk: ReportChunk,
+        blocks: &BlockList,
+    ) -> Result<Option<BlockList>, PartialReportError> {
+        if chunk.seq == 0 {
+            self.map.remove(store);
+            if !chunk.last && self.max <= self.map.len() {
+                return Err(PartialReportError::TooMany);
+            }
+            self.map.insert(
+                store.clone(),
+                PartialReport {
+                    epoch: chunk.epoch,
+                    next_seq: 0,
+                    blocks: BlockList::new(),
+                },
+            );
+        }
+        let Some(partial) = self.map.get_mut(store) else {
+            return Err(PartialReportError::OutOfOrder);
+        };
+        if partial.epoch != chunk.epoch || partial.next_seq != chunk.seq {
+            self.map.remove(store);
+            return Err(PartialReportError::OutOfOrder);
+        }
+        partial.blocks.extend(blocks);
+        partial.next_seq += 1;
+        if !chunk.last {
+            return Ok(None);
+        }
+        Ok(self.map.remove(store).map(|partial| partial.blocks))
+    }
+    pub fn remove(&mut self, store: &StoreId) {
+        self.map.remove(store);
+    }
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PartialReport {
+    epoch: u64,
+    next_seq: u32,
+    blocks: BlockList,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialReportError {
+    TooMany,
+    OutOfOrder,
+}
+impl fmt::Display for PartialReportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooMany => write!(f, "too many partial block reports in flight"),
+            Self::OutOfOrder => write!(f, "block report chunk out of order"),
+        }
+    }
+}
+impl std::error::Error for PartialReportError {}
@@ -0,0 +1,59 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{fs::virt::ClientId, proto::control::AllocBlockRespOk};
+
+#[derive(Debug, Clone)]
+pub struct RetryCache {
+    map: HashMap<(ClientId, u64), CachedAlloc>,
+    ttl: Duration,
+}
+impl RetryCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            map: HashMap::new(),
+            ttl,
+        }
+    }
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+    pub fn get(&self, client: &ClientId, request_id: u64) -> Option<&AllocBlockRespOk> {
+        self.map
+            .get(&(client.clone(), request_id))
+            .map(|cached| &cached.resp)
+    }
+    pub fn insert(
+        &mut self,
+        client: ClientId,
+        request_id: u64,
+        resp: AllocBlockRespOk,
+        now: Instant,
+    ) {
+        self.map.insert(
+            (client, request_id),
+            CachedAlloc {
+                resp,
+                inserted: now,
+            },
+        );
+    }
+    pub fn clear_timeout(&mut self, now: Instant) {
+        self.map
+            .retain(|_, cached| now.duration_since(cached.inserted) < self.ttl);
+    }
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedAlloc {
+    resp: AllocBlockRespOk,
+    inserted: Instant,
+}
@@ -0,0 +1,46 @@
+#[derive(Debug, Clone)]
+pub struct SafeMode {
+    state: SafeModeState,
+    threshold: f64,
+}
+impl SafeMode {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            state: SafeModeState::Auto,
+            threshold,
+        }
+    }
+    pub fn is_on(&self) -> bool {
+        self.state != SafeModeState::Off
+    }
+    pub fn is_manual(&self) -> bool {
+        self.state == SafeModeState::Manual
+    }
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+    pub fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+    pub fn enter(&mut self) {
+        self.state = SafeModeState::Manual;
+    }
+    pub fn leave(&mut self) {
+        self.state = SafeModeState::Off;
+    }
+    pub fn check(&mut self, reported_blocks: usize, total_blocks: usize) {
+        if self.state != SafeModeState::Auto {
+            return;
+        }
+        if total_blocks == 0 || self.threshold <= reported_blocks as f64 / total_blocks as f64 {
+            self.state = SafeModeState::Off;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SafeModeState {
+    Off,
+    Auto,
+    Manual,
+}
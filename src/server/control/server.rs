@@ -0,0 +1,217 @@
+use std::{io, sync::Arc, time::Instant};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{net::TcpListener, net::TcpStream, sync::RwLock, task};
+use tokio_util::codec::Framed;
+use tracing::{field, info_span, warn, Instrument};
+
+use crate::{
+    fs::{block::BlockReportType, perm::Caller},
+    metrics::Metrics,
+    proto::{
+        codec::FrameCodec,
+        control::{ControlReq, ControlResp, SubscribeEventsReq, SubscribeEventsResp},
+        format::WireFormat,
+        handshake, Envelope, TraceId,
+    },
+};
+
+use super::handler::{
+    rejection, request_client, request_name, request_paths, FullReportDiff, Handler,
+};
+
+type ControlFramed = Framed<TcpStream, FrameCodec<Envelope<ControlReq>, Envelope<ControlResp>>>;
+
+pub async fn serve(
+    listener: TcpListener,
+    handler: Arc<RwLock<Handler>>,
+    max_frame: usize,
+) -> io::Result<()> {
+    let metrics = handler.read().await.metrics().clone();
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let handler = handler.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_conn(stream, &handler, &metrics, max_frame).await {
+                warn!(%peer, "closing connection: {e}");
+            }
+        });
+    }
+}
+
+async fn serve_conn(
+    mut stream: TcpStream,
+    handler: &RwLock<Handler>,
+    metrics: &Metrics,
+    max_frame: usize,
+) -> io::Result<()> {
+    let negotiated = handshake::accept(&mut stream, WireFormat::ALL).await?;
+    let codec = FrameCodec::<Envelope<ControlReq>, Envelope<ControlResp>>::new(max_frame)
+        .with_version(negotiated.version)
+        .with_format(negotiated.format);
+    let mut framed = Framed::new(stream, codec);
+    while let Some(req) = framed.next().await {
+        let Envelope {
+            req_id,
+            trace_id,
+            caller,
+            msg,
+        } = req?;
+        let caller = caller.unwrap_or_else(Caller::anonymous);
+        let kind = request_name(&msg);
+        let span = info_span!(
+            "control_req",
+            req_id,
+            trace_id = trace_id.as_deref(),
+            kind,
+            path = request_paths(&msg).first().copied(),
+            client = request_client(&msg),
+            user = %caller.user,
+            outcome = field::Empty,
+        );
+        let start = Instant::now();
+        let mut subscription = None;
+        let resp = async {
+            match msg {
+                ControlReq::MetaSaveReq(req) => {
+                    let dump = handler.read().await.metasave(&caller, req);
+                    ControlResp::MetaSaveResp(dump.await)
+                }
+                // the diff is the slow part of a large report; it takes one
+                // block map shard's lock at a time and not the handler's
+                ControlReq::BlockReportReq(req)
+                    if req.chunk.is_none() && matches!(req.report.ty(), BlockReportType::Full) =>
+                {
+                    let (shards, index) = {
+                        let handler = handler.read().await;
+                        (
+                            handler.block_shards().clone(),
+                            handler.store_index(&req.store),
+                        )
+                    };
+                    let (req, diff) = task::spawn_blocking(move || {
+                        let store = req.store.clone();
+                        let diff = FullReportDiff::new(&shards, store, index, req.report.body());
+                        (req, diff)
+                    })
+                    .await
+                    .expect("diffing a block report panicked");
+                    let resp = handler.write().await.handle_block_report(req, Some(diff));
+                    ControlResp::BlockReportResp(resp)
+                }
+                // changes only store state, which has a lock of its own, so
+                // heartbeats don't wait behind each other or behind reads
+                ControlReq::HeartbeatReq(req) => {
+                    ControlResp::HeartbeatResp(handler.read().await.handle_heartbeat(req))
+                }
+                // answered at once with what is retained; the rest is
+                // streamed once the answer is sent
+                ControlReq::SubscribeEventsReq(req) => {
+                    let msg = ControlReq::SubscribeEventsReq(req.clone());
+                    let resp = handler.read().await.handle_read_req(&caller, msg);
+                    let resp = resp.expect("subscribe_events is read-only");
+                    if let ControlResp::SubscribeEventsResp(SubscribeEventsResp::Ok(ok)) = &resp {
+                        subscription = Some(SubscribeEventsReq {
+                            after_txid: ok.last_txid,
+                            ..req
+                        });
+                    }
+                    resp
+                }
+                msg => {
+                    let res = handler.read().await.handle_read_req(&caller, msg);
+                    match res {
+                        Ok(resp) => resp,
+                        Err(msg) => handler.write().await.handle_req(&caller, msg),
+                    }
+                }
+            }
+        }
+        .instrument(span.clone())
+        .await;
+        metrics.observe(
+            "dfs_control_request_seconds",
+            &[("kind", kind)],
+            start.elapsed(),
+        );
+        let outcome = match rejection(&resp) {
+            Some(reason) => {
+                span.in_scope(|| warn!(reason, "request rejected"));
+                "rejected"
+            }
+            None => "ok",
+        };
+        span.record("outcome", outcome);
+        metrics.inc(
+            "dfs_control_requests_total",
+            &[("kind", kind), ("outcome", outcome)],
+        );
+        let resp = Envelope {
+            req_id,
+            trace_id: trace_id.clone(),
+            caller: None,
+            msg: resp,
+        };
+        framed.send(resp).await?;
+        if let Some(req) = subscription {
+            return stream_events(&mut framed, handler, &caller, req_id, trace_id, req)
+                .instrument(span)
+                .await;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the events of an accepted subscription as they are recorded, each
+/// batch another response to `req_id`, until the client hangs up. The
+/// connection carries nothing else; a request on it is an error.
+async fn stream_events(
+    framed: &mut ControlFramed,
+    handler: &RwLock<Handler>,
+    caller: &Caller,
+    req_id: u64,
+    trace_id: Option<TraceId>,
+    mut req: SubscribeEventsReq,
+) -> io::Result<()> {
+    let notify = handler.read().await.event_notify().clone();
+    loop {
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        // registered before looking, so an event recorded in between still
+        // wakes us
+        notified.as_mut().enable();
+        let msg = ControlReq::SubscribeEventsReq(req.clone());
+        let resp = handler.read().await.handle_read_req(caller, msg);
+        let resp = resp.expect("subscribe_events is read-only");
+        let ControlResp::SubscribeEventsResp(SubscribeEventsResp::Ok(ok)) = &resp else {
+            unreachable!("a subscription is only refused before it starts");
+        };
+        // nothing up to there matched, if anything; no need to look again
+        req.after_txid = ok.last_txid;
+        if !ok.events.is_empty() {
+            let resp = Envelope {
+                req_id,
+                trace_id: trace_id.clone(),
+                caller: None,
+                msg: resp,
+            };
+            framed.send(resp).await?;
+            // a full batch may have left more behind
+            continue;
+        }
+        tokio::select! {
+            () = &mut notified => (),
+            frame = framed.next() => {
+                return match frame {
+                    None => Ok(()),
+                    Some(Err(e)) => Err(e.into()),
+                    Some(Ok(_)) => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "request on a subscribed connection",
+                    )),
+                };
+            }
+        }
+    }
+}
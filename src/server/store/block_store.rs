@@ -0,0 +1,657 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    fs::{
+        block::{
+            validate_block_id, BlockBody, BlockId, BlockList, BlockMeta, BlockReport,
+            BlockReportType, ChunkedChecksum, ReportedBlock,
+        },
+        virt::atomic_persist,
+    },
+    proto::{
+        control::{BlockReportReq, ControlReq},
+        store::HeartbeatReq,
+    },
+    store::{StoreId, StoreUsage},
+};
+
+use super::config::DataDirConfig;
+
+pub const CHECKSUM_CHUNK: u32 = 64 * 1024;
+
+const TMP_DIR: &str = "tmp";
+const CORRUPT_DIR: &str = "corrupt";
+const LAYOUT_FILE: &str = "layout";
+const LAYOUT_VERSION: u32 = 2;
+const META_SUFFIX: &str = ".meta";
+const READ_BUF: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct BlockStore {
+    volumes: Vec<Arc<Volume>>,
+    blocks: HashMap<BlockId, StoredBlock>,
+    next_volume: usize,
+    max_io_errors: u32,
+}
+impl BlockStore {
+    pub fn new(data_dirs: &[DataDirConfig], max_io_errors: u32) -> Self {
+        let volumes = data_dirs
+            .iter()
+            .map(|data_dir| {
+                Arc::new(Volume {
+                    dir: data_dir.path.clone(),
+                    capacity: data_dir.capacity,
+                    io_errors: AtomicU32::new(0),
+                    failed: AtomicBool::new(false),
+                })
+            })
+            .collect();
+        Self {
+            volumes,
+            blocks: HashMap::new(),
+            next_volume: 0,
+            max_io_errors,
+        }
+    }
+    pub fn volumes(&self) -> impl Iterator<Item = &Volume> {
+        self.volumes.iter().map(|volume| volume.as_ref())
+    }
+    pub fn tmp_dirs(&self) -> Vec<PathBuf> {
+        self.healthy_volumes().map(|v| v.tmp_dir()).collect()
+    }
+    pub fn get(&self, block: &BlockId) -> Option<&BlockBody> {
+        self.blocks.get(block).map(|stored| &stored.body)
+    }
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    pub fn report(&self) -> BlockList {
+        let mut list = BlockList::new();
+        for (id, stored) in &self.blocks {
+            list.push(ReportedBlock::new(id.clone(), stored.body.clone()));
+        }
+        list
+    }
+    pub fn usage(&self) -> StoreUsage {
+        let mut usage = StoreUsage::default();
+        for (i, volume) in self.volumes.iter().enumerate() {
+            if volume.is_failed() {
+                usage.failed_volumes += 1;
+                continue;
+            }
+            let used: u64 = self
+                .blocks
+                .values()
+                .filter(|stored| stored.volume == i)
+                .map(|stored| stored.body.size())
+                .sum();
+            usage.capacity += volume.capacity;
+            usage.used += used;
+            usage.remaining += volume.capacity.saturating_sub(used);
+        }
+        usage.block_count = self.blocks.len() as u64;
+        usage
+    }
+    pub fn heartbeat(&self, store: StoreId) -> HeartbeatReq {
+        let usage = self.usage();
+        HeartbeatReq {
+            store,
+            capacity: usage.capacity,
+            used: usage.used,
+            remaining: usage.remaining,
+            block_count: usage.block_count,
+            failed_volumes: usage.failed_volumes,
+        }
+    }
+    pub fn check_volumes(&mut self) -> BlockList {
+        let mut removed = BlockList::new();
+        for (i, volume) in self.volumes.iter().enumerate() {
+            if volume.is_failed() || volume.io_errors.load(Ordering::Relaxed) < self.max_io_errors {
+                continue;
+            }
+            volume.failed.store(true, Ordering::Relaxed);
+            self.blocks.retain(|id, stored| {
+                if stored.volume != i {
+                    return true;
+                }
+                removed.push(ReportedBlock::new(id.clone(), stored.body.clone()));
+                false
+            });
+        }
+        removed
+    }
+
+    pub async fn create(&mut self, block: &BlockId, generation: u64) -> io::Result<BlockWriter> {
+        validate_block_id(block)?;
+        let volume = self.pick_volume()?;
+        let tmp = volume.tmp_path(block, generation);
+        let file = volume.observe(create_file(&tmp).await)?;
+        Ok(BlockWriter {
+            block: block.clone(),
+            generation,
+            volume,
+            file: BufWriter::new(file),
+            tmp,
+            size: 0,
+            checksum: ChunkedChecksum::new(CHECKSUM_CHUNK),
+        })
+    }
+    pub async fn resume(
+        &self,
+        block: &BlockId,
+        from_generation: u64,
+        generation: u64,
+        offset: u64,
+    ) -> io::Result<BlockWriter> {
+        validate_block_id(block)?;
+        let volume = self
+            .find_partial(block, generation)
+            .await
+            .or(self.find_partial(block, from_generation).await)
+            .ok_or(io::ErrorKind::NotFound)?;
+        let tmp = volume.tmp_path(block, generation);
+        if !fs::try_exists(&tmp).await? {
+            let from = volume.tmp_path(block, from_generation);
+            volume.observe(fs::rename(from, &tmp).await)?;
+        }
+        let mut file = volume.observe(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&tmp)
+                .await,
+        )?;
+        if file.metadata().await?.len() < offset {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        volume.observe(file.set_len(offset).await)?;
+        let checksum = volume.observe(checksum_file(&mut file).await)?;
+        Ok(BlockWriter {
+            block: block.clone(),
+            generation,
+            volume,
+            file: BufWriter::new(file),
+            tmp,
+            size: offset,
+            checksum,
+        })
+    }
+    pub async fn discard_partial(&self, block: &BlockId, generation: u64) -> io::Result<()> {
+        validate_block_id(block)?;
+        for volume in self.healthy_volumes() {
+            remove_if_exists(&volume.tmp_path(block, generation)).await?;
+        }
+        Ok(())
+    }
+    pub async fn open_partial(&self, block: &BlockId, generation: u64) -> io::Result<fs::File> {
+        validate_block_id(block)?;
+        let volume = self
+            .find_partial(block, generation)
+            .await
+            .ok_or(io::ErrorKind::NotFound)?;
+        volume.observe(fs::File::open(volume.tmp_path(block, generation)).await)
+    }
+    pub async fn finalize(&mut self, writer: BlockWriter) -> io::Result<BlockBody> {
+        let BlockWriter {
+            block,
+            generation,
+            volume,
+            mut file,
+            tmp,
+            size,
+            checksum,
+        } = writer;
+        volume.observe(file.flush().await)?;
+        volume.observe(file.get_ref().sync_all().await)?;
+        drop(file);
+        let path = volume.block_path(&block, generation);
+        let meta = checksum.finish();
+        volume.observe(commit_block(&tmp, &path, &meta).await)?;
+        let body = BlockBody::with_checksum(size, meta.checksum(), generation);
+        let index = self.volume_index(&volume);
+        let stored = StoredBlock {
+            body: body.clone(),
+            volume: index,
+        };
+        if let Some(prev) = self.blocks.insert(block.clone(), stored) {
+            if prev.body.generation() != generation || prev.volume != index {
+                let prev_volume = &self.volumes[prev.volume];
+                let prev_path = prev_volume.block_path(&block, prev.body.generation());
+                prev_volume.observe(remove_block_files(&prev_path).await)?;
+            }
+        }
+        Ok(body)
+    }
+    pub async fn meta(&mut self, block: &BlockId) -> io::Result<BlockMeta> {
+        validate_block_id(block)?;
+        let Some(stored) = self.blocks.get(block) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        let generation = stored.body.generation();
+        let volume = self.volumes[stored.volume].clone();
+        let path = volume.block_path(block, generation);
+        match read_meta(&meta_path(&path)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => return volume.observe(res),
+        }
+        // blocks written before meta files existed get one on first use
+        let mut file = volume.observe(fs::File::open(&path).await)?;
+        let meta = volume.observe(checksum_file(&mut file).await)?.finish();
+        volume.observe(write_meta(&meta_path(&path), &meta).await)?;
+        self.set_checksum(block, generation, meta.checksum());
+        Ok(meta)
+    }
+    pub async fn open(&self, block: &BlockId) -> io::Result<fs::File> {
+        validate_block_id(block)?;
+        let Some(stored) = self.blocks.get(block) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        let volume = &self.volumes[stored.volume];
+        volume.observe(fs::File::open(volume.block_path(block, stored.body.generation())).await)
+    }
+    pub fn block_path(&self, block: &BlockId) -> io::Result<PathBuf> {
+        validate_block_id(block)?;
+        let stored = self.blocks.get(block).ok_or(io::ErrorKind::NotFound)?;
+        let volume = &self.volumes[stored.volume];
+        Ok(volume.block_path(block, stored.body.generation()))
+    }
+    pub fn next_after(&self, cursor: Option<&BlockId>) -> Option<BlockId> {
+        self.blocks
+            .keys()
+            .filter(|block| cursor.is_none_or(|cursor| cursor < *block))
+            .min()
+            .cloned()
+    }
+    pub fn set_checksum(&mut self, block: &BlockId, generation: u64, checksum: u32) {
+        let Some(stored) = self.blocks.get_mut(block) else {
+            return;
+        };
+        if stored.body.generation() != generation {
+            return;
+        }
+        stored.body = BlockBody::with_checksum(stored.body.size(), checksum, generation);
+    }
+    pub async fn quarantine(&mut self, block: &BlockId) -> io::Result<Option<BlockBody>> {
+        let Some(stored) = self.blocks.remove(block) else {
+            return Ok(None);
+        };
+        let volume = &self.volumes[stored.volume];
+        let path = volume.block_path(block, stored.body.generation());
+        volume.observe(volume.move_to_corrupt(&path).await)?;
+        Ok(Some(stored.body))
+    }
+    pub async fn remove(&mut self, block: &BlockId) -> io::Result<Option<BlockBody>> {
+        let Some(stored) = self.blocks.remove(block) else {
+            return Ok(None);
+        };
+        let volume = &self.volumes[stored.volume];
+        let path = volume.block_path(block, stored.body.generation());
+        volume.observe(remove_block_files(&path).await)?;
+        Ok(Some(stored.body))
+    }
+
+    pub async fn scan(&mut self) -> io::Result<ScanReport> {
+        self.blocks.clear();
+        let mut suspect = vec![];
+        for i in 0..self.volumes.len() {
+            let volume = self.volumes[i].clone();
+            if volume.is_failed() {
+                continue;
+            }
+            let res = self.scan_volume(i, &volume, &mut suspect).await;
+            volume.observe(res)?;
+        }
+        suspect.retain(|block| !self.blocks.contains_key(block));
+        Ok(ScanReport {
+            blocks: self.report(),
+            suspect,
+        })
+    }
+
+    async fn scan_volume(
+        &mut self,
+        index: usize,
+        volume: &Volume,
+        suspect: &mut Vec<BlockId>,
+    ) -> io::Result<()> {
+        // no writer survives a restart
+        gc_tmp_files(&volume.tmp_dir(), Duration::ZERO).await?;
+        let layout = volume.dir.join(LAYOUT_FILE);
+        let legacy = !fs::try_exists(&layout).await?;
+        let mut missing_meta = false;
+        let mut stale = vec![];
+        for path in files_at_depth(&volume.dir, 2).await? {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let Some((block, generation)) = parse_block_file_name(name) else {
+                continue;
+            };
+            let size = fs::metadata(&path).await?.len();
+            let body = match read_meta(&meta_path(&path)).await {
+                Ok(meta) if meta.covers(size) => {
+                    BlockBody::with_checksum(size, meta.checksum(), generation)
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound && legacy => {
+                    missing_meta = true;
+                    BlockBody::new(size, generation)
+                }
+                Err(e)
+                    if e.kind() != io::ErrorKind::NotFound
+                        && e.kind() != io::ErrorKind::InvalidData =>
+                {
+                    return Err(e);
+                }
+                _ => {
+                    volume.move_to_corrupt(&path).await?;
+                    suspect.push(block);
+                    continue;
+                }
+            };
+            let stored = StoredBlock {
+                body,
+                volume: index,
+            };
+            match self.blocks.get(&block) {
+                Some(prev) if generation < prev.body.generation() => {
+                    stale.push(path);
+                }
+                Some(prev) => {
+                    let prev_volume = &self.volumes[prev.volume];
+                    stale.push(prev_volume.block_path(&block, prev.body.generation()));
+                    self.blocks.insert(block, stored);
+                }
+                None => {
+                    self.blocks.insert(block, stored);
+                }
+            }
+        }
+        for path in stale {
+            remove_block_files(&path).await?;
+        }
+        if !missing_meta {
+            atomic_persist(layout, LAYOUT_VERSION.to_string().into_bytes()).await?;
+        }
+        Ok(())
+    }
+    fn healthy_volumes(&self) -> impl Iterator<Item = &Arc<Volume>> {
+        self.volumes.iter().filter(|volume| !volume.is_failed())
+    }
+    fn pick_volume(&mut self) -> io::Result<Arc<Volume>> {
+        for _ in 0..self.volumes.len() {
+            let volume = &self.volumes[self.next_volume % self.volumes.len()];
+            self.next_volume = (self.next_volume + 1) % self.volumes.len();
+            if !volume.is_failed() {
+                return Ok(volume.clone());
+            }
+        }
+        Err(io::Error::other("no healthy data directory"))
+    }
+    fn volume_index(&self, volume: &Arc<Volume>) -> usize {
+        self.volumes
+            .iter()
+            .position(|v| Arc::ptr_eq(v, volume))
+            .unwrap()
+    }
+    async fn find_partial(&self, block: &BlockId, generation: u64) -> Option<Arc<Volume>> {
+        for volume in self.healthy_volumes() {
+            if fs::try_exists(volume.tmp_path(block, generation))
+                .await
+                .unwrap_or(false)
+            {
+                return Some(volume.clone());
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StoredBlock {
+    body: BlockBody,
+    volume: usize,
+}
+
+#[derive(Debug)]
+pub struct Volume {
+    dir: PathBuf,
+    capacity: u64,
+    io_errors: AtomicU32,
+    failed: AtomicBool,
+}
+impl Volume {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+    pub fn io_errors(&self) -> u32 {
+        self.io_errors.load(Ordering::Relaxed)
+    }
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+    fn observe<T>(&self, res: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &res {
+            if e.kind() != io::ErrorKind::NotFound {
+                self.io_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+    fn tmp_dir(&self) -> PathBuf {
+        self.dir.join(TMP_DIR)
+    }
+    fn tmp_path(&self, block: &BlockId, generation: u64) -> PathBuf {
+        self.tmp_dir().join(format!("{block}_{generation}"))
+    }
+    fn block_path(&self, block: &BlockId, generation: u64) -> PathBuf {
+        let hash = crc32c::crc32c(block.as_bytes());
+        self.dir
+            .join(format!("{:02x}", (hash >> 8) & 0xff))
+            .join(format!("{:02x}", hash & 0xff))
+            .join(format!("{block}_{generation}"))
+    }
+    async fn move_to_corrupt(&self, path: &Path) -> io::Result<()> {
+        let corrupt_dir = self.dir.join(CORRUPT_DIR);
+        fs::create_dir_all(&corrupt_dir).await?;
+        let name = path.file_name().unwrap();
+        fs::rename(path, corrupt_dir.join(name)).await?;
+        let meta = meta_path(path);
+        match fs::rename(&meta, meta_path(&corrupt_dir.join(name))).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockWriter {
+    block: BlockId,
+    generation: u64,
+    volume: Arc<Volume>,
+    file: BufWriter<fs::File>,
+    tmp: PathBuf,
+    size: u64,
+    checksum: ChunkedChecksum,
+}
+impl BlockWriter {
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn checksum(&self) -> u32 {
+        self.checksum.checksum()
+    }
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.volume.observe(self.file.write_all(buf).await)?;
+        self.size += buf.len() as u64;
+        self.checksum.update(buf);
+        Ok(())
+    }
+    pub async fn suspend(mut self) -> io::Result<()> {
+        self.volume.observe(self.file.flush().await)?;
+        self.volume.observe(self.file.get_ref().sync_all().await)
+    }
+    pub async fn abort(self) -> io::Result<()> {
+        drop(self.file);
+        remove_if_exists(&self.tmp).await
+    }
+}
+
+pub async fn gc_tmp_files(tmp_dir: &Path, max_age: Duration) -> io::Result<usize> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for path in files_at_depth(tmp_dir, 0).await? {
+        let modified = fs::metadata(&path).await?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() < max_age {
+            continue;
+        }
+        remove_if_exists(&path).await?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+pub fn spawn_tmp_gc(tmp_dir: PathBuf, max_age: Duration, period: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let _ = gc_tmp_files(&tmp_dir, max_age).await;
+        }
+    })
+}
+
+pub fn spawn_volume_check(
+    store: StoreId,
+    block_store: Arc<Mutex<BlockStore>>,
+    control_tx: mpsc::UnboundedSender<ControlReq>,
+    period: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let removed = block_store.lock().await.check_volumes();
+            if removed.is_empty() {
+                continue;
+            }
+            let _ = control_tx.send(ControlReq::BlockReportReq(BlockReportReq {
+                store: store.clone(),
+                report: BlockReport::new(BlockReportType::Remove, removed),
+                chunk: None,
+            }));
+        }
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanReport {
+    pub blocks: BlockList,
+    pub suspect: Vec<BlockId>,
+}
+
+fn meta_path(block_path: &Path) -> PathBuf {
+    let mut path = block_path.as_os_str().to_owned();
+    path.push(META_SUFFIX);
+    PathBuf::from(path)
+}
+
+async fn read_meta(path: &Path) -> io::Result<BlockMeta> {
+    let buf = fs::read(path).await?;
+    bincode::deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_meta(path: &Path, meta: &BlockMeta) -> io::Result<()> {
+    let buf = bincode::serialize(meta).map_err(io::Error::other)?;
+    atomic_persist(path, buf).await
+}
+
+async fn checksum_file(file: &mut fs::File) -> io::Result<ChunkedChecksum> {
+    let mut checksum = ChunkedChecksum::new(CHECKSUM_CHUNK);
+    let mut buf = vec![0; READ_BUF];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(checksum);
+        }
+        checksum.update(&buf[..n]);
+    }
+}
+
+async fn create_file(path: &Path) -> io::Result<fs::File> {
+    fs::create_dir_all(path.parent().unwrap()).await?;
+    fs::File::create(path).await
+}
+
+async fn commit_block(tmp: &Path, path: &Path, meta: &BlockMeta) -> io::Result<()> {
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).await?;
+    // the meta file lands first so a visible block always has one
+    write_meta(&meta_path(path), meta).await?;
+    fs::rename(tmp, path).await?;
+    fs::File::open(dir).await?.sync_all().await
+}
+
+async fn remove_block_files(path: &Path) -> io::Result<()> {
+    remove_if_exists(path).await?;
+    remove_if_exists(&meta_path(path)).await
+}
+
+fn parse_block_file_name(name: &str) -> Option<(BlockId, u64)> {
+    let (block, generation) = name.rsplit_once('_')?;
+    let generation = generation.parse().ok()?;
+    Some((block.into(), generation))
+}
+
+async fn files_at_depth(dir: &Path, depth: usize) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = vec![(dir.to_path_buf(), 0)];
+    let mut files = vec![];
+    while let Some((dir, level)) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if level < depth && file_type.is_dir() {
+                dirs.push((entry.path(), level + 1));
+            } else if level == depth && file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+async fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
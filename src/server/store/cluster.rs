@@ -0,0 +1,53 @@
+use std::{fmt, io, path::Path};
+
+use crate::{fs::virt::atomic_persist, store::ClusterId};
+
+pub async fn load_cluster_id(path: impl AsRef<Path>) -> io::Result<Option<ClusterId>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(buf) => Ok(Some(buf.trim().into())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn accept_cluster_id(
+    path: impl AsRef<Path>,
+    cluster_id: &ClusterId,
+) -> Result<(), AcceptClusterIdError> {
+    let path = path.as_ref();
+    match load_cluster_id(path)
+        .await
+        .map_err(AcceptClusterIdError::Io)?
+    {
+        Some(persisted) if persisted != *cluster_id => {
+            Err(AcceptClusterIdError::Mismatch { persisted })
+        }
+        Some(_) => Ok(()),
+        None => atomic_persist(path, cluster_id.as_bytes().to_vec())
+            .await
+            .map_err(AcceptClusterIdError::Io),
+    }
+}
+#[derive(Debug)]
+pub enum AcceptClusterIdError {
+    Io(io::Error),
+    Mismatch { persisted: ClusterId },
+}
+impl fmt::Display for AcceptClusterIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Mismatch { persisted } => {
+                write!(f, "store belongs to cluster {persisted}")
+            }
+        }
+    }
+}
+impl std::error::Error for AcceptClusterIdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Mismatch { .. } => None,
+        }
+    }
+}
@@ -1,8 +1,120 @@
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
-use crate::store::StoreConfig;
+use crate::{server::config::FieldError, store::StoreConfig};
+
+const FULL_REPORT_CHUNK: usize = 100_000;
+const TMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+const MAX_REPLICATIONS: usize = 4;
+const BLOCK_LEASE_TIMEOUT: Duration = Duration::from_secs(60);
+const SCRUB_BYTES_PER_SEC: u64 = 5 * 1024 * 1024;
+const MAX_VOLUME_IO_ERRORS: u32 = 10;
+const CONTROL_ADDR: &str = "127.0.0.1:9000";
+const HEARTBEAT_INTERVAL_SECS: u64 = 3;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoreNodeConfig {
     pub config: StoreConfig,
+    #[serde(default = "default_control_addr")]
+    pub control_addr: SocketAddr,
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    pub data_dirs: Vec<DataDirConfig>,
+    #[serde(default = "default_max_volume_io_errors")]
+    pub max_volume_io_errors: u32,
+    #[serde(default = "default_full_report_chunk")]
+    pub full_report_chunk: usize,
+    #[serde(default = "default_tmp_max_age")]
+    pub tmp_max_age: Duration,
+    #[serde(default = "default_max_replications")]
+    pub max_replications: usize,
+    #[serde(default = "default_block_lease_timeout")]
+    pub block_lease_timeout: Duration,
+    #[serde(default = "default_scrub_bytes_per_sec")]
+    pub scrub_bytes_per_sec: u64,
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl StoreNodeConfig {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+    /// Reports every invalid field, qualified by its path under `store`.
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+        if self.data_dirs.is_empty() {
+            errors.push(FieldError::new("store.data_dirs", "must not be empty"));
+        }
+        let mut seen = HashMap::new();
+        for (i, dir) in self.data_dirs.iter().enumerate() {
+            if dir.capacity == 0 {
+                let field = format!("store.data_dirs[{i}].capacity");
+                errors.push(FieldError::new(field, "must be positive"));
+            }
+            if let Some(first) = seen.insert(&dir.path, i) {
+                let field = format!("store.data_dirs[{i}].path");
+                let reason = format!("duplicate of store.data_dirs[{first}].path");
+                errors.push(FieldError::new(field, reason));
+            }
+        }
+        // a temp file still under an open lease must not be collected
+        if self.tmp_max_age <= self.block_lease_timeout {
+            errors.push(FieldError::new(
+                "store.tmp_max_age",
+                "must be greater than block_lease_timeout",
+            ));
+        }
+        if self.full_report_chunk == 0 {
+            errors.push(FieldError::new(
+                "store.full_report_chunk",
+                "must be positive",
+            ));
+        }
+        if self.heartbeat_interval_secs == 0 {
+            errors.push(FieldError::new(
+                "store.heartbeat_interval_secs",
+                "must be positive",
+            ));
+        }
+        if self.max_replications == 0 {
+            errors.push(FieldError::new(
+                "store.max_replications",
+                "must be positive",
+            ));
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataDirConfig {
+    pub path: PathBuf,
+    pub capacity: u64,
+}
+
+fn default_control_addr() -> SocketAddr {
+    CONTROL_ADDR.parse().unwrap()
+}
+fn default_heartbeat_interval_secs() -> u64 {
+    HEARTBEAT_INTERVAL_SECS
+}
+fn default_full_report_chunk() -> usize {
+    FULL_REPORT_CHUNK
+}
+fn default_tmp_max_age() -> Duration {
+    TMP_MAX_AGE
+}
+fn default_max_replications() -> usize {
+    MAX_REPLICATIONS
+}
+fn default_block_lease_timeout() -> Duration {
+    BLOCK_LEASE_TIMEOUT
+}
+fn default_scrub_bytes_per_sec() -> u64 {
+    SCRUB_BYTES_PER_SEC
+}
+fn default_max_volume_io_errors() -> u32 {
+    MAX_VOLUME_IO_ERRORS
 }
@@ -1 +1,9 @@
+pub mod block_store;
+pub mod cluster;
 pub mod config;
+pub mod node;
+pub mod open_block;
+pub mod pipeline;
+pub mod replicator;
+pub mod scrubber;
+pub mod server;
@@ -0,0 +1,559 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+    time,
+};
+use tracing::{info, warn};
+
+use crate::{
+    client::{conn::Control, retry::RetryPolicy},
+    fs::{
+        block::{BlockBody, BlockId, BlockList, BlockReport, BlockReportType, ReportedBlock},
+        perm::Caller,
+    },
+    metrics,
+    proto::{
+        control::{BlockReportReq, ControlReq, ControlResp, ReportChunk},
+        format::WireFormat,
+        handshake, read_frame,
+        store::{
+            HeartbeatRejected, HeartbeatResp, RegisterStoreReq, RegisterStoreResp,
+            ReplicateBlockReq, StoreCommand, StoreProto,
+        },
+        write_frame,
+    },
+    store::{StoreAddr, StoreId},
+};
+
+use super::{
+    block_store::{spawn_tmp_gc, spawn_volume_check, BlockStore},
+    cluster::{accept_cluster_id, load_cluster_id},
+    config::StoreNodeConfig,
+    open_block::{spawn_block_lease_expiry, OpenBlockTable},
+    replicator::Replicator,
+    scrubber::Scrubber,
+    server::StoreServer,
+};
+
+const CLUSTER_ID_FILE: &str = "cluster_id";
+const SCRUB_CURSOR_FILE: &str = "scrub_cursor";
+/// How often temp files, idle block leases and volumes are checked.
+const HOUSEKEEPING_PERIOD: Duration = Duration::from_secs(60);
+
+/// A store node serving blocks on its configured address and keeping the
+/// control node up to date in the background.
+#[derive(Debug)]
+pub struct StoreNode {
+    id: StoreId,
+    addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
+    block_store: Arc<Mutex<BlockStore>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+impl StoreNode {
+    pub async fn start(config: &StoreNodeConfig) -> io::Result<Self> {
+        for dir in &config.data_dirs {
+            tokio::fs::create_dir_all(&dir.path).await?;
+        }
+        let id = config.config.id();
+        let mut block_store = BlockStore::new(&config.data_dirs, config.max_volume_io_errors);
+        let scan = block_store.scan().await?;
+        if !scan.suspect.is_empty() {
+            warn!(
+                suspect = scan.suspect.len(),
+                "replicas moved aside as corrupt"
+            );
+        }
+        info!(%id, blocks = scan.blocks.len(), "volumes scanned");
+        let tmp_dirs = block_store.tmp_dirs();
+        let block_store = Arc::new(Mutex::new(block_store));
+
+        let listener = TcpListener::bind(bind_addr(config.config.addr())).await?;
+        let addr = listener.local_addr()?;
+        info!(%addr, "store node listening");
+        // a configured port of 0 is only known once bound
+        let advertised = match config.config.addr() {
+            StoreAddr::Socket(configured) => {
+                StoreAddr::Socket((configured.ip(), addr.port()).into())
+            }
+            configured => configured.clone(),
+        };
+
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let replicator = Replicator::new(
+            id.clone(),
+            block_store.clone(),
+            config.max_replications,
+            control_tx.clone(),
+        );
+        let open_blocks = Arc::new(Mutex::new(OpenBlockTable::new()));
+        let server = StoreServer::new(block_store.clone(), open_blocks.clone(), replicator);
+        let metrics = server.metrics().clone();
+        let mut tasks = vec![tokio::spawn(async move {
+            if let Err(e) = server.serve(listener).await {
+                warn!("store listener failed: {e}");
+            }
+        })];
+        let mut metrics_addr = None;
+        if let Some(addr) = config.metrics_addr {
+            let listener = TcpListener::bind(addr).await?;
+            let addr = listener.local_addr()?;
+            info!(%addr, "serving metrics");
+            metrics_addr = Some(addr);
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = metrics::serve(listener, metrics).await {
+                    warn!("metrics listener failed: {e}");
+                }
+            }));
+        }
+        for tmp_dir in tmp_dirs {
+            tasks.push(spawn_tmp_gc(
+                tmp_dir,
+                config.tmp_max_age,
+                HOUSEKEEPING_PERIOD,
+            ));
+        }
+        tasks.push(spawn_block_lease_expiry(
+            open_blocks,
+            block_store.clone(),
+            config.block_lease_timeout,
+            HOUSEKEEPING_PERIOD,
+        ));
+        tasks.push(spawn_volume_check(
+            id.clone(),
+            block_store.clone(),
+            control_tx.clone(),
+            HOUSEKEEPING_PERIOD,
+        ));
+        // the first volume holds the node's own state
+        let state_dir = &config.data_dirs[0].path;
+        let scrubber = Scrubber::new(
+            id.clone(),
+            block_store.clone(),
+            control_tx,
+            config.scrub_bytes_per_sec,
+            state_dir.join(SCRUB_CURSOR_FILE),
+        );
+        tasks.push(scrubber.spawn());
+        let link = ControlLink {
+            id: id.clone(),
+            addr: advertised,
+            rack: config.config.rack().map(|rack| rack.to_string()),
+            control_addr: config.control_addr,
+            interval: config.heartbeat_interval(),
+            full_report_chunk: config.full_report_chunk,
+            cluster_id_path: state_dir.join(CLUSTER_ID_FILE),
+            block_store: block_store.clone(),
+        };
+        tasks.push(tokio::spawn(link.run(control_rx)));
+        Ok(Self {
+            id,
+            addr,
+            metrics_addr,
+            block_store,
+            tasks,
+        })
+    }
+    pub fn id(&self) -> &StoreId {
+        &self.id
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+    pub fn block_store(&self) -> &Arc<Mutex<BlockStore>> {
+        &self.block_store
+    }
+}
+impl Drop for StoreNode {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A host name can't be bound directly, so listen on every interface.
+fn bind_addr(addr: &StoreAddr) -> SocketAddr {
+    match addr {
+        StoreAddr::Socket(addr) => *addr,
+        StoreAddr::Host { port, .. } => (Ipv4Addr::UNSPECIFIED, *port).into(),
+    }
+}
+
+/// The store's side of its conversation with the control node: register,
+/// then heartbeat, carry out the commands that come back and report blocks
+/// as they come and go. Reports from the replicator, scrubber and volume
+/// check arrive on the channel and are passed along in between.
+#[derive(Debug)]
+struct ControlLink {
+    id: StoreId,
+    addr: StoreAddr,
+    rack: Option<String>,
+    control_addr: SocketAddr,
+    interval: Duration,
+    full_report_chunk: usize,
+    cluster_id_path: PathBuf,
+    block_store: Arc<Mutex<BlockStore>>,
+}
+/// What the control node has been told so far.
+#[derive(Debug, Default)]
+struct LinkState {
+    registered: bool,
+    reported: HashMap<BlockId, BlockBody>,
+    report_epoch: u64,
+}
+impl ControlLink {
+    async fn run(self, mut control_rx: mpsc::UnboundedReceiver<ControlReq>) {
+        let retry = RetryPolicy::default();
+        let control = loop {
+            match Control::connect_with(self.control_addr, Caller::anonymous(), WireFormat::Bincode)
+                .await
+            {
+                Ok(control) => break control,
+                Err(e) => {
+                    warn!(control = %self.control_addr, "cannot reach the control node: {e}");
+                    time::sleep(self.interval).await;
+                }
+            }
+        };
+        let mut state = LinkState::default();
+        let mut interval = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.tick(&control, &retry, &mut state).await {
+                        warn!("heartbeat failed: {e}");
+                        // the control node may have restarted without us
+                        state.registered = false;
+                    }
+                }
+                Some(req) = control_rx.recv() => {
+                    if let Err(e) = control.call(req, &retry).await {
+                        warn!("report to the control node failed: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn tick(
+        &self,
+        control: &Control,
+        retry: &RetryPolicy,
+        state: &mut LinkState,
+    ) -> io::Result<()> {
+        if !state.registered {
+            if !self.register(control, retry).await? {
+                return Ok(());
+            }
+            state.registered = true;
+        }
+        let heartbeat = self.block_store.lock().await.heartbeat(self.id.clone());
+        let ok = match control
+            .call(ControlReq::HeartbeatReq(heartbeat), retry)
+            .await?
+        {
+            ControlResp::HeartbeatResp(HeartbeatResp::Ok(ok)) => ok,
+            ControlResp::HeartbeatResp(HeartbeatResp::Rejected(
+                HeartbeatRejected::Unregistered,
+            )) => {
+                state.registered = false;
+                return Ok(());
+            }
+            ControlResp::HeartbeatResp(HeartbeatResp::Rejected(rejected)) => {
+                warn!(?rejected, "heartbeat refused");
+                return Ok(());
+            }
+            resp => return Err(unexpected(resp)),
+        };
+        for command in ok.commands {
+            self.run_command(command).await;
+        }
+        let current = self.current_blocks().await;
+        if ok.full_report {
+            self.full_report(control, retry, state, &current).await?;
+        } else {
+            let (added, removed) = diff_blocks(&state.reported, &current);
+            for (ty, body) in [
+                (BlockReportType::Remove, removed),
+                (BlockReportType::Add, added),
+            ] {
+                if body.is_empty() {
+                    continue;
+                }
+                self.report(control, retry, BlockReport::new(ty, body), None)
+                    .await?;
+            }
+        }
+        state.reported = current;
+        Ok(())
+    }
+
+    /// Whether the control node took us; a refusal is logged and retried on
+    /// the next tick.
+    async fn register(&self, control: &Control, retry: &RetryPolicy) -> io::Result<bool> {
+        let req = RegisterStoreReq {
+            store: self.id.clone(),
+            addr: self.addr.clone(),
+            rack: self.rack.clone(),
+            capacity: self.block_store.lock().await.usage().capacity,
+            cluster_id: load_cluster_id(&self.cluster_id_path).await?,
+        };
+        match control
+            .call(ControlReq::RegisterStoreReq(req), retry)
+            .await?
+        {
+            ControlResp::RegisterStoreResp(RegisterStoreResp::Ok { cluster_id }) => {
+                accept_cluster_id(&self.cluster_id_path, &cluster_id)
+                    .await
+                    .map_err(io::Error::other)?;
+                info!(%cluster_id, "registered with the control node");
+                Ok(true)
+            }
+            ControlResp::RegisterStoreResp(RegisterStoreResp::Rejected(rejected)) => {
+                warn!(?rejected, "registration refused");
+                Ok(false)
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    async fn current_blocks(&self) -> HashMap<BlockId, BlockBody> {
+        self.block_store
+            .lock()
+            .await
+            .report()
+            .blocks()
+            .iter()
+            .map(|block| (block.id().clone(), block.body().clone()))
+            .collect()
+    }
+
+    /// Sends every block, split into chunks of `full_report_chunk` if there
+    /// are more.
+    async fn full_report(
+        &self,
+        control: &Control,
+        retry: &RetryPolicy,
+        state: &mut LinkState,
+        current: &HashMap<BlockId, BlockBody>,
+    ) -> io::Result<()> {
+        let blocks: Vec<ReportedBlock> = current
+            .iter()
+            .map(|(id, body)| ReportedBlock::new(id.clone(), body.clone()))
+            .collect();
+        if blocks.len() <= self.full_report_chunk {
+            let report = BlockReport::new(BlockReportType::Full, block_list(&blocks));
+            return self.report(control, retry, report, None).await;
+        }
+        state.report_epoch += 1;
+        let chunks: Vec<&[ReportedBlock]> = blocks.chunks(self.full_report_chunk).collect();
+        for (seq, chunk) in chunks.iter().enumerate() {
+            let report = BlockReport::new(BlockReportType::Full, block_list(chunk));
+            let chunk = ReportChunk {
+                epoch: state.report_epoch,
+                seq: seq as u32,
+                last: seq + 1 == chunks.len(),
+            };
+            self.report(control, retry, report, Some(chunk)).await?;
+        }
+        Ok(())
+    }
+
+    async fn report(
+        &self,
+        control: &Control,
+        retry: &RetryPolicy,
+        report: BlockReport,
+        chunk: Option<ReportChunk>,
+    ) -> io::Result<()> {
+        let req = BlockReportReq {
+            store: self.id.clone(),
+            report,
+            chunk,
+        };
+        match control.call(ControlReq::BlockReportReq(req), retry).await? {
+            ControlResp::BlockReportResp(_) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    async fn run_command(&self, command: StoreCommand) {
+        match command {
+            StoreCommand::DeleteBlocks(blocks) => {
+                let mut block_store = self.block_store.lock().await;
+                for block in blocks {
+                    if let Err(e) = block_store.remove(&block).await {
+                        warn!(%block, "failed to delete a replica: {e}");
+                    }
+                }
+            }
+            // each target pulls the block from us and reports the outcome
+            // itself
+            StoreCommand::ReplicateBlock { block, targets } => {
+                for target in targets {
+                    let req = ReplicateBlockReq {
+                        block: block.clone(),
+                        store_addr: self.addr.clone(),
+                    };
+                    tokio::spawn(async move {
+                        if let Err(e) = ask_to_replicate(&target, req).await {
+                            warn!(%target, "failed to start a replication: {e}");
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+async fn ask_to_replicate(target: &StoreAddr, req: ReplicateBlockReq) -> io::Result<()> {
+    let (mut stream, _) = handshake::connect_store(target).await?;
+    write_frame(&mut stream, &StoreProto::ReplicateBlockReq(req)).await?;
+    match read_frame(&mut stream).await? {
+        StoreProto::ReplicateBlockResp(_) => Ok(()),
+        msg => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected store message: {msg:?}"),
+        )),
+    }
+}
+
+/// Replicas in `current` that are new or changed since `reported`, and
+/// those gone from it.
+pub fn diff_blocks(
+    reported: &HashMap<BlockId, BlockBody>,
+    current: &HashMap<BlockId, BlockBody>,
+) -> (BlockList, BlockList) {
+    let mut added = BlockList::new();
+    let mut removed = BlockList::new();
+    for (id, body) in current {
+        if reported.get(id) != Some(body) {
+            added.push(ReportedBlock::new(id.clone(), body.clone()));
+        }
+    }
+    for (id, body) in reported {
+        if !current.contains_key(id) {
+            removed.push(ReportedBlock::new(id.clone(), body.clone()));
+        }
+    }
+    (added, removed)
+}
+
+fn block_list(blocks: &[ReportedBlock]) -> BlockList {
+    let mut list = BlockList::new();
+    for block in blocks {
+        list.push(block.clone());
+    }
+    list
+}
+
+fn unexpected(resp: ControlResp) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response: {resp:?}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        client::DfsClient,
+        proto::control::{ListStoresReq, SafeModeAction, SafeModeReq},
+        server::control::{config::ControlNodeConfig, node::ControlNode},
+        testing::superuser,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn store_registers_reports_and_serves_over_the_network() {
+        let control_config: ControlNodeConfig = toml::from_str(
+            "addr = \"127.0.0.1:0\"\nmetrics_addr = \"127.0.0.1:0\"\nheartbeat_interval_secs = 1\ndefault_replication = 1\nstores = []\n",
+        )
+        .unwrap();
+        let control = ControlNode::start(&control_config).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let store_config: StoreNodeConfig = toml::from_str(&format!(
+            "control_addr = \"{}\"\nmetrics_addr = \"127.0.0.1:0\"\nheartbeat_interval_secs = 1\nconfig = {{ addr = \"127.0.0.1:0\", id = \"store-0\" }}\ndata_dirs = [{{ path = {:?}, capacity = 1073741824 }}]\n",
+            control.addr(),
+            dir.path(),
+        ))
+        .unwrap();
+        let store = StoreNode::start(&store_config).await.unwrap();
+
+        let client = DfsClient::connect_with(control.addr(), superuser(), WireFormat::Bincode)
+            .await
+            .unwrap();
+        // registered and heartbeating on its first tick
+        for _ in 0..100 {
+            let req = ControlReq::ListStoresReq(ListStoresReq {});
+            let resp = client.call(req).await.unwrap();
+            let ControlResp::ListStoresResp(list) = resp else {
+                panic!("{resp:?}");
+            };
+            if list.stores.iter().any(|summary| summary.alive) {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        let req = SafeModeReq {
+            action: SafeModeAction::Leave,
+        };
+        control
+            .handler()
+            .write()
+            .await
+            .handle_req(&superuser(), ControlReq::SafeModeReq(req));
+
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut writer = client.create("/f").await.unwrap();
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(store.block_store().lock().await.len(), 1);
+
+        let mut reader = client.open("/f").await.unwrap();
+        let mut read = vec![];
+        reader.read_to_end(&mut read).await.unwrap();
+        reader.close().await.unwrap();
+        assert_eq!(read, data);
+
+        let control_metrics = scrape(control.metrics_addr().unwrap()).await;
+        assert!(
+            control_metrics
+                .contains("dfs_control_requests_total{kind=\"heartbeat\",outcome=\"ok\"}"),
+            "{control_metrics}"
+        );
+        let store_metrics = scrape(store.metrics_addr().unwrap()).await;
+        assert!(
+            store_metrics.contains("dfs_store_bytes_written_total 100000"),
+            "{store_metrics}"
+        );
+    }
+
+    async fn scrape(addr: SocketAddr) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        resp
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    fs::{block::BlockId, virt::ClientId},
+};
+
+use super::block_store::BlockStore;
+
+#[derive(Debug, Clone)]
+pub struct OpenBlockTable {
+    map: HashMap<BlockId, OpenBlockAttribute>,
+    clock: Arc<dyn Clock>,
+}
+impl OpenBlockTable {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            map: HashMap::new(),
+            clock,
+        }
+    }
+    pub fn open(
+        &mut self,
+        block: BlockId,
+        generation: u64,
+        client: ClientId,
+        write: bool,
+    ) -> Result<(), OpenBlockExclusionError> {
+        let now = self.clock.now();
+        let Some(attr) = self.map.get_mut(&block) else {
+            self.map.insert(
+                block,
+                OpenBlockAttribute::new(generation, client, write, now),
+            );
+            return Ok(());
+        };
+        if attr.write || write {
+            return Err(OpenBlockExclusionError {
+                block,
+                held_for_write: attr.write,
+            });
+        }
+        attr.holders.insert(client, now);
+        Ok(())
+    }
+    pub fn lease(
+        &mut self,
+        block: &BlockId,
+        client: &ClientId,
+    ) -> Result<(), BlockLeaseNotFoundError> {
+        let now = self.clock.now();
+        let last_lease = self
+            .map
+            .get_mut(block)
+            .and_then(|attr| attr.holders.get_mut(client))
+            .ok_or(BlockLeaseNotFoundError)?;
+        *last_lease = now;
+        Ok(())
+    }
+    pub fn close(
+        &mut self,
+        block: &BlockId,
+        client: &ClientId,
+    ) -> Result<(), BlockLeaseNotFoundError> {
+        let Some(attr) = self.map.get_mut(block) else {
+            return Err(BlockLeaseNotFoundError);
+        };
+        attr.holders.remove(client).ok_or(BlockLeaseNotFoundError)?;
+        if attr.holders.is_empty() {
+            self.map.remove(block);
+        }
+        Ok(())
+    }
+    pub fn is_held_by(&self, block: &BlockId, client: &ClientId) -> bool {
+        self.map
+            .get(block)
+            .is_some_and(|attr| attr.holders.contains_key(client))
+    }
+    pub fn get(&self, block: &BlockId) -> Option<&OpenBlockAttribute> {
+        self.map.get(block)
+    }
+    pub fn clear_timeout(&mut self, ttl: Duration) -> Vec<ExpiredBlockLease> {
+        let now = self.clock.now();
+        let mut expired = vec![];
+        for (block, attr) in &mut self.map {
+            attr.holders.retain(|client, last_lease| {
+                if now.duration_since(*last_lease) <= ttl {
+                    return true;
+                }
+                expired.push(ExpiredBlockLease {
+                    block: block.clone(),
+                    generation: attr.generation,
+                    client: client.clone(),
+                    write: attr.write,
+                });
+                false
+            });
+        }
+        self.map.retain(|_, attr| !attr.holders.is_empty());
+        expired
+    }
+}
+impl Default for OpenBlockTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenBlockExclusionError {
+    pub block: BlockId,
+    pub held_for_write: bool,
+}
+impl fmt::Display for OpenBlockExclusionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.held_for_write { "write" } else { "read" };
+        write!(f, "block {} is held open for {mode}", self.block)
+    }
+}
+impl std::error::Error for OpenBlockExclusionError {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockLeaseNotFoundError;
+impl fmt::Display for BlockLeaseNotFoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no block lease held")
+    }
+}
+impl std::error::Error for BlockLeaseNotFoundError {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredBlockLease {
+    pub block: BlockId,
+    pub generation: u64,
+    pub client: ClientId,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenBlockAttribute {
+    generation: u64,
+    write: bool,
+    holders: HashMap<ClientId, Instant>,
+}
+impl OpenBlockAttribute {
+    pub fn new(generation: u64, client: ClientId, write: bool, now: Instant) -> Self {
+        Self {
+            generation,
+            write,
+            holders: HashMap::from_iter([(client, now)]),
+        }
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    pub fn write(&self) -> bool {
+        self.write
+    }
+    pub fn holders(&self) -> impl Iterator<Item = &ClientId> {
+        self.holders.keys()
+    }
+}
+
+pub async fn expire_block_leases(
+    open_blocks: &Mutex<OpenBlockTable>,
+    block_store: &Mutex<BlockStore>,
+    ttl: Duration,
+) -> io::Result<Vec<ExpiredBlockLease>> {
+    let expired = open_blocks.lock().await.clear_timeout(ttl);
+    let block_store = block_store.lock().await;
+    for lease in &expired {
+        if lease.write {
+            block_store
+                .discard_partial(&lease.block, lease.generation)
+                .await?;
+        }
+    }
+    Ok(expired)
+}
+
+pub fn spawn_block_lease_expiry(
+    open_blocks: Arc<Mutex<OpenBlockTable>>,
+    block_store: Arc<Mutex<BlockStore>>,
+    ttl: Duration,
+    period: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let _ = expire_block_leases(&open_blocks, &block_store, ttl).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{clock::ManualClock, server::store::config::DataDirConfig};
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn writer_excludes_everyone_until_it_closes() {
+        let mut table = OpenBlockTable::new();
+        let block: BlockId = "blk_1".into();
+        table.open(block.clone(), 0, "a".into(), true).unwrap();
+        let err = table.open(block.clone(), 0, "b".into(), true).unwrap_err();
+        assert!(err.held_for_write);
+        assert!(table.open(block.clone(), 0, "b".into(), false).is_err());
+        table.close(&block, &"a".into()).unwrap();
+        table.open(block.clone(), 0, "b".into(), false).unwrap();
+        table.open(block.clone(), 0, "c".into(), false).unwrap();
+        let err = table.open(block, 0, "a".into(), true).unwrap_err();
+        assert!(!err.held_for_write);
+    }
+
+    #[test]
+    fn renewed_lease_outlives_the_ttl() {
+        let clock = ManualClock::new();
+        let mut table = OpenBlockTable::with_clock(Arc::new(clock.clone()));
+        let block: BlockId = "blk_1".into();
+        table.open(block.clone(), 0, "a".into(), true).unwrap();
+        for _ in 0..4 {
+            clock.advance(TTL / 2);
+            table.lease(&block, &"a".into()).unwrap();
+            assert!(table.clear_timeout(TTL).is_empty());
+        }
+        assert!(table.is_held_by(&block, &"a".into()));
+    }
+
+    #[tokio::test]
+    async fn crashed_writer_gives_way_to_a_new_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDirConfig {
+            path: dir.path().to_path_buf(),
+            capacity: 1 << 30,
+        };
+        let mut block_store = BlockStore::new(&[data_dir], 10);
+        block_store.scan().await.unwrap();
+        let block_store = Mutex::new(block_store);
+        let clock = ManualClock::new();
+        let open_blocks = Mutex::new(OpenBlockTable::with_clock(Arc::new(clock.clone())));
+        let block: BlockId = "blk_1".into();
+
+        // the first writer gets partway and vanishes
+        let crashed: ClientId = "crashed".into();
+        let next: ClientId = "next".into();
+        let table = || open_blocks.try_lock().unwrap();
+        table()
+            .open(block.clone(), 0, crashed.clone(), true)
+            .unwrap();
+        let mut writer = block_store.lock().await.create(&block, 0).await.unwrap();
+        writer.write(b"partial").await.unwrap();
+        writer.suspend().await.unwrap();
+        assert!(table().open(block.clone(), 0, next.clone(), true).is_err());
+
+        clock.advance(TTL + Duration::from_secs(1));
+        let expired = expire_block_leases(&open_blocks, &block_store, TTL)
+            .await
+            .unwrap();
+        assert_eq!(
+            expired,
+            vec![ExpiredBlockLease {
+                block: block.clone(),
+                generation: 0,
+                client: crashed.clone(),
+                write: true,
+            }]
+        );
+        // the partial data can no longer be finalized or handed on
+        let partial = block_store.lock().await.open_partial(&block, 0).await;
+        assert_eq!(partial.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(table().lease(&block, &crashed).is_err());
+
+        table().open(block.clone(), 0, next.clone(), true).unwrap();
+        let mut writer = block_store.lock().await.create(&block, 0).await.unwrap();
+        writer.write(b"whole").await.unwrap();
+        let body = block_store.lock().await.finalize(writer).await.unwrap();
+        assert_eq!(body.size(), 5);
+        table().close(&block, &next).unwrap();
+    }
+}
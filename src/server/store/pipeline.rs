@@ -0,0 +1,380 @@
+use std::{
+    fmt,
+    io::{self, SeekFrom},
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    fs::block::BlockBody,
+    proto::{
+        handshake, read_frame,
+        store::{
+            BlockChunk, ChunkAck, ChunkAckStatus, OpenBlockReq, OpenBlockResp, ReadBlockReq,
+            ReadBlockResp, StoreProto, TransferPartialBlockReq,
+        },
+        write_frame,
+    },
+    store::StoreAddr,
+};
+
+use super::{
+    block_store::{BlockStore, BlockWriter},
+    open_block::OpenBlockTable,
+};
+
+const TRANSFER_CHUNK: usize = 64 * 1024;
+
+#[instrument(
+    skip_all,
+    fields(block = %req.block, client = %req.client, trace_id = req.trace_id.as_deref())
+)]
+pub async fn relay_block(
+    block_store: &Mutex<BlockStore>,
+    open_blocks: &Mutex<OpenBlockTable>,
+    req: OpenBlockReq,
+    upstream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<BlockBody, RelayError> {
+    let start = Instant::now();
+    let opened =
+        open_blocks
+            .lock()
+            .await
+            .open(req.block.clone(), req.generation, req.client.clone(), true);
+    if opened.is_err() {
+        let resp = OpenBlockResp {
+            permitted: false,
+            failed_target: None,
+        };
+        write_frame(upstream, &StoreProto::OpenBlockResp(resp)).await?;
+        return Err(RelayError::Excluded);
+    }
+    let res = relay_leased_block(block_store, open_blocks, &req, upstream).await;
+    let _ = open_blocks.lock().await.close(&req.block, &req.client);
+    match &res {
+        Ok(body) => info!(bytes = body.size(), elapsed = ?start.elapsed(), "block written"),
+        Err(e) => warn!(elapsed = ?start.elapsed(), "block write failed: {e}"),
+    }
+    res
+}
+
+async fn relay_leased_block(
+    block_store: &Mutex<BlockStore>,
+    open_blocks: &Mutex<OpenBlockTable>,
+    req: &OpenBlockReq,
+    upstream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<BlockBody, RelayError> {
+    let writer = {
+        let mut block_store = block_store.lock().await;
+        match req.resume {
+            Some(resume) => {
+                block_store
+                    .resume(
+                        &req.block,
+                        resume.from_generation,
+                        req.generation,
+                        resume.offset,
+                    )
+                    .await
+            }
+            None => block_store.create(&req.block, req.generation).await,
+        }
+    };
+    let mut writer = match writer {
+        Ok(writer) => writer,
+        Err(e) => {
+            let resp = OpenBlockResp {
+                permitted: false,
+                failed_target: None,
+            };
+            write_frame(upstream, &StoreProto::OpenBlockResp(resp)).await?;
+            return Err(RelayError::Io(e));
+        }
+    };
+    let mut downstream = None;
+    if let Some((next, rest)) = req.targets.split_first() {
+        match open_downstream(req, next, rest).await {
+            Ok(stream) => downstream = Some((next, stream)),
+            Err(failed) => {
+                writer.suspend().await?;
+                let resp = OpenBlockResp {
+                    permitted: false,
+                    failed_target: Some(failed.clone()),
+                };
+                write_frame(upstream, &StoreProto::OpenBlockResp(resp)).await?;
+                return Err(RelayError::DownstreamFailed(failed));
+            }
+        }
+    }
+    let resp = OpenBlockResp {
+        permitted: true,
+        failed_target: None,
+    };
+    write_frame(upstream, &StoreProto::OpenBlockResp(resp)).await?;
+
+    loop {
+        let chunk = match read_frame(upstream).await {
+            Ok(StoreProto::BlockChunk(chunk)) => chunk,
+            Ok(_) => {
+                writer.abort().await?;
+                return Err(RelayError::UnexpectedMessage);
+            }
+            Err(e) => {
+                // keep the partial data around for pipeline recovery
+                writer.suspend().await?;
+                return Err(RelayError::Io(e));
+            }
+        };
+        let offset = chunk.offset;
+        let leased = open_blocks.lock().await.lease(&req.block, &req.client);
+        if leased.is_err() {
+            // the lease timed out and the partial data is already gone
+            let _ = writer.abort().await;
+            let status = ChunkAckStatus::LocalFailed;
+            write_frame(upstream, &StoreProto::ChunkAck(ChunkAck { offset, status })).await?;
+            return Err(RelayError::LeaseExpired);
+        }
+        let status = match write_local(&mut writer, &chunk).await {
+            Ok(()) => match &mut downstream {
+                Some((next, stream)) => forward(next, stream, &chunk).await,
+                None => ChunkAckStatus::Ok,
+            },
+            Err(_) => ChunkAckStatus::LocalFailed,
+        };
+        match status {
+            ChunkAckStatus::Ok => (),
+            ChunkAckStatus::LocalFailed => {
+                writer.abort().await?;
+                write_frame(upstream, &StoreProto::ChunkAck(ChunkAck { offset, status })).await?;
+                return Err(RelayError::LocalFailed);
+            }
+            ChunkAckStatus::DownstreamFailed { ref target } => {
+                writer.suspend().await?;
+                let target = target.clone();
+                write_frame(upstream, &StoreProto::ChunkAck(ChunkAck { offset, status })).await?;
+                return Err(RelayError::DownstreamFailed(target));
+            }
+        }
+        if !chunk.last {
+            write_frame(upstream, &StoreProto::ChunkAck(ChunkAck { offset, status })).await?;
+            continue;
+        }
+        let body = block_store.lock().await.finalize(writer).await;
+        let status = match body {
+            Ok(_) => ChunkAckStatus::Ok,
+            Err(_) => ChunkAckStatus::LocalFailed,
+        };
+        write_frame(upstream, &StoreProto::ChunkAck(ChunkAck { offset, status })).await?;
+        return Ok(body?);
+    }
+}
+#[derive(Debug)]
+pub enum RelayError {
+    Io(io::Error),
+    Excluded,
+    LeaseExpired,
+    UnexpectedMessage,
+    LocalFailed,
+    DownstreamFailed(StoreAddr),
+}
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Excluded => write!(f, "block is held open by another writer"),
+            Self::LeaseExpired => write!(f, "block lease expired"),
+            Self::UnexpectedMessage => write!(f, "unexpected message in the write pipeline"),
+            Self::LocalFailed => write!(f, "local write failed"),
+            Self::DownstreamFailed(target) => write!(f, "downstream {target} failed"),
+        }
+    }
+}
+impl std::error::Error for RelayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for RelayError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+async fn open_downstream(
+    req: &OpenBlockReq,
+    next: &StoreAddr,
+    rest: &[StoreAddr],
+) -> Result<TcpStream, StoreAddr> {
+    let (mut stream, _) = handshake::connect_store(next)
+        .await
+        .map_err(|_| next.clone())?;
+    let open = OpenBlockReq {
+        client: req.client.clone(),
+        block: req.block.clone(),
+        generation: req.generation,
+        write: true,
+        targets: rest.to_vec(),
+        resume: req.resume,
+        trace_id: req.trace_id.clone(),
+    };
+    write_frame(&mut stream, &StoreProto::OpenBlockReq(open))
+        .await
+        .map_err(|_| next.clone())?;
+    match read_frame(&mut stream).await {
+        Ok(StoreProto::OpenBlockResp(OpenBlockResp {
+            permitted: true, ..
+        })) => Ok(stream),
+        Ok(StoreProto::OpenBlockResp(OpenBlockResp {
+            failed_target: Some(failed),
+            ..
+        })) => Err(failed),
+        _ => Err(next.clone()),
+    }
+}
+
+pub async fn transfer_partial_block(
+    block_store: &Mutex<BlockStore>,
+    req: TransferPartialBlockReq,
+) -> Result<(), RelayError> {
+    let mut file = block_store
+        .lock()
+        .await
+        .open_partial(&req.block, req.generation)
+        .await?;
+    let (mut stream, _) = handshake::connect_store(&req.target).await?;
+    let open = OpenBlockReq {
+        client: req.client,
+        block: req.block,
+        generation: req.new_generation,
+        write: true,
+        targets: vec![],
+        resume: None,
+        trace_id: None,
+    };
+    write_frame(&mut stream, &StoreProto::OpenBlockReq(open)).await?;
+    let StoreProto::OpenBlockResp(OpenBlockResp {
+        permitted: true, ..
+    }) = read_frame(&mut stream).await?
+    else {
+        return Err(RelayError::DownstreamFailed(req.target));
+    };
+    let mut offset = 0;
+    while offset < req.offset {
+        let len = (req.offset - offset).min(TRANSFER_CHUNK as u64) as usize;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data).await?;
+        let chunk = BlockChunk {
+            offset,
+            data,
+            last: false,
+        };
+        let ChunkAckStatus::Ok = forward(&req.target, &mut stream, &chunk).await else {
+            return Err(RelayError::DownstreamFailed(req.target));
+        };
+        offset += len as u64;
+    }
+    // the target keeps the partial replica once the stream closes; wait for
+    // it to hang up so the writer can reopen the block right away
+    stream.shutdown().await?;
+    let mut rest = vec![];
+    stream.read_to_end(&mut rest).await?;
+    Ok(())
+}
+
+#[instrument(
+    skip_all,
+    fields(block = %req.block, offset = req.offset, trace_id = req.trace_id.as_deref())
+)]
+pub async fn serve_block_read(
+    block_store: &Mutex<BlockStore>,
+    req: ReadBlockReq,
+    downstream: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<u64> {
+    let start = Instant::now();
+    let opened = {
+        let mut block_store = block_store.lock().await;
+        match block_store.get(&req.block).cloned() {
+            Some(body) => {
+                let meta = block_store.meta(&req.block).await?;
+                let file = block_store.open(&req.block).await?;
+                let body = block_store.get(&req.block).cloned().unwrap_or(body);
+                Some((body, meta, file))
+            }
+            None => None,
+        }
+    };
+    let Some((body, meta, mut file)) = opened else {
+        warn!("read of missing block");
+        let resp = ReadBlockResp {
+            body: None,
+            meta: None,
+        };
+        write_frame(downstream, &StoreProto::ReadBlockResp(resp)).await?;
+        return Ok(0);
+    };
+    let size = body.size();
+    // chunks line up with checksum chunks so readers can verify each one
+    let chunk_size = meta.chunk_size() as u64;
+    let mut offset = req.offset.min(size) / chunk_size * chunk_size;
+    let resp = ReadBlockResp {
+        body: Some(body),
+        meta: Some(meta),
+    };
+    write_frame(downstream, &StoreProto::ReadBlockResp(resp)).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let first = offset;
+    loop {
+        let len = (size - offset).min(chunk_size) as usize;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data).await?;
+        let last = offset + len as u64 == size;
+        let chunk = BlockChunk { offset, data, last };
+        write_frame(downstream, &StoreProto::BlockChunk(chunk)).await?;
+        offset += len as u64;
+        if last {
+            let bytes = size - first;
+            info!(bytes, elapsed = ?start.elapsed(), "block read");
+            return Ok(bytes);
+        }
+    }
+}
+
+async fn write_local(writer: &mut BlockWriter, chunk: &BlockChunk) -> io::Result<()> {
+    if chunk.offset != writer.size() {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    writer.write(&chunk.data).await
+}
+
+async fn forward(next: &StoreAddr, stream: &mut TcpStream, chunk: &BlockChunk) -> ChunkAckStatus {
+    if write_frame(stream, &StoreProto::BlockChunk(chunk.clone()))
+        .await
+        .is_err()
+    {
+        return ChunkAckStatus::DownstreamFailed {
+            target: next.clone(),
+        };
+    }
+    match read_frame(stream).await {
+        Ok(StoreProto::ChunkAck(ChunkAck {
+            status: ChunkAckStatus::Ok,
+            ..
+        })) => ChunkAckStatus::Ok,
+        Ok(StoreProto::ChunkAck(ChunkAck {
+            status: ChunkAckStatus::DownstreamFailed { target },
+            ..
+        })) => ChunkAckStatus::DownstreamFailed { target },
+        _ => ChunkAckStatus::DownstreamFailed {
+            target: next.clone(),
+        },
+    }
+}
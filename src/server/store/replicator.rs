@@ -0,0 +1,135 @@
+use std::{sync::Arc, time::Instant};
+
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    fs::block::{BlockBody, BlockList, BlockReport, BlockReportType, ReportedBlock},
+    proto::{
+        control::{BlockReportReq, ControlReq, ReplicationFailedReq},
+        handshake, read_frame,
+        store::{
+            ReadBlockReq, ReadBlockResp, ReplicateBlockFailure, ReplicateBlockReq,
+            ReplicateBlockResp, StoreProto,
+        },
+        write_frame,
+    },
+    store::StoreId,
+};
+
+use super::block_store::BlockStore;
+
+#[derive(Debug, Clone)]
+pub struct Replicator {
+    store: StoreId,
+    block_store: Arc<Mutex<BlockStore>>,
+    permits: Arc<Semaphore>,
+    control_tx: mpsc::UnboundedSender<ControlReq>,
+}
+impl Replicator {
+    pub fn new(
+        store: StoreId,
+        block_store: Arc<Mutex<BlockStore>>,
+        max_replications: usize,
+        control_tx: mpsc::UnboundedSender<ControlReq>,
+    ) -> Self {
+        Self {
+            store,
+            block_store,
+            permits: Arc::new(Semaphore::new(max_replications)),
+            control_tx,
+        }
+    }
+    #[instrument(skip_all, fields(block = %req.block, source = %req.store_addr))]
+    pub async fn handle(&self, req: ReplicateBlockReq) -> ReplicateBlockResp {
+        let Ok(_permit) = self.permits.clone().try_acquire_owned() else {
+            self.report_failure(&req);
+            return ReplicateBlockResp::Failed(ReplicateBlockFailure::Busy);
+        };
+        let start = Instant::now();
+        match self.pull(&req).await {
+            Ok(body) => {
+                info!(bytes = body.size(), elapsed = ?start.elapsed(), "block replicated");
+                let mut blocks = BlockList::new();
+                blocks.push(ReportedBlock::new(req.block, body));
+                let _ = self
+                    .control_tx
+                    .send(ControlReq::BlockReportReq(BlockReportReq {
+                        store: self.store.clone(),
+                        report: BlockReport::new(BlockReportType::Add, blocks),
+                        chunk: None,
+                    }));
+                ReplicateBlockResp::Ok
+            }
+            Err(failure) => {
+                warn!(?failure, elapsed = ?start.elapsed(), "block replication failed");
+                self.report_failure(&req);
+                ReplicateBlockResp::Failed(failure)
+            }
+        }
+    }
+
+    async fn pull(&self, req: &ReplicateBlockReq) -> Result<BlockBody, ReplicateBlockFailure> {
+        let (mut stream, _) = handshake::connect_store(&req.store_addr)
+            .await
+            .map_err(|_| ReplicateBlockFailure::SourceUnavailable)?;
+        let read = ReadBlockReq {
+            block: req.block.clone(),
+            offset: 0,
+            trace_id: None,
+        };
+        write_frame(&mut stream, &StoreProto::ReadBlockReq(read))
+            .await
+            .map_err(|_| ReplicateBlockFailure::SourceUnavailable)?;
+        let Ok(StoreProto::ReadBlockResp(ReadBlockResp {
+            body: Some(source), ..
+        })) = read_frame(&mut stream).await
+        else {
+            return Err(ReplicateBlockFailure::NotFound);
+        };
+        let mut writer = self
+            .block_store
+            .lock()
+            .await
+            .create(&req.block, source.generation())
+            .await
+            .map_err(|_| ReplicateBlockFailure::Io)?;
+        loop {
+            let chunk = match read_frame(&mut stream).await {
+                Ok(StoreProto::BlockChunk(chunk)) if chunk.offset == writer.size() => chunk,
+                _ => {
+                    let _ = writer.abort().await;
+                    return Err(ReplicateBlockFailure::SourceUnavailable);
+                }
+            };
+            if writer.write(&chunk.data).await.is_err() {
+                let _ = writer.abort().await;
+                return Err(ReplicateBlockFailure::Io);
+            }
+            if chunk.last {
+                break;
+            }
+        }
+        let copied =
+            BlockBody::with_checksum(writer.size(), writer.checksum(), source.generation());
+        if !source.agrees_with(&copied) {
+            let _ = writer.abort().await;
+            return Err(ReplicateBlockFailure::ChecksumMismatch);
+        }
+        self.block_store
+            .lock()
+            .await
+            .finalize(writer)
+            .await
+            .map_err(|_| ReplicateBlockFailure::Io)
+    }
+
+    fn report_failure(&self, req: &ReplicateBlockReq) {
+        let _ = self
+            .control_tx
+            .send(ControlReq::ReplicationFailedReq(ReplicationFailedReq {
+                store: self.store.clone(),
+                block: req.block.clone(),
+            }));
+    }
+}
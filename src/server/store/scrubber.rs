@@ -0,0 +1,121 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::AsyncReadExt,
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    fs::{
+        block::{BlockId, BlockList, BlockReport, BlockReportType, ReportedBlock},
+        virt::atomic_persist,
+    },
+    proto::control::{BlockReportReq, ControlReq},
+    store::StoreId,
+};
+
+use super::block_store::BlockStore;
+
+const READ_BUF: usize = 64 * 1024;
+const IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Scrubber {
+    store: StoreId,
+    block_store: Arc<Mutex<BlockStore>>,
+    control_tx: mpsc::UnboundedSender<ControlReq>,
+    bytes_per_sec: u64,
+    cursor_path: PathBuf,
+}
+impl Scrubber {
+    pub fn new(
+        store: StoreId,
+        block_store: Arc<Mutex<BlockStore>>,
+        control_tx: mpsc::UnboundedSender<ControlReq>,
+        bytes_per_sec: u64,
+        cursor_path: PathBuf,
+    ) -> Self {
+        Self {
+            store,
+            block_store,
+            control_tx,
+            bytes_per_sec,
+            cursor_path,
+        }
+    }
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move { self.run().await })
+    }
+
+    async fn run(self) {
+        let mut cursor: Option<BlockId> = tokio::fs::read_to_string(&self.cursor_path)
+            .await
+            .ok()
+            .map(|buf| buf.trim().into());
+        loop {
+            let next = self.block_store.lock().await.next_after(cursor.as_ref());
+            let Some(block) = next else {
+                // a full pass is done; start over after a pause
+                cursor = None;
+                tokio::time::sleep(IDLE).await;
+                continue;
+            };
+            let _ = self.scrub(&block).await;
+            let _ = atomic_persist(&self.cursor_path, block.as_bytes().to_vec()).await;
+            cursor = Some(block);
+        }
+    }
+
+    async fn scrub(&self, block: &BlockId) -> io::Result<()> {
+        let (body, mut file) = {
+            let block_store = self.block_store.lock().await;
+            let Some(body) = block_store.get(block).cloned() else {
+                return Ok(());
+            };
+            (body, block_store.open(block).await?)
+        };
+        let start = Instant::now();
+        let mut read = 0;
+        let mut checksum = 0;
+        let mut buf = vec![0; READ_BUF];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            checksum = crc32c::crc32c_append(checksum, &buf[..n]);
+            read += n as u64;
+            let due = Duration::from_secs_f64(read as f64 / self.bytes_per_sec as f64);
+            if let Some(ahead) = due.checked_sub(start.elapsed()) {
+                tokio::time::sleep(ahead).await;
+            }
+        }
+        let mut block_store = self.block_store.lock().await;
+        if block_store.get(block) != Some(&body) {
+            // rewritten or removed while we were reading
+            return Ok(());
+        }
+        let intact = read == body.size() && body.checksum().is_none_or(|c| c == checksum);
+        if intact {
+            block_store.set_checksum(block, body.generation(), checksum);
+            return Ok(());
+        }
+        block_store.quarantine(block).await?;
+        let mut blocks = BlockList::new();
+        blocks.push(ReportedBlock::new(block.clone(), body));
+        let _ = self
+            .control_tx
+            .send(ControlReq::BlockReportReq(BlockReportReq {
+                store: self.store.clone(),
+                report: BlockReport::new(BlockReportType::Remove, blocks),
+                chunk: None,
+            }));
+        Ok(())
+    }
+}
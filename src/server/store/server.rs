@@ -0,0 +1,136 @@
+use std::{io, sync::Arc};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::{
+    metrics::Metrics,
+    proto::{
+        format::WireFormat,
+        handshake, read_frame,
+        store::{StoreProto, TransferPartialBlockResp},
+        write_frame,
+    },
+};
+
+use super::{
+    block_store::BlockStore,
+    open_block::OpenBlockTable,
+    pipeline::{relay_block, serve_block_read, transfer_partial_block},
+    replicator::Replicator,
+};
+
+#[derive(Debug, Clone)]
+pub struct StoreServer {
+    block_store: Arc<Mutex<BlockStore>>,
+    open_blocks: Arc<Mutex<OpenBlockTable>>,
+    replicator: Replicator,
+    metrics: Arc<Metrics>,
+}
+impl StoreServer {
+    pub fn new(
+        block_store: Arc<Mutex<BlockStore>>,
+        open_blocks: Arc<Mutex<OpenBlockTable>>,
+        replicator: Replicator,
+    ) -> Self {
+        Self {
+            block_store,
+            open_blocks,
+            replicator,
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+    /// Lets several nodes in one process share a registry.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_conn(stream).await {
+                    warn!(%peer, "closing connection: {e}");
+                }
+            });
+        }
+    }
+    async fn serve_conn(&self, mut stream: TcpStream) -> io::Result<()> {
+        // block data stays compact; only control connections offer a debug format
+        handshake::accept(&mut stream, &[WireFormat::Bincode]).await?;
+        loop {
+            let msg = match read_frame(&mut stream).await {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match msg {
+                StoreProto::OpenBlockReq(req) => {
+                    // a write pipeline owns the rest of the connection
+                    let _active = self.transfer("write");
+                    let res =
+                        relay_block(&self.block_store, &self.open_blocks, req, &mut stream).await;
+                    if let Ok(body) = &res {
+                        self.metrics
+                            .add("dfs_store_bytes_written_total", &[], body.size());
+                    }
+                    return res.map(|_| ()).map_err(io::Error::other);
+                }
+                StoreProto::ReadBlockReq(req) => {
+                    let _active = self.transfer("read");
+                    let bytes = serve_block_read(&self.block_store, req, &mut stream).await?;
+                    self.metrics.add("dfs_store_bytes_read_total", &[], bytes);
+                }
+                StoreProto::TransferPartialBlockReq(req) => {
+                    let resp = match transfer_partial_block(&self.block_store, req).await {
+                        Ok(()) => TransferPartialBlockResp::Ok,
+                        Err(_) => TransferPartialBlockResp::Failed,
+                    };
+                    let resp = StoreProto::TransferPartialBlockResp(resp);
+                    write_frame(&mut stream, &resp).await?;
+                }
+                StoreProto::ReplicateBlockReq(req) => {
+                    let _active = self.transfer("replicate");
+                    let resp = StoreProto::ReplicateBlockResp(self.replicator.handle(req).await);
+                    write_frame(&mut stream, &resp).await?;
+                }
+                msg => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected message: {msg:?}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn transfer(&self, kind: &'static str) -> ActiveTransfer {
+        self.metrics
+            .inc("dfs_store_transfers_total", &[("kind", kind)]);
+        self.metrics
+            .add_gauge("dfs_store_active_transfers", &[("kind", kind)], 1.0);
+        ActiveTransfer {
+            metrics: self.metrics.clone(),
+            kind,
+        }
+    }
+}
+
+/// Counts towards `dfs_store_active_transfers` until dropped.
+struct ActiveTransfer {
+    metrics: Arc<Metrics>,
+    kind: &'static str,
+}
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        self.metrics
+            .add_gauge("dfs_store_active_transfers", &[("kind", self.kind)], -1.0);
+    }
+}
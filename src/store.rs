@@ -1,13 +1,26 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt, io,
     net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use serde::{Deserialize, Serialize};
 
+const STALE_HEARTBEATS: u32 = 3;
+
 pub type StoreId = Arc<str>;
+pub type ClusterId = Arc<str>;
+
+pub fn new_cluster_id() -> ClusterId {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("cid_{nanos:x}_{:x}", std::process::id()).into()
+}
 
 #[derive(Debug, Clone)]
 pub struct StoreStatusesMap {
@@ -19,13 +32,36 @@ impl StoreStatusesMap {
             map: HashMap::new(),
         }
     }
-    pub fn insert(&mut self, store: StoreId, config: StoreConfig) {
-        assert!(!self.map.contains_key(&store));
-        self.map.insert(store, StoreStatus::new(config));
+    pub fn insert(&mut self, store: StoreId, config: StoreConfig) -> Option<StoreStatus> {
+        self.map.insert(store, StoreStatus::new(config))
+    }
+    /// A store seen before keeps its admin state and maintenance window;
+    /// everything else starts over.
+    pub fn upsert(&mut self, store: StoreId, config: StoreConfig) {
+        let mut status = StoreStatus::new(config);
+        if let Some(old) = self.map.get(&store) {
+            status.admin_state = old.admin_state;
+            status.maintenance_until = old.maintenance_until;
+        }
+        self.map.insert(store, status);
+    }
+
+    pub fn get(&self, store: &StoreId) -> Option<&StoreStatus> {
+        self.map.get(store)
     }
     pub fn get_mut(&mut self, store: &StoreId) -> Option<&mut StoreStatus> {
         self.map.get_mut(store)
     }
+    pub fn iter(&self) -> impl Iterator<Item = (&StoreId, &StoreStatus)> {
+        self.map.iter()
+    }
+    pub fn dead(&self, ttl: Duration, now: Instant) -> impl Iterator<Item = &StoreId> {
+        self.map
+            .iter()
+            .filter(move |(_, status)| !status.is_alive(ttl, now))
+            .filter(move |(_, status)| !status.in_maintenance(now))
+            .map(|(store, _)| store)
+    }
 }
 impl Default for StoreStatusesMap {
     fn default() -> Self {
@@ -37,20 +73,78 @@ impl Default for StoreStatusesMap {
 pub struct StoreStatus {
     config: StoreConfig,
     last_heartbeat: Option<Instant>,
+    usage: StoreUsage,
+    awaiting_full_report: bool,
+    admin_state: StoreAdminState,
+    maintenance_until: Option<Instant>,
 }
 impl StoreStatus {
     pub fn new(config: StoreConfig) -> Self {
         Self {
             config,
             last_heartbeat: None,
+            usage: StoreUsage::default(),
+            awaiting_full_report: true,
+            admin_state: StoreAdminState::Normal,
+            maintenance_until: None,
         }
     }
+    pub fn maintenance_until(&self) -> Option<Instant> {
+        self.maintenance_until
+    }
+    pub fn in_maintenance(&self, now: Instant) -> bool {
+        self.maintenance_until.is_some_and(|until| now < until)
+    }
+    pub fn enter_maintenance(&mut self, until: Instant) {
+        self.maintenance_until = Some(until);
+    }
+    pub fn exit_maintenance(&mut self) {
+        self.maintenance_until = None;
+    }
+    pub fn admin_state(&self) -> StoreAdminState {
+        self.admin_state
+    }
+    pub fn set_admin_state(&mut self, admin_state: StoreAdminState) {
+        self.admin_state = admin_state;
+    }
     pub fn config(&self) -> &StoreConfig {
         &self.config
     }
+    pub fn set_config(&mut self, config: StoreConfig) {
+        self.config = config;
+    }
     pub fn beat(&mut self, now: Instant) {
         self.last_heartbeat = Some(now);
     }
+    pub fn usage(&self) -> &StoreUsage {
+        &self.usage
+    }
+    pub fn set_usage(&mut self, usage: StoreUsage) {
+        self.usage = usage;
+    }
+    pub fn remaining(&self) -> u64 {
+        self.usage.remaining
+    }
+    pub fn awaiting_full_report(&self) -> bool {
+        self.awaiting_full_report
+    }
+    pub fn set_awaiting_full_report(&mut self, awaiting_full_report: bool) {
+        self.awaiting_full_report = awaiting_full_report;
+    }
+
+    pub fn last_heartbeat(&self) -> Option<Instant> {
+        self.last_heartbeat
+    }
+    /// Stale once a few expected heartbeats are missed, dead after `dead_ttl`.
+    pub fn liveness(&self, interval: Duration, dead_ttl: Duration, now: Instant) -> StoreLiveness {
+        if !self.is_alive(dead_ttl, now) {
+            return StoreLiveness::Dead;
+        }
+        match self.is_alive(interval * STALE_HEARTBEATS, now) {
+            true => StoreLiveness::Alive,
+            false => StoreLiveness::Stale,
+        }
+    }
     pub fn is_alive(&self, ttl: Duration, now: Instant) -> bool {
         let Some(last_heartbeat) = self.last_heartbeat else {
             return false;
@@ -60,12 +154,267 @@ impl StoreStatus {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreAdminState {
+    Normal,
+    Decommissioning,
+    Decommissioned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreLiveness {
+    Alive,
+    Stale,
+    Dead,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreAdmission {
+    Admitted,
+    NotIncluded,
+    Excluded,
+}
+
+/// Entries match a store's address either as `host` or as `host:port`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostLists {
+    /// Empty admits every store.
+    pub include: HashSet<String>,
+    pub exclude: HashSet<String>,
+}
+impl HostLists {
+    pub fn admission(&self, addr: &StoreAddr) -> StoreAdmission {
+        let matches = |list: &HashSet<String>| {
+            list.contains(&addr.host()) || list.contains(&addr.to_string())
+        };
+        if !self.include.is_empty() && !matches(&self.include) {
+            StoreAdmission::NotIncluded
+        } else if matches(&self.exclude) {
+            StoreAdmission::Excluded
+        } else {
+            StoreAdmission::Admitted
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StoreUsage {
+    pub capacity: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub block_count: u64,
+    pub failed_volumes: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StoreConfig {
-    addr: SocketAddr,
+    #[serde(default)]
+    id: Option<StoreId>,
+    addr: StoreAddr,
+    #[serde(default)]
+    rack: Option<String>,
 }
 impl StoreConfig {
-    pub fn new(addr: SocketAddr) -> Self {
-        Self { addr }
+    pub fn new(addr: StoreAddr, rack: Option<String>) -> Self {
+        Self {
+            id: None,
+            addr,
+            rack,
+        }
+    }
+    pub fn with_id(mut self, id: StoreId) -> Self {
+        self.id = Some(id);
+        self
+    }
+    /// Defaults to the address.
+    pub fn id(&self) -> StoreId {
+        self.id
+            .clone()
+            .unwrap_or_else(|| self.addr.to_string().into())
+    }
+    pub fn addr(&self) -> &StoreAddr {
+        &self.addr
+    }
+    pub fn rack(&self) -> Option<&str> {
+        self.rack.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum StoreAddr {
+    Socket(SocketAddr),
+    Host { host: Arc<str>, port: u16 },
+}
+impl StoreAddr {
+    pub fn host(&self) -> String {
+        match self {
+            StoreAddr::Socket(addr) => addr.ip().to_string(),
+            StoreAddr::Host { host, .. } => host.to_string(),
+        }
+    }
+    pub fn port(&self) -> u16 {
+        match self {
+            StoreAddr::Socket(addr) => addr.port(),
+            StoreAddr::Host { port, .. } => *port,
+        }
+    }
+    pub async fn resolve(&self) -> io::Result<SocketAddr> {
+        let (host, port) = match self {
+            StoreAddr::Socket(addr) => return Ok(*addr),
+            StoreAddr::Host { host, port } => (host, *port),
+        };
+        tokio::net::lookup_host((host.as_ref(), port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{self} did not resolve"))
+            })
+    }
+}
+impl From<SocketAddr> for StoreAddr {
+    fn from(addr: SocketAddr) -> Self {
+        StoreAddr::Socket(addr)
+    }
+}
+impl FromStr for StoreAddr {
+    type Err = StoreAddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(StoreAddr::Socket(addr));
+        }
+        let err = || StoreAddrParseError(s.to_owned());
+        let (host, port) = s.rsplit_once(':').ok_or_else(err)?;
+        let port = port.parse().map_err(|_| err())?;
+        if host.is_empty() || host.contains([':', '[', ']', '/']) {
+            return Err(err());
+        }
+        Ok(StoreAddr::Host {
+            host: host.into(),
+            port,
+        })
+    }
+}
+impl TryFrom<String> for StoreAddr {
+    type Error = StoreAddrParseError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl From<StoreAddr> for String {
+    fn from(addr: StoreAddr) -> Self {
+        addr.to_string()
+    }
+}
+impl fmt::Display for StoreAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreAddr::Socket(addr) => write!(f, "{addr}"),
+            StoreAddr::Host { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreAddrParseError(String);
+impl fmt::Display for StoreAddrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid store address `{}`", self.0)
+    }
+}
+impl std::error::Error for StoreAddrParseError {}
+
+#[derive(Debug)]
+pub struct AddrResolver {
+    ttl: Duration,
+    cache: Mutex<HashMap<StoreAddr, (SocketAddr, Instant)>>,
+}
+impl AddrResolver {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+    pub async fn resolve(&self, addr: &StoreAddr) -> io::Result<SocketAddr> {
+        if let StoreAddr::Socket(addr) = addr {
+            return Ok(*addr);
+        }
+        let now = Instant::now();
+        if let Some(&(resolved, at)) = self.cache.lock().unwrap().get(addr) {
+            if now.duration_since(at) < self.ttl {
+                return Ok(resolved);
+            }
+        }
+        let resolved = addr.resolve().await?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(addr.clone(), (resolved, now));
+        Ok(resolved)
+    }
+    pub fn invalidate(&self, addr: &StoreAddr) {
+        self.cache.lock().unwrap().remove(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addr_parses_sockets_and_hosts() {
+        let socket: StoreAddr = "127.0.0.1:9000".parse().unwrap();
+        assert_eq!(socket, StoreAddr::Socket("127.0.0.1:9000".parse().unwrap()));
+        let v6: StoreAddr = "[::1]:9000".parse().unwrap();
+        assert!(matches!(v6, StoreAddr::Socket(_)));
+        let host: StoreAddr = "store-1.rack-a:9000".parse().unwrap();
+        assert_eq!(host.host(), "store-1.rack-a");
+        assert_eq!(host.port(), 9000);
+        assert_eq!(host.to_string(), "store-1.rack-a:9000");
+
+        for bad in ["store-1", ":9000", "store-1:http", "a/b:1", "::1:9000x"] {
+            assert!(bad.parse::<StoreAddr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn addr_serializes_as_a_string() {
+        for addr in ["10.0.0.1:9000", "[::1]:9000", "store-1:9000"] {
+            let parsed: StoreAddr = addr.parse().unwrap();
+            let json = serde_json::to_string(&parsed).unwrap();
+            assert_eq!(json, format!("\"{addr}\""));
+            assert_eq!(serde_json::from_str::<StoreAddr>(&json).unwrap(), parsed);
+        }
+        assert!(serde_json::from_str::<StoreAddr>("\"store-1\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn resolver_caches_until_the_ttl() {
+        let addr: StoreAddr = "store.invalid:9000".parse().unwrap();
+        let cached: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+
+        let resolver = AddrResolver::new(Duration::from_secs(60));
+        resolver
+            .cache
+            .lock()
+            .unwrap()
+            .insert(addr.clone(), (cached, Instant::now()));
+        assert_eq!(resolver.resolve(&addr).await.unwrap(), cached);
+        resolver.invalidate(&addr);
+        assert!(resolver.resolve(&addr).await.is_err());
+
+        let expired = AddrResolver::new(Duration::ZERO);
+        expired
+            .cache
+            .lock()
+            .unwrap()
+            .insert(addr.clone(), (cached, Instant::now()));
+        assert!(expired.resolve(&addr).await.is_err());
+
+        let socket = StoreAddr::Socket(cached);
+        assert_eq!(expired.resolve(&socket).await.unwrap(), cached);
+        let localhost: StoreAddr = "localhost:9000".parse().unwrap();
+        assert_eq!(resolver.resolve(&localhost).await.unwrap().port(), 9000);
     }
 }
@@ -0,0 +1,650 @@
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tempfile::TempDir;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::{mpsc, Mutex, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{
+    client::DfsClient,
+    clock::{Clock, ManualClock},
+    fs::{
+        block::{
+            BlockBody, BlockId, BlockIdGenerator, BlockReport, BlockReportType, ReplicatedBlocksMap,
+        },
+        inode::ROOT_INODE,
+        perm::{Caller, Permission, DEFAULT_SUPERUSER},
+        virt::{Directory, DirectoryAttribute, FsNode, FsNodeAttribute, FsNodeBody, OpenFileTable},
+    },
+    proto::{
+        control::{BlockReportReq, ControlReq},
+        format::WireFormat,
+        store::{HeartbeatResp, RegisterStoreReq, ReplicateBlockReq, StoreCommand},
+        MAX_FRAME,
+    },
+    server::{
+        control::{config::ControlSettings, handler::Handler, server},
+        store::{
+            block_store::BlockStore, config::DataDirConfig, node::diff_blocks,
+            open_block::OpenBlockTable, replicator::Replicator, server::StoreServer,
+        },
+    },
+    store::{StoreAddr, StoreId, StoreStatusesMap},
+};
+
+const STORE_CAPACITY: u64 = 1024 * 1024 * 1024;
+const MAX_IO_ERRORS: u32 = 10;
+const MAX_REPLICATIONS: usize = 4;
+const REPORT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// One control node and `n` stores in this process, on loopback ports.
+///
+/// Heartbeats, store commands and the control node's housekeeping only
+/// happen on [`MiniDfs::tick`], and the shared clock only moves on
+/// [`MiniDfs::advance_time`]. Block reports are the exception: each store
+/// sends them in the background every [`REPORT_INTERVAL`].
+#[derive(Debug)]
+pub struct MiniDfs {
+    clock: ManualClock,
+    handler: Arc<RwLock<Handler>>,
+    control_addr: SocketAddr,
+    control_task: JoinHandle<io::Result<()>>,
+    stores: Vec<MiniStore>,
+    _dir: TempDir,
+}
+#[derive(Debug)]
+struct MiniStore {
+    id: StoreId,
+    addr: SocketAddr,
+    block_store: Arc<Mutex<BlockStore>>,
+    replicator: Replicator,
+    server_task: Option<JoinHandle<io::Result<()>>>,
+    report_task: JoinHandle<()>,
+    forward_task: JoinHandle<()>,
+    paused: Arc<AtomicBool>,
+}
+impl MiniDfs {
+    pub async fn start(stores: usize) -> io::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let clock = ManualClock::new();
+        let handler = Arc::new(RwLock::new(handler(&clock)));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let control_addr = listener.local_addr()?;
+        let control_task = tokio::spawn(server::serve(listener, handler.clone(), MAX_FRAME));
+        let mut dfs = Self {
+            clock,
+            handler,
+            control_addr,
+            control_task,
+            stores: vec![],
+            _dir: dir,
+        };
+        for i in 0..stores {
+            let store = dfs.start_store(i).await?;
+            dfs.stores.push(store);
+        }
+        dfs.tick().await?;
+        Ok(dfs)
+    }
+
+    async fn start_store(&self, i: usize) -> io::Result<MiniStore> {
+        let id: StoreId = format!("store-{i}").into();
+        let data_dir = DataDirConfig {
+            path: self._dir.path().join(&*id),
+            capacity: STORE_CAPACITY,
+        };
+        tokio::fs::create_dir_all(&data_dir.path).await?;
+        let mut block_store = BlockStore::new(&[data_dir], MAX_IO_ERRORS);
+        block_store.scan().await?;
+        let block_store = Arc::new(Mutex::new(block_store));
+        let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+        let replicator = Replicator::new(
+            id.clone(),
+            block_store.clone(),
+            MAX_REPLICATIONS,
+            control_tx.clone(),
+        );
+        let paused = Arc::new(AtomicBool::new(false));
+        let report_task = tokio::spawn(report_blocks(
+            id.clone(),
+            block_store.clone(),
+            paused.clone(),
+            control_tx,
+        ));
+        let forward_task = {
+            let handler = self.handler.clone();
+            tokio::spawn(async move {
+                while let Some(msg) = control_rx.recv().await {
+                    handler.write().await.handle_req(&superuser(), msg);
+                }
+            })
+        };
+        let open_blocks = Arc::new(Mutex::new(OpenBlockTable::with_clock(Arc::new(
+            self.clock.clone(),
+        ))));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = StoreServer::new(block_store.clone(), open_blocks, replicator.clone());
+        let server_task = tokio::spawn(server.serve(listener));
+
+        let mut handler = self.handler.write().await;
+        handler.handle_register(RegisterStoreReq {
+            store: id.clone(),
+            addr: StoreAddr::Socket(addr),
+            rack: None,
+            capacity: STORE_CAPACITY,
+            cluster_id: None,
+        });
+        handler.handle_req(
+            &superuser(),
+            ControlReq::BlockReportReq(BlockReportReq {
+                store: id.clone(),
+                report: BlockReport::new(BlockReportType::Full, Default::default()),
+                chunk: None,
+            }),
+        );
+        Ok(MiniStore {
+            id,
+            addr,
+            block_store,
+            replicator,
+            server_task: Some(server_task),
+            report_task,
+            forward_task,
+            paused,
+        })
+    }
+
+    pub fn control_addr(&self) -> SocketAddr {
+        self.control_addr
+    }
+    /// Connects as the superuser.
+    pub async fn client(&self) -> io::Result<DfsClient> {
+        self.client_as(superuser()).await
+    }
+    pub async fn client_as(&self, caller: Caller) -> io::Result<DfsClient> {
+        DfsClient::connect_with(self.control_addr, caller, WireFormat::Bincode).await
+    }
+    pub fn handler(&self) -> &Arc<RwLock<Handler>> {
+        &self.handler
+    }
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+    pub fn store_id(&self, i: usize) -> &StoreId {
+        &self.stores[i].id
+    }
+    pub fn store_addr(&self, i: usize) -> SocketAddr {
+        self.stores[i].addr
+    }
+    pub async fn store_blocks(&self, i: usize) -> Vec<BlockId> {
+        let block_store = self.stores[i].block_store.lock().await;
+        block_store
+            .report()
+            .blocks()
+            .iter()
+            .map(|block| block.id().clone())
+            .collect()
+    }
+
+    /// Heartbeats from every running store, carries out the commands they
+    /// get back, then runs the control node's timer once.
+    pub async fn tick(&mut self) -> io::Result<()> {
+        for i in 0..self.stores.len() {
+            let store = &self.stores[i];
+            if store.server_task.is_none() || store.paused.load(Ordering::Relaxed) {
+                continue;
+            }
+            let heartbeat = store.block_store.lock().await.heartbeat(store.id.clone());
+            let resp = self.handler.write().await.handle_heartbeat(heartbeat);
+            let HeartbeatResp::Ok(ok) = resp else {
+                continue;
+            };
+            for command in ok.commands {
+                self.run_command(i, command).await?;
+            }
+        }
+        self.handler.write().await.handle_timer()?;
+        Ok(())
+    }
+
+    async fn run_command(&self, i: usize, command: StoreCommand) -> io::Result<()> {
+        let store = &self.stores[i];
+        match command {
+            StoreCommand::DeleteBlocks(blocks) => {
+                let mut block_store = store.block_store.lock().await;
+                for block in blocks {
+                    block_store.remove(&block).await?;
+                }
+            }
+            StoreCommand::ReplicateBlock { block, targets } => {
+                for target in targets {
+                    let Some(target) = self
+                        .stores
+                        .iter()
+                        .find(|other| StoreAddr::Socket(other.addr) == target)
+                    else {
+                        continue;
+                    };
+                    if target.server_task.is_none() {
+                        continue;
+                    }
+                    let req = ReplicateBlockReq {
+                        block: block.clone(),
+                        store_addr: StoreAddr::Socket(store.addr),
+                    };
+                    target.replicator.handle(req).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the shared clock forward, then [`MiniDfs::tick`]s.
+    pub async fn advance_time(&mut self, by: Duration) -> io::Result<()> {
+        self.clock.advance(by);
+        self.tick().await
+    }
+
+    /// Stops accepting connections, heartbeats and block reports for good.
+    /// Transfers already in flight run to completion.
+    pub fn kill_store(&mut self, i: usize) {
+        let store = &mut self.stores[i];
+        if let Some(task) = store.server_task.take() {
+            task.abort();
+        }
+        store.report_task.abort();
+    }
+    /// Keeps serving reads and writes but stops heartbeats and block reports.
+    pub fn pause_store(&mut self, i: usize) {
+        self.stores[i].paused.store(true, Ordering::Relaxed);
+    }
+    pub fn resume_store(&mut self, i: usize) {
+        self.stores[i].paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Flips the first byte of the replica on disk, leaving its recorded
+    /// checksum as it was.
+    pub async fn corrupt_block(&self, i: usize, block: &BlockId) -> io::Result<()> {
+        let block_store = self.stores[i].block_store.lock().await;
+        let path = block_store.block_path(block)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+        let mut first = [0];
+        file.read_exact(&mut first).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        file.write_all(&[!first[0]]).await?;
+        file.sync_all().await
+    }
+}
+impl Drop for MiniDfs {
+    fn drop(&mut self) {
+        self.control_task.abort();
+        for store in &mut self.stores {
+            if let Some(task) = store.server_task.take() {
+                task.abort();
+            }
+            store.report_task.abort();
+            store.forward_task.abort();
+        }
+    }
+}
+
+/// Stands in for the incremental reports a store sends as replicas come and
+/// go, by diffing its block map every [`REPORT_INTERVAL`].
+async fn report_blocks(
+    store: StoreId,
+    block_store: Arc<Mutex<BlockStore>>,
+    paused: Arc<AtomicBool>,
+    control_tx: mpsc::UnboundedSender<ControlReq>,
+) {
+    let mut reported: HashMap<BlockId, BlockBody> = HashMap::new();
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if paused.load(Ordering::Relaxed) {
+            continue;
+        }
+        let current: HashMap<BlockId, BlockBody> = block_store
+            .lock()
+            .await
+            .report()
+            .blocks()
+            .iter()
+            .map(|block| (block.id().clone(), block.body().clone()))
+            .collect();
+        let (added, removed) = diff_blocks(&reported, &current);
+        for (ty, body) in [
+            (BlockReportType::Remove, removed),
+            (BlockReportType::Add, added),
+        ] {
+            if body.is_empty() {
+                continue;
+            }
+            let req = BlockReportReq {
+                store: store.clone(),
+                report: BlockReport::new(ty, body),
+                chunk: None,
+            };
+            let _ = control_tx.send(ControlReq::BlockReportReq(req));
+        }
+        reported = current;
+    }
+}
+
+/// A control handler over an empty namespace with no stores, on `clock`.
+pub fn handler(clock: &ManualClock) -> Handler {
+    let root = FsNode::new(
+        FsNodeAttribute::new(ROOT_INODE, clock.system_now(), Permission::default()),
+        FsNodeBody::Directory(Directory::new(DirectoryAttribute::new())),
+    );
+    Handler::new(
+        root,
+        OpenFileTable::new(),
+        StoreStatusesMap::new(),
+        ReplicatedBlocksMap::new(),
+        BlockIdGenerator::new(),
+        ControlSettings::default(),
+        Arc::new(clock.clone()),
+    )
+}
+
+pub fn superuser() -> Caller {
+    Caller::new(DEFAULT_SUPERUSER.into(), vec![DEFAULT_SUPERUSER.into()])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use futures::TryStreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time,
+    };
+
+    use crate::{
+        proto::{
+            control::{
+                ControlResp, GetBlockLocationsReq, GetBlockLocationsResp, MkdirReq,
+                NamespaceEventKind, OpenReq, StatReq,
+            },
+            handshake, read_frame,
+            store::{OpenBlockReq, OpenBlockResp, StoreProto},
+            write_frame,
+        },
+        server::control::placement::RoundRobinPolicy,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn written_file_reads_back_from_every_replica() {
+        let mut dfs = MiniDfs::start(3).await.unwrap();
+        let client = dfs.client().await.unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut writer = client.create("/f").await.unwrap();
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut reader = client.open("/f").await.unwrap();
+        let mut read = vec![];
+        reader.read_to_end(&mut read).await.unwrap();
+        reader.close().await.unwrap();
+        assert_eq!(read, data);
+        assert_eq!(client.stat("/f").await.unwrap().len, data.len() as u64);
+
+        dfs.tick().await.unwrap();
+        let mut blocks = vec![];
+        for i in 0..3 {
+            let mut held = dfs.store_blocks(i).await;
+            held.sort();
+            blocks.push(held);
+        }
+        assert!(!blocks[0].is_empty());
+        assert!(blocks.iter().all(|held| *held == blocks[0]));
+        assert!(dfs.handler().read().await.validate().is_empty());
+    }
+
+    #[tokio::test]
+    async fn writer_adopts_the_control_node_block_size() {
+        let dfs = MiniDfs::start(1).await.unwrap();
+        dfs.handler().write().await.set_max_block_size(1000);
+        let client = dfs.client().await.unwrap();
+        let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let mut writer = client.create("/f").await.unwrap();
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let status = client.stat("/f").await.unwrap();
+        assert_eq!((status.len, status.block_count), (2500, 3));
+
+        // a later writer starts out at the size the first one learned
+        let mut writer = client.create("/g").await.unwrap();
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(client.stat("/g").await.unwrap().block_count, 3);
+
+        let mut reader = client.open("/f").await.unwrap();
+        let mut read = vec![];
+        reader.read_to_end(&mut read).await.unwrap();
+        reader.close().await.unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[tokio::test]
+    async fn write_survives_a_dead_middle_store() {
+        let mut dfs = MiniDfs::start(4).await.unwrap();
+        // round robin puts the first block on store-0, store-1 and store-2
+        let placement = Box::new(RoundRobinPolicy::new());
+        dfs.handler().write().await.set_placement_policy(placement);
+        dfs.kill_store(1);
+        let client = dfs.client().await.unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut writer = client.create("/f").await.unwrap();
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut replicas = vec![];
+        for _ in 0..100 {
+            dfs.tick().await.unwrap();
+            let req = GetBlockLocationsReq {
+                path: "/f".into(),
+                off_range: None,
+            };
+            let resp = client.call(ControlReq::GetBlockLocationsReq(req)).await;
+            let Ok(ControlResp::GetBlockLocationsResp(GetBlockLocationsResp::Ok(ok))) = resp else {
+                panic!("{resp:?}");
+            };
+            let [block] = &ok.blocks[..] else {
+                panic!("expected one block");
+            };
+            replicas = block.store_addrs.clone();
+            if replicas.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(REPORT_INTERVAL).await;
+        }
+        let expected = [0, 2, 3].map(|i| StoreAddr::Socket(dfs.store_addr(i)));
+        assert_eq!(replicas.len(), 3);
+        assert!(
+            replicas.iter().all(|addr| expected.contains(addr)),
+            "{replicas:?}"
+        );
+
+        let mut reader = client.open("/f").await.unwrap();
+        let mut read = vec![];
+        reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+    }
+
+    /// Everything logged while it is the default subscriber, without colors.
+    fn capture_logs() -> (
+        Arc<std::sync::Mutex<Vec<u8>>>,
+        tracing::subscriber::DefaultGuard,
+    ) {
+        #[derive(Clone)]
+        struct Writer(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl io::Write for Writer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let logs = Arc::new(std::sync::Mutex::new(vec![]));
+        let writer = Writer(logs.clone());
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_max_level(tracing::Level::INFO)
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[tokio::test]
+    async fn key_events_are_traced() {
+        let (logs, _guard) = capture_logs();
+        let mut dfs = MiniDfs::start(2).await.unwrap();
+        let client = dfs.client().await.unwrap();
+        client.set_trace_id(Some("trace-7".into()));
+
+        let mut writer = client.create("/f").await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let req = OpenReq {
+            client: "stalled".into(),
+            write: true,
+            create: true,
+            exclusive: false,
+            append: false,
+            overwrite: false,
+            path: "/open".into(),
+        };
+        client.call(ControlReq::OpenReq(req)).await.unwrap();
+        let req = StatReq {
+            path: "/missing".into(),
+        };
+        client.call(ControlReq::StatReq(req)).await.unwrap();
+        dfs.kill_store(1);
+        dfs.advance_time(Duration::from_secs(2 * 60 * 60))
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let lines = |needle: &str| -> Vec<&str> {
+            logs.lines().filter(|line| line.contains(needle)).collect()
+        };
+        let written = lines("block written");
+        assert!(!written.is_empty(), "{logs}");
+        assert!(written
+            .iter()
+            .all(|line| line.contains("trace_id=\"trace-7\"")));
+        let rejected = lines("request rejected");
+        assert!(
+            rejected.iter().any(|line| line.contains("kind=\"stat\"")
+                && line.contains("path=\"/missing\"")
+                && line.contains("trace_id=\"trace-7\"")),
+            "{logs}"
+        );
+        let expired = lines("lease expired");
+        assert!(
+            expired.iter().any(|line| line.contains("client=stalled")),
+            "{logs}"
+        );
+        let dead = lines("store is dead");
+        assert!(
+            dead.iter()
+                .any(|line| line.contains(dfs.store_id(1).as_ref())),
+            "{logs}"
+        );
+    }
+
+    #[tokio::test]
+    async fn namespace_events_stream_to_a_subscriber() {
+        let dfs = MiniDfs::start(1).await.unwrap();
+        let retention = Some(Duration::from_secs(60 * 60));
+        dfs.handler().write().await.set_trash_retention(retention);
+        let client = dfs.client().await.unwrap();
+        let mut events = pin!(client.subscribe_events("/a", 0));
+        let mut next = async || {
+            let next = time::timeout(Duration::from_secs(5), events.try_next());
+            next.await.unwrap().unwrap().unwrap().kind
+        };
+
+        // nothing matches yet, so the stream waits for the mkdir
+        let mkdir = async {
+            time::sleep(Duration::from_millis(50)).await;
+            let req = MkdirReq {
+                path: "/a".into(),
+                create_parents: false,
+            };
+            client.call(ControlReq::MkdirReq(req)).await.unwrap();
+        };
+        let (event, ()) = tokio::join!(next(), mkdir);
+        assert!(matches!(event, NamespaceEventKind::Create { path, is_dir: true } if path == "/a"));
+
+        let mut writer = client.create("/a/f").await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let event = next().await;
+        assert!(
+            matches!(event, NamespaceEventKind::Create { path, is_dir: false } if path == "/a/f")
+        );
+        assert!(matches!(next().await, NamespaceEventKind::Close { path } if path == "/a/f"));
+
+        // the move into the trash is reported as what it is to /a
+        client.delete("/a/f", false).await.unwrap();
+        assert!(matches!(next().await, NamespaceEventKind::Delete { path } if path == "/a/f"));
+    }
+
+    #[tokio::test]
+    async fn write_of_a_path_like_block_id_is_refused() {
+        let dfs = MiniDfs::start(1).await.unwrap();
+        let (mut stream, _) = handshake::connect(dfs.store_addr(0)).await.unwrap();
+        let req = OpenBlockReq {
+            client: "c".into(),
+            block: "../x".into(),
+            generation: 0,
+            write: true,
+            targets: vec![],
+            resume: None,
+            trace_id: None,
+        };
+        write_frame(&mut stream, &StoreProto::OpenBlockReq(req))
+            .await
+            .unwrap();
+        let resp = read_frame(&mut stream).await.unwrap();
+        assert!(matches!(
+            resp,
+            StoreProto::OpenBlockResp(OpenBlockResp {
+                permitted: false,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_of_a_path_like_block_id_is_refused() {
+        let dfs = MiniDfs::start(1).await.unwrap();
+        let mut block_store = dfs.stores[0].block_store.lock().await;
+        let block: BlockId = "../x".into();
+        let invalid = |e: io::Error| e.kind() == io::ErrorKind::InvalidInput;
+        assert!(block_store.block_path(&block).is_err_and(invalid));
+        assert!(block_store.open(&block).await.is_err_and(invalid));
+        assert!(block_store.meta(&block).await.is_err_and(invalid));
+    }
+}
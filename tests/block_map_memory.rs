@@ -0,0 +1,26 @@
+use std::mem;
+
+use dfs::fs::block::ReplicatedBlock;
+
+mod common;
+
+/// Just past a growth of the block table, where it is at its emptiest.
+const BLOCKS: usize = 120_000;
+
+/// Not counting the id strings, which are shared with the namespace.
+#[test]
+fn block_map_bytes_per_block() {
+    // replicas are inline, not a heap allocation of their own
+    assert!(mem::size_of::<ReplicatedBlock>() <= 56);
+
+    let ids = common::block_ids(BLOCKS);
+    let stores = common::store_ids();
+    let before = common::live_bytes();
+    let map = common::fill(&ids, &stores);
+    let bytes = common::live_bytes() - before;
+    assert_eq!(map.len(), BLOCKS);
+    // 275 measured, down from 357 with a Vec of store ids and a path per
+    // block
+    let per_block = bytes / BLOCKS;
+    assert!(per_block <= 276, "{per_block} bytes per block");
+}
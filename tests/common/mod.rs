@@ -0,0 +1,63 @@
+//! Heap accounting for the block map, shared by its memory test and bench.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use dfs::{
+    fs::{
+        block::{BlockBody, BlockId, ReplicatedBlock, ReplicatedBlocksMap, ReportedBlock},
+        inode::{InodeId, ROOT_INODE},
+    },
+    store::StoreId,
+};
+
+pub const BLOCKS_PER_FILE: usize = 8;
+pub const REPLICATION: usize = 3;
+pub const STORES: usize = 100;
+
+/// Counts the bytes live on the heap.
+struct Counting;
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+pub fn live_bytes() -> usize {
+    LIVE.load(Ordering::Relaxed)
+}
+
+pub fn block_ids(blocks: usize) -> Vec<BlockId> {
+    (0..blocks).map(|i| format!("blk_{i:010}").into()).collect()
+}
+pub fn store_ids() -> Vec<StoreId> {
+    (0..STORES).map(|i| format!("store-{i}").into()).collect()
+}
+
+/// One unsharded map holding every block in `ids` on [`REPLICATION`]
+/// stores.
+pub fn fill(ids: &[BlockId], stores: &[StoreId]) -> ReplicatedBlocksMap {
+    let mut map = ReplicatedBlocksMap::with_shards(NonZeroUsize::MIN);
+    for (i, id) in ids.iter().enumerate() {
+        let inode = ROOT_INODE + 1 + (i / BLOCKS_PER_FILE) as InodeId;
+        let block = ReplicatedBlock::new(BlockBody::new(1, 0), inode);
+        map.insert(id.clone(), block).unwrap();
+        for r in 0..REPLICATION {
+            let store = stores[(i + r) % STORES].clone();
+            let reported = ReportedBlock::new(id.clone(), BlockBody::new(1, 0));
+            map.push_store(store, reported).unwrap();
+        }
+    }
+    map
+}